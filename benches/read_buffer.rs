@@ -0,0 +1,93 @@
+//! Compares decoding messages with different read buffer sizes. The input returns as many bytes
+//! as the buffer has room for, like a socket with data waiting, so the number of reads shows how
+//! many system calls each size would take.
+//!
+//! Run with `cargo bench --bench read_buffer`.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::prelude::*;
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use tokio_util::codec::FramedRead;
+
+use gluon_language_server::{
+    rpc::{write_message_str, LanguageServerDecoder},
+    STDIO_READ_BUFFER_SIZE, TCP_READ_BUFFER_SIZE,
+};
+
+/// Reads from a slice and counts the reads
+struct CountingReader<'a> {
+    input: &'a [u8],
+    reads: usize,
+}
+
+impl AsyncRead for CountingReader<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.reads += 1;
+        Pin::new(&mut self.input).poll_read(cx, buf)
+    }
+}
+
+/// Frames `count` copies of `message`
+fn input(message: &str, count: usize) -> Vec<u8> {
+    let mut input = Vec::new();
+    for _ in 0..count {
+        write_message_str(&mut input, message).unwrap();
+    }
+    input
+}
+
+/// Decodes every message of `input` with a buffer of `capacity` bytes and returns the number of
+/// reads and the time it took
+fn decode(runtime: &tokio::runtime::Runtime, input: &[u8], capacity: usize) -> (usize, Duration) {
+    let mut reader = CountingReader { input, reads: 0 };
+    let start = Instant::now();
+    runtime.block_on(async {
+        let mut messages =
+            FramedRead::with_capacity(&mut reader, LanguageServerDecoder::new(), capacity);
+        while let Some(message) = messages.next().await {
+            message.unwrap();
+        }
+    });
+    (reader.reads, start.elapsed())
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    // A `textDocument/didChange` notification of a few characters
+    let small = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///project/main.glu","version":12},"contentChanges":[{"range":{"start":{"line":3,"character":4},"end":{"line":3,"character":4}},"text":"x"}]}}"#;
+    // A `textDocument/didOpen` notification of a large module
+    let large = format!(
+        r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///project/main.glu","languageId":"gluon","version":1,"text":"{}"}}}}}}"#,
+        "let x = 1\\n".repeat(100_000)
+    );
+
+    let inputs = [
+        ("100k small messages", input(small, 100_000)),
+        ("50 1MB messages", input(&large, 50)),
+    ];
+    for (name, input) in &inputs {
+        for &capacity in &[1024, STDIO_READ_BUFFER_SIZE, TCP_READ_BUFFER_SIZE] {
+            let (reads, elapsed) = decode(&runtime, input, capacity);
+            let throughput = input.len() as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+            println!(
+                "{}, {:>5} byte buffer: {:>7} reads, {:>8.1} MB/s",
+                name, capacity, reads, throughput
+            );
+        }
+    }
+}
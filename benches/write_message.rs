@@ -0,0 +1,183 @@
+//! Compares the allocations and time needed to frame messages with `write_message_str`,
+//! `write_message_into` with a reused buffer and `LanguageServerEncoder`. Also compares the
+//! peak memory of framing a large response from its JSON string with serializing it straight into
+//! the output.
+//!
+//! Run with `cargo bench --bench write_message`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+
+use tokio_util::codec::Encoder;
+
+use lsp_types::{SemanticToken, SemanticTokens};
+
+use gluon_language_server::rpc::{
+    write_message, write_message_into, write_message_str, LanguageServerEncoder, OutgoingMessage,
+};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+/// The bytes which are currently allocated and the most which have been allocated at once
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn allocated(bytes: usize) {
+    let allocated = ALLOCATED.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    PEAK.fetch_max(allocated, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        allocated(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        allocated(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const MESSAGES: usize = 100_000;
+
+/// Runs `f` once for each message and returns the allocations per message and the total time
+fn measure(mut f: impl FnMut(&str)) -> (f64, Duration) {
+    // Roughly the size of a `textDocument/semanticTokens/full` response of a small module
+    let message = format!(
+        r#"{{"jsonrpc":"2.0","id":1,"result":{{"data":[{}]}}}}"#,
+        vec!["0,4,3,1,0"; 200].join(",")
+    );
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..MESSAGES {
+        f(&message);
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    (allocations as f64 / MESSAGES as f64, elapsed)
+}
+
+/// Returns the most memory which `f` had allocated at once, on top of what was already allocated
+fn peak_memory(f: impl FnOnce()) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    f();
+    PEAK.load(Ordering::Relaxed) - before
+}
+
+/// A `textDocument/semanticTokens/full` response with 10k tokens
+fn semantic_tokens_response() -> OutgoingMessage {
+    let token = SemanticToken {
+        delta_line: 1,
+        delta_start: 4,
+        length: 12,
+        token_type: 3,
+        token_modifiers_bitset: 1,
+    };
+    let tokens = SemanticTokens {
+        result_id: None,
+        data: vec![token; 10_000],
+    };
+    OutgoingMessage::Response {
+        id: jsonrpc_core::Id::Num(1),
+        result: Ok(serde_json::to_value(tokens).unwrap()),
+    }
+}
+
+fn main() {
+    let (allocations, elapsed) = measure(|message| {
+        write_message_str(io::sink(), message).unwrap();
+    });
+    println!(
+        "write_message_str:                {:.2} allocations/message, {:?}",
+        allocations, elapsed
+    );
+
+    let mut buf = String::new();
+    let (allocations, elapsed) = measure(|message| {
+        buf.clear();
+        write_message_into(&mut buf, message);
+        io::Write::write_all(&mut io::sink(), buf.as_bytes()).unwrap();
+    });
+    println!(
+        "write_message_into (reused buf):  {:.2} allocations/message, {:?}",
+        allocations, elapsed
+    );
+
+    // The encoder takes its items by value so copying the message into one is measured as well
+    let mut encoder = LanguageServerEncoder::new();
+    let mut dst = BytesMut::new();
+    let (allocations, elapsed) = measure(|message| {
+        dst.clear();
+        encoder.encode(message.to_string(), &mut dst).unwrap();
+    });
+    println!(
+        "encoder (reused dst, owned item): {:.2} allocations/message, {:?}",
+        allocations, elapsed
+    );
+
+    let response = semantic_tokens_response();
+    // How the encoder used to frame messages, through a buffer which it kept between messages
+    let peak = peak_memory(|| {
+        let message = response.to_string();
+        let mut buf = String::new();
+        let mut dst = BytesMut::new();
+        write_message_into(&mut buf, &message);
+        dst.extend_from_slice(buf.as_bytes());
+    });
+    println!(
+        "10k semantic tokens, string framed through a buffer: {} bytes peak",
+        peak
+    );
+
+    let peak = peak_memory(|| {
+        let mut dst = BytesMut::new();
+        LanguageServerEncoder::new()
+            .encode(response.to_string(), &mut dst)
+            .unwrap();
+    });
+    println!(
+        "10k semantic tokens, string encoded:                 {} bytes peak",
+        peak
+    );
+
+    let message = response.clone();
+    let peak = peak_memory(|| {
+        let mut dst = BytesMut::new();
+        LanguageServerEncoder::new()
+            .encode(message, &mut dst)
+            .unwrap();
+    });
+    println!(
+        "10k semantic tokens, message encoded:                {} bytes peak",
+        peak
+    );
+
+    let peak = peak_memory(|| {
+        write_message(io::sink(), &response).unwrap();
+    });
+    println!(
+        "10k semantic tokens, write_message:                  {} bytes peak",
+        peak
+    );
+}
@@ -1,4 +1,4 @@
-use std::{env, io, path::Path, process::Command};
+use std::{env, fs, io, path::Path, process::Command};
 
 fn main() {
     let git_commit = env::var("GIT_COMMIT")
@@ -12,9 +12,53 @@ fn main() {
         println!("cargo:rustc-env=GIT_COMMIT={}", git_commit);
     }
 
-    const EDIT_MSG: &str = "../.git/COMMIT_EDITMSG";
+    if let Some(gluon_version) = gluon_version() {
+        println!("cargo:rustc-env=GLUON_VERSION={}", gluon_version);
+    }
+
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    const EDIT_MSG: &str = ".git/COMMIT_EDITMSG";
     if Path::new(EDIT_MSG).exists() {
         // This is the closest thing to making sure we rebuild this every time a new commit is made
         println!("cargo:rerun-if-changed={}", EDIT_MSG);
     }
 }
+
+/// Looks up the version of gluon that was locked in `Cargo.lock`. Without a lockfile, such as when
+/// the server is built as a dependency of another package, it is the version which `Cargo.toml`
+/// requires, prefixed with `^`.
+fn gluon_version() -> Option<String> {
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    match fs::read_to_string("Cargo.lock") {
+        Ok(lock_file) => {
+            println!("cargo:rerun-if-changed=Cargo.lock");
+            locked_version(&lock_file)
+        }
+        Err(_) => {
+            let manifest = fs::read_to_string("Cargo.toml").ok()?;
+            required_version(&manifest).map(|version| format!("^{}", version))
+        }
+    }
+}
+
+fn locked_version(lock_file: &str) -> Option<String> {
+    let mut lines = lock_file.lines();
+    while let Some(line) = lines.next() {
+        if line == r#"name = "gluon""# {
+            let version = lines.next()?.strip_prefix("version = ")?;
+            return Some(version.trim_matches('"').to_string());
+        }
+    }
+    None
+}
+
+fn required_version(manifest: &str) -> Option<String> {
+    let dependency = manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("gluon = "))?;
+    let version = match dependency.find("version = ") {
+        Some(start) => &dependency[start + "version = ".len()..],
+        None => dependency,
+    };
+    version.split('"').nth(1).map(String::from)
+}
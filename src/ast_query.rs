@@ -0,0 +1,252 @@
+//! Finds the nodes of a module's syntax tree at a position or in a range of its source.
+//!
+//! Nodes which lie outside `source_span` (the span of the module's file) are never returned as
+//! they were introduced by macros (such as the implicit prelude) and do not exist in the source.
+
+use std::cmp::Ordering;
+
+use gluon::base::{
+    ast::{self, AstType, Expr, SpannedExpr, SpannedIdent, SpannedPattern, ValueBinding, Visitor},
+    pos::{BytePos, HasSpan, Span},
+    symbol::Symbol,
+};
+
+/// A node of the AST which the cursor can be on
+#[derive(Clone, Copy)]
+pub enum Node<'a, 'ast> {
+    Expr(&'a SpannedExpr<'ast, Symbol>),
+    Pattern(&'a SpannedPattern<'ast, Symbol>),
+    /// The argument of a function
+    Argument(&'a SpannedIdent<Symbol>),
+    Type(&'a AstType<'ast, Symbol>),
+    Binding(&'a ValueBinding<'ast, Symbol>),
+}
+
+impl Node<'_, '_> {
+    pub fn span(&self) -> Span<BytePos> {
+        match *self {
+            Node::Expr(expr) => expr.span,
+            Node::Pattern(pattern) => pattern.span,
+            Node::Argument(arg) => arg.span,
+            Node::Type(typ) => typ.span(),
+            Node::Binding(binding) => binding.span(),
+        }
+    }
+}
+
+/// Whether `span` and `range` share at least one byte. An empty `range` (such as a cursor)
+/// overlaps the spans which contain it, including those which start or end at it.
+pub fn span_overlaps_range(span: Span<BytePos>, range: Span<BytePos>) -> bool {
+    if range.start() == range.end() {
+        span.containment(range.start()) == Ordering::Equal
+    } else {
+        span.start() < range.end() && range.start() < span.end()
+    }
+}
+
+/// Collects every node for which `select` returns `true`, in the order they are visited
+struct CollectNodes<'a, 'ast, F> {
+    source_span: Span<BytePos>,
+    select: F,
+    nodes: Vec<Node<'a, 'ast>>,
+}
+
+impl<'a, 'ast, F> CollectNodes<'a, 'ast, F>
+where
+    F: FnMut(Span<BytePos>) -> bool,
+{
+    fn push(&mut self, node: Node<'a, 'ast>) {
+        let span = node.span();
+        if self.source_span.contains(span) && (self.select)(span) {
+            self.nodes.push(node);
+        }
+    }
+}
+
+impl<'a, 'ast, F> Visitor<'a, 'ast> for CollectNodes<'a, 'ast, F>
+where
+    F: FnMut(Span<BytePos>) -> bool,
+{
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        self.push(Node::Expr(e));
+        match &e.value {
+            Expr::LetBindings(bindings, _) => {
+                for binding in bindings {
+                    self.push(Node::Binding(binding));
+                    for arg in &*binding.args {
+                        self.push(Node::Argument(&arg.name));
+                    }
+                }
+            }
+            Expr::Lambda(lambda) => {
+                for arg in &*lambda.args {
+                    self.push(Node::Argument(&arg.name));
+                }
+            }
+            _ => (),
+        }
+        ast::walk_expr(self, e)
+    }
+
+    fn visit_pattern(&mut self, p: &'a SpannedPattern<'ast, Symbol>) {
+        self.push(Node::Pattern(p));
+        ast::walk_pattern(self, &p.value)
+    }
+
+    fn visit_ast_type(&mut self, typ: &'a AstType<'ast, Symbol>) {
+        self.push(Node::Type(typ));
+        ast::walk_ast_type(self, typ)
+    }
+}
+
+fn collect_nodes<'a, 'ast>(
+    source_span: Span<BytePos>,
+    expr: &'a SpannedExpr<'ast, Symbol>,
+    select: impl FnMut(Span<BytePos>) -> bool,
+) -> Vec<Node<'a, 'ast>> {
+    let mut visitor = CollectNodes {
+        source_span,
+        select,
+        nodes: Vec::new(),
+    };
+    visitor.visit_expr(expr);
+    visitor.nodes
+}
+
+/// Returns the nodes which contain `pos`, outermost first. A node contains the positions at its
+/// start and at its end.
+pub fn enclosing_nodes<'a, 'ast>(
+    source_span: Span<BytePos>,
+    expr: &'a SpannedExpr<'ast, Symbol>,
+    pos: BytePos,
+) -> Vec<Node<'a, 'ast>> {
+    collect_nodes(source_span, expr, |span| {
+        span.containment(pos) == Ordering::Equal
+    })
+}
+
+/// Returns the smallest node which contains `pos`
+pub fn innermost_node_at<'a, 'ast>(
+    source_span: Span<BytePos>,
+    expr: &'a SpannedExpr<'ast, Symbol>,
+    pos: BytePos,
+) -> Option<Node<'a, 'ast>> {
+    innermost(enclosing_nodes(source_span, expr, pos))
+}
+
+/// Returns the nodes which overlap `range` (see `span_overlaps_range`), each node before the
+/// nodes it contains
+pub fn nodes_in_range<'a, 'ast>(
+    source_span: Span<BytePos>,
+    expr: &'a SpannedExpr<'ast, Symbol>,
+    range: Span<BytePos>,
+) -> Vec<Node<'a, 'ast>> {
+    collect_nodes(source_span, expr, |span| span_overlaps_range(span, range))
+}
+
+/// Returns the smallest of `nodes`, preferring the one visited last (the innermost) on ties
+pub fn innermost<'a, 'ast>(
+    nodes: impl IntoIterator<Item = Node<'a, 'ast>>,
+) -> Option<Node<'a, 'ast>> {
+    let len = |node: &Node| {
+        let span = node.span();
+        span.end() - span.start()
+    };
+    nodes.into_iter().fold(None, |found, node| match found {
+        Some(found) if len(&found) < len(&node) => Some(found),
+        _ => Some(node),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use gluon::{base::pos::ByteOffset, ThreadExt};
+
+    use super::*;
+
+    /// Describes `node` as its kind and the source it spans
+    fn describe(source: &str, source_span: Span<BytePos>, node: &Node) -> String {
+        let kind = match node {
+            Node::Expr(_) => "expr",
+            Node::Pattern(_) => "pattern",
+            Node::Argument(_) => "argument",
+            Node::Type(_) => "type",
+            Node::Binding(_) => "binding",
+        };
+        let span = node.span();
+        let start = (span.start() - source_span.start()).to_usize();
+        let end = (span.end() - source_span.start()).to_usize();
+        format!("{} `{}`", kind, &source[start..end])
+    }
+
+    #[tokio::test]
+    async fn queries() {
+        // `f` spans 12..13 and `1` spans 15..16
+        let source = "let f x = x\nf  1";
+        let thread = gluon::new_vm_async().await;
+        let (expr, _) = thread
+            .typecheck_str_async("ast_query", source, None)
+            .await
+            .unwrap();
+        let expr = expr.expr();
+        let source_span = thread
+            .get_database()
+            .get_filemap("ast_query")
+            .unwrap()
+            .span();
+        let pos = |offset: i64| source_span.start() + ByteOffset::from(offset);
+        let range = |start, end| Span::new(pos(start), pos(end));
+        let describe_all = |nodes: Vec<Node>| {
+            nodes
+                .iter()
+                .map(|node| describe(source, source_span, node))
+                .collect::<Vec<_>>()
+        };
+        let innermost_at = |offset| {
+            innermost_node_at(source_span, expr, pos(offset))
+                .map(|node| describe(source, source_span, &node))
+        };
+
+        assert_eq!(
+            describe_all(enclosing_nodes(source_span, expr, pos(12))),
+            ["expr `let f x = x\nf  1`", "expr `f  1`", "expr `f`"]
+        );
+        // The start and the end of a node are both in it
+        assert_eq!(innermost_at(12).as_deref(), Some("expr `f`"));
+        assert_eq!(innermost_at(13).as_deref(), Some("expr `f`"));
+        assert_eq!(innermost_at(15).as_deref(), Some("expr `1`"));
+        assert_eq!(innermost_at(16).as_deref(), Some("expr `1`"));
+        // Exactly between `f` and `1` only the application contains the position
+        assert_eq!(innermost_at(14).as_deref(), Some("expr `f  1`"));
+        assert_eq!(innermost_at(6).as_deref(), Some("argument `x`"));
+
+        assert_eq!(
+            describe_all(nodes_in_range(source_span, expr, range(13, 15))),
+            ["expr `let f x = x\nf  1`", "expr `f  1`"]
+        );
+        assert_eq!(
+            describe_all(nodes_in_range(source_span, expr, range(13, 16))),
+            ["expr `let f x = x\nf  1`", "expr `f  1`", "expr `1`"]
+        );
+        assert_eq!(
+            describe_all(nodes_in_range(source_span, expr, range(14, 14))),
+            ["expr `let f x = x\nf  1`", "expr `f  1`"]
+        );
+    }
+
+    #[test]
+    fn overlaps() {
+        let span = |start: u32, end: u32| Span::new(BytePos::from(start), BytePos::from(end));
+        assert!(span_overlaps_range(span(2, 5), span(4, 8)));
+        assert!(span_overlaps_range(span(2, 5), span(0, 10)));
+        // Touching spans share no byte
+        assert!(!span_overlaps_range(span(2, 5), span(5, 8)));
+        assert!(!span_overlaps_range(span(2, 5), span(0, 2)));
+        // An empty range overlaps at either end
+        assert!(span_overlaps_range(span(2, 5), span(2, 2)));
+        assert!(span_overlaps_range(span(2, 5), span(5, 5)));
+        assert!(!span_overlaps_range(span(2, 5), span(6, 6)));
+    }
+}
@@ -1,17 +1,38 @@
-use std::sync::Arc;
+use std::{
+    cmp::Reverse,
+    iter,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
 use gluon::{
     self,
-    base::{ast::OwnedExpr, fnv::FnvMap, metadata::Metadata, symbol::Symbol, types::ArcType},
+    base::{
+        ast::OwnedExpr,
+        fnv::{FnvMap, FnvSet},
+        metadata::Metadata,
+        pos::BytePos,
+        symbol::Symbol,
+        types::ArcType,
+    },
     compiler_pipeline::{SalvageResult, TypecheckValue},
-    import::Importer,
-    query::AsyncCompilation,
+    import::{DatabaseMut, Import, Importer},
+    query::{AsyncCompilation, CompilationBase},
     Error as GluonError, ModuleCompiler, Thread, ThreadExt,
 };
 
-use {futures::prelude::*, tokio::sync::Mutex, url::Url};
+use {
+    tokio::sync::{Mutex, RwLock, RwLockReadGuard},
+    url::Url,
+};
 
 use crate::{
+    diagnostics::{max_nesting_depth, too_deeply_nested},
+    module_loader::{FileSystemLoader, MemoryLoader, ModuleLoader},
     name::module_name_to_file_,
     text_edit::{TextChanges, Version},
 };
@@ -22,12 +43,47 @@ pub(crate) struct Module {
     #[allow(unused)] // TODO
     pub metadata: Arc<Metadata>,
     pub uri: Url,
+    /// The last successful check of the module if this check failed
+    last_good: Option<Arc<Module>>,
+}
+
+impl Module {
+    /// Returns the last successful check of the module if the latest check failed
+    pub(crate) fn last_good(&self) -> Option<&Module> {
+        self.last_good.as_deref()
+    }
+
+    /// Returns the last successful check along with the position in its source which corresponds
+    /// to `pos` in this check. Only positions before the first edit since the last successful
+    /// check have one. Bindings are only in scope after their declaration so that text means the
+    /// same in both checks.
+    pub(crate) fn last_good_position(&self, pos: BytePos) -> Option<(&Module, BytePos)> {
+        let last_good = self.last_good()?;
+        let unchanged = self
+            .source
+            .source()
+            .bytes()
+            .zip(last_good.source.source().bytes())
+            .take_while(|(l, r)| l == r)
+            .count();
+        let offset = pos - self.source.span().start();
+        if offset.to_usize() < unchanged {
+            Some((last_good, last_good.source.span().start() + offset))
+        } else {
+            None
+        }
+    }
 }
 
 pub struct State {
     pub uri: Url,
     pub version: Option<Version>,
     pub text_changes: TextChanges,
+    /// The result of the last check of the module which succeeded. Kept until a newer check
+    /// succeeds or, if the module is not open, until the module is evicted along with its state.
+    pub(crate) last_good: Option<Arc<Module>>,
+    /// When `last_good` was last stored or used, the least recently used results are evicted first
+    last_used: Instant,
 }
 
 impl State {
@@ -36,8 +92,26 @@ impl State {
             uri,
             version: None,
             text_changes: TextChanges::new(),
+            last_good: None,
+            last_used: Instant::now(),
         }
     }
+
+    /// Records a successful check of the module, replacing the previous one
+    pub(crate) fn succeeded(
+        &mut self,
+        source: Arc<gluon::base::source::FileMap>,
+        value: &TypecheckValue<Arc<OwnedExpr<Symbol>>>,
+    ) {
+        self.last_good = Some(Arc::new(Module {
+            source,
+            expr: value.expr.clone(),
+            metadata: value.metadata.clone(),
+            uri: self.uri.clone(),
+            last_good: None,
+        }));
+        self.last_used = Instant::now();
+    }
 }
 
 pub(crate) async fn get_module(
@@ -47,27 +121,204 @@ pub(crate) async fn get_module(
     Arc<gluon::base::source::FileMap>,
     TypecheckValue<Arc<OwnedExpr<Symbol>>>,
 )> {
-    let mut db = thread.get_database();
-    let m = db
-        .typechecked_source_module(module.into(), None)
+    check_module(thread, module)
         .await
-        .or_else(|err| err.value.ok_or(err.error))?;
+        .map(|(source, value, _)| (source, value))
+}
+
+/// Type checks `module`. The flag is `false` if the check failed and the value was salvaged from
+/// the error.
+async fn check_module(
+    thread: &Thread,
+    module: &str,
+) -> gluon::Result<(
+    Arc<gluon::base::source::FileMap>,
+    TypecheckValue<Arc<OwnedExpr<Symbol>>>,
+    bool,
+)> {
+    let import = thread.get_macros().get("import").expect("Import macro");
+    let import = import
+        .downcast_ref::<Import<CheckImporter>>()
+        .expect("Check importer");
+    let _checking = import.importer.checking().await;
+
+    let mut db = thread.get_database();
+    // Requests are handled on threads with a stack of `STACK_SIZE` which this would overflow
+    if let Some(source) = db.get_filemap(module) {
+        if too_deeply_nested(source.source(), max_nesting_depth(crate::STACK_SIZE)).is_some() {
+            return Err(format!("`{}` is nested too deeply to be checked", module).into());
+        }
+    }
+    let (m, succeeded) = match db.typechecked_source_module(module.into(), None).await {
+        Ok(m) => (m, true),
+        Err(err) => (err.value.ok_or(err.error)?, false),
+    };
 
     let _ = db.module_type(module.into(), None).await;
     let _ = db.module_metadata(module.into(), None).await;
 
-    Ok((db.get_filemap(module).expect("Filemap"), m))
+    Ok((db.get_filemap(module).expect("Filemap"), m, succeeded))
+}
+
+/// The default of the `checkCacheSize` setting
+pub(crate) const CHECK_CACHE_SIZE: usize = 64;
+
+/// The loaders which provide the sources of modules, in the order they are consulted
+struct Loaders {
+    /// The text of the documents which are open in the editor
+    documents: MemoryLoader,
+    /// The loaders of `ServerOptions::loaders`
+    custom: Vec<Box<dyn ModuleLoader>>,
+    /// Reads modules from the import paths of the `import!` macro
+    file_system: FileSystemLoader,
+    /// The modules whose sources the loaders added to the database. The database keeps a source
+    /// after no loader provides it any more so importing such a module must fail explicitly.
+    loaded: std::sync::Mutex<FnvSet<String>>,
+}
+
+impl Loaders {
+    fn iter(&self) -> impl Iterator<Item = &dyn ModuleLoader> {
+        iter::once(&self.documents as &dyn ModuleLoader)
+            .chain(self.custom.iter().map(|loader| &**loader))
+            .chain(iter::once(&self.file_system as &dyn ModuleLoader))
+    }
 }
 
 #[derive(Clone)]
-pub(crate) struct CheckImporter(pub(crate) Arc<Mutex<FnvMap<String, State>>>);
+pub(crate) struct CheckImporter(
+    pub(crate) Arc<Mutex<FnvMap<String, State>>>,
+    Arc<Loaders>,
+    /// The number of modules which keep the result of their last successful check
+    Arc<AtomicUsize>,
+    /// Shared by the checks which are running and held alone while the database is changed. A
+    /// change waits for the running checks, which would otherwise block on the change as soon as
+    /// they load a module.
+    Arc<RwLock<()>>,
+);
 impl CheckImporter {
-    pub(crate) fn new() -> CheckImporter {
-        CheckImporter(Arc::new(Mutex::new(FnvMap::default())))
+    pub(crate) fn new(loaders: Vec<Box<dyn ModuleLoader>>, retries: usize) -> CheckImporter {
+        CheckImporter(
+            Arc::new(Mutex::new(FnvMap::default())),
+            Arc::new(Loaders {
+                documents: MemoryLoader::new(),
+                custom: loaders,
+                file_system: FileSystemLoader::new(Vec::new()).with_retries(retries),
+                loaded: Default::default(),
+            }),
+            Arc::new(AtomicUsize::new(CHECK_CACHE_SIZE)),
+            Arc::new(RwLock::new(())),
+        )
+    }
+
+    pub(crate) fn set_check_cache_size(&self, size: usize) {
+        self.2.store(size, Ordering::Relaxed);
+    }
+
+    /// Makes the file system loader read modules from `paths`. Called whenever the paths of the
+    /// `import!` macro change.
+    pub(crate) fn set_import_paths(&self, paths: Vec<PathBuf>) {
+        self.1.file_system.set_paths(paths);
+    }
+
+    /// Stores the text of `module` as it is open in the editor, replacing the source which the
+    /// other loaders provide
+    pub(crate) async fn open_document(&self, thread: &Thread, module: &str, text: &str) {
+        self.1.documents.insert(module, text);
+        self.1.loaded.lock().unwrap().insert(module.into());
+        let (module, text) = (module.to_string(), text.to_string());
+        self.write_database(thread, move |db| db.add_module(module, &text))
+            .await;
+    }
+
+    /// Forgets the text of the closed document `module` so that importers see its source from
+    /// the other loaders again
+    pub(crate) async fn close_document(&self, thread: &Thread, module: &str) {
+        self.1.documents.remove(module);
+        if let Some(source) = self.load_source(module) {
+            let module = module.to_string();
+            self.write_database(thread, move |db| db.add_module(module, &source))
+                .await;
+        }
+    }
+
+    /// Keeps the database from being changed until the returned guard is dropped. Taken by
+    /// everything which checks modules.
+    pub(crate) async fn checking(&self) -> RwLockReadGuard<'_, ()> {
+        self.3.read().await
+    }
+
+    /// Changes the database of `thread` once no check is running. The change blocks a thread of
+    /// its own as it still waits for any other use of the database to end.
+    pub(crate) async fn write_database(
+        &self,
+        thread: &Thread,
+        write: impl FnOnce(&mut DatabaseMut) + Send + 'static,
+    ) {
+        let _writing = self.3.write().await;
+        let thread = thread.root_thread();
+        tokio::task::spawn_blocking(move || write(&mut thread.get_database_mut()))
+            .await
+            .expect("Database write panicked")
+    }
+
+    /// Drops the least recently used checks once more modules than the cache size have one.
+    /// Open documents always keep theirs so the modules which are not open share the slots which
+    /// remain. The state of an evicted module is removed as well, importing it adds it again.
+    pub(crate) fn evict(&self, modules: &mut FnvMap<String, State>) {
+        evict(modules, self.2.load(Ordering::Relaxed))
+    }
+
+    /// Registers the source of `module_name` from the first loader that provides it. Only done the
+    /// first time a module is seen as open documents and earlier imports are already known.
+    async fn load_module(
+        &self,
+        compiler: &mut ModuleCompiler<'_, '_>,
+        module_name: &str,
+    ) -> Result<(), GluonError> {
+        if self.0.lock().await.contains_key(module_name) {
+            return Ok(());
+        }
+        for loader in self.1.iter() {
+            if let Some(source) = loader
+                .load_module(module_name)
+                .map_err(|err| GluonError::from(err.to_string()))?
+            {
+                compiler
+                    .database
+                    .__internal_get_db()
+                    .add_module(module_name.into(), &source);
+                // Mark the module as seen so that a failing import does not add it again
+                self.0.lock().await.insert(
+                    module_name.into(),
+                    State::empty(
+                        module_name_to_file_(module_name)
+                            .map_err(|err| GluonError::from(err.to_string()))?,
+                    ),
+                );
+                self.1.loaded.lock().unwrap().insert(module_name.into());
+                return Ok(());
+            }
+        }
+        if self.1.loaded.lock().unwrap().contains(module_name) {
+            return Err(format!("Could not find module '{}'", module_name).into());
+        }
+        Ok(())
+    }
+
+    /// Returns the source of `module` from the first loader that provides it
+    pub(crate) fn load_source(&self, module_name: &str) -> Option<String> {
+        self.1.iter().find_map(|loader| {
+            loader.load_module(module_name).unwrap_or_else(|err| {
+                debug!("Unable to load `{}`: {}", module_name, err);
+                None
+            })
+        })
     }
 
+    /// Returns the latest check of `module`. If it failed the last successful check is available
+    /// through `Module::last_good`.
     pub(crate) async fn module(&self, thread: &Thread, module: &str) -> Option<Module> {
-        let (source, value) = get_module(thread, module)
+        let (source, value, succeeded) = check_module(thread, module)
             .await
             .map_err(|err| {
                 dbg!(&err);
@@ -75,41 +326,24 @@ impl CheckImporter {
             })
             .ok()?;
 
-        let uri = {
-            let map = self.0.lock().await;
-            map.get(module)?.uri.clone()
+        let mut map = self.0.lock().await;
+        let state = map.get_mut(module)?;
+        let last_good = if succeeded {
+            state.succeeded(source.clone(), &value);
+            None
+        } else {
+            state.last_used = Instant::now();
+            state.last_good.clone()
         };
-
-        Some(Module {
+        let checked = Module {
             source,
-            expr: value.expr.clone(),
-            metadata: value.metadata.clone(),
-            uri,
-        })
-    }
-
-    pub(crate) async fn modules(&self, thread: &Thread) -> impl Iterator<Item = Module> {
-        let uris = self
-            .0
-            .lock()
-            .await
-            .iter()
-            .map(|(module, s)| (module.clone(), s.uri.clone()))
-            .collect::<Vec<_>>();
-
-        futures::stream::iter(uris)
-            .filter_map(|(module, uri)| async move {
-                let (source, value) = get_module(thread, &module).await.ok()?;
-                Some(Module {
-                    source,
-                    expr: value.expr.clone(),
-                    metadata: value.metadata.clone(),
-                    uri,
-                })
-            })
-            .collect::<Vec<_>>()
-            .await
-            .into_iter()
+            expr: value.expr,
+            metadata: value.metadata,
+            uri: state.uri.clone(),
+            last_good,
+        };
+        self.evict(&mut map);
+        Some(checked)
     }
 }
 
@@ -121,6 +355,8 @@ impl Importer for CheckImporter {
         _: &Thread,
         module_name: &str,
     ) -> SalvageResult<ArcType> {
+        self.load_module(compiler, module_name).await?;
+
         compiler
             .database
             .module_metadata(module_name.into(), None)
@@ -132,16 +368,35 @@ impl Importer for CheckImporter {
             .await
             .or_else(|err| err.get_value())?;
 
-        self.0.lock().await.insert(
-            module_name.into(),
-            State {
-                uri: module_name_to_file_(module_name)
-                    .map_err(|err| GluonError::from(err.to_string()))?,
-                version: None,
-                text_changes: TextChanges::new(),
-            },
-        );
+        let uri =
+            module_name_to_file_(module_name).map_err(|err| GluonError::from(err.to_string()))?;
+        // Modules which are open in the client keep their state
+        self.0
+            .lock()
+            .await
+            .entry(module_name.into())
+            .or_insert_with(|| State::empty(uri));
 
         Ok(typ)
     }
 }
+
+fn evict(modules: &mut FnvMap<String, State>, capacity: usize) {
+    let open = modules
+        .values()
+        .filter(|state| state.version.is_some())
+        .count();
+    let slots = capacity.saturating_sub(open);
+    let mut cached: Vec<_> = modules
+        .iter()
+        .filter(|(_, state)| state.version.is_none() && state.last_good.is_some())
+        .map(|(module, state)| (module.clone(), state.last_used))
+        .collect();
+    if cached.len() <= slots {
+        return;
+    }
+    cached.sort_by_key(|&(_, last_used)| Reverse(last_used));
+    for (module, _) in &cached[slots..] {
+        modules.remove(module);
+    }
+}
@@ -0,0 +1,735 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use futures::channel::mpsc;
+
+use gluon::base::{
+    ast::{self, Pattern, PatternField, SpannedPattern, ValueBindings, Visitor},
+    resolve,
+    source::Source,
+    symbol::SymbolRef,
+    types::{NullInterner, TypeEnv},
+};
+
+use lsp_types::{
+    ApplyWorkspaceEditParams, CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams,
+    Command, Diagnostic, ExecuteCommandParams, MessageActionItem, MessageType, Position, Range,
+    ShowMessageRequestParams, TextEdit, WorkspaceEdit,
+};
+
+use crate::{
+    check_importer::get_module,
+    command::configuration::SettingsRef,
+    rpc::{ClientRequests, OutgoingMessage},
+};
+
+use super::*;
+
+/// The command which asks the user which module to import a name from and then imports it
+pub const CHOOSE_IMPORT_COMMAND: &str = "gluon.chooseImport";
+
+/// The kinds of actions which the server offers
+pub const ACTION_KINDS: [CodeActionKind; 4] = [
+    CodeActionKind::QUICKFIX,
+    CodeActionKind::REFACTOR_INLINE,
+    CodeActionKind::REFACTOR_REWRITE,
+    CodeActionKind::SOURCE_ORGANIZE_IMPORTS,
+];
+
+/// The arguments of `CHOOSE_IMPORT_COMMAND`
+#[derive(Serialize, Deserialize)]
+struct ChooseImport {
+    uri: Url,
+    name: String,
+    modules: Vec<String>,
+}
+
+/// Returns the name in an `Undefined variable` error
+fn undefined_variable(message: &str) -> Option<&str> {
+    let name = message.strip_prefix("Undefined variable `")?;
+    name.split('`').next()
+}
+
+/// Returns the fields of the records of the known modules other than `current_module`, as the
+/// module, the name of the field and its type
+pub(super) async fn exported_values(
+    thread: &Thread,
+    current_module: &str,
+) -> Vec<(String, String, ArcType)> {
+    let import = thread.get_macros().get("import").expect("Import macro");
+    let import = import
+        .downcast_ref::<Import<CheckImporter>>()
+        .expect("Check importer");
+    let known_modules: Vec<_> = import.importer.0.lock().await.keys().cloned().collect();
+
+    let mut values = Vec::new();
+    for module in known_modules {
+        if module == current_module {
+            continue;
+        }
+        let typ = match get_module(thread, &module).await {
+            Ok((_, value)) => value.typ,
+            Err(_) => continue,
+        };
+        let db = thread.get_database();
+        let env = db.as_env();
+        let env: &dyn TypeEnv<Type = ArcType> = &env;
+        let typ = resolve::remove_aliases(env, NullInterner::new(), typ);
+        for field in typ.row_iter() {
+            values.push((
+                module.clone(),
+                field.name.declared_name().to_string(),
+                field.typ.clone(),
+            ));
+        }
+    }
+    values.sort_by(|l, r| (&l.0, &l.1).cmp(&(&r.0, &r.1)));
+    values
+}
+
+/// Returns the modules, other than `current_module`, whose record has a field called `name`
+async fn exporting_modules(thread: &Thread, current_module: &str, name: &str) -> Vec<String> {
+    exported_values(thread, current_module)
+        .await
+        .into_iter()
+        .filter(|(_, field, _)| field == name)
+        .map(|(module, _, _)| module)
+        .collect()
+}
+
+/// Whether `kind` is `requested` or one of its sub kinds, such as `source.organizeImports` of
+/// `source`
+fn kind_matches(kind: &CodeActionKind, requested: &str) -> bool {
+    match kind.as_str().strip_prefix(requested) {
+        Some(rest) => rest.is_empty() || rest.starts_with('.'),
+        None => false,
+    }
+}
+
+/// Returns the kinds of actions which are both in `only`, if the client restricted the request to
+/// some kinds, and in `supported`, if the client listed the kinds it knows about
+fn requested_kinds(
+    only: Option<&[CodeActionKind]>,
+    supported: Option<&[String]>,
+) -> Vec<CodeActionKind> {
+    ACTION_KINDS
+        .iter()
+        .filter(|kind| only.is_none_or(|only| only.iter().any(|o| kind_matches(kind, o.as_str()))))
+        .filter(|kind| {
+            supported.is_none_or(|supported| supported.iter().any(|s| kind_matches(kind, s)))
+        })
+        .cloned()
+        .collect()
+}
+
+fn document_edit(uri: &Url, edit: TextEdit) -> WorkspaceEdit {
+    WorkspaceEdit {
+        changes: Some(std::iter::once((uri.clone(), vec![edit])).collect::<HashMap<_, _>>()),
+        ..WorkspaceEdit::default()
+    }
+}
+
+/// Inserts `let { name } = import! module` at the start of a document
+pub(super) fn import_text_edit(name: &str, module: &str) -> TextEdit {
+    TextEdit {
+        range: Default::default(),
+        new_text: format!("let {{ {} }} = import! {}\n", name, module),
+    }
+}
+
+/// Adds `let { name } = import! module` to the start of the document
+fn import_edit(uri: &Url, name: &str, module: &str) -> WorkspaceEdit {
+    document_edit(uri, import_text_edit(name, module))
+}
+
+/// Returns the module which `line` imports if it is a `let ... = import! module` binding
+fn imported_module(line: &str) -> Option<&str> {
+    if !line.starts_with("let ") {
+        return None;
+    }
+    let (_, module) = line.split_once("= import! ")?;
+    Some(module.trim())
+}
+
+/// Sorts the imports which begin `source` by the imported module and removes duplicated imports.
+/// Comments and empty lines before the imports are skipped. Returns `None` if the imports are
+/// already organized.
+fn organize_imports(source: &str) -> Option<TextEdit> {
+    let lines: Vec<_> = source.lines().map(|line| line.trim_end()).collect();
+    let start = lines
+        .iter()
+        .position(|line| !line.is_empty() && !line.starts_with("//"))?;
+    let end = start
+        + lines[start..]
+            .iter()
+            .take_while(|line| imported_module(line).is_some())
+            .count();
+    if start == end {
+        return None;
+    }
+
+    let imports = &lines[start..end];
+    let mut organized = imports.to_vec();
+    organized.sort_by_key(|line| imported_module(line));
+    organized.dedup();
+    if organized == imports {
+        return None;
+    }
+    let last_line = imports[imports.len() - 1];
+    Some(TextEdit {
+        range: Range {
+            start: Position::new(start as u32, 0),
+            end: Position::new(end as u32 - 1, last_line.encode_utf16().count() as u32),
+        },
+        new_text: organized.join("\n"),
+    })
+}
+
+/// Offers to organize the imports of the document
+fn organize_imports_action(uri: &Url, module: &Module) -> Option<CodeActionOrCommand> {
+    let edit = organize_imports(module.source.src())?;
+    Some(CodeActionOrCommand::CodeAction(CodeAction {
+        title: "Organize imports".into(),
+        kind: Some(CodeActionKind::SOURCE_ORGANIZE_IMPORTS),
+        edit: Some(document_edit(uri, edit)),
+        ..CodeAction::default()
+    }))
+}
+
+/// Where a use of the inlined binding appears, which decides whether the inlined expression
+/// must be parenthesized
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum UseContext {
+    /// The expression stands on its own, such as the body of a `let` or a record field
+    Free,
+    /// The operand of an infix operator
+    Operand,
+    /// The function or an argument of an application or the record of a projection
+    Tight,
+}
+
+struct Use {
+    span: Span<BytePos>,
+    context: UseContext,
+    /// The use is a shorthand record field (`{ x }`) which must be expanded to `x = ...`
+    shorthand_field: bool,
+    /// The use is evaluated each time a lambda (or the rest of a `do` block) is called
+    under_lambda: bool,
+}
+
+/// Collects the uses of `symbol` and the names of every binding in an expression
+struct Uses<'b> {
+    symbol: &'b SymbolRef,
+    uses: Vec<Use>,
+    bound_names: Vec<String>,
+    context: UseContext,
+    lambda_depth: usize,
+}
+
+impl Uses<'_> {
+    fn visit_in(&mut self, context: UseContext, e: &SpannedExpr<Symbol>) {
+        self.context = context;
+        self.visit_expr(e);
+    }
+
+    fn push(&mut self, span: Span<BytePos>, context: UseContext, shorthand_field: bool) {
+        self.uses.push(Use {
+            span,
+            context,
+            shorthand_field,
+            under_lambda: self.lambda_depth > 0,
+        });
+    }
+
+    fn bind(&mut self, name: &Symbol) {
+        self.bound_names.push(name.declared_name().to_string());
+    }
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for Uses<'_> {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        let context = std::mem::replace(&mut self.context, UseContext::Free);
+        match &e.value {
+            Expr::Ident(id) if id.name == *self.symbol => self.push(e.span, context, false),
+            Expr::App { func, args, .. } => {
+                self.visit_in(UseContext::Tight, func);
+                for arg in &**args {
+                    self.visit_in(UseContext::Tight, arg);
+                }
+            }
+            Expr::Infix { lhs, rhs, .. } => {
+                self.visit_in(UseContext::Operand, lhs);
+                self.visit_in(UseContext::Operand, rhs);
+            }
+            Expr::Projection(record, _, _) => self.visit_in(UseContext::Tight, record),
+            Expr::Record { exprs, .. } => {
+                for field in &**exprs {
+                    if field.value.is_none() && field.name.value == *self.symbol {
+                        self.push(field.name.span, UseContext::Free, true);
+                    }
+                }
+                ast::walk_expr(self, e)
+            }
+            Expr::Lambda(lambda) => {
+                for arg in &*lambda.args {
+                    self.bind(&arg.name.value.name);
+                }
+                self.lambda_depth += 1;
+                self.visit_expr(lambda.body);
+                self.lambda_depth -= 1;
+            }
+            Expr::LetBindings(bindings, _) => {
+                for binding in bindings {
+                    for arg in &*binding.args {
+                        self.bind(&arg.name.value.name);
+                    }
+                }
+                ast::walk_expr(self, e)
+            }
+            Expr::Do(do_) => {
+                if let Some(id) = &do_.id {
+                    self.visit_pattern(id);
+                }
+                self.visit_expr(do_.bound);
+                self.lambda_depth += 1;
+                self.visit_expr(do_.body);
+                self.lambda_depth -= 1;
+            }
+            _ => ast::walk_expr(self, e),
+        }
+    }
+
+    fn visit_pattern(&mut self, p: &'a SpannedPattern<'ast, Symbol>) {
+        match &p.value {
+            Pattern::Ident(id) => self.bind(&id.name),
+            Pattern::As(id, _) => self.bind(&id.value),
+            Pattern::Record { fields, .. } => {
+                for field in fields.iter() {
+                    if let PatternField::Value { name, value: None } = field {
+                        self.bind(&name.value);
+                    }
+                }
+            }
+            _ => (),
+        }
+        ast::walk_pattern(self, &p.value)
+    }
+}
+
+/// Collects the names which `expr` refers to
+struct ReferencedNames(Vec<String>);
+
+impl<'a, 'ast> Visitor<'a, 'ast> for ReferencedNames {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        match &e.value {
+            Expr::Ident(id) => self.0.push(id.name.declared_name().to_string()),
+            Expr::Record { exprs, .. } => {
+                for field in &**exprs {
+                    if field.value.is_none() {
+                        self.0.push(field.name.value.declared_name().to_string());
+                    }
+                }
+            }
+            _ => (),
+        }
+        ast::walk_expr(self, e)
+    }
+}
+
+/// Whether evaluating `expr` more than once, or not at all, can't be observed. Applications may
+/// be arbitrarily expensive or run effects so only values built from names and literals are pure.
+fn is_pure(expr: &SpannedExpr<Symbol>) -> bool {
+    match &expr.value {
+        Expr::Ident(_) | Expr::Literal(_) | Expr::Lambda(_) => true,
+        Expr::Projection(record, _, _) => is_pure(record),
+        Expr::Annotated(expr, _) => is_pure(expr),
+        Expr::Tuple { elems, .. } => elems.iter().all(is_pure),
+        Expr::Array(array) => array.exprs.iter().all(is_pure),
+        Expr::Record { exprs, base, .. } => {
+            exprs
+                .iter()
+                .all(|field| field.value.as_ref().is_none_or(is_pure))
+                && base.as_ref().is_none_or(|base| is_pure(base))
+        }
+        _ => false,
+    }
+}
+
+/// Whether `expr`, whose source is `text`, must be parenthesized when it replaces a use in
+/// `context`
+fn needs_parens(expr: &SpannedExpr<Symbol>, text: &str, context: UseContext) -> bool {
+    let atomic = match &expr.value {
+        // `-1` would be parsed as a subtraction
+        Expr::Literal(_) => !text.starts_with('-'),
+        Expr::Ident(_)
+        | Expr::Projection(..)
+        | Expr::Record { .. }
+        | Expr::Tuple { .. }
+        | Expr::Array(_) => true,
+        _ => false,
+    };
+    match context {
+        UseContext::Free => false,
+        UseContext::Operand => !atomic && !matches!(expr.value, Expr::App { .. }),
+        UseContext::Tight => !atomic,
+    }
+}
+
+/// Returns the text of `span` in the document
+fn span_text(module: &Module, span: Span<BytePos>) -> &str {
+    let start = module.source.span().start();
+    &module.source.src()[(span.start() - start).to_usize()..(span.end() - start).to_usize()]
+}
+
+/// Offers to inline the `let` binding whose name is at `pos` into its uses or, if it is unused,
+/// to remove it. The binding is only inlined if that does not change what the program does:
+/// an expression which is not pure is never duplicated nor moved into a lambda and no name it
+/// refers to may be shadowed at a use.
+fn inline_binding_actions(
+    uri: &Url,
+    module: &Module,
+    pos: BytePos,
+    kinds: &[CodeActionKind],
+) -> Result<Vec<CodeActionOrCommand>, ServerError<()>> {
+    let source = &module.source;
+    let let_expr = enclosing_nodes(source.span(), module.expr.expr(), pos)
+        .into_iter()
+        .filter_map(|node| match node {
+            Node::Expr(expr) => match &expr.value {
+                Expr::LetBindings(ValueBindings::Plain(binding), body)
+                    if binding.args.is_empty()
+                        && binding.name.span.containment(pos) == Ordering::Equal =>
+                {
+                    match &binding.name.value {
+                        Pattern::Ident(id) => Some((expr.span, &**binding, &id.name, &**body)),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .next_back();
+    let (let_span, binding, symbol, body) = match let_expr {
+        Some(let_expr) => let_expr,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut uses = Uses {
+        symbol,
+        uses: Vec::new(),
+        bound_names: Vec::new(),
+        context: UseContext::Free,
+        lambda_depth: 0,
+    };
+    uses.visit_expr(body);
+    uses.uses.retain(|use_| source.span().contains(use_.span));
+
+    let name = symbol.declared_name();
+    let removal = TextEdit {
+        range: byte_span_to_range(source, Span::new(let_span.start(), body.span.start()))?,
+        new_text: String::new(),
+    };
+    let mut actions = Vec::new();
+    if uses.uses.is_empty() {
+        if kinds.contains(&CodeActionKind::REFACTOR_REWRITE) {
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Remove unused binding `{}`", name),
+                kind: Some(CodeActionKind::REFACTOR_REWRITE),
+                edit: Some(document_edit(uri, removal)),
+                ..CodeAction::default()
+            }));
+        }
+        return Ok(actions);
+    }
+    if !kinds.contains(&CodeActionKind::REFACTOR_INLINE) {
+        return Ok(actions);
+    }
+
+    let expr = &binding.expr;
+    let pure = is_pure(expr);
+    if !pure && (uses.uses.len() > 1 || uses.uses[0].under_lambda) {
+        return Ok(actions);
+    }
+    let mut referenced = ReferencedNames(Vec::new());
+    referenced.visit_expr(expr);
+    if referenced
+        .0
+        .iter()
+        .any(|name| uses.bound_names.contains(name))
+    {
+        return Ok(actions);
+    }
+
+    let text = span_text(module, expr.span);
+    let mut edits = vec![removal];
+    for use_ in &uses.uses {
+        let new_text = if needs_parens(expr, text, use_.context) {
+            format!("({})", text)
+        } else {
+            text.to_string()
+        };
+        let new_text = if use_.shorthand_field {
+            format!("{} = {}", name, new_text)
+        } else {
+            new_text
+        };
+        edits.push(TextEdit {
+            range: byte_span_to_range(source, use_.span)?,
+            new_text,
+        });
+    }
+    actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+        title: format!("Inline binding `{}`", name),
+        kind: Some(CodeActionKind::REFACTOR_INLINE),
+        edit: Some(WorkspaceEdit {
+            changes: Some(std::iter::once((uri.clone(), edits)).collect()),
+            ..WorkspaceEdit::default()
+        }),
+        ..CodeAction::default()
+    }));
+    Ok(actions)
+}
+
+/// Offers to import the names which are undefined in `diagnostics`. A name which several modules
+/// export is either offered once for each module or, if `prompt` is set, as a single action which
+/// asks which module to import it from.
+async fn import_actions(
+    thread: &Thread,
+    uri: &Url,
+    diagnostics: Vec<Diagnostic>,
+    prompt: bool,
+) -> Vec<CodeActionOrCommand> {
+    let module_name = filename_to_module(&strip_file_prefix_with_thread(thread, uri));
+    let mut actions = Vec::new();
+    for diagnostic in diagnostics {
+        let name = match undefined_variable(&diagnostic.message) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let modules = exporting_modules(thread, &module_name, &name).await;
+        if prompt && modules.len() > 1 {
+            let arguments = ChooseImport {
+                uri: uri.clone(),
+                name: name.clone(),
+                modules,
+            };
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Import `{}`...", name),
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic]),
+                command: Some(Command {
+                    title: format!("Import `{}`", name),
+                    command: CHOOSE_IMPORT_COMMAND.into(),
+                    arguments: Some(vec![serde_json::to_value(arguments).expect("Arguments")]),
+                }),
+                ..CodeAction::default()
+            }));
+            continue;
+        }
+        for module in modules {
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Import `{}` from `{}`", name, module),
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic.clone()]),
+                edit: Some(import_edit(uri, &name, &module)),
+                ..CodeAction::default()
+            }));
+        }
+    }
+    actions
+}
+
+/// Asks which of `modules` to import `name` from and applies the import once the user has chosen
+async fn choose_import(
+    message_log: mpsc::Sender<OutgoingMessage>,
+    client_requests: ClientRequests,
+    arguments: ChooseImport,
+) -> Result<(), ServerError<()>> {
+    let ChooseImport { uri, name, modules } = arguments;
+    let choice = client_requests
+        .send(
+            message_log.clone(),
+            request!("window/showMessageRequest"),
+            ShowMessageRequestParams {
+                typ: MessageType::Info,
+                message: format!("Import `{}` from", name),
+                actions: Some(
+                    modules
+                        .iter()
+                        .map(|module| MessageActionItem {
+                            title: module.clone(),
+                            properties: Default::default(),
+                        })
+                        .collect(),
+                ),
+            },
+        )
+        .await?;
+    let module = match choice {
+        Some(choice) if modules.contains(&choice.title) => choice.title,
+        _ => {
+            debug!("No module was chosen to import `{}` from", name);
+            return Ok(());
+        }
+    };
+
+    let response = client_requests
+        .send(
+            message_log,
+            request!("workspace/applyEdit"),
+            ApplyWorkspaceEditParams {
+                label: Some(format!("Import `{}` from `{}`", name, module)),
+                edit: import_edit(&uri, &name, &module),
+            },
+        )
+        .await?;
+    if !response.applied {
+        info!(
+            "The client did not import `{}`: {}",
+            name,
+            response.failure_reason.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+pub fn register(
+    io: &mut IoHandler,
+    thread: &RootedThread,
+    message_log: &mpsc::Sender<OutgoingMessage>,
+    client_capabilities: &ClientCapabilitiesRef,
+    settings: &SettingsRef,
+    client_requests: &ClientRequests,
+) {
+    {
+        let thread = thread.clone();
+        let client_capabilities = client_capabilities.clone();
+        let settings = settings.clone();
+        let f = move |params: CodeActionParams| {
+            let thread = thread.clone();
+            let client_capabilities = client_capabilities.read().unwrap();
+            let kinds = requested_kinds(
+                params.context.only.as_deref(),
+                client_capabilities.code_action_kinds(),
+            );
+            // Prompting needs the client to apply the edit once the user has chosen
+            let prompt = settings.read().unwrap().prompt_ambiguous_actions
+                && client_capabilities.supports_apply_edit();
+            async move {
+                let uri = &params.text_document.uri;
+                let mut actions = Vec::new();
+                if kinds.contains(&CodeActionKind::QUICKFIX) {
+                    actions.extend(
+                        import_actions(&thread, uri, params.context.diagnostics, prompt).await,
+                    );
+                }
+                if kinds.contains(&CodeActionKind::REFACTOR_INLINE)
+                    || kinds.contains(&CodeActionKind::REFACTOR_REWRITE)
+                {
+                    actions.extend(
+                        retrieve_expr_with_pos(&thread, uri, &params.range.start, |module, pos| {
+                            inline_binding_actions(uri, module, pos, &kinds)
+                        })
+                        .await?,
+                    );
+                }
+                if kinds.contains(&CodeActionKind::SOURCE_ORGANIZE_IMPORTS) {
+                    actions.extend(
+                        retrieve_expr(&thread, uri, |module| {
+                            Ok(organize_imports_action(uri, module))
+                        })
+                        .await?,
+                    );
+                }
+                Ok::<_, ServerError<()>>(Some(actions))
+            }
+        };
+        io.add_async_method(request!("textDocument/codeAction"), f);
+    }
+
+    let message_log = message_log.clone();
+    let client_requests = client_requests.clone();
+    let f = move |params: ExecuteCommandParams| {
+        let message_log = message_log.clone();
+        let client_requests = client_requests.clone();
+        async move {
+            if params.command != CHOOSE_IMPORT_COMMAND {
+                return Err(format!("Unknown command `{}`", params.command).into());
+            }
+            let arguments = params
+                .arguments
+                .into_iter()
+                .next()
+                .ok_or("Missing the arguments of the command")?;
+            let arguments: ChooseImport = serde_json::from_value(arguments)?;
+            // The client's responses are only read once this handler has returned
+            tokio::spawn(async move {
+                if let Err(err) = choose_import(message_log, client_requests, arguments).await {
+                    error!("Unable to import: {}", err.message);
+                }
+            });
+            Ok::<_, ServerError<()>>(None)
+        }
+    };
+    io.add_async_method(request!("workspace/executeCommand"), f);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undefined_variable_name() {
+        assert_eq!(undefined_variable("Undefined variable `abc`"), Some("abc"));
+        assert_eq!(undefined_variable("Undefined type `Abc`"), None);
+    }
+
+    #[test]
+    fn requested_kinds_are_filtered() {
+        let only = [CodeActionKind::SOURCE];
+        assert_eq!(
+            requested_kinds(Some(&only), None),
+            vec![CodeActionKind::SOURCE_ORGANIZE_IMPORTS]
+        );
+        assert_eq!(
+            requested_kinds(None, Some(&["quickfix".to_string()])),
+            vec![CodeActionKind::QUICKFIX]
+        );
+        assert_eq!(
+            requested_kinds(Some(&[CodeActionKind::REFACTOR]), None),
+            vec![
+                CodeActionKind::REFACTOR_INLINE,
+                CodeActionKind::REFACTOR_REWRITE
+            ]
+        );
+        assert_eq!(
+            requested_kinds(Some(&[CodeActionKind::REFACTOR_EXTRACT]), None),
+            vec![]
+        );
+        assert!(!kind_matches(&CodeActionKind::QUICKFIX, "quick"));
+    }
+
+    #[test]
+    fn organize_leading_imports() {
+        let source = "// Imports\nlet { b } = import! std.b\nlet { a } = import! std.a\nlet { b } = import! std.b\n\nb\n";
+        let edit = organize_imports(source).unwrap();
+        assert_eq!(
+            edit.range,
+            Range::new(Position::new(1, 0), Position::new(3, 25))
+        );
+        assert_eq!(
+            edit.new_text,
+            "let { a } = import! std.a\nlet { b } = import! std.b"
+        );
+
+        assert_eq!(
+            organize_imports("let { a } = import! std.a\nlet { b } = import! std.b\na\n"),
+            None
+        );
+        assert_eq!(organize_imports("let x = 1\nx\n"), None);
+    }
+}
@@ -1,12 +1,39 @@
+use std::{
+    cmp::Ordering,
+    sync::{Arc, Mutex},
+};
+
 use futures::channel::mpsc;
 
-use lsp_types::CompletionItem;
+use gluon::base::{
+    ast::{self, Pattern, PatternField, SpannedPattern, Typed, Visitor},
+    fnv::{FnvMap, FnvSet},
+    kind::Kind,
+    pos::ByteOffset,
+    pos::Span,
+    resolve,
+    types::{self, NullInterner, TypeEnv},
+};
+
+use lsp_types::{
+    CompletionItem, CompletionItemTag, CompletionTextEdit, InsertTextFormat, TextEdit,
+};
+
+use gluon::compiler_pipeline::Typecheckable;
 
 use crate::completion;
 
-use lsp_types::{CompletionParams, CompletionResponse};
+use lsp_types::{CompletionParams, Range};
 
-use crate::{check_importer::Module, name::with_import, rpc::LanguageServerCommand, BoxFuture};
+use crate::{
+    check_importer::{get_module, Module},
+    command::configuration::SettingsRef,
+    diagnostics::{max_nesting_depth, too_deeply_nested},
+    name::with_import,
+    rpc::{LanguageServerCommand, OutgoingMessage},
+    server::ClientCapabilitiesRef,
+    BoxFuture,
+};
 
 use serde::Deserialize;
 use serde_json;
@@ -17,19 +44,1958 @@ use super::*;
 pub struct CompletionData {
     pub text_document_uri: Url,
     pub position: Position,
+    /// The module which exports the item's label, for values which are not in scope. Resolving the
+    /// item adds an import of it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import_from: Option<String>,
+}
+
+/// Whether `item` is a value of another module which has to be imported
+fn needs_import(item: &CompletionItem) -> bool {
+    item.data
+        .as_ref()
+        .is_some_and(|data| !data["import_from"].is_null())
+}
+
+/// Finds the module which each name destructured from an `import!` comes from, either directly,
+/// `let { x } = import! m`, or through a binding of the module, `let m = import! m in let { x } = m`
+#[derive(Default)]
+struct ImportedNames {
+    modules: FnvMap<String, String>,
+}
+
+impl ImportedNames {
+    fn add_pattern(&mut self, pattern: &Pattern<'_, Symbol>, module: &str) {
+        match pattern {
+            Pattern::Ident(id) => {
+                self.modules
+                    .insert(id.name.declared_name().to_string(), module.to_string());
+            }
+            Pattern::As(name, _) => {
+                self.modules
+                    .insert(name.value.declared_name().to_string(), module.to_string());
+            }
+            Pattern::Record { fields, .. } => {
+                for field in fields.iter() {
+                    let name = match field {
+                        PatternField::Value {
+                            value: Some(pattern),
+                            ..
+                        } => match &pattern.value {
+                            Pattern::Ident(id) => &id.name,
+                            _ => continue,
+                        },
+                        _ => &field.name().value,
+                    };
+                    self.modules
+                        .insert(name.declared_name().to_string(), module.to_string());
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for ImportedNames {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        if let Expr::LetBindings(bindings, _) = &e.value {
+            for binding in bindings.iter() {
+                let mut expr = &binding.expr;
+                // `import!` is expanded into the identifier of the module
+                while let Expr::MacroExpansion { replacement, .. } = &expr.value {
+                    expr = replacement;
+                }
+                let module = match &expr.value {
+                    Expr::Ident(id) if id.name.is_global() => id.name.as_pretty_str().to_string(),
+                    Expr::Ident(id) => match self.modules.get(id.name.declared_name()) {
+                        Some(module) => module.clone(),
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                self.add_pattern(&binding.name.value, &module);
+            }
+        }
+        ast::walk_expr(self, e)
+    }
+}
+
+/// Collects the names of every symbol declared in the module so that completions can be
+/// attributed to it
+fn declared_names(symbols: &[Spanned<CompletionSymbol<'_, '_>, BytePos>], names: &mut Vec<String>) {
+    for symbol in symbols {
+        names.push(symbol.value.name.declared_name().to_string());
+        declared_names(&symbol.value.children, names);
+    }
+}
+
+/// Finds the type of the innermost binding of each name which is in scope at `pos`, so that a
+/// binding which shadows another one can be told apart from it
+#[derive(Clone, Copy)]
+struct VisibleBindings<'e> {
+    env: &'e dyn TypeEnv<Type = ArcType>,
+    pos: BytePos,
+}
+
+impl VisibleBindings<'_> {
+    fn add_enclosing(&self, enclosing: &completion::Match, types: &mut FnvMap<String, ArcType>) {
+        let expr = match enclosing {
+            completion::Match::Expr(expr) => expr,
+            _ => return,
+        };
+        match &expr.value {
+            Expr::LetBindings(binds, body) => {
+                for bind in binds.iter() {
+                    if bind.expr.span.contains_pos(self.pos) {
+                        for arg in bind.args.iter() {
+                            add_binding(types, &arg.name.value.name, &arg.name.value.typ);
+                        }
+                    }
+                }
+                if body.span.contains_pos(self.pos) || binds.is_recursive() {
+                    for bind in binds.iter() {
+                        self.add_pattern(&bind.name, types);
+                    }
+                }
+            }
+            Expr::Lambda(lambda) if lambda.body.span.contains_pos(self.pos) => {
+                for arg in lambda.args.iter() {
+                    add_binding(types, &arg.name.value.name, &arg.name.value.typ);
+                }
+            }
+            Expr::Match(_, alts) => {
+                for alt in alts.iter() {
+                    if alt.expr.span.contains_pos(self.pos) {
+                        self.add_pattern(&alt.pattern, types);
+                    }
+                }
+            }
+            Expr::Do(do_) if do_.body.span.contains_pos(self.pos) => {
+                if let Some(id) = &do_.id {
+                    self.add_pattern(id, types);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn add_pattern(&self, pattern: &SpannedPattern<Symbol>, types: &mut FnvMap<String, ArcType>) {
+        match &pattern.value {
+            Pattern::Ident(id) => add_binding(types, &id.name, &id.typ),
+            Pattern::As(id, pat) => {
+                if let Ok(typ) = pat.try_type_of(self.env) {
+                    add_binding(types, &id.value, &typ);
+                }
+                self.add_pattern(pat, types);
+            }
+            Pattern::Record { typ, fields, .. } => {
+                let typ = resolve::remove_aliases(self.env, NullInterner::new(), typ.clone());
+                for field in fields.iter() {
+                    match field {
+                        PatternField::Value {
+                            value: Some(value), ..
+                        } => self.add_pattern(value, types),
+                        PatternField::Value { name, value: None } => {
+                            if let Some(field) =
+                                typ.row_iter().find(|field| field.name.name_eq(&name.value))
+                            {
+                                add_binding(types, &name.value, &field.typ);
+                            }
+                        }
+                        PatternField::Type { .. } => (),
+                    }
+                }
+            }
+            Pattern::Tuple { elems: args, .. } | Pattern::Constructor(_, args) => {
+                for arg in args.iter() {
+                    self.add_pattern(arg, types);
+                }
+            }
+            Pattern::Literal(_) | Pattern::Error => (),
+        }
+    }
+}
+
+/// Bindings are added from the innermost scope outwards so the first one of a name is visible
+fn add_binding(types: &mut FnvMap<String, ArcType>, name: &Symbol, typ: &ArcType) {
+    types
+        .entry(name.declared_name().to_string())
+        .or_insert_with(|| typ.clone());
+}
+
+impl<'a> completion::Extract<'a> for VisibleBindings<'_> {
+    type Output = FnvMap<String, ArcType>;
+
+    fn extract(self, found: &completion::Found<'a, '_>) -> Result<Self::Output, ()> {
+        let mut types = FnvMap::default();
+        for enclosing in found.enclosing_matches.iter().rev() {
+            self.add_enclosing(enclosing, &mut types);
+        }
+        Ok(types)
+    }
+
+    fn match_extract(self, found: &completion::Match<'a, '_>) -> Result<Self::Output, ()> {
+        let mut types = FnvMap::default();
+        self.add_enclosing(found, &mut types);
+        Ok(types)
+    }
+}
+
+/// Keeps one suggestion for each name, preferring the one of the binding which is visible in
+/// `visible` over the bindings it shadows
+fn innermost_suggestions(
+    suggestions: Vec<completion::Suggestion>,
+    visible: &FnvMap<String, ArcType>,
+) -> Vec<completion::Suggestion> {
+    let mut indexes = FnvMap::default();
+    let mut result: Vec<completion::Suggestion> = Vec::new();
+    for suggestion in suggestions {
+        let name: &str = suggestion.name.as_ref();
+        let label = name.split(':').next().unwrap_or(name).to_string();
+        match indexes.get(&label) {
+            Some(&index) => {
+                let is_visible = match (&suggestion.typ, visible.get(&label)) {
+                    (either::Either::Right(typ), Some(visible)) => typ == visible,
+                    _ => false,
+                };
+                if is_visible {
+                    result[index] = suggestion;
+                }
+            }
+            None => {
+                indexes.insert(label, result.len());
+                result.push(suggestion);
+            }
+        }
+    }
+    result
+}
+
+/// Whether `label` is a binding such as `_internal` which is not declared in the current module
+fn is_private(label: &str, local_names: &[String]) -> bool {
+    label.starts_with('_') && !local_names.iter().any(|name| name == label)
+}
+
+/// Finds the innermost record literal or record update (`{ x = 1, .. base }`) where `pos` is at a
+/// field name
+struct RecordFieldAt<'a, 'ast> {
+    pos: BytePos,
+    found: Option<RecordFields<'a, 'ast>>,
+}
+
+struct RecordFields<'a, 'ast> {
+    record: &'a SpannedExpr<'ast, Symbol>,
+    /// The record which is updated, `None` for a record literal
+    base: Option<&'a SpannedExpr<'ast, Symbol>>,
+    set_fields: Vec<&'a Symbol>,
+    /// The part of the field name before `pos`
+    prefix: &'a str,
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for RecordFieldAt<'a, 'ast> {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        if let Expr::Record { exprs, base, .. } = &e.value {
+            let contains = |span: Span<BytePos>| span.containment(self.pos) == Ordering::Equal;
+            let in_value = exprs.iter().any(|field| {
+                field
+                    .value
+                    .as_ref()
+                    .is_some_and(|value| contains(value.span))
+            });
+            // Between the braces of a literal or before the `..` of an update
+            let in_fields = match base {
+                Some(base) => self.pos < base.span.start(),
+                None => e.span.start() < self.pos && self.pos < e.span.end(),
+            };
+            if contains(e.span) && in_fields && !in_value {
+                // The field being written is not yet set
+                let set_fields = exprs
+                    .iter()
+                    .filter(|field| !contains(field.name.span))
+                    .map(|field| &field.name.value)
+                    .collect();
+                let prefix = exprs
+                    .iter()
+                    .find(|field| contains(field.name.span))
+                    .and_then(|field| {
+                        let len = (self.pos - field.name.span.start()).to_usize();
+                        field.name.value.declared_name().get(..len)
+                    })
+                    .unwrap_or("");
+                self.found = Some(RecordFields {
+                    record: e,
+                    base: base.as_deref(),
+                    set_fields,
+                    prefix,
+                });
+            }
+        }
+        ast::walk_expr(self, e)
+    }
+}
+
+/// Completes the fields which are not yet set when writing a record update, or a record literal
+/// which is passed where a record is expected. Returns `None` if `pos` is not at a field of such a
+/// record.
+fn record_field_completion(
+    env: &dyn TypeEnv<Type = ArcType>,
+    expr: &SpannedExpr<Symbol>,
+    pos: BytePos,
+    snippet_support: bool,
+    data: &serde_json::Value,
+) -> Option<Vec<CompletionItem>> {
+    let mut visitor = RecordFieldAt { pos, found: None };
+    visitor.visit_expr(expr);
+    let RecordFields {
+        record,
+        base,
+        set_fields,
+        prefix,
+    } = visitor.found?;
+
+    let typ = match base {
+        Some(base) => match base.try_type_of(env) {
+            Ok(typ) => typ,
+            Err(_) => return Some(Vec::new()),
+        },
+        // The fields of a literal are only known if the literal is an argument
+        None => expected_argument_type(env, expr, record.span.start())?,
+    };
+    let typ = resolve::remove_aliases(env, NullInterner::new(), typ);
+    if base.is_none() && !matches!(&*typ, Type::Record(_)) {
+        return None;
+    }
+    Some(
+        typ.row_iter()
+            .filter(|field| {
+                let name = field.name.declared_name();
+                name.starts_with(prefix)
+                    && !set_fields.iter().any(|set| set.declared_name() == name)
+            })
+            .map(|field| {
+                let label = field.name.declared_name().to_string();
+                let (insert_text, insert_text_format) = if snippet_support {
+                    (format!("{} = $0", label), InsertTextFormat::Snippet)
+                } else {
+                    (format!("{} = ", label), InsertTextFormat::PlainText)
+                };
+                CompletionItem {
+                    kind: Some(CompletionItemKind::Field),
+                    detail: Some(field.typ.to_string()),
+                    insert_text: Some(insert_text),
+                    insert_text_format: Some(insert_text_format),
+                    data: Some(data.clone()),
+                    label,
+                    ..CompletionItem::default()
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Expands the constructor `name` of type `typ` to a pattern which binds each of its arguments,
+/// `Ctor ${1:x} ${2:y}`. A constructor of a single record is expanded to the record's fields,
+/// `Ctor { x, y }`.
+fn constructor_pattern_snippet(
+    env: &dyn TypeEnv<Type = ArcType>,
+    name: &str,
+    typ: &ArcType,
+) -> String {
+    let typ = typ.remove_forall_and_implicit_args();
+    let args: Vec<_> = types::arg_iter(typ).cloned().collect();
+    let mut snippet = name.to_string();
+    if let [arg] = &args[..] {
+        let arg = resolve::remove_aliases(env, NullInterner::new(), arg.clone());
+        if let Type::Record(_) = &*arg {
+            let fields: Vec<_> = arg
+                .row_iter()
+                .map(|field| field.name.declared_name().to_string())
+                .collect();
+            snippet.push_str(&format!(" {{ {} }}", fields.join(", ")));
+            return snippet;
+        }
+    }
+    for (i, arg) in args.iter().enumerate() {
+        snippet.push_str(&format!(" ${{{}:{}}}", i + 1, binding_name(arg)));
+    }
+    snippet
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Returns the start of the word which ends at `cursor`
+fn word_start(source: &str, cursor: usize) -> usize {
+    source[..cursor]
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| is_word_char(c))
+        .last()
+        .map_or(cursor, |(i, _)| i)
+}
+
+/// The name of the copy of a module which is checked with a placeholder in it, relative to the
+/// module
+const PATCHED_MODULE: &str = "__completion";
+
+/// Type checks a copy of the module `module_name` with `source` where the text between `start` and
+/// `end` is replaced by `replacement`, for positions where the module does not parse or does not
+/// say enough, and calls `f` with the copy's source, expression and name. The copy is checked
+/// against a snapshot of the database so it is never added as a module that other checks could
+/// see or that would invalidate them.
+async fn with_patched_module<T>(
+    thread: &Thread,
+    module_name: &str,
+    source: &str,
+    (start, end): (usize, usize),
+    replacement: &str,
+    f: impl FnOnce(&gluon::base::source::FileMap, &SpannedExpr<'_, Symbol>, &str) -> Option<T>,
+) -> Option<T> {
+    let mut patched = String::with_capacity(source.len() + replacement.len());
+    patched.push_str(&source[..start]);
+    patched.push_str(replacement);
+    patched.push_str(&source[end..]);
+
+    // Requests are handled on threads with a stack of `STACK_SIZE` which this would overflow
+    if too_deeply_nested(&patched, max_nesting_depth(crate::STACK_SIZE)).is_some() {
+        return None;
+    }
+
+    let import = thread.get_macros().get("import").expect("Import macro");
+    let import = import
+        .downcast_ref::<Import<CheckImporter>>()
+        .expect("Check importer");
+    let _checking = import.importer.checking().await;
+
+    let patched_name = format!("{}.{}", module_name, PATCHED_MODULE);
+    let mut db = thread.get_database();
+    let result = (&patched[..])
+        .typecheck_expected(
+            &mut thread.module_compiler(&mut db),
+            thread,
+            &patched_name,
+            &patched,
+            None,
+        )
+        .await;
+    let value = match result {
+        Ok(value) => value,
+        Err(err) => err.value?,
+    };
+    // Another completion in the module may have checked a different copy since
+    let filemap = db
+        .get_filemap(&patched_name)
+        .filter(|filemap| filemap.source() == patched)?;
+    f(&filemap, value.expr.expr(), &patched_name)
+}
+
+/// Name which replaces the implicit argument being written so that the module can be parsed
+const IMPLICIT_PLACEHOLDER: &str = "__implicit_argument";
+
+/// Finds the function application where `pos` is at one of the implicit arguments (`f ?x`)
+struct ImplicitArgumentAt<'a, 'ast> {
+    pos: BytePos,
+    found: Option<(&'a SpannedExpr<'ast, Symbol>, usize)>,
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for ImplicitArgumentAt<'a, 'ast> {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        if let Expr::App {
+            func,
+            implicit_args,
+            ..
+        } = &e.value
+        {
+            if let Some(index) = implicit_args
+                .iter()
+                .position(|arg| arg.span.containment(self.pos) == Ordering::Equal)
+            {
+                self.found = Some((&**func, index));
+            }
+        }
+        ast::walk_expr(self, e)
+    }
+}
+
+/// Whether a value of type `actual` may be used where `expected` is wanted. Type variables match
+/// any type.
+fn types_may_match(expected: &ArcType, actual: &ArcType) -> bool {
+    match (&**expected, &**actual) {
+        (Type::Hole, _)
+        | (Type::Generic(_), _)
+        | (Type::Variable(_), _)
+        | (Type::Skolem(_), _)
+        | (_, Type::Hole)
+        | (_, Type::Generic(_))
+        | (_, Type::Variable(_))
+        | (_, Type::Skolem(_)) => true,
+        (Type::App(l, l_args), Type::App(r, r_args)) => {
+            l_args.len() == r_args.len()
+                && types_may_match(l, r)
+                && l_args
+                    .iter()
+                    .zip(r_args.iter())
+                    .all(|(l, r)| types_may_match(l, r))
+        }
+        (Type::App(..), _) | (_, Type::App(..)) => false,
+        _ => match (expected.name(), actual.name()) {
+            (Some(l), Some(r)) => l == r,
+            _ => expected == actual,
+        },
+    }
 }
 
+/// The number of type variables and implicit arguments in `typ`, fewer means more specific
+fn generality(typ: &ArcType) -> usize {
+    let mut count = typ.implicit_arg_iter().count();
+    types::walk_type(typ, |typ: &ArcType| {
+        if let Type::Generic(_) | Type::Variable(_) = **typ {
+            count += 1;
+        }
+    });
+    count
+}
+
+/// Completes the values which can be passed to the implicit argument which starts at
+/// `word_start`. Returns `None` if the function does not take an implicit argument there.
+async fn implicit_argument_completion(
+    thread: &Thread,
+    module_name: &str,
+    source: &str,
+    word_start: usize,
+    cursor: usize,
+) -> Option<Vec<CompletionItem>> {
+    let prefix = &source[word_start..cursor];
+
+    // A lone `?` does not parse so check a copy where the argument is replaced by a placeholder
+    with_patched_module(
+        thread,
+        module_name,
+        source,
+        (word_start, cursor),
+        IMPLICIT_PLACEHOLDER,
+        |filemap, expr, patched_name| {
+            let pos = filemap.span().start() + ByteOffset::from(word_start as i64);
+
+            let mut visitor = ImplicitArgumentAt { pos, found: None };
+            visitor.visit_expr(expr);
+            let (func, index) = visitor.found?;
+
+            let db = thread.get_database();
+            let env = db.as_env();
+            let func_type = func.try_type_of(&env).ok()?;
+            let expected = func_type
+                .remove_forall()
+                .implicit_arg_iter()
+                .nth(index)?
+                .clone();
+
+            let query = completion::SuggestionQuery {
+                prefix_filter: false,
+                ..completion::SuggestionQuery::default()
+            };
+            let mut candidates: Vec<_> = query
+                .suggest(&env, filemap.span(), expr, pos)
+                .into_iter()
+                .filter_map(|suggestion| {
+                    let typ = suggestion.typ.right()?;
+                    // Remove the `:Line x, Row y suffix`
+                    let label = suggestion.name.split(':').next()?.to_string();
+                    let matches = !label.starts_with("__")
+                        && label.starts_with(prefix)
+                        && types_may_match(&expected, typ.remove_forall_and_implicit_args());
+                    if matches {
+                        Some((generality(&typ), label, typ))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            candidates.sort_by(|l, r| (l.0, &l.1).cmp(&(r.0, &r.1)));
+
+            Some(
+                candidates
+                    .into_iter()
+                    .map(|(_, label, typ)| CompletionItem {
+                        kind: Some(type_to_completion_item_kind(&typ)),
+                        // Types from the copy are qualified by its name instead of the module's
+                        detail: Some(typ.to_string().replace(patched_name, module_name)),
+                        label,
+                        ..CompletionItem::default()
+                    })
+                    .collect(),
+            )
+        },
+    )
+    .await
+}
+
+/// Finds the function application where `pos` is at one of the explicit arguments (`f x`)
+struct ArgumentAt<'a, 'ast> {
+    pos: BytePos,
+    found: Option<(&'a SpannedExpr<'ast, Symbol>, usize)>,
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for ArgumentAt<'a, 'ast> {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        if let Expr::App { func, args, .. } = &e.value {
+            if let Some(index) = args
+                .iter()
+                .position(|arg| arg.span.containment(self.pos) == Ordering::Equal)
+            {
+                self.found = Some((&**func, index));
+            }
+        }
+        ast::walk_expr(self, e)
+    }
+}
+
+/// The type of the explicit argument which `pos` is at
+fn expected_argument_type(
+    env: &dyn TypeEnv<Type = ArcType>,
+    expr: &SpannedExpr<'_, Symbol>,
+    pos: BytePos,
+) -> Option<ArcType> {
+    let mut visitor = ArgumentAt { pos, found: None };
+    visitor.visit_expr(expr);
+    let (func, index) = visitor.found?;
+
+    nth_argument_type(env, func, index)
+}
+
+/// The type of the explicit argument at `index` of the function `func`
+fn nth_argument_type(
+    env: &dyn TypeEnv<Type = ArcType>,
+    func: &SpannedExpr<'_, Symbol>,
+    index: usize,
+) -> Option<ArcType> {
+    let func_type = func.try_type_of(env).ok()?;
+    let mut expected = func_type.remove_forall_and_implicit_args();
+    for _ in 0..index {
+        expected = expected.as_function()?.1.remove_forall_and_implicit_args();
+    }
+    expected.as_function().map(|(arg, _)| arg.clone())
+}
+
+/// Finds the outermost function application, or function, which ends at `end` along with the
+/// number of arguments it is applied to. An argument written after `end` is its next argument.
+struct ApplicationBefore<'a, 'ast> {
+    end: BytePos,
+    found: Option<(&'a SpannedExpr<'ast, Symbol>, usize)>,
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for ApplicationBefore<'a, 'ast> {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        if self.found.is_none() && e.span.end() == self.end {
+            match &e.value {
+                Expr::App { func, args, .. } => self.found = Some((&**func, args.len())),
+                Expr::Ident(_) | Expr::Projection(..) => self.found = Some((e, 0)),
+                _ => (),
+            }
+        }
+        ast::walk_expr(self, e)
+    }
+}
+
+/// Builds `\${1:x} ${2:y} -> $0` with a parameter for each argument of the function type `typ`.
+/// Returns `None` if `typ` is not a function.
+fn lambda_snippet(typ: &ArcType) -> Option<(String, String)> {
+    let mut names: Vec<String> = Vec::new();
+    let mut current = typ.remove_forall_and_implicit_args();
+    while let Some((arg, ret)) = current.as_function() {
+        let base_name = binding_name(arg);
+        let name = std::iter::once(base_name.clone())
+            .chain((1..).map(|i| format!("{}{}", base_name, i)))
+            .find(|name| !names.contains(name))?;
+        names.push(name);
+        current = ret.remove_forall_and_implicit_args();
+    }
+    if names.is_empty() {
+        return None;
+    }
+    let label = format!("\\{} ->", names.join(" "));
+    let params: Vec<_> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| format!("${{{}:{}}}", i + 1, name))
+        .collect();
+    Some((label, format!("\\{} -> $0", params.join(" "))))
+}
+
+/// Builds `{ x = ${1}, y = ${2} }` with each field of the record type `typ`. Returns `None` if
+/// `typ` is not a record with fields.
+fn record_snippet(env: &dyn TypeEnv<Type = ArcType>, typ: &ArcType) -> Option<(String, String)> {
+    let typ = resolve::remove_aliases(env, NullInterner::new(), typ.clone());
+    if !matches!(&*typ, Type::Record(_)) {
+        return None;
+    }
+    let names: Vec<_> = typ
+        .row_iter()
+        .map(|field| field.name.declared_name().to_string())
+        .collect();
+    if names.is_empty() {
+        return None;
+    }
+    let fields: Vec<_> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| format!("{} = ${{{}}}", name, i + 1))
+        .collect();
+    Some((
+        format!("{{ {} }}", names.join(", ")),
+        format!("{{ {} }}", fields.join(", ")),
+    ))
+}
+
+/// Offers a lambda when the argument at `cursor` is expected to be a function, such as the
+/// callback passed to `map`. The lambda takes as many parameters as the expected function and
+/// names them after their types. An argument which is expected to be a record is offered a
+/// record literal which sets each of its fields.
+fn argument_snippet_completion(
+    thread: &Thread,
+    module: &Module,
+    cursor: usize,
+) -> Option<CompletionItem> {
+    let source = &*module.source;
+    // Nothing of the argument is written yet so the checked module ends the application before it
+    let end = source.source()[..cursor].trim_end().len();
+    if end == cursor {
+        return None;
+    }
+    let mut visitor = ApplicationBefore {
+        end: source.span().start() + ByteOffset::from(end as i64),
+        found: None,
+    };
+    visitor.visit_expr(module.expr.expr());
+    let (func, index) = visitor.found?;
+
+    let db = thread.get_database();
+    let env = db.as_env();
+    let arg = nth_argument_type(&env, func, index)?;
+    let (label, snippet) = lambda_snippet(&arg).or_else(|| record_snippet(&env, &arg))?;
+    Some(CompletionItem {
+        label,
+        kind: Some(CompletionItemKind::Snippet),
+        detail: Some(arg.to_string()),
+        // Ranks the snippet before the names in scope
+        sort_text: Some(" ".into()),
+        insert_text: Some(snippet),
+        insert_text_format: Some(InsertTextFormat::Snippet),
+        ..CompletionItem::default()
+    })
+}
+
+/// A construct which is still open at the cursor, along with the column of the token which opened
+/// it
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OpenConstruct {
+    Bracket,
+    /// `if`, which is continued by `then`
+    If,
+    /// `then`, which is continued by `else`
+    Then,
+    /// `match`, which is continued by `with`
+    Match,
+    /// `let`, which is continued by `in` once it has a value
+    Let {
+        has_value: bool,
+    },
+}
+
+impl OpenConstruct {
+    fn continuation(self) -> Option<&'static str> {
+        match self {
+            OpenConstruct::If => Some("then"),
+            OpenConstruct::Then => Some("else"),
+            OpenConstruct::Match => Some("with"),
+            OpenConstruct::Let { has_value: true } => Some("in"),
+            OpenConstruct::Bracket | OpenConstruct::Let { has_value: false } => None,
+        }
+    }
+}
+
+struct Open {
+    construct: OpenConstruct,
+    column: usize,
+    /// Something has been written since the construct was opened or last continued
+    has_content: bool,
+    /// The last token needs an expression after it, such as an operator
+    dangling: bool,
+}
+
+/// Splits `text` into tokens along with their column, leaving out whitespace, comments and the
+/// contents of string and character literals
+fn tokens(text: &str) -> Vec<(&str, usize, bool)> {
+    let mut tokens = Vec::new();
+    let mut line_start = 0;
+    let mut first_on_line = true;
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        match c {
+            '\n' => {
+                line_start = end;
+                first_on_line = true;
+                continue;
+            }
+            _ if c.is_whitespace() => continue,
+            '/' if text[end..].starts_with('/') => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+            '/' if text[end..].starts_with('*') => {
+                let close = text[end + 1..]
+                    .find("*/")
+                    .map_or(text.len(), |i| end + i + 3);
+                while chars.next_if(|&(i, _)| i < close).is_some() {}
+                line_start = text[..close].rfind('\n').map_or(line_start, |i| i + 1);
+                continue;
+            }
+            '"' => {
+                while let Some((i, c)) = chars.next() {
+                    end = i + c.len_utf8();
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => (),
+                    }
+                }
+            }
+            '\'' => {
+                while let Some((i, c)) = chars.next() {
+                    end = i + c.len_utf8();
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '\'' => break,
+                        _ => (),
+                    }
+                }
+            }
+            '(' | ')' | '[' | ']' | '{' | '}' | ',' => (),
+            _ if is_word_char(c) => {
+                while let Some((i, c)) = chars.next_if(|&(_, c)| is_word_char(c) || c == '\'') {
+                    end = i + c.len_utf8();
+                }
+            }
+            _ => {
+                let operator =
+                    |c: char| !c.is_whitespace() && !is_word_char(c) && !"()[]{},\"'".contains(c);
+                while let Some((i, c)) = chars.next_if(|&(_, c)| operator(c)) {
+                    end = i + c.len_utf8();
+                }
+            }
+        }
+        let column = text[line_start..start].chars().count();
+        tokens.push((&text[start..end], column, first_on_line));
+        first_on_line = false;
+    }
+    tokens
+}
+
+/// Returns the keyword which continues the innermost construct that is open at the end of
+/// `before`, such as `then` after the condition of an `if`. Only offered once the part of the
+/// construct which comes before the keyword is written.
+fn continuation_keyword(before: &str) -> Option<&'static str> {
+    let mut stack: Vec<Open> = Vec::new();
+    // A `let` is closed by the layout once a line starts at or before its column
+    let offside = |stack: &mut Vec<Open>, column: usize| {
+        if let Some(i) = stack.iter().position(|open| {
+            matches!(open.construct, OpenConstruct::Let { .. }) && open.column >= column
+        }) {
+            stack.truncate(i);
+        }
+    };
+    // The enclosing construct is not complete until something follows `token`
+    let continued = |stack: &mut Vec<Open>| {
+        if let Some(open) = stack.last_mut() {
+            open.has_content = true;
+            open.dangling = true;
+        }
+    };
+    for (token, column, first_on_line) in tokens(before) {
+        if first_on_line && token != "in" {
+            offside(&mut stack, column);
+        }
+        let construct = match token {
+            "(" | "[" | "{" => Some(OpenConstruct::Bracket),
+            "if" => Some(OpenConstruct::If),
+            "match" => Some(OpenConstruct::Match),
+            "let" => Some(OpenConstruct::Let { has_value: false }),
+            _ => None,
+        };
+        if let Some(construct) = construct {
+            continued(&mut stack);
+            stack.push(Open {
+                construct,
+                column,
+                has_content: false,
+                dangling: false,
+            });
+            continue;
+        }
+        let top = stack.last().map(|open| open.construct);
+        match (token, top) {
+            (")", _) | ("]", _) | ("}", _) => {
+                while let Some(open) = stack.pop() {
+                    if open.construct == OpenConstruct::Bracket {
+                        break;
+                    }
+                }
+                if let Some(open) = stack.last_mut() {
+                    open.has_content = true;
+                    open.dangling = false;
+                }
+            }
+            ("then", Some(OpenConstruct::If)) => {
+                let open = stack.last_mut().unwrap();
+                open.construct = OpenConstruct::Then;
+                open.has_content = false;
+            }
+            ("else", Some(OpenConstruct::Then))
+            | ("with", Some(OpenConstruct::Match))
+            | ("in", Some(OpenConstruct::Let { .. })) => {
+                stack.pop();
+                continued(&mut stack);
+            }
+            ("=", Some(OpenConstruct::Let { has_value: false })) => {
+                let open = stack.last_mut().unwrap();
+                open.construct = OpenConstruct::Let { has_value: true };
+                open.has_content = false;
+            }
+            _ => {
+                if let Some(open) = stack.last_mut() {
+                    open.has_content = true;
+                    open.dangling = !token.starts_with(is_word_char)
+                        && !token.starts_with('"')
+                        && !token.starts_with('\'');
+                }
+            }
+        }
+    }
+    let cursor_line = before.rsplit('\n').next().unwrap_or(before);
+    if cursor_line.trim().is_empty() {
+        offside(&mut stack, cursor_line.chars().count());
+    }
+    match stack.last() {
+        Some(open) if open.has_content && !open.dangling => open.construct.continuation(),
+        _ => None,
+    }
+}
+
+/// Offers the keyword which continues the construct that the cursor is in, such as `with` after
+/// the scrutinee of a `match`
+fn continuation_keyword_completion(
+    text: &str,
+    word_start: usize,
+    cursor: usize,
+) -> Option<CompletionItem> {
+    let keyword = continuation_keyword(&text[..word_start])?;
+    if !keyword.starts_with(&text[word_start..cursor]) {
+        return None;
+    }
+    Some(CompletionItem {
+        label: keyword.into(),
+        kind: Some(CompletionItemKind::Keyword),
+        // Ranks the keyword before the names in scope
+        sort_text: Some(" ".into()),
+        ..CompletionItem::default()
+    })
+}
+
+/// Name which replaces the word after the `.` of a postfix completion so that the module can be
+/// parsed (`let` is a keyword)
+const POSTFIX_PLACEHOLDER: &str = "__postfix";
+
+/// Finds the projection of the postfix placeholder, the expression it projects from is the one
+/// being wrapped
+struct PostfixAt<'a, 'ast> {
+    found: Option<&'a SpannedExpr<'ast, Symbol>>,
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for PostfixAt<'a, 'ast> {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        if let Expr::Projection(expr, field, _) = &e.value {
+            if field.declared_name() == POSTFIX_PLACEHOLDER {
+                self.found = Some(&**expr);
+            }
+        }
+        ast::walk_expr(self, e)
+    }
+}
+
+/// Converts the name of a type, such as `HashMap`, to a variable name (`hash_map`)
+fn type_name_to_binding_name(name: &str) -> String {
+    let mut binding = String::with_capacity(name.len() + 2);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i != 0 {
+                binding.push('_');
+            }
+            binding.extend(c.to_lowercase());
+        } else {
+            binding.push(c);
+        }
+    }
+    binding
+}
+
+/// Suggests a name for a binding of a value of type `typ`
+fn binding_name(typ: &ArcType) -> String {
+    let typ = typ.remove_forall_and_implicit_args();
+    if typ.as_function().is_some() {
+        return "f".into();
+    }
+    let name = match &**typ {
+        Type::App(f, _) => return binding_name(f),
+        Type::Alias(alias) => alias.name.declared_name().to_string(),
+        Type::Ident(id) => id.name.declared_name().to_string(),
+        Type::Builtin(builtin) => builtin.to_str().to_string(),
+        Type::Record(_) => "record".into(),
+        Type::Variant(_) => "variant".into(),
+        _ => "x".into(),
+    };
+    // Remove the module prefix of a qualified name
+    type_name_to_binding_name(name.rsplit('.').next().unwrap_or(&name))
+}
+
+/// How many levels of records and functions are expanded in the documentation of a field
+const FIELD_EXPANSION_DEPTH: usize = 2;
+
+/// Prints `typ` with the records and functions it refers to expanded `depth` levels deep, so that
+/// the shape of a field's type is visible without navigating to its definition. `expanding` holds
+/// the aliases which are being expanded, an alias which refers to itself is marked as recursive
+/// instead of being expanded again.
+fn expand_type(
+    env: &dyn TypeEnv<Type = ArcType>,
+    typ: &ArcType,
+    depth: usize,
+    indent: usize,
+    expanding: &mut Vec<Symbol>,
+) -> String {
+    let alias = typ.alias_ident().cloned();
+    if let Some(alias) = &alias {
+        if expanding.contains(alias) {
+            return format!("{} /* recursive */", typ);
+        }
+    }
+    if depth == 0 {
+        return typ.to_string();
+    }
+
+    let resolved = resolve::remove_aliases(env, NullInterner::new(), typ.clone());
+    expanding.extend(alias.clone());
+    let expanded = match &*resolved {
+        Type::Forall(params, body) => {
+            let params: Vec<_> = params
+                .iter()
+                .map(|param| param.id.declared_name())
+                .collect();
+            format!(
+                "forall {} . {}",
+                params.join(" "),
+                expand_type(env, body, depth, indent, expanding)
+            )
+        }
+        Type::Record(_) if resolved.row_iter().next().is_some() => {
+            let field_indent = " ".repeat(4 * (indent + 1));
+            let mut expanded = String::from("{\n");
+            for field in resolved.row_iter() {
+                expanded.push_str(&format!(
+                    "{}{} : {},\n",
+                    field_indent,
+                    field.name.declared_name(),
+                    expand_type(env, &field.typ, depth - 1, indent + 1, expanding)
+                ));
+            }
+            expanded.push_str(&" ".repeat(4 * indent));
+            expanded.push('}');
+            expanded
+        }
+        _ if resolved.as_function().is_some() => {
+            let mut parts = Vec::new();
+            let mut current = &resolved;
+            while let Some((arg, ret)) = current.as_function() {
+                let arg_text = expand_type(env, arg, depth - 1, indent, expanding);
+                if arg.as_function().is_some() {
+                    parts.push(format!("({})", arg_text));
+                } else {
+                    parts.push(arg_text);
+                }
+                current = ret;
+            }
+            parts.push(expand_type(env, current, depth - 1, indent, expanding));
+            parts.join(" -> ")
+        }
+        _ => typ.to_string(),
+    };
+    if alias.is_some() {
+        expanding.pop();
+    }
+    expanded
+}
+
+/// Completes `expr.let` (or a prefix of `let`) with an item which rewrites it to
+/// `let <name> = expr in `, where the name is derived from the type of `expr`
+async fn postfix_let_completion(
+    thread: &Thread,
+    module_name: &str,
+    source: &gluon::base::source::FileMap,
+    word_start: usize,
+    cursor: usize,
+) -> Option<CompletionItem> {
+    let text = source.source();
+    let word = &text[word_start..cursor];
+    if !text[..word_start].ends_with('.') || !"let".starts_with(word) {
+        return None;
+    }
+    let dot = word_start - 1;
+
+    with_patched_module(
+        thread,
+        module_name,
+        text,
+        (word_start, cursor),
+        POSTFIX_PLACEHOLDER,
+        |filemap, module_expr, _| {
+            let mut visitor = PostfixAt { found: None };
+            visitor.visit_expr(module_expr);
+            let expr = visitor.found?;
+            let expr_start = (expr.span.start() - filemap.span().start()).to_usize();
+            if expr_start >= dot {
+                return None;
+            }
+
+            let db = thread.get_database();
+            let typ = expr.try_type_of(&db.as_env()).ok()?;
+
+            // Do not shadow a binding which is already in the module
+            let mut names = Vec::new();
+            declared_names(
+                &completion::all_symbols(filemap.span(), module_expr),
+                &mut names,
+            );
+            let base_name = binding_name(&typ);
+            let name = std::iter::once(base_name.clone())
+                .chain((1..).map(|i| format!("{}{}", base_name, i)))
+                .find(|name| !names.contains(name))?;
+
+            let expr_text = &text[expr_start..dot];
+            let range = Range {
+                start: codespan_lsp::byte_index_to_position(source, (), expr_start).ok()?,
+                end: codespan_lsp::byte_index_to_position(source, (), cursor).ok()?,
+            };
+            let new_text = format!("let {} = {} in ", name, expr_text);
+            Some(CompletionItem {
+                label: "let".into(),
+                kind: Some(CompletionItemKind::Snippet),
+                detail: Some(new_text.clone()),
+                filter_text: Some(format!("{}.let", expr_text)),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit { range, new_text })),
+                ..CompletionItem::default()
+            })
+        },
+    )
+    .await
+}
+
+/// Finds the projection whose receiver ends at `dot`, the receiver is the expression whose
+/// methods are completed
+struct ReceiverAt<'a, 'ast> {
+    dot: BytePos,
+    found: Option<&'a SpannedExpr<'ast, Symbol>>,
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for ReceiverAt<'a, 'ast> {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        if let Expr::Projection(expr, _, _) = &e.value {
+            if expr.span.end() == self.dot {
+                self.found = Some(&**expr);
+            }
+        }
+        ast::walk_expr(self, e)
+    }
+}
+
+/// Completes `expr.` with the functions in scope which take the type of `expr` as their first
+/// argument. Each item rewrites the projection to a call of the function, `f expr`.
+fn method_completion(
+    thread: &Thread,
+    module: &Module,
+    word_start: usize,
+    cursor: usize,
+) -> Vec<CompletionItem> {
+    let source = &*module.source;
+    let text = source.source();
+    if !text[..word_start].ends_with('.') {
+        return Vec::new();
+    }
+    let dot = word_start - 1;
+    let word = &text[word_start..cursor];
+
+    // The receiver is in the checked module as the projection of the word, or of nothing
+    let module_expr = module.expr.expr();
+    let mut visitor = ReceiverAt {
+        dot: source.span().start() + ByteOffset::from(dot as i64),
+        found: None,
+    };
+    visitor.visit_expr(module_expr);
+    let expr = match visitor.found {
+        Some(expr) => expr,
+        None => return Vec::new(),
+    };
+    let expr_start = (expr.span.start() - source.span().start()).to_usize();
+
+    let db = thread.get_database();
+    let env = db.as_env();
+    let receiver = match expr.try_type_of(&env) {
+        Ok(typ) => typ,
+        Err(_) => return Vec::new(),
+    };
+    let range = match (
+        codespan_lsp::byte_index_to_position(source, (), expr_start),
+        codespan_lsp::byte_index_to_position(source, (), cursor),
+    ) {
+        (Ok(start), Ok(end)) => Range { start, end },
+        _ => return Vec::new(),
+    };
+    let expr_text = &text[expr_start..dot];
+    let argument = if expr_text.contains(char::is_whitespace) {
+        format!("({})", expr_text)
+    } else {
+        expr_text.to_string()
+    };
+
+    // Every name in scope at the receiver, not only those which start like it
+    let query = completion::SuggestionQuery {
+        prefix_filter: false,
+        ..completion::SuggestionQuery::default()
+    };
+    let mut items: Vec<_> = query
+        .suggest(&env, source.span(), module_expr, expr.span.start())
+        .into_iter()
+        .filter_map(|suggestion| {
+            let typ = suggestion.typ.right()?;
+            let name: &str = suggestion.name.as_ref();
+            if !name.starts_with(char::is_alphabetic) || !name.starts_with(word) {
+                return None;
+            }
+            let (first, _) = typ.remove_forall_and_implicit_args().as_function()?;
+            // A function which takes any value is not a method of the receiver
+            if let Type::Generic(_) | Type::Variable(_) = **first {
+                return None;
+            }
+            if !types_may_match(first, &receiver) {
+                return None;
+            }
+            let new_text = format!("{} {}", name, argument);
+            Some(CompletionItem {
+                label: name.to_string(),
+                kind: Some(CompletionItemKind::Method),
+                detail: Some(typ.to_string()),
+                // Ranks the methods after the fields of the receiver
+                sort_text: Some(format!("~{}", name)),
+                filter_text: Some(format!("{}.{}", expr_text, name)),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit { range, new_text })),
+                ..CompletionItem::default()
+            })
+        })
+        .collect();
+    items.sort_by(|l, r| l.label.cmp(&r.label));
+    items.dedup_by(|l, r| l.label == r.label);
+    items
+}
+
+/// Whether `byte_index` is between the quotes of a string or character literal, where no names
+/// can be written
+fn in_literal(
+    source_span: Span<BytePos>,
+    expr: &SpannedExpr<'_, Symbol>,
+    byte_index: BytePos,
+) -> bool {
+    let span = match innermost_node_at(source_span, expr, byte_index) {
+        Some(Node::Expr(expr)) => match expr.value {
+            Expr::Literal(ast::Literal::String(_)) | Expr::Literal(ast::Literal::Char(_)) => {
+                expr.span
+            }
+            _ => return false,
+        },
+        Some(Node::Pattern(pattern)) => match pattern.value {
+            ast::Pattern::Literal(ast::Literal::String(_))
+            | ast::Pattern::Literal(ast::Literal::Char(_)) => pattern.span,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    span.start() < byte_index && byte_index < span.end()
+}
+
+/// Name which replaces the type being written so that annotations without a type, such as
+/// `let x : `, can be parsed
+const TYPE_PLACEHOLDER: &str = "__type";
+
+/// Whether the word which starts after `before` may be a type. Only a guess, the position is
+/// checked against the syntax tree before types are completed.
+fn may_be_type_position(before: &str) -> bool {
+    let trimmed = before.trim_end();
+    let line = trimmed.rsplit('\n').next().unwrap_or(trimmed).trim_start();
+    trimmed.ends_with(':')
+        || trimmed.ends_with("->")
+        || (trimmed.ends_with('=') && line.starts_with("type "))
+}
+
+/// Completes the types, type aliases and type constructors which are in scope when the word
+/// starting at `word_start` is part of a type. Returns `None` if it is an expression or pattern.
+async fn type_completion(
+    thread: &Thread,
+    module_name: &str,
+    source: &str,
+    word_start: usize,
+    cursor: usize,
+) -> Option<Vec<CompletionItem>> {
+    let prefix = &source[word_start..cursor];
+
+    let line_start = source[..word_start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[cursor..]
+        .find('\n')
+        .map_or(source.len(), |i| cursor + i);
+    // The annotation of a binding is usually written before its value
+    let missing_value = source[line_start..word_start]
+        .trim_start()
+        .starts_with("let ")
+        && source[cursor..line_end].trim().is_empty();
+
+    let mut replacement = String::from(TYPE_PLACEHOLDER);
+    if missing_value {
+        replacement.push_str(" = ()");
+    }
+
+    with_patched_module(
+        thread,
+        module_name,
+        source,
+        (word_start, cursor),
+        &replacement,
+        |filemap, expr, _| {
+            let pos = filemap.span().start() + ByteOffset::from(word_start as i64);
+
+            match innermost(enclosing_nodes(filemap.span(), expr, pos)) {
+                Some(Node::Type(_)) => (),
+                _ => return None,
+            }
+
+            let db = thread.get_database();
+            let query = completion::SuggestionQuery {
+                prefix_filter: false,
+                ..completion::SuggestionQuery::default()
+            };
+            let mut items: Vec<_> = query
+                .suggest(&db.as_env(), filemap.span(), expr, pos)
+                .into_iter()
+                .filter_map(|suggestion| {
+                    let kind = suggestion.typ.left()?;
+                    let label = suggestion.name.split(':').next()?.to_string();
+                    Some((label, kind))
+                })
+                .chain(
+                    [
+                        BuiltinType::Int,
+                        BuiltinType::Float,
+                        BuiltinType::String,
+                        BuiltinType::Char,
+                        BuiltinType::Byte,
+                    ]
+                    .iter()
+                    .map(|builtin| (builtin.to_str().to_string(), Kind::typ())),
+                )
+                .chain(std::iter::once((
+                    BuiltinType::Array.to_str().to_string(),
+                    Kind::function(Kind::typ(), Kind::typ()),
+                )))
+                .filter(|(label, _)| !label.starts_with("__") && label.starts_with(prefix))
+                .map(|(label, kind)| CompletionItem {
+                    kind: Some(CompletionItemKind::Class),
+                    detail: Some(kind.to_string()),
+                    label,
+                    ..CompletionItem::default()
+                })
+                .collect();
+            items.sort_by(|l, r| l.label.cmp(&r.label));
+            items.dedup_by(|l, r| l.label == r.label);
+            Some(items)
+        },
+    )
+    .await
+}
+
+/// How well `label` matches the word being completed, lower is better. Clients may match
+/// labels which do not start with the word.
+fn match_rank(label: &str, word: &str) -> u8 {
+    if label == word {
+        0
+    } else if label.starts_with(word) {
+        1
+    } else {
+        2
+    }
+}
+
+fn is_deprecated(item: &CompletionItem) -> bool {
+    item.deprecated == Some(true)
+        || item
+            .tags
+            .as_ref()
+            .is_some_and(|tags| tags.contains(&CompletionItemTag::Deprecated))
+}
+
+/// The module which declares `item` and its type, which tell apart items with the same label
+fn item_origin(item: &CompletionItem) -> (Option<&str>, Option<&str>) {
+    let label_details = item.label_details.as_ref();
+    (
+        label_details.and_then(|details| details.qualifier.as_deref()),
+        item.detail
+            .as_deref()
+            .or_else(|| label_details.and_then(|details| details.typ.as_deref())),
+    )
+}
+
+/// Orders `items` by how well they match `word`. Items in `expected`, which have the type expected
+/// at the cursor, come before the items which match `word` as well as they do and deprecated items
+/// come after them. Items only get a `sortText` if that order differs from the order of their
+/// labels.
+///
+/// The first item is preselected if it is the only exact match of `word`, or the only match of
+/// the expected type, and it is not deprecated.
+fn rank_items(items: &mut [CompletionItem], word: &str, expected: &FnvSet<String>) {
+    let key = |item: &CompletionItem| {
+        (
+            match_rank(&item.label, word),
+            needs_import(item),
+            !expected.contains(&item.label),
+            is_deprecated(item),
+        )
+    };
+    // Items which rank the same are ordered by label and then by where they come from, so that the
+    // order does not depend on the order of the scopes and stays the same while the word is typed
+    items.sort_by(|l, r| {
+        (key(l), &l.label, item_origin(l)).cmp(&(key(r), &r.label, item_origin(r)))
+    });
+    let by_label = items.windows(2).all(|pair| pair[0].label <= pair[1].label);
+    for (i, item) in items.iter_mut().enumerate() {
+        item.sort_text = if by_label {
+            None
+        } else {
+            Some(format!("{:04}", i))
+        };
+        item.preselect = None;
+    }
+
+    // Only an item which ranks above every other item is an obvious choice
+    let preselect = match &*items {
+        [first, rest @ ..] => {
+            let (rank, _, unexpected, deprecated) = key(first);
+            !deprecated
+                && (rank == 0 || (rank == 1 && !unexpected))
+                && rest.first().is_none_or(|second| {
+                    let (second_rank, _, second_unexpected, _) = key(second);
+                    (rank, unexpected) < (second_rank, second_unexpected)
+                })
+        }
+        [] => false,
+    };
+    if preselect {
+        items[0].preselect = Some(true);
+    }
+}
+
+/// The items of the last completion so that requests which only narrow the word being completed
+/// (such as clients re-querying as the user types) can be answered without checking the module
+/// again
+pub struct CompletionCache {
+    uri: Url,
+    source: String,
+    word_start: usize,
+    cursor: usize,
+    /// The items which depend on the exact position, offered before `items`
+    leading: Vec<CompletionItem>,
+    items: Vec<CompletionItem>,
+    /// The items which depend on the exact position, offered after `items`
+    trailing: Vec<CompletionItem>,
+    /// The labels of the items which have the type expected at the cursor
+    expected: FnvSet<String>,
+}
+
+impl CompletionCache {
+    fn new(
+        uri: Url,
+        source: &str,
+        cursor: usize,
+        [leading, items, trailing]: [Vec<CompletionItem>; 3],
+        expected: FnvSet<String>,
+    ) -> Self {
+        let word_start = word_start(source, cursor);
+        CompletionCache {
+            uri,
+            source: source.to_string(),
+            word_start,
+            cursor,
+            leading,
+            items,
+            trailing,
+            expected,
+        }
+    }
+
+    /// Returns the cached items matching the word at `cursor` if the only edits since the cache
+    /// was filled extended the word being completed. The items refer to `position`, the position
+    /// of `cursor`, instead of the position they were completed at.
+    fn narrow(
+        &self,
+        uri: &Url,
+        source: &str,
+        cursor: usize,
+        position: Position,
+    ) -> Option<[Vec<CompletionItem>; 3]> {
+        if *uri != self.uri
+            || cursor < self.cursor
+            || source.len() - cursor != self.source.len() - self.cursor
+            || source.get(..self.cursor) != Some(&self.source[..self.cursor])
+            || source.get(cursor..) != Some(&self.source[self.cursor..])
+        {
+            return None;
+        }
+        let added = &source[self.cursor..cursor];
+        if !added.chars().all(is_word_char) {
+            return None;
+        }
+        let word = &source[self.word_start..cursor];
+        let matching = |items: &[CompletionItem]| -> Vec<_> {
+            items
+                .iter()
+                .filter(|item| item.label.starts_with(word))
+                .map(|item| {
+                    let mut item = item.clone();
+                    if let Some(data) = item.data.as_mut().and_then(|data| data.get_mut("position"))
+                    {
+                        *data = serde_json::to_value(position).expect("Position");
+                    }
+                    // Edits which replace the word end where the word ends now
+                    if let Some(CompletionTextEdit::Edit(edit)) = &mut item.text_edit {
+                        edit.range.end = position;
+                    }
+                    item
+                })
+                .collect()
+        };
+        let mut items = matching(&self.items);
+        rank_items(&mut items, word, &self.expected);
+        Some([matching(&self.leading), items, matching(&self.trailing)])
+    }
+}
+
+/// `textDocument/completion` which responds with `itemDefaults`
+enum CompletionRequest {}
+
+impl lsp_types::request::Request for CompletionRequest {
+    type Params = CompletionParams;
+    type Result = Option<CompletionResponse>;
+    const METHOD: &'static str = lsp_types::request::Completion::METHOD;
+}
+
+/// `completionItem/resolve` on items with the `labelDetails` of LSP 3.17
+enum ResolveCompletionItemRequest {}
+
+impl lsp_types::request::Request for ResolveCompletionItemRequest {
+    type Params = ClientCompletionItem;
+    type Result = ClientCompletionItem;
+    const METHOD: &'static str = lsp_types::request::ResolveCompletionItem::METHOD;
+}
+
+/// `CompletionResponse` with `CompletionList.itemDefaults` from LSP 3.17
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum CompletionResponse {
+    Array(Vec<ClientCompletionItem>),
+    List(CompletionList),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CompletionList {
+    is_incomplete: bool,
+    item_defaults: CompletionItemDefaults,
+    items: Vec<ClientCompletionItem>,
+}
+
+/// `CompletionItemLabelDetails` as LSP 3.17 defines them. `lsp_types` only has the proposed
+/// `qualifier` and `type` which items are built with, they become `description` and `detail`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct LabelDetails {
+    /// The type, shown right after the label
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    /// The module which declares the item
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+}
+
+/// A `CompletionItem` as it is sent to and received from the client, with the `labelDetails` of
+/// LSP 3.17
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientCompletionItem {
+    #[serde(flatten)]
+    item: CompletionItem,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label_details: Option<LabelDetails>,
+    /// The text which replaces `CompletionItemDefaults::edit_range`. Clients which apply the
+    /// default range ignore `insertText`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text_edit_text: Option<String>,
+}
+
+impl From<CompletionItem> for ClientCompletionItem {
+    fn from(mut item: CompletionItem) -> Self {
+        let label_details = item.label_details.take().map(|details| LabelDetails {
+            detail: match (details.parameters, details.typ) {
+                (Some(parameters), Some(typ)) => Some(parameters + &typ),
+                (parameters, typ) => parameters.or(typ),
+            },
+            description: details.qualifier,
+        });
+        ClientCompletionItem {
+            item,
+            label_details,
+            text_edit_text: None,
+        }
+    }
+}
+
+impl From<ClientCompletionItem> for CompletionItem {
+    fn from(item: ClientCompletionItem) -> Self {
+        CompletionItem {
+            label_details: item.label_details.map(|details| {
+                lsp_types::CompletionItemLabelDetails {
+                    parameters: None,
+                    qualifier: details.description,
+                    typ: details.detail,
+                }
+            }),
+            ..item.item
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+// `commitCharacters` is left out as items do not set any commit characters of their own
+struct CompletionItemDefaults {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    edit_range: Option<Range>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    insert_text_format: Option<InsertTextFormat>,
+}
+
+fn most_common<T: PartialEq>(values: impl IntoIterator<Item = T>) -> Option<T> {
+    let mut counts: Vec<(T, usize)> = Vec::new();
+    for value in values {
+        match counts.iter_mut().find(|(counted, _)| *counted == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value, 1)),
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(_, count)| count)
+        .map(|(value, _)| value)
+}
+
+/// Moves the properties which most items share into `itemDefaults` if the client supports it, so
+/// that they are only sent once. Items only keep the properties which differ from the defaults.
+fn completion_response(
+    mut items: Vec<CompletionItem>,
+    supported_defaults: &[String],
+    edit_range: Option<Range>,
+) -> CompletionResponse {
+    if supported_defaults.is_empty() {
+        return CompletionResponse::Array(items.into_iter().map(Into::into).collect());
+    }
+    let supports = |name: &str| supported_defaults.iter().any(|supported| supported == name);
+    let mut item_defaults = CompletionItemDefaults::default();
+
+    if supports("editRange") {
+        // Items without a `textEdit` replace the word before the cursor
+        item_defaults.edit_range = edit_range;
+    }
+
+    if supports("insertTextFormat") {
+        let format = |item: &CompletionItem| {
+            item.insert_text_format
+                .unwrap_or(InsertTextFormat::PlainText)
+        };
+        if let Some(default) = most_common(items.iter().map(format)) {
+            for item in &mut items {
+                let item_format = format(item);
+                item.insert_text_format = if item_format == default {
+                    None
+                } else {
+                    Some(item_format)
+                };
+            }
+            if default != InsertTextFormat::PlainText {
+                item_defaults.insert_text_format = Some(default);
+            }
+        }
+    }
+
+    let items = items
+        .into_iter()
+        .map(|item| {
+            // Snippets such as `x = $0` of record fields are only in `insertText`
+            let text_edit_text = match &item.text_edit {
+                None if item_defaults.edit_range.is_some() => item.insert_text.clone(),
+                _ => None,
+            };
+            ClientCompletionItem {
+                text_edit_text,
+                ..item.into()
+            }
+        })
+        .collect();
+    CompletionResponse::List(CompletionList {
+        is_incomplete: false,
+        item_defaults,
+        items,
+    })
+}
+
+pub(crate) type CompletionCacheRef = Arc<Mutex<Option<CompletionCache>>>;
+
 #[derive(Clone)]
-struct Completion(RootedThread);
+struct Completion(
+    RootedThread,
+    ClientCapabilitiesRef,
+    SettingsRef,
+    CompletionCacheRef,
+);
 impl LanguageServerCommand<CompletionParams> for Completion {
     type Future = BoxFuture<Self::Output, ServerError<()>>;
     type Output = Option<CompletionResponse>;
     type Error = ();
     fn execute(&self, change: CompletionParams) -> BoxFuture<Self::Output, ServerError<()>> {
         let thread = self.0.clone();
+        let (
+            label_details_support,
+            snippet_support,
+            deprecated_tag_support,
+            preselect_support,
+            supported_defaults,
+        ) = {
+            let client_capabilities = self.1.read().unwrap();
+            (
+                client_capabilities.supports_label_details(),
+                client_capabilities.supports_snippets(),
+                client_capabilities.supports_deprecated_completion_tag(),
+                client_capabilities.supports_preselect(),
+                client_capabilities.completion_item_defaults.clone(),
+            )
+        };
+        let (postfix_completion, hide_private, kind_remap) = {
+            let settings = self.2.read().unwrap();
+            (
+                settings.postfix_completion,
+                settings.completion.hide_private,
+                settings.kind_remap.clone(),
+            )
+        };
+        let cache = self.3.clone();
         let text_document_uri = change.text_document_position.text_document.uri.clone();
         async move {
-            retrieve_expr(&thread.clone(), &text_document_uri, |module| {
+            let module_name =
+                filename_to_module(&strip_file_prefix_with_thread(&thread, &text_document_uri));
+            let current_source = thread.get_database().get_filemap(&module_name);
+            let cursor = current_source.as_ref().and_then(|source| {
+                codespan_lsp::position_to_byte_index(
+                    &**source,
+                    (),
+                    &change.text_document_position.position,
+                )
+                .ok()
+            });
+            let edit_range = match (&current_source, cursor) {
+                (Some(source), Some(cursor)) => codespan_lsp::byte_index_to_position(
+                    &**source,
+                    (),
+                    word_start(source.source(), cursor),
+                )
+                .ok()
+                .map(|start| Range {
+                    start,
+                    end: change.text_document_position.position,
+                }),
+                _ => None,
+            };
+            // Postfix, method, argument snippet and keyword items depend on the exact position so
+            // they are kept apart from the items which are ranked by how well they match the word
+            let response = |leading: Vec<CompletionItem>,
+                            mut items: Vec<CompletionItem>,
+                            trailing: Vec<CompletionItem>| {
+                if !preselect_support {
+                    for item in &mut items {
+                        item.preselect = None;
+                    }
+                }
+                let mut items: Vec<_> = leading.into_iter().chain(items).chain(trailing).collect();
+                for item in &mut items {
+                    item.kind = item.kind.map(|kind| kind_remap.completion(kind));
+                }
+                Ok(Some(completion_response(
+                    items,
+                    &supported_defaults,
+                    edit_range,
+                )))
+            };
+            // Answered before any work on the module so that typing stays cheap
+            if let (Some(source), Some(cursor)) = (&current_source, cursor) {
+                let narrowed = cache.lock().unwrap().as_ref().and_then(|cache| {
+                    cache.narrow(
+                        &text_document_uri,
+                        source.source(),
+                        cursor,
+                        change.text_document_position.position,
+                    )
+                });
+                if let Some([leading, items, trailing]) = narrowed {
+                    debug!(
+                        "Completion of {} narrowed from the cache",
+                        text_document_uri
+                    );
+                    return response(leading, items, trailing);
+                }
+            }
+
+            let checked = match cursor {
+                Some(_) => retrieve_module_from_url(&thread, &text_document_uri)
+                    .await
+                    .ok(),
+                None => None,
+            };
+            if let Some(module) = &checked {
+                let in_literal =
+                    position_to_byte_index(&module.source, &change.text_document_position.position)
+                        .is_ok_and(|byte_index| {
+                            in_literal(module.source.span(), module.expr.expr(), byte_index)
+                        });
+                if in_literal {
+                    return Ok(Some(completion_response(
+                        Vec::new(),
+                        &supported_defaults,
+                        edit_range,
+                    )));
+                }
+            }
+            let postfix = match (&current_source, cursor) {
+                (Some(source), Some(cursor)) if postfix_completion => {
+                    let word_start = word_start(source.source(), cursor);
+                    postfix_let_completion(&thread, &module_name, source, word_start, cursor).await
+                }
+                _ => None,
+            };
+            let methods = match (&checked, cursor) {
+                (Some(module), Some(cursor)) => {
+                    let word_start = word_start(module.source.source(), cursor);
+                    method_completion(&thread, module, word_start, cursor)
+                }
+                _ => Vec::new(),
+            };
+            // A lambda or a record is only offered before anything of the argument has been written
+            let argument_snippet = match (&checked, cursor) {
+                (Some(module), Some(cursor))
+                    if snippet_support && word_start(module.source.source(), cursor) == cursor =>
+                {
+                    argument_snippet_completion(&thread, module, cursor)
+                }
+                _ => None,
+            };
+            let keyword = match (&current_source, cursor) {
+                (Some(source), Some(cursor)) => continuation_keyword_completion(
+                    source.source(),
+                    word_start(source.source(), cursor),
+                    cursor,
+                ),
+                _ => None,
+            };
+            let leading: Vec<_> = argument_snippet.into_iter().chain(keyword).collect();
+            let trailing: Vec<_> = methods.into_iter().chain(postfix).collect();
+            if let (Some(source), Some(cursor)) = (&current_source, cursor) {
+                let word_start = word_start(source.source(), cursor);
+                if may_be_type_position(&source.source()[..word_start]) {
+                    let items =
+                        type_completion(&thread, &module_name, source.source(), word_start, cursor)
+                            .await;
+                    if let Some(items) = items {
+                        return response(leading, items, trailing);
+                    }
+                }
+                if source.source()[..word_start].ends_with('?') {
+                    let items = implicit_argument_completion(
+                        &thread,
+                        &module_name,
+                        source.source(),
+                        word_start,
+                        cursor,
+                    )
+                    .await;
+                    if let Some(items) = items {
+                        return response(leading, items, trailing);
+                    }
+                }
+            }
+
+            // Values of other modules are offered once something has been typed, the import is
+            // only added when the item is resolved. The standard library is left out as its
+            // modules would bury the project's, and the prelude brings the common names in scope.
+            let importable = match (&current_source, cursor) {
+                (Some(source), Some(cursor)) => {
+                    let text = source.source();
+                    let start = word_start(text, cursor);
+                    let word = &text[start..cursor];
+                    if word.is_empty() || text[..start].ends_with('.') {
+                        Vec::new()
+                    } else {
+                        code_action::exported_values(&thread, &module_name)
+                            .await
+                            .into_iter()
+                            .filter(|(module, name, _)| {
+                                !module.starts_with("std.")
+                                    && name.starts_with(word)
+                                    && !name.starts_with('_')
+                            })
+                            .collect()
+                    }
+                }
+                _ => Vec::new(),
+            };
+
+            let (items, expected) = retrieve_expr(&thread.clone(), &text_document_uri, |module| {
                 let Module {
                     ref expr,
                     ref source,
@@ -41,6 +2007,24 @@ impl LanguageServerCommand<CompletionParams> for Completion {
                 let byte_index =
                     position_to_byte_index(&**source, &change.text_document_position.position)?;
 
+                let data = serde_json::to_value(CompletionData {
+                    text_document_uri: change.text_document_position.text_document.uri.clone(),
+                    position: change.text_document_position.position,
+                    import_from: None,
+                })
+                .expect("CompletionData");
+
+                if let Some(mut items) = record_field_completion(
+                    &thread.get_database().as_env(),
+                    expr,
+                    byte_index,
+                    snippet_support,
+                    &data,
+                ) {
+                    items.sort_by(|l, r| l.label.cmp(&r.label));
+                    return Ok((items, FnvSet::default()));
+                }
+
                 let query = completion::SuggestionQuery {
                     modules: with_import(&thread, |import| {
                         import.modules(&mut thread.module_compiler(&mut thread.get_database()))
@@ -48,12 +2032,85 @@ impl LanguageServerCommand<CompletionParams> for Completion {
                     ..completion::SuggestionQuery::default()
                 };
 
+                let mut local_names = Vec::new();
+                if label_details_support || hide_private {
+                    declared_names(
+                        &completion::all_symbols(source.span(), expr),
+                        &mut local_names,
+                    );
+                }
+                let mut imported = ImportedNames::default();
+                if label_details_support {
+                    imported.visit_expr(expr);
+                }
+
                 let db = thread.get_database();
                 let suggestions = query
                     .suggest(&db.as_env(), source.span(), expr, byte_index)
                     .into_iter()
                     .filter(|suggestion| !suggestion.name.starts_with("__"))
+                    .filter(|suggestion| {
+                        let name: &str = suggestion.name.as_ref();
+                        let label = name.split(':').next().unwrap_or(name);
+                        !(hide_private && is_private(label, &local_names))
+                    })
                     .collect::<Vec<_>>();
+                // Bindings which are shadowed by an inner binding of the same name can not be
+                // referred to, only the inner binding is offered
+                let visible = completion::completion(
+                    VisibleBindings {
+                        env: &db.as_env(),
+                        pos: byte_index,
+                    },
+                    source.span(),
+                    expr,
+                    byte_index,
+                )
+                .unwrap_or_default();
+                let suggestions = innermost_suggestions(suggestions, &visible);
+
+                // Constructors in the pattern of a `match` arm are expanded to the whole arm. An
+                // arm which is still being written ends in an empty expression so the pattern need
+                // not be the smallest node.
+                let arm_snippets = snippet_support
+                    && enclosing_nodes(source.span(), expr, byte_index)
+                        .into_iter()
+                        .any(|node| matches!(node, Node::Pattern(_)));
+                let rest_of_line =
+                    &source.source()[(byte_index - source.span().start()).to_usize()..];
+                let close_arm = rest_of_line
+                    .split('\n')
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .is_empty();
+
+                let (word, word_start) = {
+                    let text = source.source();
+                    let cursor = (byte_index - source.span().start()).to_usize();
+                    let start = word_start(text, cursor);
+                    (&text[start..cursor], start)
+                };
+                // The word being completed is the argument whose type is expected
+                let expected_type = expected_argument_type(
+                    &db.as_env(),
+                    expr,
+                    source.span().start() + ByteOffset::from(word_start as i64),
+                );
+                let mut expected = FnvSet::default();
+                // Bindings with a `#[deprecated]` attribute are tagged as deprecated
+                let (_, metadata_map) = gluon::check::metadata::metadata(&db.as_env(), expr);
+                let deprecated = |label: &str| {
+                    completion::suggest_metadata(
+                        &metadata_map,
+                        &db.as_env(),
+                        source.span(),
+                        expr,
+                        byte_index,
+                        label,
+                    )
+                    .is_some_and(|metadata| metadata.get_attribute("deprecated").is_some())
+                };
 
                 let mut items: Vec<_> = suggestions
                     .into_iter()
@@ -62,42 +2119,137 @@ impl LanguageServerCommand<CompletionParams> for Completion {
                         let name: &str = ident.name.as_ref();
                         let label =
                             String::from(name.split(':').next().unwrap_or(ident.name.as_ref()));
-                        CompletionItem {
-                            insert_text: if label.starts_with(char::is_alphabetic) {
-                                None
-                            } else {
-                                Some(format!("({})", label))
+                        let detail = match ident.typ {
+                            either::Either::Right(ref typ) => match **typ {
+                                Type::Hole => None,
+                                _ => Some(format!("{}", ident.typ)),
                             },
-                            kind: Some(ident_to_completion_item_kind(&label, ident.typ.as_ref())),
+                            either::Either::Left(_) => Some(format!("{}", ident.typ)),
+                        };
+                        // Clients which can render label details get the type and the module
+                        // separately, other clients only get the type in `detail`
+                        let (detail, label_details) = if label_details_support {
+                            // Names which are imported are declared in the module as well
+                            let qualifier = match imported.modules.get(&label) {
+                                Some(module) => Some(module.clone()),
+                                None if local_names.contains(&label) => Some(module_name.clone()),
+                                None => None,
+                            };
+                            (
+                                None,
+                                Some(lsp_types::CompletionItemLabelDetails {
+                                    parameters: None,
+                                    qualifier,
+                                    typ: detail,
+                                }),
+                            )
+                        } else {
+                            (detail, None)
+                        };
+                        if let (Some(expected_type), either::Either::Right(typ)) =
+                            (&expected_type, &ident.typ)
+                        {
+                            if types_may_match(expected_type, typ) {
+                                expected.insert(label.clone());
+                            }
+                        }
+                        let kind = ident_to_completion_item_kind(&label, ident.typ.as_ref());
+                        let (insert_text, insert_text_format) = match &ident.typ {
+                            either::Either::Right(typ)
+                                if arm_snippets && kind == CompletionItemKind::Constructor =>
+                            {
+                                let mut snippet =
+                                    constructor_pattern_snippet(&db.as_env(), &label, typ);
+                                if close_arm {
+                                    snippet.push_str(" -> $0");
+                                }
+                                (Some(snippet), Some(InsertTextFormat::Snippet))
+                            }
+                            _ if label.starts_with(char::is_alphabetic) => (None, None),
+                            _ => (Some(format!("({})", label)), None),
+                        };
+                        // Clients which do not know the deprecated tag get the older property
+                        let (tags, deprecated) = match deprecated(&label) {
+                            true if deprecated_tag_support => {
+                                (Some(vec![CompletionItemTag::Deprecated]), None)
+                            }
+                            true => (None, Some(true)),
+                            false => (None, None),
+                        };
+                        CompletionItem {
+                            insert_text,
+                            insert_text_format,
+                            kind: Some(kind),
                             label,
-                            detail: match ident.typ {
-                                either::Either::Right(ref typ) => match **typ {
-                                    Type::Hole => None,
-                                    _ => Some(format!("{}", ident.typ)),
-                                },
-                                either::Either::Left(_) => Some(format!("{}", ident.typ)),
-                            },
-                            data: Some(
-                                serde_json::to_value(CompletionData {
-                                    text_document_uri: change
-                                        .text_document_position
-                                        .text_document
-                                        .uri
-                                        .clone(),
-                                    position: change.text_document_position.position,
-                                })
-                                .expect("CompletionData"),
-                            ),
+                            detail,
+                            label_details,
+                            tags,
+                            deprecated,
+                            data: Some(data.clone()),
                             ..CompletionItem::default()
                         }
                     })
                     .collect();
 
-                items.sort_by(|l, r| l.label.cmp(&r.label));
+                let in_scope: FnvSet<_> = items.iter().map(|item| item.label.clone()).collect();
+                let imports = importable
+                    .iter()
+                    .filter(|(_, name, _)| !in_scope.contains(name))
+                    .map(|(module, name, typ)| {
+                        let data = serde_json::to_value(CompletionData {
+                            text_document_uri: change
+                                .text_document_position
+                                .text_document
+                                .uri
+                                .clone(),
+                            position: change.text_document_position.position,
+                            import_from: Some(module.clone()),
+                        })
+                        .expect("CompletionData");
+                        let detail = Some(typ.to_string());
+                        let (detail, label_details) = if label_details_support {
+                            (
+                                None,
+                                Some(lsp_types::CompletionItemLabelDetails {
+                                    parameters: None,
+                                    qualifier: Some(module.clone()),
+                                    typ: detail,
+                                }),
+                            )
+                        } else {
+                            (detail, None)
+                        };
+                        CompletionItem {
+                            kind: Some(ident_to_completion_item_kind(
+                                name,
+                                either::Either::Right(typ),
+                            )),
+                            label: name.clone(),
+                            detail,
+                            label_details,
+                            data: Some(data),
+                            ..CompletionItem::default()
+                        }
+                    });
+                items.extend(imports);
 
-                Ok(Some(CompletionResponse::Array(items)))
+                rank_items(&mut items, word, &expected);
+
+                Ok((items, expected))
             })
-            .await
+            .await?;
+
+            if let (Some(source), Some(cursor)) = (&current_source, cursor) {
+                *cache.lock().unwrap() = Some(CompletionCache::new(
+                    text_document_uri,
+                    source.source(),
+                    cursor,
+                    [leading.clone(), items.clone(), trailing.clone()],
+                    expected,
+                ));
+            }
+
+            response(leading, items, trailing)
         }
         .boxed()
     }
@@ -107,17 +2259,36 @@ impl LanguageServerCommand<CompletionParams> for Completion {
     }
 }
 
-pub fn register(io: &mut IoHandler, thread: &RootedThread, message_log: &mpsc::Sender<String>) {
+pub fn register(
+    io: &mut IoHandler,
+    thread: &RootedThread,
+    message_log: &mpsc::Sender<OutgoingMessage>,
+    client_capabilities: &ClientCapabilitiesRef,
+    settings: &SettingsRef,
+    cache: &CompletionCacheRef,
+) {
     io.add_async_method(
-        request!("textDocument/completion"),
-        Completion(thread.clone()),
+        None::<CompletionRequest>,
+        Completion(
+            thread.clone(),
+            client_capabilities.clone(),
+            settings.clone(),
+            cache.clone(),
+        ),
     );
 
     let thread = thread.clone();
     let message_log = message_log.clone();
-    let resolve = move |mut item: CompletionItem| {
+    let client_capabilities = client_capabilities.clone();
+    let resolve = move |mut item: ClientCompletionItem| {
+        let text_edit_text = item.text_edit_text.take();
+        let mut item = CompletionItem::from(item);
         let thread = thread.clone();
         let message_log = message_log.clone();
+        let markdown = client_capabilities
+            .read()
+            .unwrap()
+            .supports_markdown_completion();
         async move {
             let data: CompletionData =
                 CompletionData::deserialize(item.data.as_ref().unwrap()).expect("CompletionData");
@@ -127,7 +2298,8 @@ pub fn register(io: &mut IoHandler, thread: &RootedThread, message_log: &mpsc::S
             let label = item.label.clone();
             log_message!(message_log.clone(), "{:?}", data.text_document_uri).await;
 
-            let comment = retrieve_expr_with_pos(
+            let is_field = item.kind == Some(CompletionItemKind::Field);
+            let (comment, structure, in_scope) = retrieve_expr_with_pos(
                 &thread,
                 &data.text_document_uri,
                 &data.position,
@@ -137,7 +2309,7 @@ pub fn register(io: &mut IoHandler, thread: &RootedThread, message_log: &mpsc::S
                     let module_expr = module.expr.expr();
                     let (_, metadata_map) =
                         gluon::check::metadata::metadata(&type_env, module_expr);
-                    Ok(completion::suggest_metadata(
+                    let comment = completion::suggest_metadata(
                         &metadata_map,
                         &type_env,
                         module.source.span(),
@@ -145,19 +2317,418 @@ pub fn register(io: &mut IoHandler, thread: &RootedThread, message_log: &mpsc::S
                         byte_index,
                         &label,
                     )
-                    .and_then(|metadata| metadata.comment.clone()))
+                    .and_then(|metadata| metadata.comment.clone());
+
+                    // Fields of records and functions show the structure of their type
+                    let text = module.source.source();
+                    let cursor = (byte_index - module.source.span().start()).to_usize();
+                    let is_field = is_field
+                        || text
+                            .get(..word_start(text, cursor))
+                            .is_some_and(|before| before.ends_with('.'));
+                    let structure = if is_field {
+                        // The client may have filtered the items by more than what was typed
+                        let query = completion::SuggestionQuery {
+                            prefix_filter: false,
+                            ..completion::SuggestionQuery::default()
+                        };
+                        query
+                            .suggest(&type_env, module.source.span(), module_expr, byte_index)
+                            .into_iter()
+                            .find(|suggestion| {
+                                suggestion.name.split(':').next() == Some(label.as_str())
+                            })
+                            .and_then(|suggestion| suggestion.typ.right())
+                            .filter(|typ| {
+                                let resolved = resolve::remove_aliases_cow(
+                                    &type_env,
+                                    NullInterner::new(),
+                                    typ,
+                                );
+                                let resolved = resolved.remove_forall();
+                                matches!(&**resolved, Type::Record(_))
+                                    || resolved.as_function().is_some()
+                            })
+                            .map(|typ| {
+                                expand_type(
+                                    &type_env,
+                                    &typ,
+                                    FIELD_EXPANSION_DEPTH,
+                                    0,
+                                    &mut Vec::new(),
+                                )
+                            })
+                    } else {
+                        None
+                    };
+
+                    // A value of another module may have been imported since it was offered
+                    let in_scope = data.import_from.is_some() && {
+                        let query = completion::SuggestionQuery {
+                            prefix_filter: false,
+                            ..completion::SuggestionQuery::default()
+                        };
+                        query
+                            .suggest(&type_env, module.source.span(), module_expr, byte_index)
+                            .iter()
+                            .any(|suggestion| {
+                                suggestion.name.split(':').next() == Some(label.as_str())
+                            })
+                    };
+                    Ok((comment, structure, in_scope))
                 },
             )
             .await?;
 
+            let comment = match &data.import_from {
+                Some(module) => {
+                    if !in_scope {
+                        item.additional_text_edits =
+                            Some(vec![code_action::import_text_edit(&label, module)]);
+                    }
+                    match comment {
+                        Some(comment) => Some(comment),
+                        None => get_module(&thread, module)
+                            .await
+                            .ok()
+                            .and_then(|(_, value)| {
+                                value.metadata.module.get(&label)?.comment.clone()
+                            }),
+                    }
+                }
+                None => comment,
+            };
+
             log_message!(message_log2, "{:?}", comment).await;
 
             item.documentation = Some(make_documentation(
-                None::<&str>,
+                structure,
                 comment.as_ref().map_or("", |comment| &comment.content),
+                markdown,
             ));
-            Ok(item)
+            Ok(ClientCompletionItem {
+                text_edit_text,
+                ..item.into()
+            })
         }
     };
-    io.add_async_method(request!("completionItem/resolve"), resolve);
+    io.add_async_method(None::<ResolveCompletionItemRequest>, resolve);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(label: &str, insert_text_format: Option<InsertTextFormat>) -> CompletionItem {
+        CompletionItem {
+            label: label.into(),
+            insert_text_format,
+            ..CompletionItem::default()
+        }
+    }
+
+    #[test]
+    fn item_defaults_are_overridden_by_differing_items() {
+        let items = vec![
+            item("a", Some(InsertTextFormat::Snippet)),
+            item("b", None),
+            item("c", Some(InsertTextFormat::Snippet)),
+        ];
+        let edit_range = Range {
+            start: Position::new(1, 2),
+            end: Position::new(1, 4),
+        };
+        let supported = vec!["editRange".to_string(), "insertTextFormat".to_string()];
+
+        match completion_response(items, &supported, Some(edit_range)) {
+            CompletionResponse::List(list) => {
+                assert_eq!(
+                    list.item_defaults,
+                    CompletionItemDefaults {
+                        edit_range: Some(edit_range),
+                        insert_text_format: Some(InsertTextFormat::Snippet),
+                    }
+                );
+                assert_eq!(
+                    list.items,
+                    vec![
+                        item("a", None).into(),
+                        item("b", Some(InsertTextFormat::PlainText)).into(),
+                        item("c", None).into(),
+                    ]
+                );
+            }
+            response => panic!("Expected a list: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn default_edit_range_replaces_with_the_insert_text() {
+        let edit_range = Range {
+            start: Position::new(1, 2),
+            end: Position::new(1, 4),
+        };
+        let items = vec![
+            CompletionItem {
+                insert_text: Some("a = $0".into()),
+                ..item("a", Some(InsertTextFormat::Snippet))
+            },
+            CompletionItem {
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                    range: edit_range,
+                    new_text: "b".into(),
+                })),
+                ..item("b", None)
+            },
+        ];
+        let text_edit_texts = |supported: &[&str]| {
+            let supported: Vec<_> = supported.iter().map(|s| s.to_string()).collect();
+            match completion_response(items.clone(), &supported, Some(edit_range)) {
+                CompletionResponse::List(list) => list
+                    .items
+                    .into_iter()
+                    .map(|item| item.text_edit_text)
+                    .collect::<Vec<_>>(),
+                response => panic!("Expected a list: {:?}", response),
+            }
+        };
+
+        assert_eq!(
+            text_edit_texts(&["editRange"]),
+            vec![Some("a = $0".to_string()), None]
+        );
+        assert_eq!(text_edit_texts(&["insertTextFormat"]), vec![None, None]);
+    }
+
+    #[test]
+    fn no_item_defaults_without_client_support() {
+        let items = vec![item("a", Some(InsertTextFormat::Snippet))];
+        match completion_response(items.clone(), &[], None) {
+            CompletionResponse::Array(array) => {
+                assert_eq!(array, items.into_iter().map(Into::into).collect::<Vec<_>>())
+            }
+            response => panic!("Expected an array: {:?}", response),
+        }
+    }
+
+    fn ranked(items: &[(&str, bool)], word: &str) -> Vec<(String, bool, Option<String>)> {
+        let mut items: Vec<_> = items
+            .iter()
+            .map(|&(label, deprecated)| CompletionItem {
+                tags: if deprecated {
+                    Some(vec![CompletionItemTag::Deprecated])
+                } else {
+                    None
+                },
+                ..item(label, None)
+            })
+            .collect();
+        rank_items(&mut items, word, &FnvSet::default());
+        items
+            .into_iter()
+            .map(|item| (item.label.clone(), is_deprecated(&item), item.sort_text))
+            .collect()
+    }
+
+    #[test]
+    fn label_details_are_sent_as_detail_and_description() {
+        let item = CompletionItem {
+            label_details: Some(lsp_types::CompletionItemLabelDetails {
+                parameters: None,
+                qualifier: Some("std.function".into()),
+                typ: Some("Int".into()),
+            }),
+            ..item("x", None)
+        };
+        let json = serde_json::to_value(ClientCompletionItem::from(item.clone())).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "label": "x",
+                "labelDetails": { "detail": "Int", "description": "std.function" },
+            })
+        );
+
+        let received: ClientCompletionItem = serde_json::from_value(json).unwrap();
+        assert_eq!(CompletionItem::from(received), item);
+    }
+
+    #[test]
+    fn items_with_the_same_label_are_ordered_by_origin() {
+        let items = vec![
+            CompletionItem {
+                detail: Some("String".into()),
+                ..item("x", None)
+            },
+            CompletionItem {
+                label_details: Some(lsp_types::CompletionItemLabelDetails {
+                    parameters: None,
+                    qualifier: Some("test".into()),
+                    typ: Some("Int".into()),
+                }),
+                ..item("x", None)
+            },
+            CompletionItem {
+                detail: Some("Float".into()),
+                ..item("x", None)
+            },
+            item("xy", None),
+        ];
+        let order = |mut items: Vec<CompletionItem>| {
+            rank_items(&mut items, "x", &FnvSet::default());
+            items
+                .into_iter()
+                .map(|item| item_origin(&item).1.map(String::from))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            Some("Float".to_string()),
+            Some("String".to_string()),
+            Some("Int".to_string()),
+            None,
+        ];
+        assert_eq!(order(items.clone()), expected);
+        assert_eq!(order(items.into_iter().rev().collect()), expected);
+    }
+
+    #[test]
+    fn deprecated_items_rank_below_equal_matches() {
+        // Exact, prefix and fuzzy matches, each deprecated or not. Local and imported names with
+        // the same label are told apart by the tag alone.
+        let items = [
+            ("xab", false),
+            ("abd", true),
+            ("ab", true),
+            ("abc", false),
+            ("ab", false),
+            ("xabd", true),
+            ("abe", false),
+            ("xa_b", false),
+        ];
+        let expected = [
+            ("ab", false),
+            ("ab", true),
+            ("abc", false),
+            ("abe", false),
+            ("abd", true),
+            ("xa_b", false),
+            ("xab", false),
+            ("xabd", true),
+        ];
+        assert_eq!(
+            ranked(&items, "ab"),
+            expected
+                .iter()
+                .enumerate()
+                .map(|(i, &(label, deprecated))| {
+                    (label.to_string(), deprecated, Some(format!("{:04}", i)))
+                })
+                .collect::<Vec<_>>()
+        );
+
+        // A deprecated match which is worse than the others keeps the order of the labels
+        assert_eq!(
+            ranked(&[("abc", false), ("ab", false), ("abd", true)], "ab"),
+            vec![
+                ("ab".to_string(), false, None),
+                ("abc".to_string(), false, None),
+                ("abd".to_string(), true, None),
+            ]
+        );
+    }
+
+    fn preselected(labels: &[&str], word: &str, expected: &[&str]) -> Option<String> {
+        let mut items: Vec<_> = labels.iter().map(|label| item(label, None)).collect();
+        let expected = expected.iter().map(|label| label.to_string()).collect();
+        rank_items(&mut items, word, &expected);
+        assert!(items.iter().skip(1).all(|item| item.preselect.is_none()));
+        items
+            .into_iter()
+            .next()
+            .filter(|item| item.preselect == Some(true))
+            .map(|item| item.label)
+    }
+
+    #[test]
+    fn preselect_only_obvious_matches() {
+        assert_eq!(
+            preselected(&["abc", "ab", "abd"], "ab", &[]),
+            Some("ab".into())
+        );
+        assert_eq!(
+            preselected(&["abc", "abd", "xab"], "ab", &["abd", "xab"]),
+            Some("abd".into())
+        );
+        // Several items match equally well
+        assert_eq!(preselected(&["abc", "abd"], "ab", &[]), None);
+        assert_eq!(preselected(&["abc", "abd"], "ab", &["abc", "abd"]), None);
+        // Matching the expected type is not enough without matching the word
+        assert_eq!(preselected(&["abc", "xab"], "ab", &["xab"]), None);
+    }
+
+    #[test]
+    fn binding_name_from_type() {
+        assert_eq!(type_name_to_binding_name("Map"), "map");
+        assert_eq!(type_name_to_binding_name("HashMap"), "hash_map");
+
+        assert_eq!(binding_name(&Type::string()), "string");
+        assert_eq!(binding_name(&Type::array(Type::int())), "array");
+        assert_eq!(
+            binding_name(&Type::function(vec![Type::int()], Type::int())),
+            "f"
+        );
+    }
+
+    #[test]
+    fn continuation_keywords() {
+        let cases = vec![
+            // After a complete condition
+            ("if x ", Some("then")),
+            ("if f (g x) ", Some("then")),
+            ("if x == ", None),
+            ("if ", None),
+            ("if (x ", None),
+            // After the expression of the `then` branch
+            ("if x then 1 ", Some("else")),
+            ("if x then ", None),
+            ("if x then 1 else ", None),
+            ("if x then 1 else if y ", Some("then")),
+            // After the scrutinee
+            ("match x ", Some("with")),
+            ("match x with\n| A -> 1 ", None),
+            ("let y = match x ", Some("with")),
+            // After the value of a binding
+            ("let x = 1 ", Some("in")),
+            ("let x = ", None),
+            ("let x ", None),
+            ("let x = 1 in ", None),
+            ("let f = \\x -> x ", Some("in")),
+            // A `let` is closed by a line which starts at or before it
+            ("let x = 1\nx ", None),
+            ("let x = 1\n", None),
+            ("let f x =\n    let y = x\n    ", None),
+            ("let f x =\n    let y = x\n    y ", Some("in")),
+            ("let x =\n    1\n    ", Some("in")),
+            // Keywords in comments and literals are not part of the code
+            ("if x // then\n  ", Some("then")),
+            ("if \"then\" ", Some("then")),
+            ("if x /* then */ ", Some("then")),
+            // The innermost construct is continued
+            ("match if x then 1 else 2 ", Some("with")),
+            ("if (match x with | A -> true) ", Some("then")),
+            ("x ", None),
+        ];
+        for (before, expected) in cases {
+            assert_eq!(continuation_keyword(before), expected, "{:?}", before);
+        }
+    }
+
+    #[test]
+    fn continuation_keyword_matches_word() {
+        let text = "if x th";
+        let item = continuation_keyword_completion(text, 5, text.len()).unwrap();
+        assert_eq!(item.label, "then");
+        assert_eq!(item.kind, Some(CompletionItemKind::Keyword));
+        assert_eq!(continuation_keyword_completion("if x el", 5, 7), None);
+    }
 }
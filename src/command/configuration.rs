@@ -0,0 +1,588 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use lsp_types::{
+    request::Request, CompletionItemKind, DidChangeConfigurationParams,
+    DidChangeWatchedFilesParams, SymbolKind,
+};
+
+use serde::de::DeserializeOwned;
+
+use serde_json::Value;
+
+use gluon::{
+    base::source::Source,
+    salsa::{Database, Durability},
+};
+
+use crate::{
+    command::{
+        completion::CompletionCacheRef,
+        symbol::{self, SymbolIndexRef},
+    },
+    diagnostics::DiagnosticsQueue,
+    project,
+    rpc::Entry,
+};
+
+use super::*;
+
+/// `gluon/reload` discards everything the server has cached so that changes made outside of the
+/// editor (such as to the files of imported modules or the project's settings file) are picked up
+pub enum Reload {}
+
+impl Request for Reload {
+    type Params = ();
+    type Result = ();
+    const METHOD: &'static str = "gluon/reload";
+}
+
+/// The settings the client sent last in `workspace/didChangeConfiguration`
+pub(crate) type SettingsRef = Arc<RwLock<Settings>>;
+
+/// The `gluon` section of the client's settings
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Settings {
+    /// Directories searched for imported modules in addition to the default import paths
+    #[serde(default)]
+    module_paths: Vec<PathBuf>,
+    /// Offer postfix completions such as `expr.let`
+    #[serde(default)]
+    pub(crate) postfix_completion: bool,
+    /// Ask which option to apply with `window/showMessageRequest` when a code action has several
+    /// options, instead of offering one action for each option
+    #[serde(default)]
+    pub(crate) prompt_ambiguous_actions: bool,
+    /// Format documents before they are saved, with `textDocument/willSaveWaitUntil`
+    #[serde(default)]
+    pub(crate) format_on_save: bool,
+    /// Milliseconds to wait before completing. A completion which is followed by another
+    /// completion or an edit of the same document within this time is not computed.
+    #[serde(default = "default_completion_debounce")]
+    pub(crate) completion_debounce: u64,
+    /// Bytes of stack of the thread which checks modules. Expressions which are nested too deeply
+    /// for it are reported instead of being checked.
+    #[serde(default = "default_analysis_stack_size")]
+    pub(crate) analysis_stack_size: usize,
+    /// Replaces the kinds of symbols and completion items with kinds which the client has icons
+    /// for
+    #[serde(default)]
+    pub(crate) kind_remap: KindRemap,
+    /// The number of modules which keep the result of their last successful check. Open documents
+    /// always keep theirs.
+    #[serde(default = "default_check_cache_size")]
+    pub(crate) check_cache_size: usize,
+    #[serde(default)]
+    pub(crate) hover: HoverSettings,
+    #[serde(default)]
+    pub(crate) completion: CompletionSettings,
+    #[serde(default)]
+    pub(crate) diagnostics: DiagnosticsSettings,
+}
+
+/// The `gluon.kindRemap` setting. Maps the name of a `SymbolKind` or `CompletionItemKind`, such as
+/// `EnumMember`, to the name of the kind to respond with instead.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct KindRemap(BTreeMap<String, String>);
+
+impl KindRemap {
+    pub(crate) fn symbol(&self, kind: SymbolKind) -> SymbolKind {
+        self.remap(kind)
+    }
+
+    pub(crate) fn completion(&self, kind: CompletionItemKind) -> CompletionItemKind {
+        self.remap(kind)
+    }
+
+    /// Kinds are named as in the specification, which is also how they are debug printed. Names
+    /// which are not a kind of the same type leave the kind as it is.
+    fn remap<K>(&self, kind: K) -> K
+    where
+        K: Copy + fmt::Debug + DeserializeOwned,
+    {
+        let target = match self.0.get(&format!("{:?}", kind)) {
+            Some(target) => target,
+            None => return kind,
+        };
+        // Kinds are serialized as their number, which start at 1 for both types
+        (1..=u8::MAX)
+            .map_while(|n| serde_json::from_value::<K>(n.into()).ok())
+            .find(|kind| format!("{:?}", kind) == *target)
+            .unwrap_or_else(|| {
+                warn!("Unknown kind `{}` in kindRemap", target);
+                kind
+            })
+    }
+}
+
+/// The `gluon.hover` settings
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HoverSettings {
+    /// Show the fields of records as a markdown table, if the client renders markdown hovers
+    #[serde(default)]
+    pub(crate) record_tables: bool,
+}
+
+/// The `gluon.diagnostics` settings
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticsSettings {
+    /// Publish warnings with the severity of errors
+    #[serde(default)]
+    pub(crate) warnings_as_errors: bool,
+}
+
+/// The `gluon.completion` settings
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletionSettings {
+    /// Leave out bindings starting with `_` unless they are declared in the module which is
+    /// completed
+    #[serde(default = "default_hide_private")]
+    pub(crate) hide_private: bool,
+}
+
+impl Default for CompletionSettings {
+    fn default() -> Self {
+        CompletionSettings {
+            hide_private: default_hide_private(),
+        }
+    }
+}
+
+fn default_hide_private() -> bool {
+    true
+}
+
+fn default_completion_debounce() -> u64 {
+    50
+}
+
+fn default_analysis_stack_size() -> usize {
+    crate::STACK_SIZE
+}
+
+fn default_check_cache_size() -> usize {
+    crate::check_importer::CHECK_CACHE_SIZE
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            module_paths: Vec::new(),
+            postfix_completion: false,
+            prompt_ambiguous_actions: false,
+            format_on_save: false,
+            completion_debounce: default_completion_debounce(),
+            analysis_stack_size: default_analysis_stack_size(),
+            kind_remap: KindRemap::default(),
+            check_cache_size: default_check_cache_size(),
+            hover: HoverSettings::default(),
+            completion: CompletionSettings::default(),
+            diagnostics: DiagnosticsSettings::default(),
+        }
+    }
+}
+
+/// The settings as they were sent by the client and read from the project's settings file
+#[derive(Default)]
+pub struct SettingsSources {
+    /// The root of the workspace, which holds the project's settings file
+    root: Option<PathBuf>,
+    project: Value,
+    client: Value,
+}
+
+pub(crate) type SettingsSourcesRef = Arc<Mutex<SettingsSources>>;
+
+impl SettingsSources {
+    /// Reads the settings file of the project in `root`
+    pub(crate) fn set_root(&mut self, root: PathBuf) {
+        self.project = project::read_settings(&root);
+        self.root = Some(root);
+    }
+
+    /// Sets the settings sent by the client, in `initializationOptions` or
+    /// `workspace/didChangeConfiguration`
+    pub(crate) fn set_client(&mut self, client: Value) {
+        self.client = client;
+    }
+
+    /// The project's settings with the client's settings applied on top of them. A setting with an
+    /// invalid value keeps its default, the other settings still apply.
+    fn settings(&self) -> Settings {
+        let mut settings = self.project.clone();
+        merge(&mut settings, &self.client);
+        if settings.is_null() {
+            return Settings::default();
+        }
+        remove_invalid_settings(&mut settings, "", &|field| field);
+        serde_json::from_value(settings).unwrap_or_else(|err| {
+            warn!("Invalid settings: {}", err);
+            Settings::default()
+        })
+    }
+}
+
+/// Removes the settings of the `settings` object at `path` which can not be deserialized,
+/// reporting each of them. `nest` puts a field of the object at its place in the settings.
+fn remove_invalid_settings(settings: &mut Value, path: &str, nest: &dyn Fn(Value) -> Value) {
+    let fields = match settings {
+        Value::Object(fields) => fields,
+        _ => return,
+    };
+    let mut invalid = Vec::new();
+    for (key, value) in fields.iter_mut() {
+        let setting = nest(Value::Object(
+            Some((key.clone(), value.clone())).into_iter().collect(),
+        ));
+        if serde_json::from_value::<Settings>(setting).is_ok() {
+            continue;
+        }
+        let path = format!("{}{}", path, key);
+        if value.is_object() {
+            // Keep the valid settings of the object
+            let nest = |field| {
+                nest(Value::Object(
+                    Some((key.clone(), field)).into_iter().collect(),
+                ))
+            };
+            remove_invalid_settings(value, &format!("{}.", path), &nest);
+        } else {
+            warn!("Ignoring the invalid setting `{}`: {}", path, value);
+            invalid.push(key.clone());
+        }
+    }
+    for key in invalid {
+        fields.remove(&key);
+    }
+}
+
+/// Sets the fields of `overrides` in `base`, merging the fields which are objects in both
+fn merge(base: &mut Value, overrides: &Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge(base.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (_, Value::Null) => (),
+        (base, overrides) => *base = overrides.clone(),
+    }
+}
+
+/// The `gluon` section of the settings in `workspace/didChangeConfiguration`
+fn client_settings(params: &DidChangeConfigurationParams) -> Value {
+    params.settings.get("gluon").cloned().unwrap_or_default()
+}
+
+/// Replaces the current settings with the ones from `sources`
+pub(crate) fn apply_settings(thread: &Thread, current: &SettingsRef, sources: &SettingsSources) {
+    let settings = sources.settings();
+    let import = thread.get_macros().get("import").expect("Import macro");
+    let import = import
+        .downcast_ref::<Import<CheckImporter>>()
+        .expect("Check importer");
+    let mut current = current.write().unwrap();
+    // Replace the paths which were added by the previous settings
+    let mut paths = import.paths.write().unwrap();
+    paths.retain(|path| !current.module_paths.contains(path));
+    paths.extend(settings.module_paths.iter().cloned());
+    import.importer.set_import_paths(paths.clone());
+    import
+        .importer
+        .set_check_cache_size(settings.check_cache_size);
+    *current = settings;
+}
+
+/// Clears every cache which could hold results computed with outdated settings and checks the
+/// open documents again so that their diagnostics are up to date
+pub(crate) async fn invalidate_all_caches(
+    thread: &Thread,
+    completion_cache: &CompletionCacheRef,
+    symbol_index: &SymbolIndexRef,
+    mut diagnostics: DiagnosticsQueue,
+) {
+    *completion_cache.lock().unwrap() = None;
+    symbol_index.lock().await.clear();
+
+    let import = thread.get_macros().get("import").expect("Import macro");
+    let import = import
+        .downcast_ref::<Import<CheckImporter>>()
+        .expect("Check importer");
+    let open_documents: Vec<_> = {
+        let mut modules = import.importer.0.lock().await;
+        // Modules which are not open are added back as they are imported again
+        modules.retain(|_, state| state.version.is_some());
+        modules
+            .iter()
+            .map(|(module, state)| (module.clone(), state.uri.clone(), state.version))
+            .collect()
+    };
+
+    // Module sources read from the import paths are only read again in a new revision
+    import
+        .importer
+        .write_database(thread, |db| {
+            db.salsa_runtime_mut().synthetic_write(Durability::LOW)
+        })
+        .await;
+
+    for (module, uri, version) in open_documents {
+        let source = match thread.get_database().get_filemap(&module) {
+            Some(filemap) => filemap.src().to_string(),
+            None => continue,
+        };
+        let entry = Entry {
+            key: uri,
+            value: source,
+            version: version.expect("Open document"),
+        };
+        if diagnostics.send(entry).await.is_err() {
+            return;
+        }
+    }
+}
+
+pub fn register(
+    io: &mut IoHandler,
+    thread: &RootedThread,
+    settings: &SettingsRef,
+    settings_sources: &SettingsSourcesRef,
+    completion_cache: &CompletionCacheRef,
+    symbol_index: &SymbolIndexRef,
+    diagnostics: &DiagnosticsQueue,
+) {
+    {
+        let thread = thread.clone();
+        let current_settings = settings.clone();
+        let settings_sources = settings_sources.clone();
+        let completion_cache = completion_cache.clone();
+        let symbol_index = symbol_index.clone();
+        let diagnostics = diagnostics.clone();
+        let f = move |params: DidChangeConfigurationParams| {
+            {
+                let mut sources = settings_sources.lock().unwrap();
+                sources.set_client(client_settings(&params));
+                apply_settings(&thread, &current_settings, &sources);
+            }
+
+            let thread = thread.clone();
+            let completion_cache = completion_cache.clone();
+            let symbol_index = symbol_index.clone();
+            let diagnostics = diagnostics.clone();
+            tokio::spawn(async move {
+                invalidate_all_caches(&thread, &completion_cache, &symbol_index, diagnostics).await
+            });
+        };
+        io.add_notification(notification!("workspace/didChangeConfiguration"), f);
+    }
+    {
+        let thread = thread.clone();
+        let current_settings = settings.clone();
+        let settings_sources = settings_sources.clone();
+        let completion_cache = completion_cache.clone();
+        let symbol_index = symbol_index.clone();
+        let diagnostics = diagnostics.clone();
+        let f = move |params: DidChangeWatchedFilesParams| {
+            let settings_changed = {
+                let mut sources = settings_sources.lock().unwrap();
+                let settings_file = sources
+                    .root
+                    .as_ref()
+                    .map(|root| root.join(project::SETTINGS_FILE));
+                let changed = params
+                    .changes
+                    .iter()
+                    .any(|change| change.uri.to_file_path().ok() == settings_file);
+                if let (true, Some(root)) = (changed, sources.root.clone()) {
+                    info!("Reading the changed {}", project::SETTINGS_FILE);
+                    sources.set_root(root);
+                    apply_settings(&thread, &current_settings, &sources);
+                }
+                changed
+            };
+
+            let thread = thread.clone();
+            let completion_cache = completion_cache.clone();
+            let symbol_index = symbol_index.clone();
+            let diagnostics = diagnostics.clone();
+            tokio::spawn(async move {
+                symbol::forget_deleted_modules(&thread, &symbol_index, &params.changes).await;
+                if settings_changed {
+                    invalidate_all_caches(&thread, &completion_cache, &symbol_index, diagnostics)
+                        .await
+                }
+            });
+        };
+        io.add_notification(notification!("workspace/didChangeWatchedFiles"), f);
+    }
+
+    let thread = thread.clone();
+    let current_settings = settings.clone();
+    let settings_sources = settings_sources.clone();
+    let completion_cache = completion_cache.clone();
+    let symbol_index = symbol_index.clone();
+    let diagnostics = diagnostics.clone();
+    let f = move |()| {
+        {
+            let mut sources = settings_sources.lock().unwrap();
+            if let Some(root) = sources.root.clone() {
+                sources.set_root(root);
+                apply_settings(&thread, &current_settings, &sources);
+            }
+        }
+        let thread = thread.clone();
+        let completion_cache = completion_cache.clone();
+        let symbol_index = symbol_index.clone();
+        let diagnostics = diagnostics.clone();
+        async move {
+            invalidate_all_caches(&thread, &completion_cache, &symbol_index, diagnostics).await;
+            Ok::<_, ServerError<()>>(())
+        }
+    };
+    io.add_async_method(None::<Reload>, f);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_only(params: &DidChangeConfigurationParams) -> Settings {
+        SettingsSources {
+            client: client_settings(params),
+            ..SettingsSources::default()
+        }
+        .settings()
+    }
+
+    #[test]
+    fn settings_from_params() {
+        let params = DidChangeConfigurationParams {
+            settings: serde_json::json!({
+                "gluon": {
+                    "modulePaths": ["lib"],
+                    "maxNumberOfProblems": 100,
+                    "analysisStackSize": 1048576,
+                    "checkCacheSize": 8,
+                    "kindRemap": { "EnumMember": "Constant" },
+                    "hover": { "recordTables": true },
+                    "completion": { "hidePrivate": false },
+                    "diagnostics": { "warningsAsErrors": true }
+                }
+            }),
+        };
+        assert_eq!(
+            client_only(&params),
+            Settings {
+                module_paths: vec![PathBuf::from("lib")],
+                postfix_completion: false,
+                prompt_ambiguous_actions: false,
+                format_on_save: false,
+                completion_debounce: 50,
+                analysis_stack_size: 1048576,
+                kind_remap: KindRemap(
+                    vec![("EnumMember".to_string(), "Constant".to_string())]
+                        .into_iter()
+                        .collect()
+                ),
+                check_cache_size: 8,
+                hover: HoverSettings {
+                    record_tables: true,
+                },
+                completion: CompletionSettings {
+                    hide_private: false,
+                },
+                diagnostics: DiagnosticsSettings {
+                    warnings_as_errors: true,
+                },
+            }
+        );
+
+        let params = DidChangeConfigurationParams {
+            settings: serde_json::json!({ "other": {} }),
+        };
+        assert_eq!(client_only(&params), Settings::default());
+    }
+
+    #[test]
+    fn client_settings_override_project_settings() {
+        let sources = SettingsSources {
+            root: None,
+            project: serde_json::json!({
+                "postfixCompletion": true,
+                "completionDebounce": 100,
+                "hover": { "recordTables": true },
+            }),
+            client: serde_json::json!({
+                "completionDebounce": 0,
+                "hover": {},
+            }),
+        };
+        assert_eq!(
+            sources.settings(),
+            Settings {
+                postfix_completion: true,
+                completion_debounce: 0,
+                hover: HoverSettings {
+                    record_tables: true,
+                },
+                ..Settings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_settings_keep_their_defaults() {
+        let sources = SettingsSources {
+            root: None,
+            project: serde_json::json!({
+                "postfixCompletion": true,
+                "completionDebounce": "soon",
+                "hover": { "recordTables": 1 },
+                "completion": { "hidePrivate": false },
+            }),
+            client: serde_json::json!({
+                "diagnostics": { "warningsAsErrors": true },
+                "checkCacheSize": -1,
+            }),
+        };
+        assert_eq!(
+            sources.settings(),
+            Settings {
+                postfix_completion: true,
+                completion: CompletionSettings {
+                    hide_private: false,
+                },
+                diagnostics: DiagnosticsSettings {
+                    warnings_as_errors: true,
+                },
+                ..Settings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn remap_kinds() {
+        let remap: KindRemap = serde_json::from_value(serde_json::json!({
+            "EnumMember": "Constant",
+            "Variable": "Field",
+            "Function": "NotAKind",
+        }))
+        .unwrap();
+        assert_eq!(remap.symbol(SymbolKind::EnumMember), SymbolKind::Constant);
+        assert_eq!(
+            remap.completion(CompletionItemKind::EnumMember),
+            CompletionItemKind::Constant
+        );
+        assert_eq!(remap.symbol(SymbolKind::Variable), SymbolKind::Field);
+        assert_eq!(remap.symbol(SymbolKind::Function), SymbolKind::Function);
+        assert_eq!(remap.symbol(SymbolKind::Struct), SymbolKind::Struct);
+    }
+}
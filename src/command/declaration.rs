@@ -0,0 +1,114 @@
+use lsp_types::{GotoDefinitionParams, GotoDefinitionResponse, Location};
+
+use gluon::base::{
+    ast::{self, Pattern, Visitor},
+    pos::Span,
+    symbol::SymbolRef,
+};
+
+use crate::{byte_span_to_range, completion, position_to_byte_index};
+
+use super::{definition::find_symbol, *};
+
+/// Finds the innermost `rec` or `type` group which binds `symbol` and returns the span of the
+/// group's header, from the start of the group to the end of its first name
+struct GroupHeader<'s> {
+    symbol: &'s SymbolRef,
+    header: Option<Span<BytePos>>,
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for GroupHeader<'_> {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        match &e.value {
+            Expr::LetBindings(ast::ValueBindings::Recursive(bindings), _) => {
+                let binds_symbol = bindings.iter().any(|binding| match &binding.name.value {
+                    Pattern::Ident(id) => *id.name == *self.symbol,
+                    _ => false,
+                });
+                if binds_symbol {
+                    self.header = Some(Span::new(e.span.start(), bindings[0].name.span.end()));
+                }
+            }
+            Expr::TypeBindings(bindings, _) => {
+                // Types are referred to by the name of their finalized alias
+                let binds_symbol = bindings.iter().any(|binding| {
+                    let name = binding
+                        .finalized_alias
+                        .as_ref()
+                        .map_or(&binding.name.value, |alias| &alias.name);
+                    **name == *self.symbol
+                });
+                if binds_symbol {
+                    self.header = Some(Span::new(e.span.start(), bindings[0].name.span.end()));
+                }
+            }
+            _ => (),
+        }
+        ast::walk_expr(self, e)
+    }
+}
+
+pub fn register(io: &mut IoHandler, thread: &RootedThread) {
+    let thread = thread.clone();
+    let f = move |params: GotoDefinitionParams| {
+        let thread = thread.clone();
+        async move {
+            let module = retrieve_module_from_url(
+                &thread,
+                &params.text_document_position_params.text_document.uri,
+            )
+            .await?;
+
+            let pos = position_to_byte_index(
+                &module.source,
+                &params.text_document_position_params.position,
+            )?;
+            let module_expr = module.expr.expr();
+            let search_symbol = match completion::symbol(module.source.span(), module_expr, pos) {
+                Ok(search_symbol) => search_symbol,
+                Err(_) => {
+                    return Ok(None);
+                }
+            };
+
+            debug!("Found symbol {}", search_symbol);
+
+            if search_symbol.is_global() {
+                // Builtin modules have no source to go to
+                return Ok(retrieve_module(&thread, search_symbol.as_pretty_str())
+                    .await
+                    .ok()
+                    .map(|module| {
+                        GotoDefinitionResponse::Scalar(Location {
+                            uri: module.uri,
+                            range: Default::default(),
+                        })
+                    }));
+            }
+
+            let mut group = GroupHeader {
+                symbol: search_symbol,
+                header: None,
+            };
+            group.visit_expr(module_expr);
+
+            let span = match group.header {
+                Some(header) => header,
+                None => {
+                    let all_symbols = completion::all_symbols(module.source.span(), module_expr);
+                    match find_symbol(all_symbols, search_symbol) {
+                        Some(symbol) => symbol.span,
+                        None => return Ok(None),
+                    }
+                }
+            };
+            Ok(Some(GotoDefinitionResponse::Scalar(Location {
+                uri: module.uri.clone(),
+                range: byte_span_to_range(&module.source, span)?,
+            })))
+        }
+    };
+    io.add_async_method(request!("textDocument/declaration"), f);
+}
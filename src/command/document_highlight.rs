@@ -1,9 +1,39 @@
+use gluon::base::{
+    ast::{self, Pattern, PatternField, SpannedPattern, Visitor},
+    symbol::SymbolRef,
+};
+
 use lsp_types::{DocumentHighlight, DocumentHighlightParams};
 
 use super::*;
 
 use crate::{byte_span_to_range, completion, position_to_byte_index};
 
+/// Collects the names of shorthand record pattern fields (`{ x }`) which bind `symbol`.
+/// `find_all_symbols` only sees identifier patterns so these bindings, which is how imported
+/// names are usually brought into scope, would otherwise be left out.
+struct ShorthandFields<'b> {
+    symbol: &'b SymbolRef,
+    spans: Vec<Span<BytePos>>,
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for ShorthandFields<'_> {
+    type Ident = Symbol;
+
+    fn visit_pattern(&mut self, p: &'a SpannedPattern<'ast, Symbol>) {
+        if let Pattern::Record { fields, .. } = &p.value {
+            for field in fields.iter() {
+                if let PatternField::Value { name, value: None } = field {
+                    if name.value == *self.symbol {
+                        self.spans.push(name.span);
+                    }
+                }
+            }
+        }
+        ast::walk_pattern(self, &p.value)
+    }
+}
+
 pub fn register(io: &mut IoHandler, thread: &RootedThread) {
     let thread = thread.clone();
     let f = move |params: DocumentHighlightParams| {
@@ -22,11 +52,26 @@ pub fn register(io: &mut IoHandler, thread: &RootedThread) {
                         &params.text_document_position_params.position,
                     )?;
 
-                    let symbol_spans =
+                    let mut symbol_spans =
                         completion::find_all_symbols(source.span(), expr, byte_index)
                             .map(|t| t.1)
                             .unwrap_or(Vec::new());
 
+                    if let Ok(symbol) = completion::symbol(source.span(), expr, byte_index) {
+                        let mut shorthand = ShorthandFields {
+                            symbol,
+                            spans: Vec::new(),
+                        };
+                        shorthand.visit_expr(expr);
+                        symbol_spans.extend(shorthand.spans);
+                    }
+
+                    // Only occurrences inside this file are highlighted, anything else (such as
+                    // spans introduced by macros) has no place in the current document
+                    symbol_spans.retain(|span| source.span().contains(*span));
+                    symbol_spans.sort_by_key(|span| span.start());
+                    symbol_spans.dedup();
+
                     symbol_spans
                         .into_iter()
                         .map(|span| {
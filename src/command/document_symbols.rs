@@ -1,13 +1,27 @@
 use lsp_types::{DocumentSymbolParams, DocumentSymbolResponse};
 
-use crate::completion;
+use crate::{
+    command::configuration::{KindRemap, SettingsRef},
+    completion,
+};
 
 use super::*;
 
-pub fn register(io: &mut IoHandler, thread: &RootedThread) {
+fn remap_kinds(symbols: &mut [DocumentSymbol], kind_remap: &KindRemap) {
+    for symbol in symbols {
+        symbol.kind = kind_remap.symbol(symbol.kind);
+        if let Some(children) = &mut symbol.children {
+            remap_kinds(children, kind_remap);
+        }
+    }
+}
+
+pub fn register(io: &mut IoHandler, thread: &RootedThread, settings: &SettingsRef) {
     let thread = thread.clone();
+    let settings = settings.clone();
     let f = move |params: DocumentSymbolParams| {
         let thread = thread.clone();
+        let kind_remap = settings.read().unwrap().kind_remap.clone();
         async move {
             retrieve_expr(&thread, &params.text_document.uri, |module| {
                 let expr = module.expr.expr();
@@ -16,7 +30,8 @@ pub fn register(io: &mut IoHandler, thread: &RootedThread) {
 
                 let source = &module.source;
 
-                let x = completion_symbols_to_document_symbols(source, &symbols)?;
+                let mut x = completion_symbols_to_document_symbols(source, &symbols)?;
+                remap_kinds(&mut x, &kind_remap);
                 Ok(Some(DocumentSymbolResponse::Nested(x)))
             })
             .await
@@ -0,0 +1,136 @@
+use std::sync::Arc;
+
+use jsonrpc_core::Id;
+
+use lsp_types::request::Request;
+
+use crate::{
+    rpc::{ClientRequests, PipelineStats},
+    text_edit::Version,
+};
+
+use super::*;
+
+/// `gluon/dumpState` responds with a snapshot of the server's message pipeline for debugging.
+/// Only answered when this module logs at the `trace` level, e.g. with
+/// `RUST_LOG=gluon_language_server::command::dump_state=trace`.
+pub enum DumpState {}
+
+impl Request for DumpState {
+    type Params = ();
+    type Result = DumpStateResult;
+    const METHOD: &'static str = "gluon/dumpState";
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpStateResult {
+    /// The number of messages which have been read from the input
+    pub frames_decoded: usize,
+    pub decoder: DecoderState,
+    /// The number of messages which have been read but not handled yet, including the
+    /// `gluon/dumpState` request itself
+    pub dispatch_queue_depth: usize,
+    /// The requests which the server has sent to the client and which are waiting for a response
+    pub pending_requests: Vec<PendingRequest>,
+    /// The modules which the server knows about, ordered by uri
+    pub documents: Vec<DocumentState>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecoderState {
+    /// Whether the decoder has started on a message which has not been read completely
+    pub partial_frame: bool,
+    /// Bytes which have been read but not consumed by the decoder
+    pub buffered_bytes: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingRequest {
+    pub id: Id,
+    pub method: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentState {
+    pub uri: Url,
+    /// The version of an open document. `null` for modules which are not open in the editor
+    pub version: Option<Version>,
+    /// Whether the result of the last successful check of the module is kept
+    pub cached: bool,
+}
+
+async fn dump_state(
+    thread: &Thread,
+    stats: &PipelineStats,
+    client_requests: &ClientRequests,
+) -> DumpStateResult {
+    let import = thread.get_macros().get("import").expect("Import macro");
+    let import = import
+        .downcast_ref::<Import<CheckImporter>>()
+        .expect("Check importer");
+    let mut documents: Vec<_> = import
+        .importer
+        .0
+        .lock()
+        .await
+        .values()
+        .map(|state| DocumentState {
+            uri: state.uri.clone(),
+            version: state.version,
+            cached: state.last_good.is_some(),
+        })
+        .collect();
+    documents.sort_by(|l, r| l.uri.as_str().cmp(r.uri.as_str()));
+
+    DumpStateResult {
+        frames_decoded: stats.frames_decoded(),
+        decoder: DecoderState {
+            partial_frame: stats.partial_frame(),
+            buffered_bytes: stats.buffered_bytes(),
+        },
+        dispatch_queue_depth: stats.dispatch_queue_depth(),
+        pending_requests: client_requests
+            .pending()
+            .into_iter()
+            .map(|(id, method)| PendingRequest {
+                id,
+                method: method.into(),
+            })
+            .collect(),
+        documents,
+    }
+}
+
+pub fn register(
+    io: &mut IoHandler,
+    thread: &RootedThread,
+    stats: &Arc<PipelineStats>,
+    client_requests: &ClientRequests,
+) {
+    let thread = thread.clone();
+    let stats = stats.clone();
+    let client_requests = client_requests.clone();
+    let f = move |_: ()| {
+        let thread = thread.clone();
+        let stats = stats.clone();
+        let client_requests = client_requests.clone();
+        async move {
+            if !log_enabled!(log::Level::Trace) {
+                return Err(format!(
+                    "`{}` requires trace logging, set `RUST_LOG={}=trace`",
+                    DumpState::METHOD,
+                    module_path!()
+                )
+                .into());
+            }
+            let state = dump_state(&thread, &stats, &client_requests).await;
+            trace!("{}", serde_json::to_string(&state)?);
+            Ok::<_, ServerError<()>>(state)
+        }
+    };
+    io.add_async_method(None::<DumpState>, f);
+}
@@ -0,0 +1,211 @@
+use std::{sync::Arc, time::Duration};
+
+use gluon::{
+    compiler_pipeline::Executable,
+    vm::{internal::ValuePrinter, Error as VmError},
+    Error as GluonError,
+};
+
+use lsp_types::request::Request;
+
+use crate::rpc;
+
+use super::*;
+
+/// `gluon/evaluate` evaluates an expression with the top level bindings of a module in scope and
+/// responds with the value and its type. `IO` actions are returned as values without being run.
+///
+/// The server's own virtual machine only typechecks imports, so evaluations run on a separate one
+/// which compiles and runs the modules they import.
+pub enum Evaluate {}
+
+impl Request for Evaluate {
+    type Params = EvaluateParams;
+    type Result = EvaluateResult;
+    const METHOD: &'static str = "gluon/evaluate";
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EvaluateParams {
+    /// The module whose bindings are in scope
+    pub uri: Url,
+    pub expression: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EvaluateResult {
+    /// The value, pretty printed
+    pub value: String,
+    #[serde(rename = "type")]
+    pub typ: String,
+}
+
+/// The `data` of the error of a failed evaluation
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EvaluateError {
+    pub kind: EvaluateErrorKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EvaluateErrorKind {
+    /// The expression does not parse or typecheck
+    Compile,
+    /// Evaluating the expression failed, such as by a panic or by running out of memory
+    Runtime,
+    /// The expression was interrupted after running for `EVALUATION_TIMEOUT`
+    Timeout,
+}
+
+/// How long an evaluation may run before it is interrupted
+const EVALUATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most memory which the values of an evaluation may use
+const EVALUATION_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// The offset of the expression which the module evaluates to, after its top level bindings
+fn body_start(source: &gluon::base::source::FileMap, expr: &SpannedExpr<'_, Symbol>) -> usize {
+    let mut body = expr;
+    while let Expr::LetBindings(_, next) | Expr::TypeBindings(_, next) = &body.value {
+        body = next;
+    }
+    let span = source.span();
+    if span.start() <= body.span.start() && body.span.start() <= span.end() {
+        (body.span.start() - span.start()).to_usize()
+    } else {
+        0
+    }
+}
+
+fn evaluate_error(kind: EvaluateErrorKind, err: impl fmt::Display) -> ServerError<EvaluateError> {
+    ServerError {
+        code: jsonrpc_core::ErrorCode::InternalError,
+        message: err.to_string(),
+        data: Some(EvaluateError { kind }),
+    }
+}
+
+/// The virtual machine which runs the evaluations, created for the first one
+#[derive(Default)]
+struct Evaluator(std::sync::Mutex<Option<RootedThread>>);
+
+impl Evaluator {
+    async fn get(&self) -> RootedThread {
+        if let Some(vm) = self.0.lock().unwrap().clone() {
+            return vm;
+        }
+        let vm = gluon::VmBuilder::new().build_async().await;
+        self.0.lock().unwrap().get_or_insert(vm).clone()
+    }
+
+    /// Replaces the virtual machine after an evaluation was interrupted, since the interruption
+    /// may have stopped the loading of a module which it would otherwise remember as failed
+    fn reset(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+/// Evaluates `expr_str` on a thread of its own which is interrupted if it runs for too long or if
+/// the client cancels the request
+async fn evaluate(
+    evaluator: &Evaluator,
+    name: String,
+    expr_str: String,
+) -> Result<EvaluateResult, ServerError<EvaluateError>> {
+    let vm = evaluator
+        .get()
+        .await
+        .new_thread()
+        .map_err(|err| evaluate_error(EvaluateErrorKind::Runtime, err))?;
+    vm.set_memory_limit(EVALUATION_MEMORY_LIMIT);
+
+    // The virtual machine does not yield while it runs so it gets a thread of its own, which
+    // leaves the runtime's threads free to notice the timeout
+    let runtime = tokio::runtime::Handle::current();
+    let evaluation_vm = vm.clone();
+    let mut evaluation = tokio::task::spawn_blocking(move || {
+        runtime.block_on(async move {
+            let vm = evaluation_vm;
+            let mut db = vm.get_database();
+            let value = expr_str
+                .run_expr(
+                    &mut vm.module_compiler(&mut db),
+                    vm.clone(),
+                    &name,
+                    &expr_str,
+                    None,
+                )
+                .await?;
+            let env = vm.get_env();
+            let debug_level = vm.global_env().get_debug_level();
+            let printed =
+                ValuePrinter::new(&env, &value.typ, value.value.get_variant(), &debug_level)
+                    .width(80)
+                    .to_string();
+            Ok::<_, GluonError>(EvaluateResult {
+                value: printed,
+                typ: value.typ.to_string(),
+            })
+        })
+    });
+    let result = tokio::select! {
+        result = &mut evaluation => result,
+        _ = tokio::time::sleep(EVALUATION_TIMEOUT) => {
+            vm.interrupt();
+            evaluator.reset();
+            evaluation.await
+        }
+        cancelled = rpc::cancelled() => {
+            vm.interrupt();
+            evaluator.reset();
+            let _ = evaluation.await;
+            return Err(cancelled);
+        }
+    };
+
+    match result.map_err(|err| evaluate_error(EvaluateErrorKind::Runtime, err))? {
+        Ok(result) => Ok(result),
+        Err(GluonError::VM(VmError::Interrupted)) => Err(evaluate_error(
+            EvaluateErrorKind::Timeout,
+            format!("Evaluation took longer than {:?}", EVALUATION_TIMEOUT),
+        )),
+        Err(err @ GluonError::Parse(_))
+        | Err(err @ GluonError::Typecheck(_))
+        | Err(err @ GluonError::Macro(_))
+        | Err(err @ GluonError::Multiple(_)) => {
+            Err(evaluate_error(EvaluateErrorKind::Compile, err))
+        }
+        Err(err) => Err(evaluate_error(EvaluateErrorKind::Runtime, err)),
+    }
+}
+
+pub fn register(io: &mut IoHandler, thread: &RootedThread) {
+    let thread = thread.clone();
+    let evaluator = Arc::new(Evaluator::default());
+    let f = move |params: EvaluateParams| {
+        let thread = thread.clone();
+        let evaluator = evaluator.clone();
+        async move {
+            // The expression replaces the body of the module, after its top level bindings
+            let (module, source) = retrieve_expr(&thread, &params.uri, |module| {
+                let source = module.source.source();
+                let start = body_start(&module.source, module.expr.expr());
+                Ok((
+                    filename_to_module(module.source.name()),
+                    format!("{}{}", &source[..start], params.expression),
+                ))
+            })
+            .await
+            .map_err(|err| ServerError {
+                code: err.code,
+                message: err.message,
+                data: None,
+            })?;
+            evaluate(&evaluator, format!("{}.__evaluate", module), source).await
+        }
+    };
+    io.add_async_method(None::<Evaluate>, f);
+}
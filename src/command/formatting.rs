@@ -1,28 +1,615 @@
-use lsp_types::{DocumentFormattingParams, TextEdit};
+use std::{sync::Arc, time::Duration};
 
-use gluon::{base::source::Source, ThreadExt};
+use lsp_types::{
+    request::Request, DocumentFormattingParams, DocumentRangeFormattingParams, FormattingOptions,
+    Position, Range, TextDocumentIdentifier, TextEdit, WillSaveTextDocumentParams,
+};
 
-use super::{byte_span_to_range, retrieve_expr, Handler, IoHandler, RootedThread};
+use gluon::{
+    base::source::{FileMap, Source},
+    ThreadExt,
+};
 
-pub fn register(io: &mut IoHandler, thread: &RootedThread) {
-    let thread = thread.clone();
-    let format = move |params: DocumentFormattingParams| {
+use crate::command::configuration::SettingsRef;
+
+use super::{
+    byte_span_to_range, retrieve_expr, Handler, IoHandler, RootedThread, ServerError, Thread, Url,
+};
+
+/// The indentation width used by `gluon_format`
+const FORMATTER_INDENT: usize = 4;
+
+/// How long `textDocument/willSaveWaitUntil` may format before the document is saved unformatted
+const FORMAT_ON_SAVE_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Re-indents `formatted` according to `tabSize` and `insertSpaces`. Lines which begin inside a
+/// string literal are left as is since their leading whitespace is part of the string.
+fn reindent(formatted: &str, options: &FormattingOptions) -> String {
+    if options.insert_spaces && options.tab_size as usize == FORMATTER_INDENT {
+        return formatted.to_string();
+    }
+    let mut output = String::with_capacity(formatted.len());
+    let mut in_string = None;
+    for line in formatted.split_inclusive('\n') {
+        let content = line.trim_start_matches(' ');
+        let spaces = line.len() - content.len();
+        if in_string.is_none() && spaces > 0 {
+            let (levels, rest) = (spaces / FORMATTER_INDENT, spaces % FORMATTER_INDENT);
+            if options.insert_spaces {
+                output.extend((0..levels * options.tab_size as usize + rest).map(|_| ' '));
+            } else {
+                output.extend((0..levels).map(|_| '\t'));
+                output.extend((0..rest).map(|_| ' '));
+            }
+            output.push_str(content);
+        } else {
+            output.push_str(line);
+        }
+        in_string = scan_line(line, in_string).0;
+    }
+    output
+}
+
+/// Returns the delimiter which closes a string that is still open at the end of `line` and where
+/// the line comment of `line` starts, if it has one. `r#"` strings are closed by `"#` and
+/// ordinary strings by `"`.
+fn scan_line(line: &str, mut in_string: Option<String>) -> (Option<String>, Option<usize>) {
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match &in_string {
+            Some(end) => {
+                if c == '\\' && end == "\"" {
+                    chars.next();
+                } else if line[i..].starts_with(&**end) {
+                    for _ in 1..end.len() {
+                        chars.next();
+                    }
+                    in_string = None;
+                }
+            }
+            None => match c {
+                '/' if line[i..].starts_with("//") => return (None, Some(i)),
+                '\'' => {
+                    // Skip character literals so that `'"'` does not start a string
+                    let rest = &line[i + 1..];
+                    let len = if rest.starts_with('\\') { 2 } else { 1 };
+                    if rest.get(len..len + 1) == Some("'") {
+                        for _ in 0..=len {
+                            chars.next();
+                        }
+                    }
+                }
+                '"' => in_string = Some("\"".into()),
+                'r' if !line[..i].ends_with(|c: char| c.is_alphanumeric() || c == '_')
+                    && line[i + 1..].trim_start_matches('#').starts_with('"') =>
+                {
+                    let hashes = line[i + 1..].len() - line[i + 1..].trim_start_matches('#').len();
+                    for _ in 0..=hashes {
+                        chars.next();
+                    }
+                    in_string = Some(format!("\"{}", "#".repeat(hashes)));
+                }
+                _ => (),
+            },
+        }
+    }
+    (in_string, None)
+}
+
+/// A `//` comment of the source which is being formatted
+struct LineComment<'a> {
+    text: &'a str,
+    /// Whether the comment follows code on the same line
+    trailing: bool,
+}
+
+fn line_comments(source: &str) -> Vec<LineComment<'_>> {
+    let mut comments = Vec::new();
+    let mut in_string = None;
+    for line in source.lines() {
+        let (next, comment_start) = scan_line(line, in_string.take());
+        in_string = next;
+        if let Some(start) = comment_start {
+            comments.push(LineComment {
+                text: line[start..].trim_end(),
+                trailing: !line[..start].trim().is_empty(),
+            });
+        }
+    }
+    comments
+}
+
+/// `gluon_format` moves a comment which ends a line of code to a line of its own after the code.
+/// Moves such comments back to the end of the code they followed in `source`.
+fn restore_trailing_comments(source: &str, formatted: &str) -> String {
+    let comments = line_comments(source);
+    let mut next_comment = 0;
+    let mut output: Vec<String> = Vec::new();
+    let mut in_string = None;
+    for line in formatted.lines() {
+        let starts_in_string = in_string.is_some();
+        in_string = scan_line(line, in_string).0;
+        let comment = line.trim_start();
+        if !starts_in_string && comment.starts_with("//") {
+            // Comments keep their order so search from the comment which was found last
+            if let Some(i) = comments[next_comment..]
+                .iter()
+                .position(|original| original.text == comment.trim_end())
+            {
+                next_comment += i + 1;
+                let follows_code = output.last().is_some_and(|previous| {
+                    let previous = previous.trim_start();
+                    !previous.is_empty() && !previous.starts_with("//")
+                });
+                if comments[next_comment - 1].trailing && follows_code {
+                    let previous = output.last_mut().unwrap();
+                    previous.push(' ');
+                    previous.push_str(comment);
+                    continue;
+                }
+            }
+        }
+        output.push(line.to_string());
+    }
+    let mut output = output.join("\n");
+    if formatted.ends_with('\n') {
+        output.push('\n');
+    }
+    output
+}
+
+/// Formats the module of `uri` and indents the result according to `options`. Returns the
+/// source which was formatted along with the formatted source.
+async fn format_module(
+    thread: &Thread,
+    uri: &Url,
+    options: &FormattingOptions,
+) -> Result<(Arc<FileMap>, String), ServerError<()>> {
+    let source = retrieve_expr(thread, uri, |module| Ok(module.source.clone())).await?;
+    let formatted = format_source(thread, &source, options).await?;
+    Ok((source, formatted))
+}
+
+async fn format_source(
+    thread: &Thread,
+    source: &FileMap,
+    options: &FormattingOptions,
+) -> Result<String, ServerError<()>> {
+    // `format_expr` blocks on the formatting, which may need this thread to make progress
+    let formatted = thread
+        .format_expr_async(
+            &mut gluon_format::Formatter::default(),
+            &source.name().to_string(),
+            source.src(),
+        )
+        .await?;
+    let formatted = restore_trailing_comments(source.src(), &formatted);
+    Ok(reindent(&formatted, options))
+}
+
+/// Lines `start..end` of the original source which are replaced by `new_text`
+#[derive(Debug, PartialEq)]
+struct Hunk {
+    start: usize,
+    end: usize,
+    new_text: String,
+}
+
+/// Above this many pairs of lines the changed lines are replaced as a whole instead of being
+/// compared line by line
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// The groups of consecutive lines which differ between `original` and `formatted`
+fn line_hunks(original: &str, formatted: &str) -> Vec<Hunk> {
+    let old: Vec<_> = original.split_inclusive('\n').collect();
+    let new: Vec<_> = formatted.split_inclusive('\n').collect();
+    let prefix = old.iter().zip(&new).take_while(|(l, r)| l == r).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(l, r)| l == r)
+        .count();
+    let old_lines = &old[prefix..old.len() - suffix];
+    let new_lines = &new[prefix..new.len() - suffix];
+    if old_lines.is_empty() && new_lines.is_empty() {
+        return Vec::new();
+    }
+    if (old_lines.len() + 1) * (new_lines.len() + 1) > MAX_DIFF_CELLS {
+        return vec![Hunk {
+            start: prefix,
+            end: prefix + old_lines.len(),
+            new_text: new_lines.concat(),
+        }];
+    }
+
+    // The length of the longest common subsequence of `old_lines[i..]` and `new_lines[j..]`
+    let width = new_lines.len() + 1;
+    let mut lcs = vec![0u32; (old_lines.len() + 1) * width];
+    for i in (0..old_lines.len()).rev() {
+        for j in (0..new_lines.len()).rev() {
+            lcs[i * width + j] = if old_lines[i] == new_lines[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut hunks = Vec::new();
+    let mut current: Option<Hunk> = None;
+    let (mut i, mut j) = (0, 0);
+    while i < old_lines.len() || j < new_lines.len() {
+        if i < old_lines.len() && j < new_lines.len() && old_lines[i] == new_lines[j] {
+            hunks.extend(current.take());
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let hunk = current.get_or_insert_with(|| Hunk {
+            start: prefix + i,
+            end: prefix + i,
+            new_text: String::new(),
+        });
+        if j < new_lines.len()
+            && (i == old_lines.len() || lcs[i * width + j + 1] >= lcs[(i + 1) * width + j])
+        {
+            hunk.new_text.push_str(new_lines[j]);
+            j += 1;
+        } else {
+            i += 1;
+            hunk.end = prefix + i;
+        }
+    }
+    hunks.extend(current);
+
+    // Lines which were changed in place get a hunk each, so that formatting a range does not
+    // replace the neighbouring lines as well
+    let mut split = Vec::with_capacity(hunks.len());
+    for hunk in hunks {
+        let new_lines: Vec<_> = hunk.new_text.split_inclusive('\n').collect();
+        let pairs = (hunk.end - hunk.start).min(new_lines.len());
+        if pairs <= 1 {
+            split.push(hunk);
+            continue;
+        }
+        for (i, line) in new_lines[..pairs - 1].iter().enumerate() {
+            split.push(Hunk {
+                start: hunk.start + i,
+                end: hunk.start + i + 1,
+                new_text: line.to_string(),
+            });
+        }
+        split.push(Hunk {
+            start: hunk.start + pairs - 1,
+            end: hunk.end,
+            new_text: new_lines[pairs - 1..].concat(),
+        });
+    }
+    split
+}
+
+/// Replaces the lines of `source` which differ from `formatted`. If `range` is given only the
+/// changed lines which it touches are replaced.
+fn hunk_edits(
+    source: &FileMap,
+    formatted: &str,
+    range: Option<&Range>,
+) -> Result<Vec<TextEdit>, ServerError<()>> {
+    let end = byte_span_to_range(source, source.span())?.end;
+    let source = source.src();
+    let line_count = source.split_inclusive('\n').count();
+    let position = |line: usize| {
+        if line < line_count {
+            Position::new(line as u32, 0)
+        } else {
+            end
+        }
+    };
+    Ok(line_hunks(source, formatted)
+        .into_iter()
+        .filter(|hunk| range.is_none_or(|range| hunk_in_range(hunk, range)))
+        .map(|hunk| TextEdit {
+            range: Range {
+                start: position(hunk.start),
+                end: position(hunk.end),
+            },
+            new_text: hunk.new_text,
+        })
+        .collect())
+}
+
+/// Replaces the lines of `source` which differ from `formatted` and which `range` touches
+fn format_lines_in_range(source: &str, formatted: &str, range: &Range) -> String {
+    let lines: Vec<_> = source.split_inclusive('\n').collect();
+    let mut output = String::with_capacity(formatted.len());
+    let mut line = 0;
+    for hunk in line_hunks(source, formatted) {
+        if !hunk_in_range(&hunk, range) {
+            continue;
+        }
+        output.extend(lines[line..hunk.start].iter().copied());
+        output.push_str(&hunk.new_text);
+        line = hunk.end;
+    }
+    output.extend(lines[line..].iter().copied());
+    output
+}
+
+/// Whether `hunk` changes any of the lines which `range` touches
+fn hunk_in_range(hunk: &Hunk, range: &Range) -> bool {
+    let first = range.start.line as usize;
+    // A range which ends at the start of a line does not include that line
+    let last = if range.end.character == 0 && range.end.line > range.start.line {
+        range.end.line as usize - 1
+    } else {
+        range.end.line as usize
+    };
+    if hunk.start == hunk.end {
+        first <= hunk.start && hunk.start <= last + 1
+    } else {
+        hunk.start <= last && first < hunk.end
+    }
+}
+
+/// `gluon/formatPreview` responds with the formatted text of a document without changing it, so
+/// that an extension can show the formatting as a diff before applying it
+pub enum FormatPreview {}
+
+impl Request for FormatPreview {
+    type Params = FormatPreviewParams;
+    type Result = FormatPreviewResult;
+    const METHOD: &'static str = "gluon/formatPreview";
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormatPreviewParams {
+    pub text_document: TextDocumentIdentifier,
+    /// Only the changed lines which the range touches are formatted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range>,
+    /// Defaults to indenting with four spaces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<FormattingOptions>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormatPreviewResult {
+    /// The whole text of the document, formatted
+    pub text: String,
+    /// `true` if the document could not be formatted (because it does not parse) and `text` is
+    /// unchanged
+    pub skipped: bool,
+}
+
+pub fn register(io: &mut IoHandler, thread: &RootedThread, settings: &SettingsRef) {
+    {
         let thread = thread.clone();
-        async move {
-            retrieve_expr(&thread, &params.text_document.uri, |module| {
-                let formatted = thread.format_expr(
-                    &mut gluon_format::Formatter::default(),
-                    &module.source.name().to_string(),
-                    module.source.src(),
-                )?;
-                let range = byte_span_to_range(&module.source, module.source.span())?;
+        let preview = move |params: FormatPreviewParams| {
+            let thread = thread.clone();
+            async move {
+                let source = retrieve_expr(&thread, &params.text_document.uri, |module| {
+                    Ok(module.source.clone())
+                })
+                .await?;
+                let options = params.options.unwrap_or_else(|| FormattingOptions {
+                    tab_size: FORMATTER_INDENT as u32,
+                    insert_spaces: true,
+                    ..FormattingOptions::default()
+                });
+                let result = match format_source(&thread, &source, &options).await {
+                    Ok(formatted) => FormatPreviewResult {
+                        text: match &params.range {
+                            Some(range) => format_lines_in_range(source.src(), &formatted, range),
+                            None => formatted,
+                        },
+                        skipped: false,
+                    },
+                    Err(err) => {
+                        debug!(
+                            "Unable to format `{}`: {}",
+                            params.text_document.uri, err.message
+                        );
+                        FormatPreviewResult {
+                            text: source.src().to_string(),
+                            skipped: true,
+                        }
+                    }
+                };
+                Ok::<_, ServerError<()>>(result)
+            }
+        };
+        io.add_async_method(None::<FormatPreview>, preview);
+    }
+
+    {
+        let thread = thread.clone();
+        let format = move |params: DocumentFormattingParams| {
+            let thread = thread.clone();
+            async move {
+                let (source, formatted) =
+                    format_module(&thread, &params.text_document.uri, &params.options).await?;
+                let range = byte_span_to_range(&source, source.span())?;
                 Ok(Some(vec![TextEdit {
                     range,
                     new_text: formatted,
                 }]))
-            })
-            .await
+            }
+        };
+        io.add_async_method(request!("textDocument/formatting"), format);
+    }
+
+    {
+        // `gluon_format` only formats whole modules so the range only selects which of the changed
+        // lines are replaced
+        let thread = thread.clone();
+        let format_range = move |params: DocumentRangeFormattingParams| {
+            let thread = thread.clone();
+            async move {
+                let (source, formatted) =
+                    format_module(&thread, &params.text_document.uri, &params.options).await?;
+                Ok(Some(hunk_edits(&source, &formatted, Some(&params.range))?))
+            }
+        };
+        io.add_async_method(request!("textDocument/rangeFormatting"), format_range);
+    }
+
+    let thread = thread.clone();
+    let settings = settings.clone();
+    let format_on_save = move |params: WillSaveTextDocumentParams| {
+        let thread = thread.clone();
+        let enabled = settings.read().unwrap().format_on_save;
+        async move {
+            if !enabled {
+                return Ok(None);
+            }
+            let uri = params.text_document.uri;
+            // Formatting runs on a task of its own so that it can be abandoned once the timeout
+            // has passed
+            let format = tokio::spawn({
+                let uri = uri.clone();
+                async move {
+                    let options = FormattingOptions {
+                        tab_size: FORMATTER_INDENT as u32,
+                        insert_spaces: true,
+                        ..FormattingOptions::default()
+                    };
+                    let (source, formatted) = format_module(&thread, &uri, &options).await?;
+                    hunk_edits(&source, &formatted, None)
+                }
+            });
+            // A document which can not be formatted, such as one which does not parse, is saved
+            // as is
+            match tokio::time::timeout(FORMAT_ON_SAVE_TIMEOUT, format).await {
+                Ok(Ok(Ok(edits))) => Ok::<_, ServerError<()>>(Some(edits)),
+                Ok(Ok(Err(err))) => {
+                    debug!("Unable to format `{}` on save: {}", uri, err.message);
+                    Ok(None)
+                }
+                Ok(Err(err)) => {
+                    error!("Formatting `{}` on save failed: {}", uri, err);
+                    Ok(None)
+                }
+                Err(_) => {
+                    warn!(
+                        "Formatting `{}` took longer than {:?}, saving it unformatted",
+                        uri, FORMAT_ON_SAVE_TIMEOUT
+                    );
+                    Ok(None)
+                }
+            }
         }
     };
-    io.add_async_method(request!("textDocument/formatting"), format);
+    io.add_async_method(request!("textDocument/willSaveWaitUntil"), format_on_save);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(tab_size: u32, insert_spaces: bool) -> FormattingOptions {
+        FormattingOptions {
+            tab_size,
+            insert_spaces,
+            ..FormattingOptions::default()
+        }
+    }
+
+    #[test]
+    fn reindent_spaces_and_tabs() {
+        let formatted = "let f x =\n    let y =\n        x\n      + 1\n    y\nf\n";
+        assert_eq!(reindent(formatted, &options(4, true)), formatted);
+        assert_eq!(
+            reindent(formatted, &options(2, true)),
+            "let f x =\n  let y =\n    x\n    + 1\n  y\nf\n"
+        );
+        assert_eq!(
+            reindent(formatted, &options(4, false)),
+            "let f x =\n\tlet y =\n\t\tx\n\t  + 1\n\ty\nf\n"
+        );
+    }
+
+    #[test]
+    fn trailing_comments_stay_after_their_code() {
+        let source =
+            "/// The answer\nlet   x =    42 // not 41\n// standalone\nlet s = \"// text\"\nx\n";
+        let formatted =
+            "/// The answer\nlet x = 42\n// not 41\n// standalone\nlet s = \"// text\"\nx\n";
+        assert_eq!(
+            restore_trailing_comments(source, formatted),
+            "/// The answer\nlet x = 42 // not 41\n// standalone\nlet s = \"// text\"\nx\n"
+        );
+    }
+
+    #[test]
+    fn hunks_of_changed_lines() {
+        let original = "let x =  1\nlet y = 2\nlet z =  3\nx\n";
+        let formatted = "let x = 1\nlet y = 2\nlet z = 3\nlet w = 4\nx\n";
+        let hunks = line_hunks(original, formatted);
+        assert_eq!(
+            hunks,
+            vec![
+                Hunk {
+                    start: 0,
+                    end: 1,
+                    new_text: "let x = 1\n".into(),
+                },
+                Hunk {
+                    start: 2,
+                    end: 3,
+                    new_text: "let z = 3\nlet w = 4\n".into(),
+                },
+            ]
+        );
+
+        let range = Range::new(Position::new(2, 0), Position::new(3, 0));
+        assert!(!hunk_in_range(&hunks[0], &range));
+        assert!(hunk_in_range(&hunks[1], &range));
+
+        // Neighbouring lines which change in place are separate hunks
+        assert_eq!(
+            line_hunks("x  \ny  \n", "x\ny\n"),
+            vec![
+                Hunk {
+                    start: 0,
+                    end: 1,
+                    new_text: "x\n".into(),
+                },
+                Hunk {
+                    start: 1,
+                    end: 2,
+                    new_text: "y\n".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reindent_skips_string_contents() {
+        let formatted = r##"let x =
+    "a
+    b\"
+    c"
+let y =
+    r#"a"
+    b"#
+let z = '"'
+    z
+"##;
+        assert_eq!(
+            reindent(formatted, &options(2, true)),
+            r##"let x =
+  "a
+    b\"
+    c"
+let y =
+  r#"a"
+    b"#
+let z = '"'
+  z
+"##
+        );
+    }
 }
@@ -1,61 +1,339 @@
+use std::cmp::Ordering;
+
+use gluon::base::{
+    ast::Typed,
+    pos::ByteOffset,
+    resolve,
+    source::Source,
+    types::{ArgType, NullInterner, TypeEnv},
+};
+
 use {
     futures::prelude::*,
     jsonrpc_core::IoHandler,
     lsp_types::{Hover, HoverContents, HoverParams, MarkedString},
 };
 
-use crate::{completion, rpc::LanguageServerCommand, BoxFuture};
+use crate::{
+    command::configuration::SettingsRef, completion, rpc::LanguageServerCommand, BoxFuture,
+};
 
 use super::*;
 
-struct HoverCommand(RootedThread);
+/// Renders the fields of `typ` as a markdown table. Returns `None` if `typ` is not a record.
+fn record_table(env: &dyn TypeEnv<Type = ArcType>, typ: &ArcType) -> Option<String> {
+    let resolved = resolve::remove_aliases(env, NullInterner::new(), typ.clone());
+    match &*resolved {
+        Type::Record(_) if resolved.row_iter().next().is_some() => (),
+        _ => return None,
+    }
+    // Types which do not fit on one line are printed on several but a cell must be a single line
+    let cell = |text: String| {
+        text.split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .replace('|', "\\|")
+    };
+    let mut table = String::new();
+    if let Some(alias) = typ.alias_ident() {
+        table.push_str(&format!("`{}`\n\n", alias.declared_name()));
+    }
+    table.push_str("| Field | Type |\n| --- | --- |\n");
+    for field in resolved.row_iter() {
+        table.push_str(&format!(
+            "| `{}` | `{}` |\n",
+            field.name.declared_name(),
+            cell(field.typ.to_string())
+        ));
+    }
+    Some(table)
+}
+
+/// The implicit arguments (such as `[Eq a]`) which must be resolved before the explicit arguments
+/// of a function of type `typ` can be passed
+fn constraints(typ: &ArcType) -> Vec<&ArcType> {
+    let mut constraints = Vec::new();
+    let mut typ = typ.remove_forall();
+    while let Some((ArgType::Implicit, arg, ret)) = typ.as_function_with_type() {
+        constraints.push(arg);
+        typ = ret.remove_forall();
+    }
+    constraints
+}
+
+/// The name of the binding which an implicit argument refers to, such as `std.int.eq`
+fn instance_name(expr: &SpannedExpr<'_, Symbol>) -> Option<String> {
+    match &expr.value {
+        // Bindings generated by the compiler (such as the implicit prelude) are left out
+        Expr::Ident(id) => {
+            let name = id.name.declared_name();
+            if name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                Some(name.to_string())
+            } else {
+                None
+            }
+        }
+        Expr::Projection(expr, field, _) => Some(match instance_name(expr) {
+            Some(module) => format!("{}.{}", module, field.declared_name()),
+            None => field.declared_name().to_string(),
+        }),
+        // An instance which itself has implicit arguments
+        Expr::App { func, .. } => instance_name(func),
+        _ => None,
+    }
+}
+
+/// The instances which were selected for the implicit arguments of the function at `span`, if it
+/// is called there
+fn selected_instances(
+    source_span: Span<BytePos>,
+    expr: &SpannedExpr<'_, Symbol>,
+    span: Span<BytePos>,
+) -> Vec<Option<String>> {
+    enclosing_nodes(source_span, expr, span.start())
+        .into_iter()
+        .find_map(|node| match node {
+            Node::Expr(Spanned {
+                value:
+                    Expr::App {
+                        func,
+                        implicit_args,
+                        ..
+                    },
+                ..
+            }) if func.span == span => Some(implicit_args.iter().map(instance_name).collect()),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+/// Lists the constraints of `typ` in markdown along with the instance which was selected for
+/// each, if known. `None` if `typ` has no constraints.
+fn constraints_section(typ: &ArcType, instances: &[Option<String>]) -> Option<String> {
+    let constraints = constraints(typ);
+    if constraints.is_empty() {
+        return None;
+    }
+    let mut section = String::from("Constraints:\n\n");
+    for (i, constraint) in constraints.into_iter().enumerate() {
+        let constraint = constraint.to_string();
+        let constraint = constraint.split_whitespace().collect::<Vec<_>>().join(" ");
+        match instances.get(i) {
+            Some(Some(instance)) => {
+                section.push_str(&format!("* `{}` from `{}`\n", constraint, instance))
+            }
+            _ => section.push_str(&format!("* `{}`\n", constraint)),
+        }
+    }
+    Some(section)
+}
+
+/// The type of the identifier or literal at `byte_index`. Anywhere else (such as the whitespace
+/// in `f x`) it is the type of the surrounding expression, and the returned flag is `false`.
+pub(crate) fn type_at(
+    env: &dyn TypeEnv<Type = ArcType>,
+    source_span: Span<BytePos>,
+    expr: &SpannedExpr<'_, Symbol>,
+    byte_index: BytePos,
+) -> Option<(either::Either<ArcKind, ArcType>, Span<BytePos>, bool)> {
+    let extract = (completion::TypeAt { env }, completion::SpanAt);
+    match completion::completion(extract, source_span, expr, byte_index) {
+        Ok((typ, span)) if span.containment(byte_index) == Ordering::Equal => {
+            Some((typ, span, true))
+        }
+        _ => {
+            let exprs = enclosing_nodes(source_span, expr, byte_index)
+                .into_iter()
+                .filter(|node| matches!(node, Node::Expr(_)));
+            match innermost(exprs) {
+                Some(Node::Expr(found)) => {
+                    let typ = found.try_type_of(env).ok();
+                    typ.map(|typ| (either::Either::Right(typ), found.span, false))
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CharClass {
+    Identifier,
+    /// Brackets and separators, which are tokens on their own
+    Bracket,
+    Operator,
+}
+
+/// `None` for whitespace
+fn char_class(c: char) -> Option<CharClass> {
+    match c {
+        _ if c.is_whitespace() => None,
+        _ if c.is_alphanumeric() || c == '_' || c == '\'' => Some(CharClass::Identifier),
+        '(' | ')' | '[' | ']' | '{' | '}' | ',' | ';' | '"' => Some(CharClass::Bracket),
+        _ => Some(CharClass::Operator),
+    }
+}
+
+/// The length of the string or character literal which `text` starts with, `None` if it does not
+/// start with one or the literal is not closed
+fn literal_len(text: &str) -> Option<usize> {
+    if let Some(raw) = text.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let body = raw[hashes..].strip_prefix('"')?;
+        let close = format!("\"{}", &raw[..hashes]);
+        return Some(text.len() - body.len() + body.find(&close)? + close.len());
+    }
+    let quote = text.chars().next().filter(|&c| c == '"' || c == '\'')?;
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            _ if c == quote => return Some(i + c.len_utf8()),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Returns `true` if `text` is a single token, such as an identifier, an operator or a literal
+fn is_token(text: &str) -> bool {
+    let literal = ["\"", "'", "r\"", "r#"];
+    if literal.iter().any(|start| text.starts_with(start)) {
+        // The text of an expression such as `"a" ++ "b"` starts with a literal as well
+        return literal_len(text) == Some(text.len());
+    }
+    !text.is_empty()
+        && text.chars().all(|c| {
+            matches!(
+                char_class(c),
+                Some(CharClass::Identifier) | Some(CharClass::Operator)
+            )
+        })
+}
+
+/// The span of the token at `byte_index`: a run of identifier or operator characters or a single
+/// bracket or separator. `None` on whitespace.
+fn token_at(source: &gluon::base::source::FileMap, byte_index: BytePos) -> Option<Span<BytePos>> {
+    let text = source.source();
+    let offset = (byte_index - source.span().start()).to_usize();
+    let c = text.get(offset..)?.chars().next()?;
+    let class = char_class(c)?;
+    let (start, end) = if class == CharClass::Bracket {
+        (offset, offset + c.len_utf8())
+    } else {
+        let start = text[..offset]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| char_class(c) == Some(class))
+            .last()
+            .map_or(offset, |(i, _)| i);
+        let end = text[offset..]
+            .char_indices()
+            .find(|&(_, c)| char_class(c) != Some(class))
+            .map_or(text.len(), |(i, _)| offset + i);
+        (start, end)
+    };
+    let span_start = source.span().start();
+    Some(Span::new(
+        span_start + ByteOffset::from(start as i64),
+        span_start + ByteOffset::from(end as i64),
+    ))
+}
+
+struct HoverCommand(RootedThread, ClientCapabilitiesRef, SettingsRef);
 impl LanguageServerCommand<HoverParams> for HoverCommand {
     type Future = BoxFuture<Self::Output, ServerError<()>>;
     type Output = Option<Hover>;
     type Error = ();
     fn execute(&self, change: HoverParams) -> BoxFuture<Option<Hover>, ServerError<()>> {
         let thread = self.0.clone();
+        let markdown = self.1.read().unwrap().supports_markdown_hover();
+        let record_tables = markdown && self.2.read().unwrap().hover.record_tables;
         async move {
-            retrieve_expr(
+            retrieve_expr_with_pos(
                 &thread,
                 &change.text_document_position_params.text_document.uri,
-                |module| {
+                &change.text_document_position_params.position,
+                |module, byte_index| {
                     let expr = module.expr.expr();
 
                     let source = &module.source;
-                    let byte_index = position_to_byte_index(
-                        &source,
-                        &change.text_document_position_params.position,
-                    )?;
 
                     let db = thread.get_database();
                     let env = db.as_env();
                     let (_, metadata_map) = gluon::check::metadata::metadata(&env, &expr);
                     let opt_metadata =
                         completion::get_metadata(&metadata_map, source.span(), expr, byte_index);
-                    let extract = (completion::TypeAt { env: &env }, completion::SpanAt);
-                    Ok(
-                        completion::completion(extract, source.span(), expr, byte_index)
-                            .map(|(typ, span)| {
-                                let contents = match opt_metadata.and_then(|m| m.comment.as_ref()) {
-                                    Some(comment) => HoverContents::Markup(MarkupContent {
-                                        kind: MarkupKind::Markdown,
-                                        value: format!("{}\n\n{}", typ, comment.content),
-                                    }),
-                                    None => {
-                                        HoverContents::Scalar(MarkedString::from_language_code(
-                                            "gluon".into(),
-                                            format!("{}", typ),
-                                        ))
-                                    }
-                                };
-                                Some(Hover {
-                                    contents,
-                                    range: byte_span_to_range(&source, span).ok(),
+                    let found = type_at(&env, source.span(), expr, byte_index).map(
+                        |(typ, span, identifier)| {
+                            let comment = opt_metadata
+                                .filter(|_| identifier)
+                                .and_then(|m| m.comment.as_ref());
+                            // The type may be of an expression which encloses the token at the
+                            // cursor but only the token is highlighted
+                            let highlight = if is_token(source.src_slice(span)) {
+                                Some(span)
+                            } else {
+                                token_at(source, byte_index)
+                            };
+                            let constraints = match &typ {
+                                either::Either::Right(typ) if markdown && identifier => {
+                                    let instances = selected_instances(source.span(), expr, span);
+                                    constraints_section(typ, &instances)
+                                }
+                                _ => None,
+                            };
+                            (typ, highlight, comment, constraints)
+                        },
+                    );
+                    Ok(found.map(|(typ, highlight, comment, constraints)| {
+                        let table = match &typ {
+                            either::Either::Right(typ) if record_tables => record_table(&env, typ),
+                            _ => None,
+                        };
+                        let typ = typ.to_string();
+                        let contents = match (table, comment) {
+                            (None, comment) if constraints.is_some() => {
+                                let mut value = format!(
+                                    "```gluon\n{}\n```\n\n{}",
+                                    typ,
+                                    constraints.unwrap_or_default()
+                                );
+                                if let Some(comment) = comment {
+                                    value.push_str(&format!("\n{}", comment.content));
+                                }
+                                HoverContents::Markup(MarkupContent {
+                                    kind: MarkupKind::Markdown,
+                                    value,
                                 })
-                            })
-                            .unwrap_or_else(|()| None),
-                    )
+                            }
+                            (Some(table), comment) => HoverContents::Markup(MarkupContent {
+                                kind: MarkupKind::Markdown,
+                                value: match comment {
+                                    Some(comment) => format!("{}\n{}", table, comment.content),
+                                    None => table,
+                                },
+                            }),
+                            (None, Some(comment)) => HoverContents::Markup(MarkupContent {
+                                kind: if markdown {
+                                    MarkupKind::Markdown
+                                } else {
+                                    MarkupKind::PlainText
+                                },
+                                value: format!("{}\n\n{}", typ, comment.content),
+                            }),
+                            (None, None) => HoverContents::Scalar(
+                                MarkedString::from_language_code("gluon".into(), typ),
+                            ),
+                        };
+                        Hover {
+                            contents,
+                            range: highlight
+                                .and_then(|span| byte_span_to_range(&source, span).ok()),
+                        }
+                    }))
                 },
             )
             .await
@@ -68,6 +346,18 @@ impl LanguageServerCommand<HoverParams> for HoverCommand {
     }
 }
 
-pub fn register(io: &mut IoHandler, thread: &RootedThread) {
-    io.add_async_method(request!("textDocument/hover"), HoverCommand(thread.clone()));
+pub fn register(
+    io: &mut IoHandler,
+    thread: &RootedThread,
+    client_capabilities: &ClientCapabilitiesRef,
+    settings: &SettingsRef,
+) {
+    io.add_async_method(
+        request!("textDocument/hover"),
+        HoverCommand(
+            thread.clone(),
+            client_capabilities.clone(),
+            settings.clone(),
+        ),
+    );
 }
@@ -1,56 +1,269 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::channel::mpsc;
+
 use jsonrpc_core::IoHandler;
 
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
 use lsp_types::{
-    CompletionOptions, InitializeError, InitializeParams, InitializeResult, ServerCapabilities,
-    ServerInfo, SignatureHelpOptions, TextDocumentSyncCapability, TextDocumentSyncKind,
-    WorkDoneProgressOptions,
+    CompletionOptions, CompletionOptionsCompletionItem, InitializeError, InitializeParams,
+    InitializeResult, InitializedParams, NumberOrString, ProgressParams, ProgressParamsValue,
+    SemanticTokensFullOptions, SemanticTokensOptions, ServerCapabilities, ServerInfo,
+    SignatureHelpOptions, TextDocumentSyncCapability, TextDocumentSyncKind,
+    TextDocumentSyncOptions, TextDocumentSyncSaveOptions, WorkDoneProgress, WorkDoneProgressBegin,
+    WorkDoneProgressCreateParams, WorkDoneProgressEnd, WorkDoneProgressOptions,
+    WorkDoneProgressReport,
 };
 
-use crate::{rpc::LanguageServerCommand, BoxFuture};
+use crate::{
+    check_importer::{get_module, State},
+    command::configuration::{self, SettingsRef, SettingsSourcesRef},
+    project,
+    rpc::{self, ClientRequests, LanguageServerCommand, OutgoingMessage},
+    server::{ClientCapabilities, ClientCapabilitiesRef},
+    startup::{self, Phase, StartupTimingsRef},
+    BoxFuture,
+};
 
 use super::*;
 
-struct Initialize(RootedThread);
-impl LanguageServerCommand<InitializeParams> for Initialize {
+/// `initialize` with access to the parts of the parameters which `lsp_types` does not know about
+/// yet
+enum InitializeRequest {}
+
+impl lsp_types::request::Request for InitializeRequest {
+    type Params = InitializeParamsJson;
+    type Result = InitializeResultJson;
+    const METHOD: &'static str = lsp_types::request::Initialize::METHOD;
+}
+
+struct InitializeParamsJson {
+    params: InitializeParams,
+    json: serde_json::Value,
+}
+
+impl InitializeParamsJson {
+    fn completion_item_defaults(&self) -> Vec<String> {
+        self.json
+            .pointer("/capabilities/textDocument/completion/completionList/itemDefaults")
+            .and_then(|item_defaults| serde_json::from_value(item_defaults.clone()).ok())
+            .unwrap_or_default()
+    }
+
+    fn workspace_symbol_resolve_properties(&self) -> Vec<String> {
+        self.json
+            .pointer("/capabilities/workspace/symbol/resolveSupport/properties")
+            .and_then(|properties| serde_json::from_value(properties.clone()).ok())
+            .unwrap_or_default()
+    }
+
+    /// The encoding of the positions which the server and the client agree on. `None` if the
+    /// client did not offer any encodings, in which case both sides use UTF-16 without saying so.
+    fn position_encoding(&self) -> Option<String> {
+        let encodings: Vec<String> = self
+            .json
+            .pointer("/capabilities/general/positionEncodings")
+            .and_then(|encodings| serde_json::from_value(encodings.clone()).ok())?;
+        // Positions are only computed in UTF-16 code units
+        encodings
+            .into_iter()
+            .find(|encoding| encoding == POSITION_ENCODING_UTF16)
+    }
+}
+
+const POSITION_ENCODING_UTF16: &str = "utf-16";
+
+impl Serialize for InitializeParamsJson {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.json.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for InitializeParamsJson {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let json = serde_json::Value::deserialize(deserializer)?;
+        Ok(InitializeParamsJson {
+            params: serde_json::from_value(json.clone()).map_err(D::Error::custom)?,
+            json,
+        })
+    }
+}
+
+/// `InitializeResult` with the capabilities which `lsp_types` does not know about yet
+struct InitializeResultJson {
+    result: InitializeResult,
+    position_encoding: Option<String>,
+}
+
+impl Serialize for InitializeResultJson {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        use serde::ser::Error as _;
+
+        let mut json = serde_json::to_value(&self.result).map_err(S::Error::custom)?;
+        if let Some(position_encoding) = &self.position_encoding {
+            json["capabilities"]["positionEncoding"] = position_encoding.clone().into();
+        }
+        // `WorkspaceSymbolOptions` has no `resolveProvider` yet
+        json["capabilities"]["workspaceSymbolProvider"] =
+            serde_json::json!({ "resolveProvider": true });
+        json.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for InitializeResultJson {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let json = serde_json::Value::deserialize(deserializer)?;
+        Ok(InitializeResultJson {
+            position_encoding: json
+                .pointer("/capabilities/positionEncoding")
+                .and_then(|encoding| encoding.as_str())
+                .map(|encoding| encoding.to_string()),
+            result: serde_json::from_value(json).map_err(D::Error::custom)?,
+        })
+    }
+}
+
+/// The directories of the project's modules which are indexed once the client is initialized
+type ProjectDirectories = Arc<Mutex<Vec<PathBuf>>>;
+
+struct Initialize(
+    RootedThread,
+    ClientCapabilitiesRef,
+    Arc<AtomicBool>,
+    ProjectDirectories,
+    SettingsRef,
+    SettingsSourcesRef,
+    StartupTimingsRef,
+);
+impl LanguageServerCommand<InitializeParamsJson> for Initialize {
     type Future = BoxFuture<Self::Output, ServerError<Self::Error>>;
-    type Output = InitializeResult;
+    type Output = InitializeResultJson;
     type Error = InitializeError;
     fn execute(
         &self,
-        change: InitializeParams,
-    ) -> BoxFuture<InitializeResult, ServerError<InitializeError>> {
+        request: InitializeParamsJson,
+    ) -> BoxFuture<InitializeResultJson, ServerError<InitializeError>> {
+        let completion_item_defaults = request.completion_item_defaults();
+        let workspace_symbol_resolve_properties = request.workspace_symbol_resolve_properties();
+        let position_encoding = request.position_encoding();
+        let change = request.params;
         let thread = self.0.clone();
+        let client_capabilities = self.1.clone();
+        let ready = self.2.clone();
+        let project_directories = self.3.clone();
+        let settings = self.4.clone();
+        let settings_sources = self.5.clone();
+        let startup = self.6.clone();
         async move {
+            *client_capabilities.write().unwrap() = ClientCapabilities {
+                lsp: change.capabilities,
+                completion_item_defaults,
+                workspace_symbol_resolve_properties,
+            };
+
+            let threads = change
+                .initialization_options
+                .as_ref()
+                .and_then(|options| options.get("threads"));
+            if let Some(threads) = threads {
+                // The runtime, and with it its threads, has to exist before `initialize` is read
+                warn!(
+                    "Ignoring `initializationOptions.threads: {}`, start the server with \
+                     `--threads` instead",
+                    threads
+                );
+            }
+
             let import = thread.get_macros().get("import").expect("Import macro");
             let import = import
                 .downcast_ref::<Import<CheckImporter>>()
                 .expect("Check importer");
-            if let Some(ref uri) = change.root_uri {
-                import.add_path(
+            let root = match change.root_uri {
+                Some(ref uri) => Some(
                     uri.to_file_path()
                         .map_err(|()| "Unable to convert root_uri to file path")?,
-                );
+                ),
+                None => None,
+            };
+            if let Some(root) = root.clone() {
+                // Modules in the project's module directories are named relative to those
+                // directories so they must be searched before the root
+                let directories = startup::measure(&startup, Phase::ScanModulePaths, || {
+                    project::module_directories(&root)
+                });
+                info!("Discovered module directories {:?}", directories);
+                import
+                    .paths
+                    .write()
+                    .unwrap()
+                    .splice(0..0, directories.iter().cloned());
+                import.add_path(root);
+                import
+                    .importer
+                    .set_import_paths(import.paths.read().unwrap().clone());
+                *project_directories.lock().unwrap() = directories;
             }
 
-            Ok(InitializeResult {
+            // The project's settings file provides the defaults of the client's settings
+            let client_settings = change.initialization_options.clone().unwrap_or_default();
+            startup::measure(&startup, Phase::ReadSettings, || {
+                let mut sources = settings_sources.lock().unwrap();
+                if let Some(root) = root {
+                    sources.set_root(root);
+                }
+                sources.set_client(client_settings);
+                configuration::apply_settings(&thread, &settings, &sources);
+            });
+
+            ready.store(true, Ordering::SeqCst);
+
+            let result = InitializeResult {
                 server_info: Some(ServerInfo {
-                    name: "Gluon language server".into(),
+                    name: "gluon-language-server".into(),
                     version: Some(match option_env!("GIT_COMMIT") {
                         Some(git_commit) => format!("{}-{}", env!("CARGO_PKG_VERSION"), git_commit),
                         None => env!("CARGO_PKG_VERSION").into(),
                     }),
                 }),
                 capabilities: ServerCapabilities {
-                    text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                        TextDocumentSyncKind::Incremental,
+                    text_document_sync: Some(TextDocumentSyncCapability::Options(
+                        TextDocumentSyncOptions {
+                            open_close: Some(true),
+                            change: Some(TextDocumentSyncKind::Incremental),
+                            will_save: None,
+                            will_save_wait_until: Some(true),
+                            save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                        },
                     )),
                     completion_provider: Some(CompletionOptions {
                         resolve_provider: Some(true),
-                        trigger_characters: Some(vec![".".into()]),
+                        trigger_characters: Some(vec![".".into(), "?".into()]),
                         work_done_progress_options: WorkDoneProgressOptions {
                             work_done_progress: None,
                         },
                         all_commit_characters: None,
+                        completion_item: Some(CompletionOptionsCompletionItem {
+                            label_details_support: Some(true),
+                        }),
                     }),
                     signature_help_provider: Some(SignatureHelpOptions {
                         trigger_characters: None,
@@ -61,12 +274,46 @@ impl LanguageServerCommand<InitializeParams> for Initialize {
                     }),
                     hover_provider: Some(true.into()),
                     document_formatting_provider: Some(lsp_types::OneOf::Left(true)),
+                    document_range_formatting_provider: Some(lsp_types::OneOf::Left(true)),
                     document_highlight_provider: Some(lsp_types::OneOf::Left(true)),
                     document_symbol_provider: Some(lsp_types::OneOf::Left(true)),
                     workspace_symbol_provider: Some(lsp_types::OneOf::Left(true)),
+                    declaration_provider: Some(lsp_types::DeclarationCapability::Simple(true)),
                     definition_provider: Some(lsp_types::OneOf::Left(true)),
+                    code_action_provider: Some(
+                        lsp_types::CodeActionOptions {
+                            code_action_kinds: Some(super::code_action::ACTION_KINDS.to_vec()),
+                            work_done_progress_options: WorkDoneProgressOptions {
+                                work_done_progress: None,
+                            },
+                            resolve_provider: None,
+                        }
+                        .into(),
+                    ),
+                    execute_command_provider: Some(lsp_types::ExecuteCommandOptions {
+                        commands: vec![super::code_action::CHOOSE_IMPORT_COMMAND.into()],
+                        work_done_progress_options: WorkDoneProgressOptions {
+                            work_done_progress: None,
+                        },
+                    }),
+                    semantic_tokens_provider: Some(
+                        SemanticTokensOptions {
+                            legend: super::semantic_tokens::legend(),
+                            range: None,
+                            full: Some(SemanticTokensFullOptions::Delta { delta: Some(true) }),
+                            work_done_progress_options: WorkDoneProgressOptions {
+                                work_done_progress: None,
+                            },
+                        }
+                        .into(),
+                    ),
                     ..ServerCapabilities::default()
                 },
+                offset_encoding: None,
+            };
+            Ok(InitializeResultJson {
+                result,
+                position_encoding,
             })
         }
         .boxed()
@@ -77,6 +324,177 @@ impl LanguageServerCommand<InitializeParams> for Initialize {
     }
 }
 
-pub fn register(io: &mut IoHandler, thread: &RootedThread) {
-    io.add_async_method(request!("initialize"), Initialize(thread.clone()));
+async fn report_progress(message_log: &mpsc::Sender<OutgoingMessage>, progress: WorkDoneProgress) {
+    rpc::send_response(
+        message_log.clone(),
+        notification!("$/progress"),
+        ProgressParams {
+            token: NumberOrString::String(INDEX_PROJECT_TOKEN.into()),
+            value: ProgressParamsValue::WorkDone(progress),
+        },
+    )
+    .await;
+}
+
+const INDEX_PROJECT_TOKEN: &str = "gluon/indexProject";
+
+/// Typechecks the top level modules of the project so that they are ready for the first
+/// completion and are included in workspace symbols
+async fn index_project(
+    thread: RootedThread,
+    message_log: mpsc::Sender<OutgoingMessage>,
+    client_requests: ClientRequests,
+    directories: Vec<PathBuf>,
+    progress: bool,
+    startup: StartupTimingsRef,
+) {
+    let modules: Vec<_> = startup::measure(&startup, Phase::ScanModulePaths, || {
+        directories
+            .iter()
+            .flat_map(|directory| {
+                project::top_level_modules(directory).unwrap_or_else(|err| {
+                    error!("Unable to read `{}`: {}", directory.display(), err);
+                    Vec::new()
+                })
+            })
+            .collect()
+    });
+    startup::measure_async(
+        &startup,
+        Phase::IndexProject,
+        index_modules(thread, message_log, client_requests, modules, progress),
+    )
+    .await
+}
+
+async fn index_modules(
+    thread: RootedThread,
+    message_log: mpsc::Sender<OutgoingMessage>,
+    client_requests: ClientRequests,
+    modules: Vec<(String, PathBuf)>,
+    progress: bool,
+) {
+    if modules.is_empty() {
+        return;
+    }
+
+    // Progress may only be reported once the client has created the token
+    let progress = progress
+        && match client_requests
+            .send(
+                message_log.clone(),
+                request!("window/workDoneProgress/create"),
+                WorkDoneProgressCreateParams {
+                    token: NumberOrString::String(INDEX_PROJECT_TOKEN.into()),
+                },
+            )
+            .await
+        {
+            Ok(()) => true,
+            Err(err) => {
+                debug!("Unable to create the progress token: {}", err.message);
+                false
+            }
+        };
+    if progress {
+        report_progress(
+            &message_log,
+            WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                title: "Indexing".into(),
+                cancellable: Some(false),
+                message: None,
+                percentage: Some(0),
+            }),
+        )
+        .await;
+    }
+
+    let import = thread.get_macros().get("import").expect("Import macro");
+    let import = import
+        .downcast_ref::<Import<CheckImporter>>()
+        .expect("Check importer");
+    for (i, (module, path)) in modules.iter().enumerate() {
+        match get_module(&thread, module).await {
+            Ok(_) => {
+                if let Ok(uri) = Url::from_file_path(path) {
+                    import
+                        .importer
+                        .0
+                        .lock()
+                        .await
+                        .entry(module.clone())
+                        .or_insert_with(|| State::empty(uri));
+                }
+            }
+            Err(err) => debug!("Unable to index `{}`: {}", module, err),
+        }
+        if progress {
+            report_progress(
+                &message_log,
+                WorkDoneProgress::Report(WorkDoneProgressReport {
+                    cancellable: Some(false),
+                    message: Some(module.clone()),
+                    percentage: Some(((i + 1) * 100 / modules.len()) as u32),
+                }),
+            )
+            .await;
+        }
+    }
+
+    if progress {
+        report_progress(
+            &message_log,
+            WorkDoneProgress::End(WorkDoneProgressEnd { message: None }),
+        )
+        .await;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn register(
+    io: &mut IoHandler,
+    thread: &RootedThread,
+    message_log: &mpsc::Sender<OutgoingMessage>,
+    client_requests: &ClientRequests,
+    client_capabilities: &ClientCapabilitiesRef,
+    ready: &Arc<AtomicBool>,
+    settings: &SettingsRef,
+    settings_sources: &SettingsSourcesRef,
+    startup: &StartupTimingsRef,
+) {
+    let project_directories = ProjectDirectories::default();
+    io.add_async_method(
+        None::<InitializeRequest>,
+        Initialize(
+            thread.clone(),
+            client_capabilities.clone(),
+            ready.clone(),
+            project_directories.clone(),
+            settings.clone(),
+            settings_sources.clone(),
+            startup.clone(),
+        ),
+    );
+
+    let thread = thread.clone();
+    let message_log = message_log.clone();
+    let client_requests = client_requests.clone();
+    let client_capabilities = client_capabilities.clone();
+    let startup = startup.clone();
+    let f = move |_: InitializedParams| {
+        let directories = project_directories.lock().unwrap().clone();
+        let progress = client_capabilities
+            .read()
+            .unwrap()
+            .supports_work_done_progress();
+        tokio::spawn(index_project(
+            thread.clone(),
+            message_log.clone(),
+            client_requests.clone(),
+            directories,
+            progress,
+            startup.clone(),
+        ));
+    };
+    io.add_notification(notification!("initialized"), f);
 }
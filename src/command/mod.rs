@@ -11,7 +11,7 @@ use gluon::{
         ast::{Expr, SpannedExpr},
         filename_to_module,
         kind::ArcKind,
-        pos::{BytePos, Spanned},
+        pos::{BytePos, Span, Spanned},
         symbol::Symbol,
         types::{ArcType, BuiltinType, Type, TypeExt, TypePtr},
     },
@@ -30,23 +30,34 @@ use {
 };
 
 use crate::{
+    ast_query::{enclosing_nodes, innermost, innermost_node_at, Node},
     byte_span_to_range,
     check_importer::{CheckImporter, Module},
     name::strip_file_prefix_with_thread,
     position_to_byte_index,
     rpc::ServerError,
-    server::Handler,
+    server::{ClientCapabilitiesRef, Handler},
 };
 
+pub mod code_action;
 pub mod completion;
+pub mod configuration;
+pub mod declaration;
 pub mod definition;
 pub mod document_highlight;
 pub mod document_symbols;
+pub mod dump_state;
+pub mod evaluate;
 pub mod formatting;
 pub mod hover;
 pub mod initialize;
+pub mod module_graph;
+pub mod node_info;
+pub mod ping;
+pub mod semantic_tokens;
 pub mod signature_help;
 pub mod symbol;
+pub mod type_at;
 
 fn type_to_completion_item_kind(typ: &ArcType) -> CompletionItemKind {
     match **typ {
@@ -75,19 +86,28 @@ fn ident_to_completion_item_kind(
     }
 }
 
-fn make_documentation<T>(typ: Option<T>, comment: &str) -> Documentation
+/// Creates markdown documentation, or plain text if the client does not support `markdown`
+fn make_documentation<T>(typ: Option<T>, comment: &str, markdown: bool) -> Documentation
 where
     T: fmt::Display,
 {
     use std::fmt::Write;
     let mut value = String::new();
     if let Some(typ) = typ {
-        write!(value, "```gluon\n{}\n```\n", typ).unwrap();
+        if markdown {
+            write!(value, "```gluon\n{}\n```\n", typ).unwrap();
+        } else {
+            writeln!(value, "{}", typ).unwrap();
+        }
     }
     value.push_str(comment);
 
     Documentation::MarkupContent(MarkupContent {
-        kind: MarkupKind::Markdown,
+        kind: if markdown {
+            MarkupKind::Markdown
+        } else {
+            MarkupKind::PlainText
+        },
         value,
     })
 }
@@ -128,7 +148,11 @@ fn completion_symbols_to_document_symbols_inner(
             CompletionSymbolContent::Type { .. } => true,
         })
         .map(|symbol| completion_symbol_to_document_symbol(source, symbol, parent_kind))
-        .collect()
+        .collect::<Result<Vec<_>, _>>()
+        .map(|mut symbols| {
+            symbols.sort_by_key(|symbol| (symbol.range.start.line, symbol.range.start.character));
+            symbols
+        })
 }
 
 fn completion_symbol_to_document_symbol(
@@ -178,26 +202,6 @@ fn completion_symbol_to_document_symbol(
     })
 }
 
-fn completion_symbol_to_symbol_information(
-    source: &gluon::base::source::FileMap,
-    symbol: Spanned<CompletionSymbol<'_, '_>, BytePos>,
-    uri: Url,
-) -> Result<SymbolInformation, ServerError<()>> {
-    let kind = completion_symbol_kind(&symbol.value);
-    #[allow(deprecated)]
-    Ok(SymbolInformation {
-        kind,
-        location: Location {
-            uri,
-            range: byte_span_to_range(source, symbol.span)?,
-        },
-        name: symbol.value.name.declared_name().to_string(),
-        container_name: None,
-        deprecated: Default::default(),
-        tags: Default::default(),
-    })
-}
-
 fn expr_to_kind(expr: &SpannedExpr<Symbol>, typ: &ArcType) -> SymbolKind {
     match expr.value {
         // import! "std/prelude.glu" will replace itself with a symbol like `std.prelude
@@ -240,6 +244,7 @@ where
         return f(source_module);
     }
     Err(ServerError {
+        code: jsonrpc_core::ErrorCode::InternalError,
         message: {
             let m = import.importer.0.lock().await;
             format!(
@@ -260,11 +265,40 @@ async fn retrieve_expr_with_pos<F, R>(
 ) -> Result<R, ServerError<()>>
 where
     F: FnOnce(&Module, BytePos) -> Result<R, ServerError<()>>,
+{
+    retrieve_expr_at(
+        thread,
+        text_document_uri,
+        |source| Ok(position_to_byte_index(source, position)?),
+        f,
+    )
+    .await
+}
+
+/// Like `retrieve_expr_with_pos` but with the byte index computed from the module's source by
+/// `byte_index`
+async fn retrieve_expr_at<I, F, R>(
+    thread: &Thread,
+    text_document_uri: &Url,
+    byte_index: I,
+    f: F,
+) -> Result<R, ServerError<()>>
+where
+    I: FnOnce(&gluon::base::source::FileMap) -> Result<BytePos, ServerError<()>>,
+    F: FnOnce(&Module, BytePos) -> Result<R, ServerError<()>>,
 {
     retrieve_expr(thread, text_document_uri, move |module| {
-        let byte_index = position_to_byte_index(&*module.source, position)?;
+        let byte_index = byte_index(&module.source)?;
 
-        f(module, byte_index)
+        // A failed check may have lost the nodes or types of the whole module so positions which
+        // are unaffected by the edits since the last successful check are answered by that check
+        match module.last_good_position(byte_index) {
+            Some((last_good, byte_index)) => {
+                debug!("Using the last successful check of {}", module.uri);
+                f(last_good, byte_index)
+            }
+            None => f(module, byte_index),
+        }
     })
     .await
 }
@@ -287,6 +321,7 @@ async fn retrieve_module(thread: &Thread, module: &str) -> Result<Module, Server
         Ok(source_module)
     } else {
         Err(ServerError {
+            code: jsonrpc_core::ErrorCode::InternalError,
             message: {
                 let m = import.importer.0.lock().await;
                 format!(
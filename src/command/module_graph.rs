@@ -0,0 +1,59 @@
+use lsp_types::request::Request;
+
+use crate::{
+    diagnostics::DependencyGraphRef,
+    name::{module_name_to_file, with_import},
+};
+
+use super::*;
+
+/// `gluon/moduleGraph` responds with the imports between the modules which the server knows
+/// about, the open documents and the modules which they import directly or transitively
+pub enum ModuleGraph {}
+
+impl Request for ModuleGraph {
+    type Params = ();
+    type Result = ModuleGraphResult;
+    const METHOD: &'static str = "gluon/moduleGraph";
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleGraphResult {
+    /// Sorted by the importing and then the imported module
+    pub edges: Vec<ModuleGraphEdge>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleGraphEdge {
+    /// The importing module
+    pub from: Url,
+    /// The imported module
+    pub to: Url,
+    /// `true` if the imported module imports `from`, directly or transitively
+    pub cyclic: bool,
+}
+
+pub fn register(io: &mut IoHandler, thread: &RootedThread, dependencies: &DependencyGraphRef) {
+    let thread = thread.clone();
+    let dependencies = dependencies.clone();
+    let f = move |_: ()| {
+        let thread = thread.clone();
+        let dependencies = dependencies.clone();
+        async move {
+            let imports = dependencies.lock().unwrap().edges();
+            let importer = with_import(&thread, |import| import.importer.clone());
+            let mut edges = Vec::with_capacity(imports.len());
+            for (from, to, cyclic) in imports {
+                edges.push(ModuleGraphEdge {
+                    from: module_name_to_file(&importer, &from).await,
+                    to: module_name_to_file(&importer, &to).await,
+                    cyclic,
+                });
+            }
+            Ok::<_, ServerError<()>>(ModuleGraphResult { edges })
+        }
+    };
+    io.add_async_method(None::<ModuleGraph>, f);
+}
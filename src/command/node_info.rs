@@ -0,0 +1,82 @@
+use gluon::base::ast::Typed;
+
+use lsp_types::{request::Request, Range, TextDocumentPositionParams};
+
+use super::*;
+
+/// `gluon/nodeInfo` describes the innermost AST node at a position so that extensions can offer
+/// context sensitive actions. Responds with `null` if the position is not inside any node.
+pub enum NodeInfo {}
+
+impl Request for NodeInfo {
+    type Params = TextDocumentPositionParams;
+    type Result = Option<NodeInfoResult>;
+    const METHOD: &'static str = "gluon/nodeInfo";
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfoResult {
+    pub kind: NodeKind,
+    /// The range of the whole node
+    pub range: Range,
+    /// The type of the node. Omitted for type expressions
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NodeKind {
+    /// An expression such as `f x` or `"abc"`
+    Expression,
+    /// A pattern of a `let` or `match`, or the argument of a function
+    Pattern,
+    /// A type such as `Int` in `let x : Int = 1`
+    Type,
+    /// A `let` binding, from the bound pattern to the end of the bound expression
+    Binding,
+}
+
+pub fn register(io: &mut IoHandler, thread: &RootedThread) {
+    let thread = thread.clone();
+    let f = move |params: TextDocumentPositionParams| {
+        let thread = thread.clone();
+        async move {
+            retrieve_expr_with_pos(
+                &thread,
+                &params.text_document.uri,
+                &params.position,
+                |module, byte_index| {
+                    let expr = module.expr.expr();
+                    let source = &module.source;
+
+                    let db = thread.get_database();
+                    let env = db.as_env();
+                    let node = match innermost(enclosing_nodes(source.span(), expr, byte_index)) {
+                        Some(node) => node,
+                        None => return Ok(None),
+                    };
+                    let (kind, typ) = match node {
+                        Node::Expr(expr) => (NodeKind::Expression, expr.try_type_of(&env).ok()),
+                        Node::Pattern(pattern) => {
+                            (NodeKind::Pattern, pattern.value.try_type_of(&env).ok())
+                        }
+                        Node::Argument(arg) => (NodeKind::Pattern, Some(arg.value.typ.clone())),
+                        Node::Type(_) => (NodeKind::Type, None),
+                        Node::Binding(binding) => {
+                            (NodeKind::Binding, Some(binding.resolved_type.clone()))
+                        }
+                    };
+                    Ok(Some(NodeInfoResult {
+                        kind,
+                        range: byte_span_to_range(source, node.span())?,
+                        typ: typ.map(|typ| typ.to_string()),
+                    }))
+                },
+            )
+            .await
+        }
+    };
+    io.add_async_method(None::<NodeInfo>, f);
+}
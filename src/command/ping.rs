@@ -0,0 +1,43 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use lsp_types::request::Request;
+
+use super::*;
+
+/// `gluon/ping` is answered at any time, even before `initialize`, so that supervisors can check
+/// whether the server is alive
+pub enum Ping {}
+
+impl Request for Ping {
+    type Params = ();
+    type Result = PingResult;
+    const METHOD: &'static str = "gluon/ping";
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PingResult {
+    /// Milliseconds since the server started
+    pub uptime_ms: u64,
+    /// Whether the client has sent `initialize`
+    pub ready: bool,
+}
+
+pub fn register(io: &mut IoHandler, ready: &Arc<AtomicBool>) {
+    let start = Instant::now();
+    let ready = ready.clone();
+    let f = move |()| {
+        let result = PingResult {
+            uptime_ms: start.elapsed().as_millis() as u64,
+            ready: ready.load(Ordering::SeqCst),
+        };
+        async move { Ok::<_, ServerError<()>>(result) }
+    };
+    io.add_async_method(None::<Ping>, f);
+}
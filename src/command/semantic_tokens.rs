@@ -0,0 +1,295 @@
+use std::sync::{Arc, Mutex};
+
+use gluon::base::{
+    ast::{self, Pattern, SpannedIdent, SpannedPattern, Visitor},
+    fnv::FnvMap,
+    pos::{ByteOffset, Span},
+    source::FileMap,
+};
+
+use lsp_types::{
+    SemanticToken, SemanticTokenType, SemanticTokens, SemanticTokensDelta,
+    SemanticTokensDeltaParams, SemanticTokensEdit, SemanticTokensFullDeltaResult,
+    SemanticTokensLegend, SemanticTokensParams, SemanticTokensResult,
+};
+
+use super::*;
+
+const TOKEN_TYPES: &[SemanticTokenType] = &[
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::PARAMETER,
+    SemanticTokenType::ENUM_MEMBER,
+];
+
+const VARIABLE: u32 = 0;
+const FUNCTION: u32 = 1;
+const PARAMETER: u32 = 2;
+const ENUM_MEMBER: u32 = 3;
+
+pub(crate) fn legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: TOKEN_TYPES.to_vec(),
+        token_modifiers: Vec::new(),
+    }
+}
+
+fn ident_token_type(name: &str, typ: &ArcType) -> u32 {
+    if name.starts_with(char::is_uppercase) {
+        ENUM_MEMBER
+    } else if typ.remove_forall().as_function().is_some() {
+        FUNCTION
+    } else {
+        VARIABLE
+    }
+}
+
+struct CollectTokens {
+    source_span: Span<BytePos>,
+    tokens: Vec<(Span<BytePos>, u32)>,
+}
+
+impl CollectTokens {
+    fn push(&mut self, span: Span<BytePos>, token_type: u32) {
+        // Skip anything introduced by macros (such as the implicit prelude) as it does not exist
+        // in the source
+        if self.source_span.contains(span) && span.start() != span.end() {
+            self.tokens.push((span, token_type));
+        }
+    }
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for CollectTokens {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        if let Expr::Ident(id) = &e.value {
+            self.push(e.span, ident_token_type(id.name.declared_name(), &id.typ));
+        }
+        ast::walk_expr(self, e)
+    }
+
+    fn visit_pattern(&mut self, p: &'a SpannedPattern<'ast, Symbol>) {
+        match &p.value {
+            Pattern::Ident(id) => {
+                self.push(p.span, ident_token_type(id.name.declared_name(), &id.typ))
+            }
+            Pattern::Constructor(id, _) => {
+                let name = id.name.declared_name();
+                let span = Span::new(
+                    p.span.start(),
+                    p.span.start() + ByteOffset::from(name.len() as i64),
+                );
+                self.push(span, ENUM_MEMBER)
+            }
+            _ => (),
+        }
+        ast::walk_pattern(self, &p.value)
+    }
+
+    fn visit_spanned_typed_ident(&mut self, id: &'a SpannedIdent<Symbol>) {
+        self.push(id.span, PARAMETER);
+        self.visit_ident(&id.value)
+    }
+}
+
+fn semantic_tokens(
+    source: &FileMap,
+    expr: &SpannedExpr<Symbol>,
+) -> Result<Vec<SemanticToken>, ServerError<()>> {
+    let mut collector = CollectTokens {
+        source_span: source.span(),
+        tokens: Vec::new(),
+    };
+    collector.visit_expr(expr);
+
+    let mut tokens = collector.tokens;
+    tokens.sort_by_key(|(span, _)| span.start());
+    tokens.dedup_by_key(|(span, _)| span.start());
+
+    let mut previous = Position::default();
+    let mut result = Vec::with_capacity(tokens.len());
+    for (span, token_type) in tokens {
+        let range = byte_span_to_range(source, span)?;
+        if range.start.line != range.end.line {
+            continue;
+        }
+        result.push(SemanticToken {
+            delta_line: range.start.line - previous.line,
+            delta_start: if range.start.line == previous.line {
+                range.start.character - previous.character
+            } else {
+                range.start.character
+            },
+            length: range.end.character - range.start.character,
+            token_type,
+            token_modifiers_bitset: 0,
+        });
+        previous = range.start;
+    }
+    Ok(result)
+}
+
+/// Computes the smallest single edit which turns `old` into `new`
+fn diff_tokens(old: &[SemanticToken], new: &[SemanticToken]) -> Vec<SemanticTokensEdit> {
+    // Each token is encoded as 5 integers and the edits index into the encoded array
+    const TOKEN_LEN: usize = 5;
+
+    let prefix = old.iter().zip(new).take_while(|(l, r)| l == r).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(l, r)| l == r)
+        .count();
+
+    let deleted = &old[prefix..old.len() - suffix];
+    let inserted = &new[prefix..new.len() - suffix];
+    if deleted.is_empty() && inserted.is_empty() {
+        return Vec::new();
+    }
+    vec![SemanticTokensEdit {
+        start: (prefix * TOKEN_LEN) as u32,
+        delete_count: (deleted.len() * TOKEN_LEN) as u32,
+        data: if inserted.is_empty() {
+            None
+        } else {
+            Some(inserted.to_vec())
+        },
+    }]
+}
+
+/// The last tokens sent for each document so that later requests can be answered with a delta
+#[derive(Default)]
+pub(crate) struct PreviousTokens {
+    next_result_id: u64,
+    documents: FnvMap<Url, (String, Vec<SemanticToken>)>,
+}
+
+pub(crate) type PreviousTokensRef = Arc<Mutex<PreviousTokens>>;
+
+impl PreviousTokens {
+    fn store(&mut self, uri: Url, tokens: Vec<SemanticToken>) -> String {
+        let result_id = self.next_result_id.to_string();
+        self.next_result_id += 1;
+        self.documents.insert(uri, (result_id.clone(), tokens));
+        result_id
+    }
+
+    /// Drops the tokens of a closed document, a delta for it is then answered with all tokens
+    pub(crate) fn forget(&mut self, uri: &Url) {
+        self.documents.remove(uri);
+    }
+}
+
+pub fn register(io: &mut IoHandler, thread: &RootedThread, previous_tokens: &PreviousTokensRef) {
+    {
+        let thread = thread.clone();
+        let previous_tokens = previous_tokens.clone();
+        let f = move |params: SemanticTokensParams| {
+            let thread = thread.clone();
+            let previous_tokens = previous_tokens.clone();
+            async move {
+                let uri = params.text_document.uri;
+                let tokens = retrieve_expr(&thread, &uri, |module| {
+                    semantic_tokens(&module.source, module.expr.expr())
+                })
+                .await?;
+
+                let result_id = previous_tokens.lock().unwrap().store(uri, tokens.clone());
+                Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
+                    result_id: Some(result_id),
+                    data: tokens,
+                })))
+            }
+        };
+        io.add_async_method(request!("textDocument/semanticTokens/full"), f);
+    }
+    {
+        let thread = thread.clone();
+        let previous_tokens = previous_tokens.clone();
+        let f = move |params: SemanticTokensDeltaParams| {
+            let thread = thread.clone();
+            let previous_tokens = previous_tokens.clone();
+            async move {
+                let uri = params.text_document.uri;
+                let tokens = retrieve_expr(&thread, &uri, |module| {
+                    semantic_tokens(&module.source, module.expr.expr())
+                })
+                .await?;
+
+                let mut previous_tokens = previous_tokens.lock().unwrap();
+                let edits = match previous_tokens.documents.get(&uri) {
+                    Some((result_id, previous)) if *result_id == params.previous_result_id => {
+                        Some(diff_tokens(previous, &tokens))
+                    }
+                    // The client refers to a result we no longer have so send everything
+                    _ => None,
+                };
+                let result_id = previous_tokens.store(uri, tokens.clone());
+                Ok(Some(match edits {
+                    Some(edits) => {
+                        SemanticTokensFullDeltaResult::TokensDelta(SemanticTokensDelta {
+                            result_id: Some(result_id),
+                            edits,
+                        })
+                    }
+                    None => SemanticTokensFullDeltaResult::Tokens(SemanticTokens {
+                        result_id: Some(result_id),
+                        data: tokens,
+                    }),
+                }))
+            }
+        };
+        io.add_async_method(request!("textDocument/semanticTokens/full/delta"), f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(delta_line: u32, delta_start: u32) -> SemanticToken {
+        SemanticToken {
+            delta_line,
+            delta_start,
+            length: 1,
+            token_type: VARIABLE,
+            token_modifiers_bitset: 0,
+        }
+    }
+
+    #[test]
+    fn diff_tokens_test() {
+        let old = [token(0, 0), token(1, 0), token(1, 2)];
+
+        assert_eq!(diff_tokens(&old, &old), vec![]);
+
+        assert_eq!(
+            diff_tokens(&old, &[token(0, 0), token(1, 4), token(1, 2)]),
+            vec![SemanticTokensEdit {
+                start: 5,
+                delete_count: 5,
+                data: Some(vec![token(1, 4)]),
+            }]
+        );
+
+        assert_eq!(
+            diff_tokens(&old, &[token(0, 0), token(1, 2)]),
+            vec![SemanticTokensEdit {
+                start: 5,
+                delete_count: 5,
+                data: None,
+            }]
+        );
+
+        assert_eq!(
+            diff_tokens(&old, &[token(0, 0), token(1, 0), token(1, 2), token(3, 0)]),
+            vec![SemanticTokensEdit {
+                start: 15,
+                delete_count: 0,
+                data: Some(vec![token(3, 0)]),
+            }]
+        );
+    }
+}
@@ -10,13 +10,22 @@ use super::*;
 
 use crate::completion;
 
-pub fn register(io: &mut IoHandler, thread: &RootedThread) {
+pub fn register(
+    io: &mut IoHandler,
+    thread: &RootedThread,
+    client_capabilities: &ClientCapabilitiesRef,
+) {
     let thread = thread.clone();
+    let client_capabilities = client_capabilities.clone();
 
     io.add_async_method(
         request!("textDocument/signatureHelp"),
         move |params: SignatureHelpParams| {
             let thread = thread.clone();
+            let markdown = client_capabilities
+                .read()
+                .unwrap()
+                .supports_markdown_signature_help();
             async move {
                 retrieve_expr(
                     &thread,
@@ -62,6 +71,7 @@ pub fn register(io: &mut IoHandler, thread: &RootedThread) {
                                             documentation: Some(make_documentation(
                                                 Some(&help.typ),
                                                 &comment.as_ref().map_or("", |c| &c.content),
+                                                markdown,
                                             )),
                                             parameters: Some(
                                                 ::gluon::base::types::arg_iter(&help.typ)
@@ -72,6 +82,7 @@ pub fn register(io: &mut IoHandler, thread: &RootedThread) {
                                                         documentation: Some(make_documentation(
                                                             Some(typ),
                                                             "",
+                                                            markdown,
                                                         )),
                                                     })
                                                     .collect(),
@@ -1,43 +1,368 @@
+use std::sync::Arc;
+
 use super::*;
 
-use lsp_types::WorkspaceSymbolParams;
+use gluon::base::{fnv::FnvMap, pos::ByteOffset, source::FileMap};
+
+use lsp_types::{request::Request, FileChangeType, FileEvent, OneOf, WorkspaceSymbolParams};
+
+use crate::{
+    command::configuration::SettingsRef, completion, rpc, server::ClientCapabilitiesRef,
+    text_edit::Version,
+};
+
+/// `workspace/symbol` with the LSP 3.17 response of symbols whose location is resolved later,
+/// which `lsp_types` does not know about yet
+enum WorkspaceSymbolRequest {}
+
+impl Request for WorkspaceSymbolRequest {
+    type Params = WorkspaceSymbolParams;
+    type Result = Option<WorkspaceSymbolResponse>;
+    const METHOD: &'static str = lsp_types::request::WorkspaceSymbol::METHOD;
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum WorkspaceSymbolResponse {
+    Flat(Vec<SymbolInformation>),
+    Nested(Vec<WorkspaceSymbol>),
+}
+
+/// `workspaceSymbol/resolve` computes the range of a symbol which `workspace/symbol` only
+/// returned the document of
+pub enum WorkspaceSymbolResolve {}
+
+impl Request for WorkspaceSymbolResolve {
+    type Params = WorkspaceSymbol;
+    type Result = WorkspaceSymbol;
+    const METHOD: &'static str = "workspaceSymbol/resolve";
+}
+
+/// A symbol found by `workspace/symbol` (LSP 3.17)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceSymbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container_name: Option<String>,
+    /// The document of the symbol until it is resolved
+    pub location: OneOf<Location, WorkspaceLocation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorkspaceLocation {
+    pub uri: Url,
+}
+
+/// Finds the indexed symbol which a `WorkspaceSymbol` was created from
+#[derive(Serialize, Deserialize)]
+struct SymbolData {
+    module: String,
+    /// Byte offsets of the symbol in the module's source
+    start: usize,
+    end: usize,
+}
+
+/// The symbols of every known module. Each module is only indexed again when its version changes
+/// so that editing one module does not re-index the whole project.
+#[derive(Default)]
+pub struct SymbolIndex(FnvMap<String, IndexedModule>);
+
+struct IndexedModule {
+    /// The version of the module that `symbols` were collected from, `None` if the module is not
+    /// open in the client
+    version: Option<Version>,
+    uri: Url,
+    /// The source which the spans of `symbols` point into
+    source: Arc<FileMap>,
+    symbols: Vec<IndexedSymbol>,
+}
+
+/// A symbol whose range is only computed once it is asked for
+struct IndexedSymbol {
+    name: String,
+    kind: SymbolKind,
+    span: Span<BytePos>,
+}
+
+impl IndexedModule {
+    fn location(&self, symbol: &IndexedSymbol) -> Result<Location, ServerError<()>> {
+        Ok(Location {
+            uri: self.uri.clone(),
+            range: byte_span_to_range(&self.source, symbol.span)?,
+        })
+    }
+
+    fn symbol_data(&self, module: &str, symbol: &IndexedSymbol) -> SymbolData {
+        let start = self.source.span().start();
+        SymbolData {
+            module: module.to_string(),
+            start: (symbol.span.start() - start).to_usize(),
+            end: (symbol.span.end() - start).to_usize(),
+        }
+    }
+}
+
+pub(crate) type SymbolIndexRef = Arc<tokio::sync::Mutex<SymbolIndex>>;
 
-use crate::completion;
+impl SymbolIndex {
+    /// Indexes the modules which were added or changed since the last update and removes the
+    /// modules which are no longer known
+    async fn update(&mut self, thread: &Thread) -> Result<(), ServerError<()>> {
+        let import = thread.get_macros().get("import").expect("Import macro");
+        let import = import
+            .downcast_ref::<Import<CheckImporter>>()
+            .expect("Check importer");
+        let versions: Vec<_> = import
+            .importer
+            .0
+            .lock()
+            .await
+            .iter()
+            .map(|(module, state)| (module.clone(), state.version))
+            .collect();
 
-pub fn register(io: &mut IoHandler, thread: &RootedThread) {
-    let thread = thread.clone();
-    let f = move |params: WorkspaceSymbolParams| {
+        self.0
+            .retain(|module, _| versions.iter().any(|(name, _)| name == module));
+
+        for (name, version) in versions {
+            // Each module is indexed completely or not at all
+            rpc::check_cancelled().await?;
+            if self
+                .0
+                .get(&name)
+                .is_some_and(|indexed| indexed.version == version)
+            {
+                continue;
+            }
+            let module = match import.importer.module(thread, &name).await {
+                Some(module) => module,
+                None => {
+                    self.0.remove(&name);
+                    continue;
+                }
+            };
+            debug!("Indexing the symbols of `{}`", name);
+            let symbols = completion::all_symbols(module.source.span(), module.expr.expr())
+                .into_iter()
+                .map(|symbol| IndexedSymbol {
+                    name: symbol.value.name.declared_name().to_string(),
+                    kind: completion_symbol_kind(&symbol.value),
+                    span: symbol.span,
+                })
+                .collect();
+            self.0.insert(
+                name,
+                IndexedModule {
+                    version,
+                    uri: module.uri.clone(),
+                    source: module.source.clone(),
+                    symbols,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the module name, the module and the symbol of every symbol whose name contains
+    /// `query`
+    fn matching<'a>(
+        &'a self,
+        query: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a IndexedModule, &'a IndexedSymbol)> {
+        self.0.iter().flat_map(move |(name, module)| {
+            module
+                .symbols
+                .iter()
+                .filter(move |symbol| symbol.name.contains(query))
+                .map(move |symbol| (&name[..], module, symbol))
+        })
+    }
+
+    /// Computes the location of the symbol which `data` describes
+    fn resolve(&self, name: &str, data: &SymbolData) -> Result<Location, ServerError<()>> {
+        let not_indexed = || ServerError {
+            code: jsonrpc_core::ErrorCode::InternalError,
+            message: format!(
+                "`{}` is no longer indexed in `{}`, search for it again",
+                name, data.module
+            ),
+            data: None,
+        };
+        let module = self.0.get(&data.module).ok_or_else(not_indexed)?;
+        let start = module.source.span().start();
+        let span = Span::new(
+            start + ByteOffset::from(data.start as i64),
+            start + ByteOffset::from(data.end as i64),
+        );
+        let symbol = module
+            .symbols
+            .iter()
+            .find(|symbol| symbol.span == span && symbol.name == name)
+            .ok_or_else(not_indexed)?;
+        module.location(symbol)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Lower is better, exact matches come before prefix matches which come before other matches
+fn match_score(name: &str, query: &str) -> u8 {
+    if name == query {
+        0
+    } else if name.starts_with(query) {
+        1
+    } else {
+        2
+    }
+}
+
+/// Orders by how well the symbol matches, then by name and finally by where it is defined
+fn symbol_order<'a>(
+    (_, module, symbol): &(&str, &'a IndexedModule, &'a IndexedSymbol),
+    query: &str,
+) -> (u8, &'a str, &'a str, BytePos) {
+    (
+        match_score(&symbol.name, query),
+        &symbol.name,
+        module.uri.as_str(),
+        symbol.span.start(),
+    )
+}
+
+/// Forgets the modules which were deleted outside of the editor
+pub(crate) async fn forget_deleted_modules(
+    thread: &Thread,
+    symbol_index: &SymbolIndexRef,
+    changes: &[FileEvent],
+) {
+    let import = thread.get_macros().get("import").expect("Import macro");
+    let import = import
+        .downcast_ref::<Import<CheckImporter>>()
+        .expect("Check importer");
+    for change in changes {
+        if change.typ != FileChangeType::Deleted {
+            continue;
+        }
+        let module = filename_to_module(&strip_file_prefix_with_thread(thread, &change.uri));
+        let mut modules = import.importer.0.lock().await;
+        // Open documents still exist in the client
+        if modules
+            .get(&module)
+            .is_some_and(|state| state.version.is_none())
+        {
+            modules.remove(&module);
+            symbol_index.lock().await.0.remove(&module);
+        }
+    }
+}
+
+pub fn register(
+    io: &mut IoHandler,
+    thread: &RootedThread,
+    client_capabilities: &ClientCapabilitiesRef,
+    settings: &SettingsRef,
+    symbol_index: &SymbolIndexRef,
+) {
+    {
         let thread = thread.clone();
-        async move {
-            let import = thread.get_macros().get("import").expect("Import macro");
-            let import = import
-                .downcast_ref::<Import<CheckImporter>>()
-                .expect("Check importer");
-
-            let mut symbols = Vec::<SymbolInformation>::new();
-
-            for module in import.importer.modules(&thread).await {
-                let source = &module.source;
-
-                let expr = module.expr.expr();
-
-                symbols.extend(
-                    completion::all_symbols(module.source.span(), expr)
-                        .into_iter()
-                        .filter(|symbol| symbol.value.name.declared_name().contains(&params.query))
-                        .map(|symbol| {
-                            completion_symbol_to_symbol_information(
-                                &source,
-                                symbol,
-                                module.uri.clone(),
-                            )
-                        })
-                        .collect::<Result<Vec<_>, _>>()?,
-                );
+        let client_capabilities = client_capabilities.clone();
+        let settings = settings.clone();
+        let symbol_index = symbol_index.clone();
+        let f = move |params: WorkspaceSymbolParams| {
+            let thread = thread.clone();
+            let resolve_locations = client_capabilities
+                .read()
+                .unwrap()
+                .supports_workspace_symbol_location_resolve();
+            let kind_remap = settings.read().unwrap().kind_remap.clone();
+            let symbol_index = symbol_index.clone();
+            async move {
+                let mut symbol_index = symbol_index.lock().await;
+                symbol_index.update(&thread).await?;
+
+                let query = &params.query[..];
+                let mut symbols: Vec<_> = symbol_index.matching(query).collect();
+
+                // Modules are stored in a hash map so sort to get the same order on every request
+                symbols.sort_by(|l, r| symbol_order(l, query).cmp(&symbol_order(r, query)));
+
+                // Clients which resolve the location only need the range of the symbol they pick
+                let response = if resolve_locations {
+                    WorkspaceSymbolResponse::Nested(
+                        symbols
+                            .into_iter()
+                            .map(|(name, module, symbol)| WorkspaceSymbol {
+                                name: symbol.name.clone(),
+                                kind: kind_remap.symbol(symbol.kind),
+                                container_name: Some(name.to_string()),
+                                location: OneOf::Right(WorkspaceLocation {
+                                    uri: module.uri.clone(),
+                                }),
+                                data: Some(
+                                    serde_json::to_value(module.symbol_data(name, symbol))
+                                        .expect("Symbol data"),
+                                ),
+                            })
+                            .collect(),
+                    )
+                } else {
+                    WorkspaceSymbolResponse::Flat(
+                        symbols
+                            .into_iter()
+                            .map(|(_, module, symbol)| {
+                                #[allow(deprecated)]
+                                Ok(SymbolInformation {
+                                    name: symbol.name.clone(),
+                                    kind: kind_remap.symbol(symbol.kind),
+                                    location: module.location(symbol)?,
+                                    container_name: None,
+                                    deprecated: Default::default(),
+                                    tags: Default::default(),
+                                })
+                            })
+                            .collect::<Result<_, ServerError<()>>>()?,
+                    )
+                };
+
+                Ok(Some(response))
             }
+        };
+        io.add_async_method(None::<WorkspaceSymbolRequest>, f);
+    }
 
-            Ok(Some(symbols))
+    let symbol_index = symbol_index.clone();
+    let f = move |mut symbol: WorkspaceSymbol| {
+        let symbol_index = symbol_index.clone();
+        async move {
+            if let OneOf::Right(_) = symbol.location {
+                let data = symbol
+                    .data
+                    .clone()
+                    .ok_or("Missing the data of the workspace symbol")?;
+                let data: SymbolData = serde_json::from_value(data)?;
+                let location = symbol_index.lock().await.resolve(&symbol.name, &data)?;
+                symbol.location = OneOf::Left(location);
+            }
+            Ok::<_, ServerError<()>>(symbol)
         }
     };
-    io.add_async_method(request!("workspace/symbol"), f);
+    io.add_async_method(None::<WorkspaceSymbolResolve>, f);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_score_test() {
+        assert_eq!(match_score("test", "test"), 0);
+        assert_eq!(match_score("test1", "test"), 1);
+        assert_eq!(match_score("my_test", "test"), 2);
+    }
 }
@@ -0,0 +1,56 @@
+use gluon::base::pos::ByteOffset;
+
+use lsp_types::request::Request;
+
+use super::*;
+
+/// `gluon/typeAt` responds with the type at a byte offset of a module, as `hover` would show it.
+/// Responds with `null` if there is no expression at the offset.
+pub enum TypeAt {}
+
+impl Request for TypeAt {
+    type Params = TypeAtParams;
+    type Result = Option<String>;
+    const METHOD: &'static str = "gluon/typeAt";
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeAtParams {
+    pub uri: Url,
+    /// The offset in bytes from the start of the module's source
+    pub offset: usize,
+}
+
+pub fn register(io: &mut IoHandler, thread: &RootedThread) {
+    let thread = thread.clone();
+    let f = move |params: TypeAtParams| {
+        let thread = thread.clone();
+        async move {
+            let offset = params.offset;
+            retrieve_expr_at(
+                &thread,
+                &params.uri,
+                |source| {
+                    if offset > source.source().len() {
+                        return Err(format!(
+                            "Offset {} is past the end of `{}`",
+                            offset, params.uri
+                        )
+                        .into());
+                    }
+                    Ok(source.span().start() + ByteOffset::from(offset as i64))
+                },
+                |module, byte_index| {
+                    let db = thread.get_database();
+                    let env = db.as_env();
+                    let found =
+                        hover::type_at(&env, module.source.span(), module.expr.expr(), byte_index);
+                    Ok(found.map(|(typ, _, _)| typ.to_string()))
+                },
+            )
+            .await
+        }
+    };
+    io.add_async_method(None::<TypeAt>, f);
+}
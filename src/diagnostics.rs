@@ -1,17 +1,24 @@
 use std::{
-    collections::{hash_map, BTreeMap},
+    collections::{BTreeMap, BTreeSet},
     fmt,
     marker::Unpin,
+    sync::Arc,
 };
 
 use gluon::{
     base::{
+        ast::{self, Expr, SpannedExpr, Visitor},
         filename_to_module,
+        fnv::{FnvMap, FnvSet},
         pos::{self, ByteIndex, ByteOffset},
         source::{self, Source},
+        symbol::Symbol,
     },
+    check::typecheck::{HelpError, TypeError},
     import::Import,
-    query::{AsyncCompilation, CompilationBase},
+    parser::Error as ParseError,
+    query::AsyncCompilation,
+    vm::macros::Error as MacroError,
     Error as GluonError, Result as GluonResult, RootedThread, Thread, ThreadExt,
 };
 
@@ -26,15 +33,139 @@ use {
 use crate::{
     byte_span_to_range, cancelable,
     check_importer::{CheckImporter, State},
+    command::{configuration::SettingsRef, semantic_tokens::PreviousTokensRef},
     name::{
         codespan_name_to_file, module_name_to_file, strip_file_prefix,
         strip_file_prefix_with_thread,
     },
-    rpc::{self, send_response, Entry, ServerError},
-    server::{Handler, ShutdownReceiver},
-    text_edit::{TextChanges, Version},
+    rpc::{self, send_response, DocumentOrder, Entry, OutgoingMessage, ServerError},
+    server::{ClientCapabilitiesRef, Handler, ShutdownReceiver},
+    startup::{self, Phase, StartupTimingsRef},
+    text_edit::Version,
 };
 
+/// The `code` of each kind of diagnostic along with a summary of what it means. Codes are never
+/// reused for another kind of error so that clients can filter diagnostics by them.
+pub const DIAGNOSTIC_CODES: &[(&str, &str)] = &[
+    ("E0001", "Invalid token"),
+    ("E0002", "Invalid indentation"),
+    ("E0003", "Unknown token"),
+    ("E0004", "Unexpected token"),
+    ("E0005", "Unexpected end of file"),
+    ("E0006", "Extra token"),
+    ("E0007", "Invalid infix expression"),
+    ("E0008", "Syntax error"),
+    ("E0101", "Undefined variable"),
+    ("E0102", "Not a function"),
+    ("E0103", "Undefined type"),
+    ("E0104", "Undefined field"),
+    ("E0105", "Wrong number of pattern arguments"),
+    ("E0106", "Type mismatch"),
+    ("E0107", "Kind mismatch"),
+    ("E0108", "Invalid recursive value"),
+    ("E0109", "Duplicate type definition"),
+    ("E0110", "Duplicate field"),
+    ("E0111", "Projection of a type without fields"),
+    ("E0112", "No record with the fields"),
+    ("E0113", "Empty match"),
+    ("E0114", "Type error"),
+    ("E0115", "Unresolved implicit argument"),
+    ("E0116", "Type constructor returns the wrong type"),
+    ("E0201", "Macro error"),
+    ("import-cycle", "Import cycle"),
+    ("E0901", "Error"),
+    ("E0902", "Expression too deeply nested"),
+    ("W0001", "Shadowed binding"),
+];
+
+/// Where each code of `DIAGNOSTIC_CODES` is explained, under a heading of its own so that the
+/// lowercase code links to it
+const DIAGNOSTIC_CODES_DOCUMENTATION: &str = concat!(
+    env!("CARGO_PKG_REPOSITORY"),
+    "/blob/master/docs/diagnostics.md"
+);
+
+/// The code of the diagnostics on the imports of an import cycle
+const IMPORT_CYCLE_CODE: &str = "import-cycle";
+
+/// The code of errors which do not come from parsing, typechecking or macros
+const OTHER_ERROR_CODE: &str = "E0901";
+
+/// The code of the diagnostic on a module which is nested too deeply to be checked
+const TOO_DEEPLY_NESTED_CODE: &str = "E0902";
+
+/// The code of the warning on a `let` binding which hides another binding of the same name
+const SHADOWED_BINDING_CODE: &str = "W0001";
+
+/// Bytes of stack which checking an expression may use for each level of nesting. On an
+/// unoptimized build a bracket or an `if` takes about 12.5 KiB, a lambda or an infix operator
+/// about 15 KiB.
+const STACK_PER_NESTING_LEVEL: usize = 16 * 1024;
+
+/// How many `let` or `type` bindings in a row take as much stack as a level of nesting. Each one
+/// nests the rest of its block but takes only about 2 KiB.
+const BINDINGS_PER_NESTING_LEVEL: usize = 6;
+
+/// How deeply expressions may be nested to be checked on a stack of `stack_size` bytes
+pub(crate) fn max_nesting_depth(stack_size: usize) -> usize {
+    stack_size / STACK_PER_NESTING_LEVEL
+}
+
+trait DiagnosticCode {
+    /// The code of the error, one of `DIAGNOSTIC_CODES`
+    fn code(&self) -> &'static str;
+}
+
+impl DiagnosticCode for ParseError {
+    fn code(&self) -> &'static str {
+        match self {
+            ParseError::Token(_) => "E0001",
+            ParseError::Layout(_) => "E0002",
+            ParseError::InvalidToken => "E0003",
+            ParseError::UnexpectedToken(..) => "E0004",
+            ParseError::UnexpectedEof(_) => "E0005",
+            ParseError::ExtraToken(_) => "E0006",
+            ParseError::Infix(_) => "E0007",
+            ParseError::Message(_) => "E0008",
+        }
+    }
+}
+
+impl DiagnosticCode for HelpError<Symbol> {
+    fn code(&self) -> &'static str {
+        match self.error {
+            TypeError::UndefinedVariable(_) => "E0101",
+            TypeError::NotAFunction(_) => "E0102",
+            TypeError::UndefinedType(_) => "E0103",
+            TypeError::UndefinedField(..) => "E0104",
+            TypeError::PatternError { .. } => "E0105",
+            TypeError::Unification(..) => "E0106",
+            TypeError::KindError(_) => "E0107",
+            TypeError::RecursionCheck(_) => "E0108",
+            TypeError::DuplicateTypeDefinition(_) => "E0109",
+            TypeError::DuplicateField(_) => "E0110",
+            TypeError::InvalidProjection(_) => "E0111",
+            TypeError::UndefinedRecord { .. } => "E0112",
+            TypeError::EmptyCase => "E0113",
+            TypeError::Message(_) => "E0114",
+            TypeError::UnableToResolveImplicit(_) => "E0115",
+            TypeError::TypeConstructorReturnsWrongType { .. } => "E0116",
+        }
+    }
+}
+
+impl DiagnosticCode for MacroError {
+    fn code(&self) -> &'static str {
+        "E0201"
+    }
+}
+
+/// Links `code` to its documentation
+fn code_description(code: &str) -> Option<CodeDescription> {
+    let href = format!("{}#{}", DIAGNOSTIC_CODES_DOCUMENTATION, code.to_lowercase());
+    Url::parse(&href).ok().map(|href| CodeDescription { href })
+}
+
 fn create_diagnostics<'a>(
     diagnostics: &'a mut BTreeMap<Url, Vec<lsp_types::Diagnostic>>,
     importer: &'a CheckImporter,
@@ -55,9 +186,12 @@ async fn create_diagnostics_(
         err: &pos::Spanned<T, pos::BytePos>,
     ) -> Result<lsp_types::Diagnostic, ServerError<()>>
     where
-        T: fmt::Debug + fmt::Display + AsDiagnostic,
+        T: fmt::Debug + fmt::Display + AsDiagnostic + DiagnosticCode,
     {
+        let code = err.value.code();
         Ok(lsp_types::Diagnostic {
+            code: Some(NumberOrString::String(code.into())),
+            code_description: code_description(code),
             source: Some("gluon".to_string()),
             ..make_lsp_diagnostic(code_map, err.as_diagnostic(&code_map), |filename| {
                 codespan_name_to_file(filename).map_err(|err| {
@@ -73,7 +207,7 @@ async fn create_diagnostics_(
         in_file_error: &gluon::base::error::InFile<T>,
     ) -> Result<(), ServerError<()>>
     where
-        T: fmt::Debug + fmt::Display + AsDiagnostic,
+        T: fmt::Debug + fmt::Display + AsDiagnostic + DiagnosticCode,
     {
         let errors = diagnostics
             .entry(module_name_to_file(importer, &in_file_error.source_name()).await)
@@ -103,6 +237,8 @@ async fn create_diagnostics_(
             .push(lsp_types::Diagnostic {
                 message: format!("{}", err),
                 severity: Some(DiagnosticSeverity::Error),
+                code: Some(NumberOrString::String(OTHER_ERROR_CODE.into())),
+                code_description: code_description(OTHER_ERROR_CODE),
                 source: Some("gluon".to_string()),
                 ..Default::default()
             }),
@@ -110,16 +246,840 @@ async fn create_diagnostics_(
     Ok(())
 }
 
+/// The characters of a gluon source which are code, along with the range of each of them.
+/// Comments and string, raw string and character literals are skipped the way the lexer skips
+/// them, so that the text can be scanned without parsing it.
+struct CodeChars<'a> {
+    chars: std::str::Chars<'a>,
+    position: Position,
+    /// Whether the previous character was part of an identifier, in which case an `r` does not
+    /// start a raw string
+    in_identifier: bool,
+}
+
+impl<'a> CodeChars<'a> {
+    fn new(source: &'a str) -> Self {
+        CodeChars {
+            chars: source.chars(),
+            position: Position::new(0, 0),
+            in_identifier: false,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.clone().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.position = Position::new(self.position.line + 1, 0);
+        } else {
+            self.position.character += c.len_utf16() as u32;
+        }
+        Some(c)
+    }
+
+    fn bump_if(&mut self, f: impl FnOnce(char) -> bool) -> Option<char> {
+        match self.peek() {
+            Some(c) if f(c) => self.bump(),
+            _ => None,
+        }
+    }
+
+    fn skip_raw_string(&mut self) {
+        let mut delimiters = 0;
+        while self.bump_if(|c| c == '#').is_some() {
+            delimiters += 1;
+        }
+        if self.bump_if(|c| c == '"').is_none() {
+            return;
+        }
+        while let Some(c) = self.bump() {
+            if c == '"' {
+                let mut found = 0;
+                while found < delimiters && self.bump_if(|c| c == '#').is_some() {
+                    found += 1;
+                }
+                if found == delimiters {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for CodeChars<'_> {
+    type Item = (Range, char);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.position;
+            let c = self.bump()?;
+            let in_identifier = std::mem::replace(&mut self.in_identifier, false);
+            match c {
+                '/' if self.peek() == Some('/') => while self.bump_if(|c| c != '\n').is_some() {},
+                '/' if self.peek() == Some('*') => {
+                    self.bump();
+                    let mut previous = ' ';
+                    while let Some(c) = self.bump() {
+                        if previous == '*' && c == '/' {
+                            break;
+                        }
+                        previous = c;
+                    }
+                }
+                'r' if !in_identifier && matches!(self.peek(), Some('"' | '#')) => {
+                    self.skip_raw_string()
+                }
+                '"' => {
+                    while let Some(c) = self.bump() {
+                        match c {
+                            '\\' => {
+                                self.bump();
+                            }
+                            '"' => break,
+                            _ => (),
+                        }
+                    }
+                }
+                '\'' => {
+                    if self.bump() == Some('\\') {
+                        self.bump();
+                    }
+                    self.bump_if(|c| c == '\'');
+                }
+                _ => {
+                    self.in_identifier = is_identifier(c);
+                    return Some((Range::new(start, self.position), c));
+                }
+            }
+        }
+    }
+}
+
+fn is_identifier(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The modules which `source` imports along with the range of each `import!`. The text is
+/// scanned instead of the syntax tree since the modules of an import cycle can not be compiled.
+fn scan_imports(source: &str) -> Vec<(String, Range)> {
+    const IMPORT: &str = "import!";
+    let code: Vec<_> = CodeChars::new(source).collect();
+    // Characters are adjacent unless a comment or a literal was skipped between them
+    let adjacent = |i: usize| i > 0 && code[i - 1].0.end == code[i].0.start;
+
+    let mut imports = Vec::new();
+    let mut i = 0;
+    while i < code.len() {
+        let is_import = code[i..].len() >= IMPORT.len()
+            && code[i..]
+                .iter()
+                .zip(IMPORT.chars())
+                .all(|(&(_, c), expected)| c == expected)
+            && (0..IMPORT.len()).skip(1).all(|j| adjacent(i + j))
+            && !(adjacent(i) && is_identifier(code[i - 1].1));
+        if !is_import {
+            i += 1;
+            continue;
+        }
+        let start = code[i].0.start;
+        i += IMPORT.len();
+        while i < code.len() && code[i].1.is_whitespace() {
+            i += 1;
+        }
+        let mut module = String::new();
+        let mut end = start;
+        while i < code.len()
+            && (is_identifier(code[i].1) || code[i].1 == '.')
+            && (module.is_empty() || adjacent(i))
+        {
+            module.push(code[i].1);
+            end = code[i].0.end;
+            i += 1;
+        }
+        if !module.is_empty() {
+            imports.push((module, Range::new(start, end)));
+        }
+    }
+    imports
+}
+
+/// Splits the code of `source` into words, operators and punctuation along with their ranges,
+/// enough to tell how deeply its expressions nest without parsing it
+fn code_tokens(source: &str) -> Vec<(Range, String)> {
+    let mut tokens: Vec<(Range, String)> = Vec::new();
+    let mut previous = None;
+    for (range, c) in CodeChars::new(source) {
+        let class = if is_identifier(c) {
+            Some(0)
+        } else if c != '\\' && gluon::base::ast::is_operator_char(c) {
+            Some(1)
+        } else {
+            None
+        };
+        let adjacent = matches!(tokens.last(), Some((last, _)) if last.end == range.start);
+        match tokens.last_mut() {
+            Some((last, token)) if adjacent && class.is_some() && class == previous => {
+                last.end = range.end;
+                token.push(c);
+            }
+            _ if c.is_whitespace() => (),
+            _ => tokens.push((range, c.to_string())),
+        }
+        previous = class;
+    }
+    tokens
+}
+
+/// The nesting of a bracket, or of the whole source, which `too_deeply_nested` tracks
+#[derive(Default)]
+struct NestingFrame {
+    /// Operators, lambdas and `if`s since the start of the current expression, each of which nests
+    /// the rest of it
+    chain: usize,
+    /// The columns of the `let` and `type` bindings of the current block, each of which nests the
+    /// bindings after it
+    bindings: Vec<u32>,
+}
+
+impl NestingFrame {
+    fn depth(&self) -> usize {
+        self.chain + self.bindings.len() / BINDINGS_PER_NESTING_LEVEL
+    }
+}
+
+/// The range of the first token in `source` which nests expressions more than `max_depth` levels
+/// deep. Comments and string and character literals are skipped. The text is scanned instead of
+/// the syntax tree since walking the tree is what would overflow the stack.
+///
+/// The depth is an estimate. Brackets, `if`s, lambdas and infix operators each nest one level
+/// until the end of their expression, which is taken to be the closing bracket or the next `,`,
+/// `|`, `=`, `let` or `in`. A sequence of `let` and `type` bindings nests a level for every
+/// `BINDINGS_PER_NESTING_LEVEL` bindings, a binding ends the ones of a block which is indented
+/// more deeply. Operators are counted without regard to precedence or layout, which makes the
+/// estimate deeper rather than shallower than the syntax tree.
+pub(crate) fn too_deeply_nested(source: &str, max_depth: usize) -> Option<Range> {
+    // The frame of each open bracket, after the one of the whole source
+    let mut frames = vec![NestingFrame::default()];
+    // The depth of the frames before the last one
+    let mut outer_depth = 0;
+    for (range, token) in code_tokens(source) {
+        let frame = frames.last_mut().expect("frame");
+        match &token[..] {
+            "(" | "[" | "{" => {
+                outer_depth += frame.depth() + 1;
+                frames.push(NestingFrame::default());
+            }
+            ")" | "]" | "}" => {
+                if frames.len() > 1 {
+                    frames.pop();
+                    let frame = frames.last().expect("frame");
+                    outer_depth -= frame.depth() + 1;
+                }
+                continue;
+            }
+            "," | "|" | "=" | "in" => {
+                frame.chain = 0;
+                if token == "," {
+                    frame.bindings.clear();
+                }
+                continue;
+            }
+            "let" | "type" => {
+                let column = range.start.character;
+                frame.chain = 0;
+                while matches!(frame.bindings.last(), Some(&last) if last > column) {
+                    frame.bindings.pop();
+                }
+                frame.bindings.push(column);
+            }
+            "if" | "\\" => frame.chain += 1,
+            // Projections and type annotations do not nest
+            "." | ":" => continue,
+            _ if gluon::base::ast::is_operator_char(token.chars().next().expect("token")) => {
+                frame.chain += 1
+            }
+            _ => continue,
+        }
+        let frame = frames.last().expect("frame");
+        if outer_depth + frame.depth() > max_depth {
+            return Some(range);
+        }
+    }
+    None
+}
+
+/// Describes the import cycle of `modules`, which starts and ends with the same module
+fn import_cycle_message(modules: &[&str]) -> String {
+    let cycle: Vec<_> = modules
+        .iter()
+        .map(|module| format!("`{}`", module))
+        .collect();
+    format!("Import cycle: {}", cycle.join(" -> "))
+}
+
+/// Collects the modules imported by an expanded module, `import!` expands to the name of the
+/// module prefixed with `@`
+#[derive(Default)]
+struct ImportedModules(BTreeSet<String>);
+
+impl<'a, 'ast> Visitor<'a, 'ast> for ImportedModules {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        if let Expr::Ident(id) = &e.value {
+            if let Some(module) = id.name.as_str().strip_prefix('@') {
+                self.0.insert(module.into());
+            }
+        }
+        ast::walk_expr(self, e)
+    }
+}
+
+/// Adds the names which `pattern` binds to `names`, along with where they are bound
+fn pattern_bindings(
+    pattern: &ast::SpannedPattern<Symbol>,
+    names: &mut Vec<(String, pos::Span<ByteIndex>)>,
+) {
+    match &pattern.value {
+        ast::Pattern::Ident(id) => names.push((id.name.declared_name().into(), pattern.span)),
+        ast::Pattern::As(name, pattern) => {
+            names.push((name.value.declared_name().into(), name.span));
+            pattern_bindings(pattern, names);
+        }
+        ast::Pattern::Constructor(_, args) => {
+            for arg in args.iter() {
+                pattern_bindings(arg, names);
+            }
+        }
+        ast::Pattern::Tuple { elems, .. } => {
+            for elem in elems.iter() {
+                pattern_bindings(elem, names);
+            }
+        }
+        ast::Pattern::Record { fields, .. } => {
+            for field in fields.iter() {
+                match field {
+                    ast::PatternField::Value { name, value: None } => {
+                        names.push((name.value.declared_name().into(), name.span))
+                    }
+                    ast::PatternField::Value {
+                        value: Some(pattern),
+                        ..
+                    } => pattern_bindings(pattern, names),
+                    ast::PatternField::Type { .. } => (),
+                }
+            }
+        }
+        ast::Pattern::Literal(_) | ast::Pattern::Error => (),
+    }
+}
+
+/// Finds the `let` bindings which hide a binding of the same name from an enclosing `let`,
+/// function argument or pattern. Names starting with `_` are meant to be unused and may be
+/// bound again.
+#[derive(Default)]
+struct ShadowedBindings {
+    /// The names in scope, innermost last
+    scope: Vec<String>,
+    shadowed: Vec<(String, pos::Span<ByteIndex>)>,
+}
+
+impl ShadowedBindings {
+    fn visit_scoped<'a, 'ast>(
+        &mut self,
+        names: impl IntoIterator<Item = String>,
+        e: &'a SpannedExpr<'ast, Symbol>,
+    ) {
+        let len = self.scope.len();
+        self.scope.extend(names);
+        self.visit_expr(e);
+        self.scope.truncate(len);
+    }
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for ShadowedBindings {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a SpannedExpr<'ast, Symbol>) {
+        match &e.value {
+            Expr::LetBindings(bindings, body) => {
+                let mut names = Vec::new();
+                for binding in bindings.iter() {
+                    pattern_bindings(&binding.name, &mut names);
+                }
+                for (name, span) in &names {
+                    if !name.starts_with('_') && self.scope.contains(name) {
+                        self.shadowed.push((name.clone(), *span));
+                    }
+                }
+                let names: Vec<_> = names.into_iter().map(|(name, _)| name).collect();
+                let len = self.scope.len();
+                if bindings.is_recursive() {
+                    self.scope.extend(names.iter().cloned());
+                }
+                for binding in bindings.iter() {
+                    let args = binding
+                        .args
+                        .iter()
+                        .map(|arg| arg.name.value.name.declared_name().to_string());
+                    self.visit_scoped(args, &binding.expr);
+                }
+                self.scope.truncate(len);
+                self.visit_scoped(names, body);
+            }
+            Expr::Lambda(lambda) => {
+                let args = lambda
+                    .args
+                    .iter()
+                    .map(|arg| arg.name.value.name.declared_name().to_string());
+                self.visit_scoped(args, lambda.body);
+            }
+            Expr::Match(expr, alts) => {
+                self.visit_expr(expr);
+                for alt in alts.iter() {
+                    let mut names = Vec::new();
+                    pattern_bindings(&alt.pattern, &mut names);
+                    self.visit_scoped(names.into_iter().map(|(name, _)| name), &alt.expr);
+                }
+            }
+            Expr::Do(do_expr) => {
+                self.visit_expr(do_expr.bound);
+                let mut names = Vec::new();
+                if let Some(id) = &do_expr.id {
+                    pattern_bindings(id, &mut names);
+                }
+                self.visit_scoped(names.into_iter().map(|(name, _)| name), do_expr.body);
+            }
+            _ => ast::walk_expr(self, e),
+        }
+    }
+}
+
+/// Warns about the `let` bindings of the module in `source` which shadow another binding
+fn shadowed_binding_warnings(
+    source: &source::FileMap,
+    expr: &SpannedExpr<Symbol>,
+) -> Vec<lsp_types::Diagnostic> {
+    let mut visitor = ShadowedBindings::default();
+    visitor.visit_expr(expr);
+    visitor
+        .shadowed
+        .into_iter()
+        // Bindings which macros generate have no place in the source
+        .filter(|(_, span)| source.span().contains(*span))
+        .filter_map(|(name, span)| {
+            Some(lsp_types::Diagnostic {
+                range: byte_span_to_range(source, span).ok()?,
+                severity: Some(DiagnosticSeverity::Warning),
+                code: Some(NumberOrString::String(SHADOWED_BINDING_CODE.into())),
+                code_description: code_description(SHADOWED_BINDING_CODE),
+                source: Some("gluon".to_string()),
+                message: format!("`{}` shadows a binding of the same name", name),
+                ..lsp_types::Diagnostic::default()
+            })
+        })
+        .collect()
+}
+
+/// The modules which each checked module imports, which tells which modules have to be checked
+/// again when a module changes
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    imports: FnvMap<String, BTreeSet<String>>,
+}
+
+impl DependencyGraph {
+    /// Replaces the modules which `module` imports directly
+    fn set_imports(&mut self, module: &str, imports: BTreeSet<String>) {
+        self.imports.insert(module.into(), imports);
+    }
+
+    /// Returns the modules which import `module`, directly or transitively, ordered by their
+    /// distance from `module`
+    fn dependents(&self, module: &str) -> Vec<String> {
+        let mut dependents: Vec<String> = Vec::new();
+        let mut current = module.to_string();
+        let mut next = 0;
+        loop {
+            let mut importers: Vec<_> = self
+                .imports
+                .iter()
+                .filter(|(importer, imports)| {
+                    imports.contains(&current)
+                        && *importer != module
+                        && !dependents.contains(importer)
+                })
+                .map(|(importer, _)| importer.clone())
+                .collect();
+            importers.sort();
+            dependents.extend(importers);
+
+            match dependents.get(next) {
+                Some(dependent) => current = dependent.clone(),
+                None => break,
+            }
+            next += 1;
+        }
+        dependents
+    }
+
+    /// Returns every import as `(importer, imported, cyclic)`, sorted by the modules. An import is
+    /// cyclic if the imported module leads back to the importer.
+    pub(crate) fn edges(&self) -> Vec<(String, String, bool)> {
+        let mut edges: Vec<_> = self
+            .imports
+            .iter()
+            .flat_map(|(importer, imports)| {
+                imports.iter().map(move |imported| {
+                    let cyclic = self.reaches(imported, importer);
+                    (importer.clone(), imported.clone(), cyclic)
+                })
+            })
+            .collect();
+        edges.sort();
+        edges
+    }
+
+    /// Finds an import cycle which `module` is part of or imports. Returns the modules on the way
+    /// from `module` to the cycle followed by the modules of the cycle, along with the index of
+    /// the first module of the cycle. The last module imports the first module of the cycle.
+    fn cycle(&self, module: &str) -> Option<(Vec<String>, usize)> {
+        // Modules whose imports do not lead to a cycle
+        let mut acyclic = BTreeSet::new();
+        // The modules from `module` to the one being searched along with the imports which are
+        // left to follow
+        let mut path = vec![(module, self.imports.get(module).into_iter().flatten())];
+        while let Some((current, imports)) = path.last_mut() {
+            let imported = match imports.next() {
+                Some(imported) => &**imported,
+                None => {
+                    acyclic.insert(*current);
+                    path.pop();
+                    continue;
+                }
+            };
+            if let Some(start) = path.iter().position(|(module, _)| *module == imported) {
+                let modules = path.iter().map(|(module, _)| module.to_string()).collect();
+                return Some((modules, start));
+            }
+            if !acyclic.contains(imported) {
+                let imports = self.imports.get(imported).into_iter().flatten();
+                path.push((imported, imports));
+            }
+        }
+        None
+    }
+
+    /// Returns `true` if `to` is `from` or imported by it, directly or transitively
+    fn reaches(&self, from: &str, to: &str) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut queue = vec![from];
+        while let Some(module) = queue.pop() {
+            if module == to {
+                return true;
+            }
+            if seen.insert(module) {
+                queue.extend(self.imports.get(module).into_iter().flatten().map(|m| &**m));
+            }
+        }
+        false
+    }
+}
+
+/// The imports of the checked modules, shared with `gluon/moduleGraph`
+pub(crate) type DependencyGraphRef = Arc<std::sync::Mutex<DependencyGraph>>;
+
 struct DiagnosticsWorker {
     thread: RootedThread,
-    message_log: mpsc::Sender<String>,
+    message_log: mpsc::Sender<OutgoingMessage>,
+    dependency_diagnostics: bool,
+    /// The last diagnostics published for each file along with the version they were created from
+    published: FnvMap<Url, (Option<Version>, Vec<lsp_types::Diagnostic>)>,
+    closed: ClosedDocuments,
+    client_capabilities: ClientCapabilitiesRef,
+    settings: SettingsRef,
+    dependencies: DependencyGraphRef,
 }
 
 impl DiagnosticsWorker {
-    pub fn new(thread: RootedThread, message_log: mpsc::Sender<String>) -> Self {
+    pub fn new(
+        thread: RootedThread,
+        message_log: mpsc::Sender<OutgoingMessage>,
+        dependency_diagnostics: bool,
+        closed: ClosedDocuments,
+        client_capabilities: ClientCapabilitiesRef,
+        settings: SettingsRef,
+        dependencies: DependencyGraphRef,
+    ) -> Self {
         DiagnosticsWorker {
             thread,
             message_log,
+            dependency_diagnostics,
+            published: FnvMap::default(),
+            closed,
+            client_capabilities,
+            settings,
+            dependencies,
+        }
+    }
+
+    fn importer(&self) -> CheckImporter {
+        let import = self
+            .thread
+            .get_macros()
+            .get("import")
+            .expect("Import macro");
+        let import = import
+            .downcast_ref::<Import<CheckImporter>>()
+            .expect("Check importer");
+        import.importer.clone()
+    }
+
+    /// Records the imports of `name` and of every module which it imports, directly or
+    /// transitively, in the dependency graph. With dependency diagnostics enabled it also creates
+    /// the diagnostics of each of these modules which is open or has been loaded. Diagnostics which
+    /// were already published for the same version of a module are skipped so that modules
+    /// imported from several roots are only published once.
+    async fn dependency_diagnostics(
+        &mut self,
+        name: &str,
+    ) -> Result<Vec<(Url, Option<Version>, Vec<lsp_types::Diagnostic>)>, ServerError<()>> {
+        let importer = self.importer();
+
+        let mut dependencies = Vec::new();
+        let mut seen = BTreeSet::new();
+        seen.insert(name.to_string());
+        let mut queue = vec![name.to_string()];
+        while let Some(module) = queue.pop() {
+            // Extern modules such as `std.string.prim` have no source to check
+            if self.thread.get_database().get_filemap(&module).is_none() {
+                continue;
+            }
+            let result = {
+                let _checking = importer.checking().await;
+                self.thread
+                    .get_database()
+                    .typechecked_source_module(module.clone(), None)
+                    .await
+            };
+            let (value, error) = match result {
+                Ok(value) => (Some(value), None),
+                Err(err) => (err.value, Some(err.error)),
+            };
+            if let Some(value) = value {
+                let mut imported = ImportedModules::default();
+                imported.visit_expr(value.expr.expr());
+                for imported in &imported.0 {
+                    if seen.insert(imported.clone()) {
+                        queue.push(imported.clone());
+                    }
+                }
+                self.dependencies
+                    .lock()
+                    .unwrap()
+                    .set_imports(&module, imported.0);
+            }
+
+            if module == name || !self.dependency_diagnostics {
+                continue;
+            }
+            let (uri, version) = match importer.0.lock().await.get(&module) {
+                Some(state) => (state.uri.clone(), state.version),
+                None => continue,
+            };
+
+            let mut module_diagnostics = BTreeMap::new();
+            if let Some(err) = error {
+                create_diagnostics(&mut module_diagnostics, &importer, &uri, &err).await?;
+            }
+            // Errors in other files are found when their modules are visited
+            let module_diagnostics = module_diagnostics.remove(&uri).unwrap_or_default();
+
+            // A module's errors can change without its version changing when one of its imports
+            // changes, so only skip it if the diagnostics are the same as well
+            let published = (version, module_diagnostics);
+            match self.published.get(&uri) {
+                Some(previous) if *previous == published => continue,
+                // No errors to publish or to clear
+                None if published.1.is_empty() => continue,
+                _ => (),
+            }
+            self.published.insert(uri.clone(), published.clone());
+            dependencies.push((uri, published.0, published.1));
+        }
+        Ok(dependencies)
+    }
+
+    /// Returns the source of `module` as it is open in the editor, from a module loader or from
+    /// the import paths
+    async fn module_source(&self, module: &str) -> Option<String> {
+        if let Some(source) = self.thread.get_database().get_filemap(module) {
+            return Some(source.src().to_string());
+        }
+        self.importer().load_source(module)
+    }
+
+    /// Finds an import cycle which `name` is part of or imports, before the compiler gets stuck on
+    /// it. Returns each import on the way from `name` to the cycle followed by the imports of the
+    /// cycle, as the importing module and the range of the `import!`, along with the index of
+    /// the first import of the cycle.
+    ///
+    /// The imports of `name` are scanned from `source` and the imports of the other modules are
+    /// taken from the dependency graph, the source of modules which are not in the graph yet is
+    /// scanned.
+    async fn import_cycle(
+        &mut self,
+        name: &str,
+        source: &str,
+    ) -> Option<(Vec<(String, Range)>, usize)> {
+        let mut graph = DependencyGraph::default();
+        let mut sources = FnvMap::default();
+        sources.insert(name.to_string(), source.to_string());
+        let mut queue = vec![name.to_string()];
+        while let Some(module) = queue.pop() {
+            // Modules of the standard library never import the modules of a project
+            if graph.imports.contains_key(&module) || module.starts_with("std.") {
+                continue;
+            }
+            let known = if module == name {
+                None
+            } else {
+                self.dependencies
+                    .lock()
+                    .unwrap()
+                    .imports
+                    .get(&module)
+                    .cloned()
+            };
+            let imports = match known {
+                Some(imports) => imports,
+                None => {
+                    let source = match sources.get(&module) {
+                        Some(source) => source.clone(),
+                        None => match self.module_source(&module).await {
+                            Some(source) => source,
+                            None => continue,
+                        },
+                    };
+                    let imports = scan_imports(&source).into_iter().map(|(m, _)| m).collect();
+                    sources.insert(module.clone(), source);
+                    imports
+                }
+            };
+            queue.extend(imports.iter().cloned());
+            graph.set_imports(&module, imports);
+        }
+
+        let (path, start) = graph.cycle(name)?;
+        let mut edges = Vec::new();
+        for (i, module) in path.iter().enumerate() {
+            let imported = path.get(i + 1).unwrap_or(&path[start]);
+            let source = match sources.get(module) {
+                Some(source) => source.clone(),
+                None => self.module_source(module).await?,
+            };
+            // The graph may be out of date for modules which were not scanned
+            let (_, range) = scan_imports(&source)
+                .into_iter()
+                .find(|(m, _)| m == imported)?;
+            edges.push((module.clone(), range));
+        }
+        let mut dependencies = self.dependencies.lock().unwrap();
+        for module in &path[start..] {
+            dependencies.set_imports(module, graph.imports[module].clone());
+        }
+        Some((edges, start))
+    }
+
+    /// Creates a diagnostic on each import of the import cycle found by `import_cycle`, and on the
+    /// import of `name` which leads to the cycle if `name` is not part of it
+    async fn import_cycle_diagnostics(
+        &self,
+        name: &str,
+        edges: &[(String, Range)],
+        start: usize,
+    ) -> Vec<(Url, Option<Version>, Vec<lsp_types::Diagnostic>)> {
+        let importer = self.importer();
+        let cycle = &edges[start..];
+        let diagnostic = |range, message| lsp_types::Diagnostic {
+            range,
+            severity: Some(DiagnosticSeverity::Error),
+            code: Some(NumberOrString::String(IMPORT_CYCLE_CODE.into())),
+            code_description: code_description(IMPORT_CYCLE_CODE),
+            source: Some("gluon".to_string()),
+            message,
+            ..lsp_types::Diagnostic::default()
+        };
+
+        let mut diagnostics = Vec::new();
+        if start != 0 {
+            let (_, range) = &edges[0];
+            let modules: Vec<_> = cycle
+                .iter()
+                .chain(Some(&cycle[0]))
+                .map(|(module, _)| &module[..])
+                .collect();
+            diagnostics.push((name, diagnostic(*range, import_cycle_message(&modules))));
+        }
+        for (i, (module, range)) in cycle.iter().enumerate() {
+            // Each module sees the cycle starting from itself
+            let modules: Vec<_> = cycle[i..]
+                .iter()
+                .chain(&cycle[..=i])
+                .map(|(module, _)| &module[..])
+                .collect();
+            diagnostics.push((module, diagnostic(*range, import_cycle_message(&modules))));
+        }
+
+        let mut publish = Vec::new();
+        for (module, diagnostic) in diagnostics {
+            let uri = module_name_to_file(&importer, module).await;
+            let version = importer
+                .0
+                .lock()
+                .await
+                .get(module)
+                .and_then(|state| state.version);
+            publish.push((uri, version, vec![diagnostic]));
+        }
+        publish
+    }
+
+    /// Checks the open documents which import the modules of `changed`, directly or transitively,
+    /// since their diagnostics may have changed along with them. A document is checked once
+    /// however many of its imports changed. Other modules are left alone.
+    pub async fn recheck_dependents(&mut self, changed: &BTreeSet<Url>) {
+        let names: Vec<_> = changed
+            .iter()
+            .map(|uri| filename_to_module(&strip_file_prefix_with_thread(&self.thread, uri)))
+            .collect();
+        let mut dependents: Vec<String> = Vec::new();
+        {
+            let graph = self.dependencies.lock().unwrap();
+            for name in &names {
+                for dependent in graph.dependents(name) {
+                    if !dependents.contains(&dependent) {
+                        dependents.push(dependent);
+                    }
+                }
+            }
+        }
+
+        let importer = self.importer();
+        for dependent in dependents {
+            let (uri, version) = match importer.0.lock().await.get(&dependent) {
+                Some(state) if state.version.is_some() => (state.uri.clone(), state.version),
+                _ => continue,
+            };
+            let source = match self.thread.get_database().get_filemap(&dependent) {
+                Some(source) => source.src().to_string(),
+                None => continue,
+            };
+            debug!("Checking {} again since one of its imports changed", uri);
+            self.run_diagnostics(&uri, version, &source).await;
         }
     }
 
@@ -129,6 +1089,10 @@ impl DiagnosticsWorker {
         version: Option<Version>,
         fileinput: &str,
     ) {
+        if self.closed.lock().await.contains(uri_filename) {
+            debug!("Skipping diagnostics of closed document {}", uri_filename);
+            return;
+        }
         info!("Running diagnostics on {}", uri_filename);
 
         let filename = strip_file_prefix_with_thread(&self.thread, uri_filename);
@@ -136,8 +1100,44 @@ impl DiagnosticsWorker {
 
         self.thread.get_database().update_filemap(&name, fileinput);
 
-        let diagnostics = match self.typecheck(uri_filename, &name, version).await {
-            Ok(_) => Some((uri_filename.clone(), vec![])).into_iter().collect(),
+        // The compiler can not check the modules of an import cycle
+        if let Some((edges, start)) = self.import_cycle(&name, fileinput).await {
+            debug!("Import cycle in {}", uri_filename);
+            let importer = self.importer();
+            document_state(&mut *importer.0.lock().await, uri_filename, &name, version);
+            let publish = self.import_cycle_diagnostics(&name, &edges, start).await;
+            self.publish(uri_filename, &name, publish).await;
+            return;
+        }
+
+        // Checking an expression which is nested too deeply would overflow the stack
+        let stack_size = self.settings.read().unwrap().analysis_stack_size;
+        if let Some(range) = too_deeply_nested(fileinput, max_nesting_depth(stack_size)) {
+            debug!("{} is nested too deeply to be checked", uri_filename);
+            let importer = self.importer();
+            document_state(&mut *importer.0.lock().await, uri_filename, &name, version);
+            let diagnostic = lsp_types::Diagnostic {
+                range,
+                severity: Some(DiagnosticSeverity::Error),
+                code: Some(NumberOrString::String(TOO_DEEPLY_NESTED_CODE.into())),
+                code_description: code_description(TOO_DEEPLY_NESTED_CODE),
+                source: Some("gluon".to_string()),
+                message: format!(
+                    "Expression too deeply nested, more than {} levels can not be checked",
+                    max_nesting_depth(stack_size)
+                ),
+                ..lsp_types::Diagnostic::default()
+            };
+            let publish = Some((uri_filename.clone(), version, vec![diagnostic]));
+            self.publish(uri_filename, &name, publish).await;
+            return;
+        }
+
+        let result = self
+            .typecheck(uri_filename, &name, version, stack_size)
+            .await;
+        let diagnostics = match result {
+            Ok(warnings) => Some((uri_filename.clone(), warnings)).into_iter().collect(),
             Err(err) => {
                 debug!("Diagnostics result on `{}`: {}", uri_filename, err);
                 let mut diagnostics = BTreeMap::new();
@@ -162,15 +1162,79 @@ impl DiagnosticsWorker {
             }
         };
 
-        let message_log = self.message_log.clone();
+        if self.dependency_diagnostics {
+            if let Some(diagnostics) = diagnostics.get(uri_filename) {
+                self.published
+                    .insert(uri_filename.clone(), (version, diagnostics.clone()));
+            }
+        }
+        let dependencies = match self.dependency_diagnostics(&name).await {
+            Ok(dependencies) => dependencies,
+            Err(err) => {
+                error!("Unable to create diagnostics: {}", err.message);
+                Vec::new()
+            }
+        };
+
+        let publish = diagnostics
+            .into_iter()
+            .map(|(uri, diagnostics)| (uri, version, diagnostics))
+            .chain(dependencies);
+        self.publish(uri_filename, &name, publish).await;
+    }
+
+    /// Publishes the diagnostics found by checking `name`, leaving out the parts which the client
+    /// does not support
+    async fn publish(
+        &self,
+        uri_filename: &Url,
+        name: &str,
+        publish: impl IntoIterator<Item = (Url, Option<Version>, Vec<lsp_types::Diagnostic>)>,
+    ) {
+        let (related_information, tags, code_descriptions) = {
+            let client_capabilities = self.client_capabilities.read().unwrap();
+            (
+                client_capabilities.supports_related_information(),
+                client_capabilities.supports_diagnostic_tags(),
+                client_capabilities.supports_code_description(),
+            )
+        };
+        let warnings_as_errors = self.settings.read().unwrap().diagnostics.warnings_as_errors;
 
-        for (source_name, diagnostic) in diagnostics {
+        // The document may have been closed while it was checked. Holding the lock while
+        // publishing ensures that nothing is published after the empty diagnostics sent on close.
+        let closed = self.closed.lock().await;
+        for (uri, version, mut diagnostics) in publish {
+            // Versions only exist while a document is open so versioned diagnostics of a closed
+            // document are stale
+            if version.is_some() && closed.contains(&uri) {
+                debug!("Dropping diagnostics of closed document {}", uri);
+                if uri == *uri_filename {
+                    // Checking the document marked it as open again
+                    self.forget_document(name, version).await;
+                }
+                continue;
+            }
+            for diagnostic in &mut diagnostics {
+                if !related_information {
+                    diagnostic.related_information = None;
+                }
+                if !tags {
+                    diagnostic.tags = None;
+                }
+                if !code_descriptions {
+                    diagnostic.code_description = None;
+                }
+                diagnostic.severity = diagnostic
+                    .severity
+                    .map(|severity| published_severity(severity, warnings_as_errors));
+            }
             send_response(
-                message_log.clone(),
+                self.message_log.clone(),
                 notification!("textDocument/publishDiagnostics"),
                 PublishDiagnosticsParams {
-                    uri: source_name,
-                    diagnostics: diagnostic,
+                    uri,
+                    diagnostics,
                     version,
                 },
             )
@@ -178,69 +1242,155 @@ impl DiagnosticsWorker {
         }
     }
 
+    async fn forget_document(&self, name: &str, version: Option<Version>) {
+        let importer = self.importer();
+        let mut modules = importer.0.lock().await;
+        if modules
+            .get(name)
+            .is_some_and(|state| state.version == version)
+        {
+            modules.remove(name);
+        }
+    }
+
     async fn typecheck(
         &mut self,
         uri_filename: &Url,
         name: &str,
         version: Option<Version>,
-    ) -> GluonResult<()> {
-        let result = self
-            .thread
-            .get_database()
-            .typechecked_source_module(name.into(), None)
-            .await;
-
-        let import = self
-            .thread
-            .get_macros()
-            .get("import")
-            .expect("Import macro");
-        let import = import
-            .downcast_ref::<Import<CheckImporter>>()
-            .expect("Check importer");
-        let mut importer = import.importer.0.lock().await;
-
-        match importer.entry(name.into()) {
-            hash_map::Entry::Occupied(mut entry) => {
-                let module = entry.get_mut();
+        stack_size: usize,
+    ) -> GluonResult<Vec<lsp_types::Diagnostic>> {
+        // The runtime's threads may have a smaller stack than the one which is configured
+        let thread = self.thread.clone();
+        let module = name.to_string();
+        let runtime = tokio::runtime::Handle::current();
+        let importer = self.importer();
+        let checking = importer.checking().await;
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        std::thread::Builder::new()
+            .name("gluon-analysis".into())
+            .stack_size(stack_size)
+            .spawn(move || {
+                let result = runtime.block_on(async {
+                    thread
+                        .get_database()
+                        .typechecked_source_module(module, None)
+                        .await
+                });
+                let _ = sender.send(result);
+            })?;
+        // The sender is dropped without sending if checking panicked
+        let result = receiver
+            .await
+            .map_err(|_| GluonError::from(format!("Checking `{}` failed", name)))?;
+        drop(checking);
 
-                if version.is_some() {
-                    module.version = version;
-                }
+        let mut modules = importer.0.lock().await;
+        let state = document_state(&mut modules, uri_filename, name, version);
 
-                module.uri = uri_filename.clone();
-            }
-            hash_map::Entry::Vacant(entry) => {
-                entry.insert(self::State {
-                    uri: uri_filename.clone(),
-                    version: version,
-                    text_changes: TextChanges::new(),
-                });
-            }
+        let value = result?;
+        let mut warnings = Vec::new();
+        if let Some(source) = self.thread.get_database().get_filemap(name) {
+            warnings = shadowed_binding_warnings(&source, value.expr.expr());
+            state.succeeded(source, &value);
+            importer.evict(&mut modules);
         }
-        result?;
-        Ok(())
+        Ok(warnings)
+    }
+}
+
+/// The state of the module `name` of the document `uri_filename`, updated to `version`
+fn document_state<'a>(
+    modules: &'a mut FnvMap<String, State>,
+    uri_filename: &Url,
+    name: &str,
+    version: Option<Version>,
+) -> &'a mut State {
+    let state = modules
+        .entry(name.into())
+        .or_insert_with(|| State::empty(uri_filename.clone()));
+    if version.is_some() {
+        state.version = version;
     }
+    state.uri = uri_filename.clone();
+    state
 }
 
+/// The documents which the client has closed since they were last opened
+type ClosedDocuments = Arc<tokio::sync::Mutex<FnvSet<Url>>>;
+
+/// How long no document has to change before the documents which import the changed ones are
+/// checked again
+const RECHECK_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Queue of the documents which need to be checked and have their diagnostics published
+pub(crate) type DiagnosticsQueue = rpc::UniqueSink<Url, String, Version>;
+
+#[allow(clippy::too_many_arguments)]
 pub fn register(
     io: &mut IoHandler,
     thread: &RootedThread,
-    message_log: &mpsc::Sender<String>,
+    message_log: &mpsc::Sender<OutgoingMessage>,
     shutdown: ShutdownReceiver,
-) {
+    client_capabilities: &ClientCapabilitiesRef,
+    settings: &SettingsRef,
+    document_order: &DocumentOrder,
+    dependency_diagnostics: bool,
+    dependencies: &DependencyGraphRef,
+    previous_tokens: &PreviousTokensRef,
+    startup: &StartupTimingsRef,
+) -> DiagnosticsQueue {
+    let closed = ClosedDocuments::default();
+
     let work_queue = {
         let (diagnostic_sink, diagnostic_stream) = rpc::unique_queue();
 
-        let mut diagnostics_runner = DiagnosticsWorker::new(thread.clone(), message_log.clone());
+        let mut diagnostics_runner = DiagnosticsWorker::new(
+            thread.clone(),
+            message_log.clone(),
+            dependency_diagnostics,
+            closed.clone(),
+            client_capabilities.clone(),
+            settings.clone(),
+            dependencies.clone(),
+        );
 
+        // Only the first check is part of starting up
+        let mut startup = startup.clone();
         tokio::spawn(cancelable(shutdown, async move {
             futures::pin_mut!(diagnostic_stream);
-            while let Some(entry) = diagnostic_stream.next().await {
-                let entry: Entry<Url, String, _> = entry;
-                diagnostics_runner
-                    .run_diagnostics(&entry.key, Some(entry.version), &entry.value)
-                    .await;
+            // The documents which were checked since their dependents were last checked
+            let mut changed = BTreeSet::new();
+            loop {
+                // Dependents are checked once no document has been changed for a while instead
+                // of after every keystroke
+                let entry = if changed.is_empty() {
+                    diagnostic_stream.next().await
+                } else {
+                    match tokio::time::timeout(RECHECK_DELAY, diagnostic_stream.next()).await {
+                        Ok(entry) => entry,
+                        Err(_) => {
+                            diagnostics_runner.recheck_dependents(&changed).await;
+                            changed.clear();
+                            continue;
+                        }
+                    }
+                };
+                let entry: Entry<Url, String, _> = match entry {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                startup::measure_async(
+                    &startup.take(),
+                    Phase::FirstCheck,
+                    diagnostics_runner.run_diagnostics(
+                        &entry.key,
+                        Some(entry.version),
+                        &entry.value,
+                    ),
+                )
+                .await;
+                changed.insert(entry.key);
             }
         }));
 
@@ -250,16 +1400,25 @@ pub fn register(
     {
         let work_queue = work_queue.clone();
         let thread = thread.clone();
+        let closed = closed.clone();
+        let document_order = document_order.clone();
 
         let f = move |change: DidOpenTextDocumentParams| {
             let mut work_queue = work_queue.clone();
             let thread = thread.clone();
-            tokio::spawn(async move {
+            let closed = closed.clone();
+            document_order.spawn(change.text_document.uri.clone(), async move {
+                closed.lock().await.remove(&change.text_document.uri);
                 let filename = strip_file_prefix_with_thread(&thread, &change.text_document.uri);
                 let module = filename_to_module(&filename);
-                thread
-                    .get_database_mut()
-                    .add_module(module.into(), &change.text_document.text);
+                let import = thread.get_macros().get("import").expect("Import macro");
+                let import = import
+                    .downcast_ref::<Import<CheckImporter>>()
+                    .expect("Check importer");
+                import
+                    .importer
+                    .open_document(&thread, &module, &change.text_document.text)
+                    .await;
                 let _ = work_queue
                     .send(Entry {
                         key: change.text_document.uri,
@@ -276,10 +1435,54 @@ pub fn register(
         let f = move |_: DidSaveTextDocumentParams| {};
         io.add_notification(notification!("textDocument/didSave"), f);
     }
+    {
+        let thread = thread.clone();
+        let message_log = message_log.clone();
+        let document_order = document_order.clone();
+        let previous_tokens = previous_tokens.clone();
+
+        let f = move |params: DidCloseTextDocumentParams| {
+            let thread = thread.clone();
+            let message_log = message_log.clone();
+            let closed = closed.clone();
+            previous_tokens
+                .lock()
+                .unwrap()
+                .forget(&params.text_document.uri);
+            document_order.spawn(params.text_document.uri.clone(), async move {
+                let uri = params.text_document.uri;
+                let filename = strip_file_prefix_with_thread(&thread, &uri);
+                let module = filename_to_module(&filename);
+
+                let import = thread.get_macros().get("import").expect("Import macro");
+                let import = import
+                    .downcast_ref::<Import<CheckImporter>>()
+                    .expect("Check importer");
+                import.importer.0.lock().await.remove(&module);
+                import.importer.close_document(&thread, &module).await;
+
+                // Any check which is still running publishes after this (and is dropped) or has
+                // already published before it
+                let mut closed = closed.lock().await;
+                closed.insert(uri.clone());
+                send_response(
+                    message_log,
+                    notification!("textDocument/publishDiagnostics"),
+                    PublishDiagnosticsParams {
+                        uri,
+                        diagnostics: Vec::new(),
+                        version: None,
+                    },
+                )
+                .await;
+            });
+        };
+        io.add_notification(notification!("textDocument/didClose"), f);
+    }
 
     async fn did_change<S>(
         thread: &Thread,
-        message_log: mpsc::Sender<String>,
+        message_log: mpsc::Sender<OutgoingMessage>,
         mut work_queue: S,
         change: DidChangeTextDocumentParams,
     ) where
@@ -337,9 +1540,13 @@ pub fn register(
         match result {
             Ok((new_version, source)) => {
                 module_state.version = Some(new_version);
-                thread
-                    .get_database_mut()
-                    .add_module(module_name.into(), &source);
+                // Changing the module waits for the checks which are running, and those may wait
+                // for the modules to import theirs
+                drop(modules);
+                import
+                    .importer
+                    .open_document(thread, &module_name, &source)
+                    .await;
                 debug!("Changed to\n{}", source);
                 work_queue
                     .send(Entry {
@@ -355,30 +1562,30 @@ pub fn register(
     }
 
     {
+        let work_queue = work_queue.clone();
         let thread = thread.clone();
         let message_log = message_log.clone();
+        let document_order = document_order.clone();
 
         let f = move |change: DidChangeTextDocumentParams| {
             let work_queue = work_queue.clone();
             let thread = thread.clone();
             let message_log = message_log.clone();
-            tokio::spawn(async move {
-                if let Err(err) = ::std::panic::AssertUnwindSafe(did_change(
+            document_order.spawn(change.text_document.uri.clone(), async move {
+                did_change(
                     &thread,
-                    message_log.clone(),
-                    work_queue.clone().sink_map_err(|_| ()),
+                    message_log,
+                    work_queue.sink_map_err(|_| ()),
                     change,
-                ))
-                .catch_unwind()
+                )
                 .await
-                {
-                    error!("{:?}", err);
-                }
             });
         };
 
         io.add_notification(notification!("textDocument/didChange"), f);
     }
+
+    work_queue
 }
 
 pub fn make_lsp_severity(severity: Severity) -> lsp_types::DiagnosticSeverity {
@@ -390,6 +1597,19 @@ pub fn make_lsp_severity(severity: Severity) -> lsp_types::DiagnosticSeverity {
     }
 }
 
+/// The severity which a diagnostic is published with. With `warningsAsErrors` warnings are errors.
+fn published_severity(
+    severity: lsp_types::DiagnosticSeverity,
+    warnings_as_errors: bool,
+) -> lsp_types::DiagnosticSeverity {
+    match severity {
+        lsp_types::DiagnosticSeverity::Warning if warnings_as_errors => {
+            lsp_types::DiagnosticSeverity::Error
+        }
+        severity => severity,
+    }
+}
+
 const UNKNOWN_POS: lsp_types::Position = lsp_types::Position {
     character: 0,
     line: 0,
@@ -489,3 +1709,234 @@ where
         ..lsp_types::Diagnostic::default()
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostic_severity_is_preserved() {
+        let code_map = source::CodeMap::new();
+        let cases = vec![
+            (Diagnostic::error(), DiagnosticSeverity::Error),
+            (Diagnostic::warning(), DiagnosticSeverity::Warning),
+            (Diagnostic::note(), DiagnosticSeverity::Information),
+            (Diagnostic::help(), DiagnosticSeverity::Hint),
+        ];
+        for (diagnostic, severity) in cases {
+            let diagnostic =
+                make_lsp_diagnostic(&code_map, diagnostic.with_message("x"), |_| Err(())).unwrap();
+            assert_eq!(diagnostic.severity, Some(severity));
+        }
+    }
+
+    #[test]
+    fn warnings_as_errors() {
+        let code_map = source::CodeMap::new();
+        let warning =
+            make_lsp_diagnostic(&code_map, Diagnostic::warning().with_message("x"), |_| {
+                Err(())
+            })
+            .unwrap()
+            .severity
+            .unwrap();
+        assert_eq!(
+            published_severity(warning, false),
+            DiagnosticSeverity::Warning
+        );
+        assert_eq!(published_severity(warning, true), DiagnosticSeverity::Error);
+        // Only warnings are promoted
+        for &severity in &[DiagnosticSeverity::Information, DiagnosticSeverity::Hint] {
+            assert_eq!(published_severity(severity, true), severity);
+        }
+    }
+
+    #[test]
+    fn diagnostic_codes_are_unique_and_documented() {
+        let codes: BTreeSet<_> = DIAGNOSTIC_CODES.iter().map(|(code, _)| *code).collect();
+        assert_eq!(codes.len(), DIAGNOSTIC_CODES.len());
+
+        let documentation = include_str!("../docs/diagnostics.md");
+        for (code, summary) in DIAGNOSTIC_CODES {
+            let heading = format!("### {}\n\n**{}**\n", code, summary);
+            assert!(
+                documentation.contains(&heading),
+                "`{}` is not documented",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn errors_map_to_the_same_code() {
+        let undefined_variable = HelpError {
+            error: TypeError::UndefinedVariable(Symbol::from("x")),
+            help: None,
+        };
+        let empty_case = HelpError {
+            error: TypeError::EmptyCase,
+            help: None,
+        };
+        let cases: Vec<(&dyn DiagnosticCode, &str)> = vec![
+            (&ParseError::InvalidToken, "E0003"),
+            (&ParseError::ExtraToken(gluon::parser::Token::In), "E0006"),
+            (&undefined_variable, "E0101"),
+            (&empty_case, "E0113"),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.code(), code);
+            assert!(DIAGNOSTIC_CODES.iter().any(|(known, _)| *known == code));
+        }
+        assert_eq!(
+            code_description("E0101").unwrap().href.as_str(),
+            "https://github.com/gluon-lang/gluon_language-server/blob/master/docs/diagnostics.md#e0101"
+        );
+    }
+
+    #[test]
+    fn scan_imports_of_source() {
+        let source = r##"
+let int = import! std.int
+let { x } = import!  zz_b // import! commented_out
+let reimport! = 1
+(import! zz_c).x
+/* import! block_comment
+   import! still_commented */
+let s = "// import! in_string" ++ import! zz_d
+let raw = r#"import! in_raw_string "# "# ++ 'i'
+let ch = '"' import! /* c */ zz_e
+"##;
+        let range =
+            |line, start, end| Range::new(Position::new(line, start), Position::new(line, end));
+        assert_eq!(
+            scan_imports(source),
+            vec![
+                ("std.int".to_string(), range(1, 10, 25)),
+                ("zz_b".to_string(), range(2, 12, 25)),
+                ("zz_c".to_string(), range(4, 1, 13)),
+                ("zz_d".to_string(), range(7, 34, 46)),
+                ("zz_e".to_string(), range(9, 13, 33)),
+            ]
+        );
+    }
+
+    #[test]
+    fn nesting_depth_of_source() {
+        let source = "f (g [1, { x = 2 }]) // ((((\n\"(((\" '(' /* ((( */ (h 3)\n";
+        assert_eq!(too_deeply_nested(source, 3), None);
+        assert_eq!(
+            too_deeply_nested(source, 2),
+            Some(Range::new(Position::new(0, 9), Position::new(0, 10)))
+        );
+        assert_eq!(
+            too_deeply_nested(source, 0),
+            Some(Range::new(Position::new(0, 2), Position::new(0, 3)))
+        );
+        assert_eq!(
+            too_deeply_nested("(())", 1),
+            Some(Range::new(Position::new(0, 1), Position::new(0, 2)))
+        );
+        assert_eq!(too_deeply_nested("r#\"((\"# \"(", 0), None);
+    }
+
+    #[test]
+    fn nesting_depth_without_brackets() {
+        let range =
+            |line, start, end| Range::new(Position::new(line, start), Position::new(line, end));
+
+        // Each operator nests the rest of the chain, projections do not
+        let source = "a.b + 1 - 2 <|> (3 * 4)";
+        assert_eq!(too_deeply_nested(source, 5), None);
+        assert_eq!(too_deeply_nested(source, 4), Some(range(0, 19, 20)));
+        assert_eq!(too_deeply_nested(source, 2), Some(range(0, 12, 15)));
+
+        // A new binding or field starts a new expression
+        let source = "let x = 1 + 2\nlet y = { a = 1 + 2, b = if c then 3 else 4 }\ny";
+        assert_eq!(too_deeply_nested(source, 2), None);
+        assert_eq!(too_deeply_nested(source, 1), Some(range(1, 16, 17)));
+
+        let source = "\\x -> \\y -> x";
+        assert_eq!(too_deeply_nested(source, 4), None);
+        assert_eq!(too_deeply_nested(source, 3), Some(range(0, 9, 11)));
+
+        // Bindings in a row nest, the bindings of a block end with it
+        let bindings = "let x = 1\n".repeat(BINDINGS_PER_NESTING_LEVEL * 3 - 1);
+        assert_eq!(too_deeply_nested(&bindings, 2), None);
+        let source = format!("{}let y = 2\ny", bindings);
+        assert_eq!(
+            too_deeply_nested(&source, 2),
+            Some(range(BINDINGS_PER_NESTING_LEVEL as u32 * 3 - 1, 0, 3))
+        );
+        let source = format!(
+            "let f x =\n{}    x\nlet y = 2\ny",
+            bindings.replace("let", "    let")
+        );
+        assert_eq!(too_deeply_nested(&source, 3), None);
+        assert_eq!(
+            too_deeply_nested(&source, 2),
+            Some(range(BINDINGS_PER_NESTING_LEVEL as u32 * 3 - 1, 4, 7))
+        );
+    }
+
+    #[test]
+    fn dependents_of_module_chain() {
+        let mut graph = DependencyGraph::default();
+        let imports = |modules: &[&str]| modules.iter().map(|m| m.to_string()).collect();
+        graph.set_imports("a", imports(&["std.int"]));
+        graph.set_imports("b", imports(&["a"]));
+        graph.set_imports("c", imports(&["b", "std.int"]));
+        graph.set_imports("unrelated", imports(&["std.int"]));
+
+        assert_eq!(graph.dependents("a"), vec!["b", "c"]);
+        assert_eq!(graph.dependents("b"), vec!["c"]);
+        assert_eq!(graph.dependents("c"), Vec::<String>::new());
+        assert_eq!(
+            graph.dependents("std.int"),
+            vec!["a", "c", "unrelated", "b"]
+        );
+
+        // Imports are replaced when a module is checked again
+        graph.set_imports("c", imports(&["a"]));
+        assert_eq!(graph.dependents("b"), Vec::<String>::new());
+        assert_eq!(graph.dependents("a"), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn cycle_of_imports() {
+        let mut graph = DependencyGraph::default();
+        let imports = |modules: &[&str]| modules.iter().map(|m| m.to_string()).collect();
+        graph.set_imports("a", imports(&["std.int", "b"]));
+        graph.set_imports("b", imports(&["c"]));
+        graph.set_imports("c", imports(&["d"]));
+        graph.set_imports("d", imports(&["b"]));
+
+        let path = |modules: &[&str]| modules.iter().map(|m| m.to_string()).collect();
+        assert_eq!(graph.cycle("a"), Some((path(&["a", "b", "c", "d"]), 1)));
+        assert_eq!(graph.cycle("c"), Some((path(&["c", "d", "b"]), 0)));
+
+        graph.set_imports("d", imports(&["std.int"]));
+        assert_eq!(graph.cycle("a"), None);
+    }
+
+    #[test]
+    fn edges_of_import_cycle() {
+        let mut graph = DependencyGraph::default();
+        let imports = |modules: &[&str]| modules.iter().map(|m| m.to_string()).collect();
+        graph.set_imports("a", imports(&["b", "std.int"]));
+        graph.set_imports("b", imports(&["c"]));
+        graph.set_imports("c", imports(&["a"]));
+        graph.set_imports("d", imports(&["a"]));
+
+        let edge = |from: &str, to: &str, cyclic| (from.to_string(), to.to_string(), cyclic);
+        assert_eq!(
+            graph.edges(),
+            vec![
+                edge("a", "b", true),
+                edge("a", "std.int", false),
+                edge("b", "c", true),
+                edge("c", "a", true),
+                edge("d", "a", false),
+            ]
+        );
+    }
+}
@@ -39,12 +39,16 @@ export function activate(context: ExtensionContext) {
 
     let config = workspace.getConfiguration("gluon");
     let serverPath = config.get("language-server.path", "gluon_language-server");
+    // The server's threads are started before it is initialized so they are configured with an
+    // argument instead of `initializationOptions`
+    let threads = config.get<number | null>("threads", null);
+    let args = threads ? ["--threads", String(threads)] : [];
 
     // If the extension is launched in debug mode then the debug server options are used
     // Otherwise the run options are used
     let serverOptions: ServerOptions = {
         command: serverPath,
-        args: [],
+        args: args,
         options: {
             env: {
                 "RUST_BACKTRACE": "1",
@@ -63,8 +67,8 @@ export function activate(context: ExtensionContext) {
         synchronize: {
             // Synchronize the setting section 'languageServerExample' to the server
             configurationSection: 'gluon',
-            // Notify the server about file changes to '.clientrc files contain in the workspace
-            fileEvents: workspace.createFileSystemWatcher('**/.clientrc')
+            // Notify the server about changes to the project's settings and modules
+            fileEvents: workspace.createFileSystemWatcher('**/{.gluonrc,*.glu}')
         }
     }
 
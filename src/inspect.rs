@@ -0,0 +1,239 @@
+//! `--inspect`, which prints the syntax trees that the server's analysis works on
+
+use std::{fmt::Write, path::Path};
+
+use anyhow::anyhow;
+
+use gluon::{
+    base::{
+        ast::{self, Expr, Typed, Visitor},
+        source::FileMap,
+        symbol::Symbol,
+        types::{ArcType, TypeEnv},
+    },
+    import::Import,
+    Thread, ThreadExt,
+};
+
+use lsp_types::Range;
+
+use crate::byte_span_to_range;
+
+/// An expression and the expressions it contains
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Node {
+    /// The kind of expression, such as `App` or `Ident`
+    kind: &'static str,
+    /// The name of an identifier, the field of a projection or the value of a literal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    range: Range,
+    /// The inferred type. Omitted from the parsed tree
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    typ: Option<String>,
+    children: Vec<Node>,
+}
+
+/// The top level expressions of a module
+#[derive(Debug, Serialize)]
+pub(crate) struct Inspection {
+    parsed: Vec<Node>,
+    typed: Vec<Node>,
+}
+
+/// Builds the tree of the expressions it visits
+struct TreeBuilder<'e> {
+    source: &'e FileMap,
+    env: Option<&'e dyn TypeEnv<Type = ArcType>>,
+    /// The children of each expression which is being visited
+    stack: Vec<Vec<Node>>,
+}
+
+impl<'a, 'ast, 'e> Visitor<'a, 'ast> for TreeBuilder<'e> {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, e: &'a ast::SpannedExpr<'ast, Symbol>) {
+        self.stack.push(Vec::new());
+        ast::walk_expr(self, e);
+        let children = self.stack.pop().expect("Children");
+        let parent = self.stack.last_mut().expect("Parent");
+
+        // Expressions which macros (such as the implicit prelude) add are located in other
+        // modules or have empty spans
+        let span = self.source.span();
+        let range = match byte_span_to_range(self.source, e.span) {
+            Ok(range)
+                if span.start() <= e.span.start()
+                    && e.span.end() <= span.end()
+                    && e.span.start() != e.span.end() =>
+            {
+                range
+            }
+            _ => {
+                parent.extend(children);
+                return;
+            }
+        };
+
+        let detail = match &e.value {
+            Expr::Ident(id) => Some(id.name.declared_name().to_string()),
+            Expr::Projection(_, field, _) => Some(field.declared_name().to_string()),
+            Expr::Literal(literal) => Some(match literal {
+                ast::Literal::Byte(b) => format!("{}b", b),
+                ast::Literal::Int(i) => i.to_string(),
+                ast::Literal::Float(f) => f.to_string(),
+                ast::Literal::String(s) => format!("{:?}", s),
+                ast::Literal::Char(c) => format!("{:?}", c),
+            }),
+            _ => None,
+        };
+        let node = Node {
+            kind: e.value.kind(),
+            detail,
+            range,
+            typ: self
+                .env
+                .and_then(|env| e.try_type_of(env).ok())
+                .map(|typ| typ.to_string()),
+            children,
+        };
+        parent.push(node);
+    }
+}
+
+fn tree(
+    source: &FileMap,
+    env: Option<&dyn TypeEnv<Type = ArcType>>,
+    expr: &ast::SpannedExpr<'_, Symbol>,
+) -> Vec<Node> {
+    let mut builder = TreeBuilder {
+        source,
+        env,
+        stack: vec![Vec::new()],
+    };
+    builder.visit_expr(expr);
+    builder.stack.pop().expect("Root")
+}
+
+/// Parses and type checks the module in `path`. Modules are imported relative to the directory
+/// of `path`.
+pub(crate) async fn inspect(thread: &Thread, path: &Path) -> Result<Inspection, anyhow::Error> {
+    let source = std::fs::read_to_string(path)?;
+    if let Some(directory) = path.parent() {
+        let import = thread.get_macros().get("import").expect("Import macro");
+        if let Some(import) = import.downcast_ref::<Import>() {
+            import.add_path(directory);
+        }
+    }
+    let module = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| anyhow!("`{}` is not the path of a module", path.display()))?;
+
+    let parsed = thread
+        .parse_expr(thread.global_env().type_cache(), module, &source)
+        .map_err(|err| anyhow!("{}", err))?;
+    let (typed, _) = thread
+        .typecheck_str_async(module, &source, None)
+        .await
+        .map_err(|err| anyhow!("{}", err))?;
+
+    let filemap = thread
+        .get_database()
+        .get_filemap(module)
+        .ok_or_else(|| anyhow!("No source for `{}`", module))?;
+    let db = thread.get_database();
+    let env = db.as_env();
+    Ok(Inspection {
+        parsed: tree(&filemap, None, parsed.expr()),
+        typed: tree(&filemap, Some(&env), typed.expr()),
+    })
+}
+
+/// Longer types, such as the records of modules, are cut off. `--inspect-json` prints them whole.
+const MAX_TYPE_WIDTH: usize = 120;
+
+fn write_node(output: &mut String, node: &Node, depth: usize) {
+    let Range { start, end } = node.range;
+    write!(
+        output,
+        "{}{} {}:{}-{}:{}",
+        "  ".repeat(depth),
+        node.kind,
+        start.line + 1,
+        start.character + 1,
+        end.line + 1,
+        end.character + 1
+    )
+    .unwrap();
+    if let Some(detail) = &node.detail {
+        write!(output, " {}", detail).unwrap();
+    }
+    if let Some(typ) = &node.typ {
+        // Types which span several lines are joined so that each node stays on one line
+        let mut typ = typ.split_whitespace().collect::<Vec<_>>().join(" ");
+        if let Some((end, _)) = typ.char_indices().nth(MAX_TYPE_WIDTH) {
+            typ.truncate(end);
+            typ.push_str(" ...");
+        }
+        write!(output, " : {}", typ).unwrap();
+    }
+    output.push('\n');
+    for child in &node.children {
+        write_node(output, child, depth + 1);
+    }
+}
+
+/// Prints each expression on a line of its own, indented below the expression which contains it
+pub(crate) fn render(inspection: &Inspection) -> String {
+    let mut output = String::from("Parsed:\n");
+    for node in &inspection.parsed {
+        write_node(&mut output, node, 1);
+    }
+    output.push_str("\nTyped:\n");
+    for node in &inspection.typed {
+        write_node(&mut output, node, 1);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn inspect_module() {
+        let path = std::env::temp_dir().join("inspect_module.glu");
+        std::fs::write(&path, "let id x = x\nid 1\n").unwrap();
+
+        let thread = gluon::new_vm_async().await;
+        let inspection = inspect(&thread, &path).await.unwrap();
+        assert_eq!(
+            render(&inspection),
+            "Parsed:
+  LetBindings 1:1-2:5
+    Ident 1:12-1:13 x
+    App 2:1-2:5
+      Ident 2:1-2:3 id
+      Literal 2:4-2:5 1
+
+Typed:
+  LetBindings 1:1-2:5 : Int
+    Ident 1:12-1:13 x : a
+    App 2:1-2:5 : Int
+      Ident 2:1-2:3 id : Int -> Int
+      Literal 2:4-2:5 1 : Int
+"
+        );
+
+        let json = serde_json::to_value(&inspection).unwrap();
+        assert_eq!(json["parsed"][0]["kind"], "LetBindings");
+        assert_eq!(json["parsed"][0].get("type"), None);
+        assert_eq!(json["typed"][0]["children"][1]["type"], "Int");
+        assert_eq!(
+            json["typed"][0]["children"][1]["range"]["start"],
+            serde_json::json!({ "line": 1, "character": 0 })
+        );
+    }
+}
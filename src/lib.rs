@@ -11,32 +11,248 @@ mod server;
 #[macro_use]
 pub mod rpc;
 
+pub mod ast_query;
 mod check_importer;
 mod command;
 mod diagnostics;
+mod inspect;
+mod module_loader;
 mod name;
+mod project;
+mod startup;
 mod text_edit;
 
 use gluon::either;
 
 use futures::prelude::*;
 
-pub use crate::{command::completion::CompletionData, server::Server};
+pub use crate::{
+    command::{
+        completion::CompletionData,
+        configuration::Reload,
+        dump_state::{DecoderState, DocumentState, DumpState, DumpStateResult, PendingRequest},
+        evaluate::{Evaluate, EvaluateError, EvaluateErrorKind, EvaluateParams, EvaluateResult},
+        formatting::{FormatPreview, FormatPreviewParams, FormatPreviewResult},
+        module_graph::{ModuleGraph, ModuleGraphEdge, ModuleGraphResult},
+        node_info::{NodeInfo, NodeInfoResult, NodeKind},
+        ping::{Ping, PingResult},
+        symbol::{WorkspaceLocation, WorkspaceSymbol, WorkspaceSymbolResolve},
+        type_at::{TypeAt, TypeAtParams},
+    },
+    diagnostics::DIAGNOSTIC_CODES,
+    module_loader::{ArchiveLoader, FileSystemLoader, MemoryLoader, ModuleLoader},
+    server::{FlushStrategy, Server, ServerOptions, STDIO_READ_BUFFER_SIZE, TCP_READ_BUFFER_SIZE},
+};
 
 pub type BoxFuture<I, E> = std::pin::Pin<Box<dyn Future<Output = Result<I, E>> + Send + 'static>>;
 
-pub async fn run() -> Result<(), anyhow::Error> {
+/// The version of the language server protocol which the server implements
+pub const LSP_PROTOCOL_VERSION: &str = "3.17";
+
+fn long_version() -> String {
+    format!(
+        "{}\ncommit: {}\ngluon: {}\nLSP protocol: {}",
+        env!("CARGO_PKG_VERSION"),
+        option_env!("GIT_COMMIT").unwrap_or("unknown"),
+        option_env!("GLUON_VERSION").unwrap_or("unknown"),
+        LSP_PROTOCOL_VERSION
+    )
+}
+
+pub fn run() -> Result<(), anyhow::Error> {
     ::env_logger::init();
 
-    let _matches = clap::App::new("debugger")
+    let long_version = long_version();
+    let matches = clap::App::new("gluon_language-server")
         .version(env!("CARGO_PKG_VERSION"))
+        .long_version(&*long_version)
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .after_help(
+            "The server communicates over stdin and stdout. \
+             Logging is configured with the `RUST_LOG` environment variable.",
+        )
+        .arg(
+            clap::Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .help("Shut down if no message is received from the client for this many seconds")
+                .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|err| err.to_string())),
+        )
+        .arg(
+            clap::Arg::with_name("keepalive")
+                .long("keepalive")
+                .value_name("SECONDS")
+                .help(
+                    "Send a `$/gluon/keepalive` notification if no message has been sent or \
+                     received for this many seconds",
+                )
+                .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|err| err.to_string())),
+        )
+        .arg(
+            clap::Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .help(
+                    "The number of threads which handle requests and check modules. Defaults to \
+                     the number of CPUs. Reading and writing stdin and stdout happens on separate \
+                     threads which are not counted.",
+                )
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(0) => Err("Expected at least one thread".into()),
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err.to_string()),
+                }),
+        )
+        .arg(
+            clap::Arg::with_name("load-retries")
+                .long("load-retries")
+                .value_name("COUNT")
+                .help(
+                    "How often reading a module which failed with a transient error is retried. \
+                     Defaults to 3.",
+                )
+                .validator(|s| {
+                    s.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|err| err.to_string())
+                }),
+        )
+        .arg(
+            clap::Arg::with_name("read-buffer-size")
+                .long("read-buffer-size")
+                .value_name("BYTES")
+                .help(
+                    "The size of the buffer which messages from the client are read into. \
+                     Defaults to 8192 for stdin.",
+                )
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(0) => Err("Expected a buffer of at least one byte".into()),
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err.to_string()),
+                }),
+        )
+        .arg(
+            clap::Arg::with_name("max-content-length")
+                .long("max-content-length")
+                .value_name("BYTES")
+                .help(
+                    "The largest message which is accepted from the client. \
+                     Defaults to 16 MiB.",
+                )
+                .validator(|s| {
+                    s.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|err| err.to_string())
+                }),
+        )
+        .arg(
+            clap::Arg::with_name("no-dependency-diagnostics")
+                .long("no-dependency-diagnostics")
+                .help("Only publish the errors of a checked module, not of the modules it imports"),
+        )
+        .arg(
+            clap::Arg::with_name("measure-startup")
+                .long("measure-startup")
+                .help(
+                    "Print how long each phase of starting the server took to stderr, once the \
+                     project is indexed and the first document is checked",
+                ),
+        )
+        .arg(clap::Arg::with_name("lenient").long("lenient").help(
+            "Accept messages from clients which omit the `jsonrpc` field or send it as \
+                     something other than the string \"2.0\", instead of rejecting them",
+        ))
+        .arg(
+            clap::Arg::with_name("inspect")
+                .long("inspect")
+                .value_name("FILE")
+                .help(
+                    "Print the parsed and the type checked expressions of a module, with the \
+                     types which were inferred, instead of starting the server",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("inspect-json")
+                .long("inspect-json")
+                .value_name("FILE")
+                .conflicts_with("inspect")
+                .help("Like `--inspect` but prints JSON"),
+        )
         .get_matches();
 
-    let thread = gluon::new_vm_async().await;
-    Server::start(thread, tokio::io::stdin(), tokio::io::stdout()).await?;
-    Ok(())
+    let inspect = matches
+        .value_of("inspect")
+        .map(|path| (path, false))
+        .or_else(|| matches.value_of("inspect-json").map(|path| (path, true)));
+    if let Some((path, json)) = inspect {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let inspection = runtime.block_on(async move {
+            let thread = gluon::new_vm_async().await;
+            inspect::inspect(&thread, std::path::Path::new(path)).await
+        })?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&inspection)?);
+        } else {
+            print!("{}", inspect::render(&inspection));
+        }
+        return Ok(());
+    }
+
+    let options = ServerOptions {
+        idle_timeout: matches
+            .value_of("idle-timeout")
+            .map(|s| std::time::Duration::from_secs(s.parse().unwrap())),
+        dependency_diagnostics: !matches.is_present("no-dependency-diagnostics"),
+        keepalive: matches
+            .value_of("keepalive")
+            .map(|s| std::time::Duration::from_secs(s.parse().unwrap())),
+        read_buffer_size: matches
+            .value_of("read-buffer-size")
+            .map_or(STDIO_READ_BUFFER_SIZE, |s| s.parse().unwrap()),
+        load_retries: matches
+            .value_of("load-retries")
+            .map_or(module_loader::DEFAULT_RETRIES, |s| s.parse().unwrap()),
+        max_content_length: matches
+            .value_of("max-content-length")
+            .map_or(rpc::DEFAULT_MAX_CONTENT_LENGTH, |s| s.parse().unwrap()),
+        measure_startup: matches.is_present("measure-startup"),
+        lenient: matches.is_present("lenient"),
+        ..ServerOptions::default()
+    };
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = matches.value_of("threads") {
+        runtime.worker_threads(threads.parse().unwrap());
+    }
+    runtime.thread_stack_size(STACK_SIZE);
+    let runtime = runtime.build()?;
+    // `block_on` runs the server, and the requests it handles, on the calling thread so that
+    // thread needs the same stack as the runtime's threads
+    let (runtime, result) = std::thread::Builder::new()
+        .name("server".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let result = runtime.block_on(async move {
+                let thread = gluon::new_vm_async().await;
+                Server::start_with_options(thread, options, tokio::io::stdin(), tokio::io::stdout())
+                    .await
+            });
+            (runtime, result)
+        })?
+        .join()
+        .unwrap_or_else(|err| std::panic::resume_unwind(err));
+    // Reading stdin occupies a blocking thread which would otherwise keep the runtime from
+    // shutting down until the client closes the pipe
+    runtime.shutdown_background();
+    result
 }
 
+/// The stack size of the runtime's threads, the thread which runs the server and the default of
+/// the thread which checks modules. Checking and walking a syntax tree recurses once for each
+/// level of nesting.
+pub(crate) const STACK_SIZE: usize = 64 * 1024 * 1024;
+
 async fn cancelable<T, F, G>(f: F, g: G) -> T
 where
     F: Future<Output = T>,
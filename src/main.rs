@@ -1,6 +1,5 @@
-#[tokio::main]
-async fn main() {
-    if let Err(err) = gluon_language_server::run().await {
+fn main() {
+    if let Err(err) = gluon_language_server::run() {
         eprintln!("{}", err);
         std::process::exit(1);
     }
@@ -0,0 +1,401 @@
+//! Pluggable resolution of module sources
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::Duration,
+};
+
+use gluon::base::fnv::{FnvMap, FnvSet};
+
+/// Provides the source code of modules which are imported but not open in the editor.
+///
+/// Loaders are consulted in order the first time a module is imported, after the documents which
+/// are open in the editor and before the `FileSystemLoader` which reads the import paths of the
+/// `import!` macro.
+pub trait ModuleLoader: Send + Sync + 'static {
+    /// Returns the source of `module` (such as `std.prelude`) or `None` if this loader does not
+    /// provide it
+    fn load_module(&self, module: &str) -> io::Result<Option<String>>;
+}
+
+/// How often `FileSystemLoader` retries a read which failed with a transient error
+pub const DEFAULT_RETRIES: usize = 3;
+
+/// The delay before the first retry, it doubles for each following retry up to
+/// `MAX_RETRY_BACKOFF`
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// The longest delay between two retries. Loaders are called synchronously by the check which
+/// imports the module, so the delay blocks the thread which runs it, possibly one of the runtime's
+/// workers.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Whether `err` may go away by itself, such as a file which an editor has locked while saving it
+fn is_transient(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => true,
+        // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+        _ if cfg!(windows) => matches!(err.raw_os_error(), Some(32) | Some(33)),
+        _ => false,
+    }
+}
+
+/// Calls `f` until it succeeds, fails with an error which is not transient or has been retried
+/// `retries` times
+fn retry<T>(retries: usize, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = RETRY_BACKOFF;
+    for _ in 0..retries {
+        match f() {
+            Err(err) if is_transient(&err) => {
+                debug!("Retrying in {:?}: {}", backoff, err);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+            }
+            result => return result,
+        }
+    }
+    f()
+}
+
+/// Loads modules from `.glu` files below a set of root directories. A path to a `.tar.gz`, `.tgz`
+/// or `.tar` archive is treated as a directory holding the files of the archive.
+pub struct FileSystemLoader {
+    paths: RwLock<Vec<PathBuf>>,
+    retries: usize,
+    /// Archives are read the first time a module is looked up in them. `None` if reading failed,
+    /// the archive is then skipped.
+    archives: Mutex<FnvMap<PathBuf, Option<Arc<ArchiveLoader>>>>,
+}
+
+impl FileSystemLoader {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        FileSystemLoader {
+            paths: RwLock::new(paths),
+            retries: DEFAULT_RETRIES,
+            archives: Mutex::default(),
+        }
+    }
+
+    /// Sets how often a read which fails with a transient error is retried before the error is
+    /// returned. Defaults to 3.
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Replaces the root directories, such as when the import paths change
+    pub fn set_paths(&self, paths: Vec<PathBuf>) {
+        *self.paths.write().unwrap() = paths;
+    }
+
+    fn archive(&self, path: &Path) -> Option<Arc<ArchiveLoader>> {
+        let mut archives = self.archives.lock().unwrap();
+        archives
+            .entry(path.to_owned())
+            .or_insert_with(|| match retry(self.retries, || ArchiveLoader::open(path)) {
+                Ok(archive) => Some(Arc::new(archive)),
+                Err(err) => {
+                    error!("Unable to read the archive `{}`: {}", path.display(), err);
+                    None
+                }
+            })
+            .clone()
+    }
+}
+
+impl ModuleLoader for FileSystemLoader {
+    fn load_module(&self, module: &str) -> io::Result<Option<String>> {
+        let mut filename = module.replace('.', "/");
+        filename.push_str(".glu");
+
+        let paths = self.paths.read().unwrap().clone();
+        for path in &paths {
+            if ArchiveLoader::is_archive(path) {
+                if let Some(archive) = self.archive(path) {
+                    if let Some(source) = archive.load_module(module)? {
+                        return Ok(Some(source));
+                    }
+                }
+                continue;
+            }
+            match retry(self.retries, || fs::read_to_string(path.join(&filename))) {
+                Ok(source) => return Ok(Some(source)),
+                // Import paths which are not directories hold no modules
+                Err(err) if err.kind() == io::ErrorKind::NotFound || !path.is_dir() => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Serves the `.glu` files of a tar archive, which may be gzip compressed, as modules named after
+/// their path in the archive
+pub struct ArchiveLoader {
+    data: ArchiveData,
+    compressed: bool,
+    /// The modules in the archive. Their sources are only read when they are loaded.
+    modules: FnvSet<String>,
+}
+
+enum ArchiveData {
+    File(PathBuf),
+    Memory(Vec<u8>),
+}
+
+impl ArchiveLoader {
+    /// Lists the modules of the archive at `path`, which is decompressed if its name ends with
+    /// `.gz` or `.tgz`
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let name = path.to_string_lossy();
+        let compressed = name.ends_with(".gz") || name.ends_with(".tgz");
+        Self::new(ArchiveData::File(path.to_owned()), compressed)
+    }
+
+    /// Reads a gzip compressed tar archive. It is kept compressed in memory.
+    pub fn from_tar_gz(reader: impl Read) -> io::Result<Self> {
+        Self::from_reader(reader, true)
+    }
+
+    pub fn from_tar(reader: impl Read) -> io::Result<Self> {
+        Self::from_reader(reader, false)
+    }
+
+    fn from_reader(mut reader: impl Read, compressed: bool) -> io::Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Self::new(ArchiveData::Memory(data), compressed)
+    }
+
+    fn new(data: ArchiveData, compressed: bool) -> io::Result<Self> {
+        let mut loader = ArchiveLoader {
+            data,
+            compressed,
+            modules: FnvSet::default(),
+        };
+        let mut modules = FnvSet::default();
+        // Only the headers are read, the entries' contents are skipped
+        for entry in tar::Archive::new(loader.reader()?).entries()? {
+            if let Some(name) = module_name(&entry?.path()?) {
+                modules.insert(name);
+            }
+        }
+        loader.modules = modules;
+        Ok(loader)
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        let reader: Box<dyn Read> = match &self.data {
+            ArchiveData::File(path) => Box::new(fs::File::open(path)?),
+            ArchiveData::Memory(data) => Box::new(&data[..]),
+        };
+        Ok(if self.compressed {
+            Box::new(flate2::read::GzDecoder::new(reader))
+        } else {
+            reader
+        })
+    }
+
+    fn is_archive(path: &Path) -> bool {
+        let name = path.to_string_lossy();
+        [".tar.gz", ".tgz", ".tar"]
+            .iter()
+            .any(|extension| name.ends_with(extension))
+    }
+}
+
+/// The name of the module in the file at `path` (`std/list.glu` is `std.list`)
+fn module_name(path: &Path) -> Option<String> {
+    if path.extension()? != "glu" {
+        return None;
+    }
+    let path = path.with_extension("");
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_str()?),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    Some(components.join("."))
+}
+
+impl ModuleLoader for ArchiveLoader {
+    fn load_module(&self, module: &str) -> io::Result<Option<String>> {
+        if !self.modules.contains(module) {
+            return Ok(None);
+        }
+        for entry in tar::Archive::new(self.reader()?).entries()? {
+            let mut entry = entry?;
+            if module_name(&entry.path()?).as_deref() == Some(module) {
+                let mut source = String::new();
+                entry.read_to_string(&mut source)?;
+                return Ok(Some(source));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Serves modules which only exist in memory
+#[derive(Clone, Default)]
+pub struct MemoryLoader {
+    modules: Arc<RwLock<FnvMap<String, String>>>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces the source of `module`
+    pub fn insert(&self, module: impl Into<String>, source: impl Into<String>) {
+        self.modules
+            .write()
+            .unwrap()
+            .insert(module.into(), source.into());
+    }
+
+    pub fn remove(&self, module: &str) -> Option<String> {
+        self.modules.write().unwrap().remove(module)
+    }
+}
+
+impl ModuleLoader for MemoryLoader {
+    fn load_module(&self, module: &str) -> io::Result<Option<String>> {
+        Ok(self.modules.read().unwrap().get(module).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_system_loader() {
+        let loader = FileSystemLoader::new(vec![PathBuf::from("tests")]);
+        assert!(loader.load_module("main").unwrap().is_some());
+        assert_eq!(loader.load_module("does_not_exist").unwrap(), None);
+    }
+
+    /// Fails with a transient error `failures` times before it provides the module
+    struct FlakyLoader {
+        failures: std::sync::atomic::AtomicUsize,
+    }
+
+    impl ModuleLoader for FlakyLoader {
+        fn load_module(&self, _module: &str) -> io::Result<Option<String>> {
+            use std::sync::atomic::Ordering;
+            if self.failures.load(Ordering::SeqCst) == 0 {
+                return Ok(Some("1".to_string()));
+            }
+            self.failures.fetch_sub(1, Ordering::SeqCst);
+            Err(io::ErrorKind::Interrupted.into())
+        }
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        let loader = FlakyLoader { failures: 2.into() };
+        assert_eq!(
+            retry(3, || loader.load_module("flaky")).unwrap(),
+            Some("1".to_string())
+        );
+
+        let loader = FlakyLoader { failures: 2.into() };
+        assert_eq!(
+            retry(1, || loader.load_module("flaky")).unwrap_err().kind(),
+            io::ErrorKind::Interrupted
+        );
+    }
+
+    #[test]
+    fn retry_backoff_is_capped() {
+        // Without the cap the delays would add up to more than 10 seconds
+        let loader = FlakyLoader {
+            failures: 10.into(),
+        };
+        let start = std::time::Instant::now();
+        assert!(retry(10, || loader.load_module("flaky")).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn no_retry_of_missing_files() {
+        let mut calls = 0;
+        let result = retry(3, || {
+            calls += 1;
+            fs::read_to_string("tests/does_not_exist.glu")
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(calls, 1);
+    }
+
+    fn tar_gz(files: &[(&str, &str)]) -> Vec<u8> {
+        let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn archive_loader() {
+        let archive = tar_gz(&[
+            ("./lib/util.glu", "1"),
+            ("lib/README.md", "# lib"),
+            ("main.glu", "2"),
+        ]);
+        let loader = ArchiveLoader::from_tar_gz(&archive[..]).unwrap();
+        assert_eq!(
+            loader.load_module("lib.util").unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(loader.load_module("main").unwrap(), Some("2".to_string()));
+        assert_eq!(loader.load_module("lib.README").unwrap(), None);
+    }
+
+    #[test]
+    fn file_system_loader_reads_archives() {
+        let dir = std::env::temp_dir().join(format!("gluon_archives_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("lib.tar.gz");
+        fs::write(&archive, tar_gz(&[("lib/util.glu", "1")])).unwrap();
+        let broken = dir.join("broken.tgz");
+        fs::write(&broken, "not an archive").unwrap();
+
+        // The broken archive is skipped
+        let loader = FileSystemLoader::new(vec![broken, archive, PathBuf::from("tests")]);
+        assert_eq!(
+            loader.load_module("lib.util").unwrap(),
+            Some("1".to_string())
+        );
+        assert!(loader.load_module("main").unwrap().is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn memory_loader() {
+        let loader = MemoryLoader::new();
+        loader.insert("virtual.module", "1");
+        assert_eq!(
+            loader.load_module("virtual.module").unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(loader.remove("virtual.module"), Some("1".to_string()));
+        assert_eq!(loader.load_module("virtual.module").unwrap(), None);
+    }
+}
@@ -0,0 +1,122 @@
+//! Discovery of the layout of a gluon project
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+/// Directories which conventionally contain the modules of a project
+const MODULE_DIRECTORIES: &[&str] = &["src", "lib"];
+
+/// The file in the root of a project which holds the project's defaults for the `gluon` settings,
+/// as a JSON object
+pub(crate) const SETTINGS_FILE: &str = ".gluonrc";
+
+/// Returns the directories in `root` which contain the modules of the project. Empty if the
+/// project does not have a recognizable layout.
+pub(crate) fn module_directories(root: &Path) -> Vec<PathBuf> {
+    MODULE_DIRECTORIES
+        .iter()
+        .map(|directory| root.join(directory))
+        .filter(|directory| directory.is_dir())
+        .collect()
+}
+
+/// Returns the name and path of each module directly inside `directory`
+pub(crate) fn top_level_modules(directory: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut modules = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("glu".as_ref()) {
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                modules.push((name.to_string(), path.clone()));
+            }
+        }
+    }
+    modules.sort();
+    Ok(modules)
+}
+
+/// Reads the settings in the `SETTINGS_FILE` of `root`, with relative `modulePaths` resolved
+/// against `root`. Returns `null` if the file does not exist, and warns and returns `null` if it
+/// can not be read or is not a JSON object.
+pub(crate) fn read_settings(root: &Path) -> Value {
+    let path = root.join(SETTINGS_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Value::Null,
+        Err(err) => {
+            warn!("Unable to read {}: {}", path.display(), err);
+            return Value::Null;
+        }
+    };
+    let mut settings = match serde_json::from_str(&contents) {
+        Ok(settings @ Value::Object(_)) => settings,
+        Ok(_) => {
+            warn!("Ignoring {}, expected a JSON object", path.display());
+            return Value::Null;
+        }
+        Err(err) => {
+            warn!("Ignoring {}: {}", path.display(), err);
+            return Value::Null;
+        }
+    };
+    if let Some(Value::Array(paths)) = settings.get_mut("modulePaths") {
+        for path in paths {
+            if let Some(relative) = path.as_str() {
+                *path = root.join(relative).to_string_lossy().into_owned().into();
+            }
+        }
+    }
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discover_module_directories() {
+        let root = std::env::temp_dir().join(format!("gluon_project_{}", std::process::id()));
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::write(root.join("src/main.glu"), "1").unwrap();
+        fs::write(root.join("src/README.md"), "").unwrap();
+        fs::write(root.join("src/nested/inner.glu"), "1").unwrap();
+
+        assert_eq!(module_directories(&root), vec![root.join("src")]);
+        assert_eq!(
+            top_level_modules(&root.join("src")).unwrap(),
+            vec![("main".to_string(), root.join("src/main.glu"))]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(module_directories(&root), Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn read_project_settings() {
+        let root = std::env::temp_dir().join(format!("gluon_settings_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        assert_eq!(read_settings(&root), Value::Null);
+
+        fs::write(root.join(SETTINGS_FILE), "{ \"modulePaths\": [\"deps\"] ").unwrap();
+        assert_eq!(read_settings(&root), Value::Null);
+
+        fs::write(
+            root.join(SETTINGS_FILE),
+            r#"{ "modulePaths": ["deps"], "postfixCompletion": true }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            read_settings(&root),
+            serde_json::json!({
+                "modulePaths": [root.join("deps")],
+                "postfixCompletion": true,
+            })
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
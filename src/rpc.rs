@@ -6,40 +6,129 @@ use std::{
     marker::Unpin,
     pin::Pin,
     str,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     task::{self, Poll},
 };
 
 use anyhow::anyhow;
 
 use combine::{
-    error::ParseError,
-    from_str,
+    error::{ParseError, StreamError},
+    many1,
     parser::{
         combinator::{any_send_partial_state, AnySendPartialState},
-        range::{range, take, take_while1},
+        range::{range, take, take_while, take_while1},
     },
     skip_many,
-    stream::{easy, PartialStream, RangeStream},
-    Parser,
+    stream::{easy, PartialStream, RangeStream, StreamErrorFor},
+    value, Parser,
 };
 
-use bytes::{buf::Buf, BufMut, BytesMut};
+use bytes::{
+    buf::{Buf, BufMut},
+    BytesMut,
+};
 
 use tokio_util::codec::{Decoder, Encoder};
 
-use futures::{channel::mpsc, prelude::*, Sink, Stream};
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    Sink, Stream,
+};
 
-use jsonrpc_core::{Error, ErrorCode, Params, RpcMethodSimple, RpcNotificationSimple, Value};
+use gluon::base::fnv::FnvMap;
 
-use lsp_types::{notification, LogMessageParams, MessageType};
+use url::Url;
+
+use jsonrpc_core::{
+    Error, ErrorCode, Id, IoHandler, Output, Params, Response, RpcMethodSimple,
+    RpcNotificationSimple, Value,
+};
+
+use lsp_types::{notification, request, LogMessageParams, MessageType};
 
 use serde;
 use serde_json::{self, from_value, to_string, to_value};
 
 use crate::BoxFuture;
 
+/// The LSP error code of a request whose result is outdated by a change of the document
+pub const CONTENT_MODIFIED: ErrorCode = ErrorCode::ServerError(-32801);
+
+/// The LSP error code of a request which the client cancelled with `$/cancelRequest`
+pub const REQUEST_CANCELLED: ErrorCode = ErrorCode::ServerError(-32800);
+
+tokio::task_local! {
+    static CANCELLATION: Cancellation;
+}
+
+/// Signals that the client cancelled the request which is being handled. Handlers can not be
+/// stopped at any point since they may be in the middle of a query of the database, so the ones
+/// which take long check for it where stopping leaves nothing half done.
+#[derive(Clone, Default)]
+pub struct Cancellation(Arc<(AtomicBool, tokio::sync::Notify)>);
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        (self.0).0.store(true, Ordering::SeqCst);
+        (self.0).1.notify_waiters();
+    }
+
+    fn is_cancelled(&self) -> bool {
+        (self.0).0.load(Ordering::SeqCst)
+    }
+
+    /// Runs `future`, the handling of a request, so that it is cancelled through `self`
+    pub async fn scope<F>(self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        CANCELLATION.scope(self, future).await
+    }
+}
+
+fn request_cancelled<E>() -> ServerError<E> {
+    ServerError {
+        code: REQUEST_CANCELLED,
+        message: "The request was cancelled".into(),
+        data: None,
+    }
+}
+
+/// Returns a `RequestCancelled` error if the client has cancelled the request which is being
+/// handled. Yields first so that the server gets to read a cancellation.
+pub async fn check_cancelled<E>() -> Result<(), ServerError<E>> {
+    let () = tokio::task::yield_now().await;
+    match CANCELLATION.try_with(Cancellation::is_cancelled) {
+        Ok(true) => Err(request_cancelled()),
+        _ => Ok(()),
+    }
+}
+
+/// Completes with a `RequestCancelled` error once the client cancels the request which is being
+/// handled, or never if it can not be cancelled
+pub async fn cancelled<E>() -> ServerError<E> {
+    let cancellation = match CANCELLATION.try_with(Cancellation::clone) {
+        Ok(cancellation) => cancellation,
+        Err(_) => future::pending().await,
+    };
+    loop {
+        let notified = (cancellation.0).1.notified();
+        if cancellation.is_cancelled() {
+            return request_cancelled();
+        }
+        notified.await;
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ServerError<E> {
+    /// The code of the error response, `InternalError` unless the command picks another
+    pub code: ErrorCode,
     pub message: String,
     pub data: Option<E>,
 }
@@ -50,6 +139,7 @@ where
 {
     fn from(err: E) -> ServerError<D> {
         ServerError {
+            code: ErrorCode::InternalError,
             message: err.to_string(),
             data: None,
         }
@@ -127,42 +217,59 @@ where
 {
     type Out = BoxFuture<Value, Error>;
     fn call(&self, param: Params) -> Self::Out {
-        let value = match param {
-            Params::Map(map) => Value::Object(map),
-            Params::Array(arr) => Value::Array(arr),
-            Params::None => Value::Null,
-        };
-        let err = match from_value(value) {
+        let err = match from_params(param) {
             Ok(value) => {
                 return self
                     .0
                     .execute(value)
                     .map(|result| match result {
-                        Ok(value) => {
-                            Ok(to_value(&value).expect("result data could not be serialized"))
-                        }
-                        Err(error) => Err(Error {
+                        Ok(value) => to_value(&value).map_err(|err| Error {
                             code: ErrorCode::InternalError,
+                            message: format!("The result could not be serialized: {}", err),
+                            data: None,
+                        }),
+                        Err(error) => Err(Error {
+                            code: error.code,
                             message: error.message,
-                            data: error
-                                .data
-                                .as_ref()
-                                .map(|v| to_value(v).expect("error data could not be serialized")),
+                            data: error.data.as_ref().and_then(error_data),
                         }),
                     })
                     .boxed()
             }
             Err(err) => err,
         };
-        let data = self.0.invalid_params();
-        futures::future::err(Error {
-            code: ErrorCode::InvalidParams,
-            message: format!("Invalid params: {}", err),
-            data: data
-                .as_ref()
-                .map(|v| to_value(v).expect("error data could not be serialized")),
-        })
-        .boxed()
+        invalid_params_response(err, self.0.invalid_params().as_ref())
+    }
+}
+
+/// Creates the response to a request whose parameters could not be deserialized.
+pub(crate) fn invalid_params_response<D>(
+    err: impl fmt::Display,
+    data: Option<&D>,
+) -> BoxFuture<Value, Error>
+where
+    D: serde::Serialize,
+{
+    futures::future::err(Error {
+        code: ErrorCode::InvalidParams,
+        message: format!("Invalid params: {}", err),
+        data: data.and_then(error_data),
+    })
+    .boxed()
+}
+
+/// Serializes the `data` of an error response. Data that can't be serialized is left out rather
+/// than failing the whole response.
+fn error_data<D>(data: &D) -> Option<Value>
+where
+    D: serde::Serialize,
+{
+    match to_value(data) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            error!("Error data could not be serialized: {}", err);
+            None
+        }
     }
 }
 
@@ -172,19 +279,58 @@ where
     P: for<'de> serde::Deserialize<'de> + 'static,
 {
     fn execute(&self, param: Params) {
-        match param {
-            Params::Map(map) => match from_value(Value::Object(map)) {
-                Ok(value) => {
-                    self.0.execute(value);
-                }
-                Err(err) => error!("{}", err), // FIXME log_message!("Invalid parameters. Reason: {}", err),
-            },
-            _ => (), // FIXME log_message!("Invalid parameters: {:?}", param),
+        match from_params(param) {
+            Ok(value) => {
+                self.0.execute(value);
+            }
+            Err(err) => error!("Invalid parameters: {}", err),
         }
     }
 }
 
-pub(crate) async fn log_message(sender: mpsc::Sender<String>, message: String) {
+/// Deserializes the parameters of a request or notification. Absent, `null` and `{}` parameters
+/// are accepted interchangeably by methods without parameters, whether they take `()` or an empty
+/// struct.
+fn from_params<P>(params: Params) -> Result<P, serde_json::Error>
+where
+    P: for<'de> serde::Deserialize<'de>,
+{
+    let value = match params {
+        Params::Map(map) => Value::Object(map),
+        Params::Array(arr) => Value::Array(arr),
+        Params::None => Value::Null,
+    };
+    let alternative = match &value {
+        Value::Null => Some(Value::Object(Default::default())),
+        Value::Object(map) if map.is_empty() => Some(Value::Null),
+        _ => None,
+    };
+    from_value(value).or_else(|err| match alternative {
+        Some(alternative) => from_value(alternative).map_err(|_| err),
+        None => Err(err),
+    })
+}
+
+/// Handles the request `json` like `IoHandler::handle_request`, except that the response is not
+/// serialized into a string. Its result is serialized straight into the output once the response
+/// is written.
+pub async fn handle_request(handlers: &IoHandler, json: &str) -> Option<OutgoingMessage> {
+    let request = match serde_json::from_str(json) {
+        Ok(request) => request,
+        Err(_) => {
+            return Some(OutgoingMessage::Response {
+                id: Id::Null,
+                result: Err(Error::parse_error()),
+            })
+        }
+    };
+    handlers
+        .handle_rpc_request(request)
+        .await
+        .map(OutgoingMessage::from)
+}
+
+pub(crate) async fn log_message(sender: mpsc::Sender<OutgoingMessage>, message: String) {
     debug!("{}", message);
     send_response(
         sender,
@@ -206,82 +352,371 @@ macro_rules! log_message {
     } }
 }
 
-pub async fn send_response<T>(mut sender: mpsc::Sender<String>, _: Option<T>, value: T::Params)
-where
+pub async fn send_response<T>(
+    mut sender: mpsc::Sender<OutgoingMessage>,
+    _: Option<T>,
+    value: T::Params,
+) where
     T: notification::Notification,
     T::Params: serde::Serialize,
 {
-    let r = format!(
-        r#"{{"jsonrpc": "2.0", "method": "{}", "params": {} }}"#,
-        T::METHOD,
-        serde_json::to_value(value).unwrap()
-    );
-    let _ = sender.send(r).await;
+    let message = OutgoingMessage::Notification {
+        method: T::METHOD.into(),
+        params: serde_json::to_value(value).unwrap(),
+    };
+    let _ = sender.send(message).await;
+}
+
+/// The requests sent to the client which are waiting for the client's response
+#[derive(Clone, Default)]
+pub struct ClientRequests(Arc<Mutex<PendingRequests>>);
+
+#[derive(Default)]
+struct PendingRequests {
+    next_id: u64,
+    /// The method of each request and where to send its response
+    waiting: FnvMap<Id, (&'static str, ResponseSender)>,
+}
+
+type ResponseSender = oneshot::Sender<Result<Value, Error>>;
+
+impl ClientRequests {
+    /// Sends a request to the client and waits for the client's response. The response is only
+    /// read once the handler which is currently running has returned so this must not be awaited
+    /// by a handler.
+    pub(crate) async fn send<T>(
+        &self,
+        mut sender: mpsc::Sender<OutgoingMessage>,
+        _: Option<T>,
+        value: T::Params,
+    ) -> Result<T::Result, ServerError<()>>
+    where
+        T: request::Request,
+        T::Params: serde::Serialize,
+        T::Result: serde::de::DeserializeOwned,
+    {
+        let (response_sender, response) = oneshot::channel();
+        let id = {
+            let mut pending = self.0.lock().unwrap();
+            pending.next_id += 1;
+            let id = Id::Num(pending.next_id);
+            pending
+                .waiting
+                .insert(id.clone(), (T::METHOD, response_sender));
+            id
+        };
+        let message = OutgoingMessage::Request {
+            id,
+            method: T::METHOD.into(),
+            params: to_value(value)?,
+        };
+        sender
+            .send(message)
+            .await
+            .map_err(|_| "Unable to send a request to the client")?;
+        match response.await {
+            Ok(Ok(result)) => Ok(from_value(result)?),
+            Ok(Err(err)) => Err(ServerError {
+                code: err.code,
+                message: err.message,
+                data: None,
+            }),
+            Err(_) => Err("The client did not respond".into()),
+        }
+    }
+
+    /// Passes the client's `response` to the request which is waiting for it. Returns `false` if
+    /// no request is waiting for it.
+    pub(crate) fn respond(&self, response: &str) -> bool {
+        let (id, result) = match serde_json::from_str(response) {
+            Ok(Output::Success(success)) => (success.id, Ok(success.result)),
+            Ok(Output::Failure(failure)) => (failure.id, Err(failure.error)),
+            Err(_) => return false,
+        };
+        match self.0.lock().unwrap().waiting.remove(&id) {
+            Some((_, sender)) => {
+                let _ = sender.send(result);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The id and method of each request which is waiting for the client's response, ordered by
+    /// id
+    pub(crate) fn pending(&self) -> Vec<(Id, &'static str)> {
+        let pending = self.0.lock().unwrap();
+        let mut requests: Vec<_> = pending
+            .waiting
+            .iter()
+            .map(|(id, (method, _))| (id.clone(), *method))
+            .collect();
+        requests.sort_by_key(|(id, _)| match id {
+            Id::Num(id) => *id,
+            _ => 0,
+        });
+        requests
+    }
+}
+
+/// Counts the bytes written to it, which gives the `Content-Length` of a message without
+/// serializing it into memory
+#[derive(Default)]
+struct ByteCount(usize);
+
+impl Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serializes `value` once to measure it and once more straight into `output`, so that large
+/// messages, such as the semantic tokens of a big module, are never held in memory as a string
+fn write_framed<W, T>(mut output: W, value: &T) -> io::Result<()>
+where
+    W: Write,
+    T: serde::Serialize,
+{
+    let mut length = ByteCount::default();
+    serde_json::to_writer(&mut length, value)?;
+    write!(output, "Content-Length: {}\r\n\r\n", length.0)?;
+    serde_json::to_writer(&mut output, value)?;
+    Ok(())
 }
 
-pub fn write_message<W, T>(output: W, value: &T) -> io::Result<()>
+pub fn write_message<W, T>(mut output: W, value: &T) -> io::Result<()>
 where
     W: Write,
     T: serde::Serialize,
 {
-    let response = to_string(&value).unwrap();
-    write_message_str(output, &response)
+    if log_enabled!(log::Level::Debug) {
+        debug!("Respond: {}", to_string(value)?);
+    }
+    write_framed(&mut output, value)?;
+    output.flush()
 }
 
+/// Writes `response` with its header straight into `output`, without copying it into a buffer
 pub fn write_message_str<W>(mut output: W, response: &str) -> io::Result<()>
 where
     W: Write,
 {
     debug!("Respond: {}", response);
-    write!(
-        output,
-        "Content-Length: {}\r\n\r\n{}",
-        response.len(),
-        response
-    )?;
+    write!(output, "Content-Length: {}\r\n\r\n", response.len())?;
+    output.write_all(response.as_bytes())?;
     output.flush()?;
     Ok(())
 }
 
+/// Appends `response` with its header to `buf`. Reusing `buf` for every message avoids allocating
+/// once the buffer has grown to fit the largest message.
+pub fn write_message_into(buf: &mut String, response: &str) {
+    use std::fmt::Write;
+
+    buf.reserve(response.len() + 40); // Ensure Content-Length fits
+    write!(buf, "Content-Length: {}\r\n\r\n", response.len()).unwrap();
+    buf.push_str(response);
+}
+
+/// Counts the messages which pass through the server so that `gluon/dumpState` can report them
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    frames_decoded: AtomicUsize,
+    frames_dispatched: AtomicUsize,
+    partial_frame: AtomicBool,
+    buffered_bytes: AtomicUsize,
+}
+
+impl PipelineStats {
+    /// The number of messages which have been read from the input
+    pub fn frames_decoded(&self) -> usize {
+        self.frames_decoded.load(Ordering::SeqCst)
+    }
+
+    /// Whether the decoder has started on a message which has not been read completely
+    pub fn partial_frame(&self) -> bool {
+        self.partial_frame.load(Ordering::SeqCst)
+    }
+
+    /// The number of bytes which have been read but which the decoder has not consumed yet
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes.load(Ordering::SeqCst)
+    }
+
+    /// The number of messages which have been read but not handled yet, including the message
+    /// which is being handled
+    pub fn dispatch_queue_depth(&self) -> usize {
+        self.frames_decoded()
+            .saturating_sub(self.frames_dispatched.load(Ordering::SeqCst))
+    }
+
+    /// Records that a decoded message has been handled
+    pub fn dispatched(&self) {
+        self.frames_dispatched.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Errors which the server loop reports specifically when reading messages
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The input ended before the body of a message had been read completely
+    Truncated { expected: usize, got: usize },
+    /// The `Content-Length` of a message was larger than the decoder accepts. The decoder skips
+    /// the body and continues with the next message.
+    TooLong { length: usize, max: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { expected, got } => write!(
+                f,
+                "The input ended after {} of the {} bytes of a message",
+                got, expected
+            ),
+            DecodeError::TooLong { length, max } => write!(
+                f,
+                "The message has a Content-Length of {} bytes, more than the maximum of {} bytes",
+                length, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// The largest `Content-Length` which a decoder accepts unless configured otherwise
+pub const DEFAULT_MAX_CONTENT_LENGTH: usize = 16 * 1024 * 1024;
+
 pub struct LanguageServerDecoder {
     state: AnySendPartialState,
+    stats: Arc<PipelineStats>,
+    /// The `Content-Length` of the message whose body is being read
+    content_length: Option<usize>,
+    /// Messages which declare a longer body are rejected before it is buffered
+    max_content_length: usize,
+    /// The bytes of the body of a rejected message which are still to be skipped
+    skip: usize,
 }
 
 impl LanguageServerDecoder {
     pub fn new() -> LanguageServerDecoder {
+        LanguageServerDecoder::with_stats(Default::default())
+    }
+
+    /// Creates a decoder which records the messages it decodes in `stats`
+    pub fn with_stats(stats: Arc<PipelineStats>) -> LanguageServerDecoder {
         LanguageServerDecoder {
             state: Default::default(),
+            stats,
+            content_length: None,
+            max_content_length: DEFAULT_MAX_CONTENT_LENGTH,
+            skip: 0,
         }
     }
+
+    /// Sets the largest `Content-Length` which is accepted
+    pub fn with_max_content_length(mut self, max_content_length: usize) -> LanguageServerDecoder {
+        self.max_content_length = max_content_length;
+        self
+    }
+}
+
+/// The headers of a message which the server cares about, collected from the header lines
+#[derive(Default)]
+struct Headers {
+    content_length: Option<usize>,
+}
+
+impl Extend<Option<usize>> for Headers {
+    fn extend<T: IntoIterator<Item = Option<usize>>>(&mut self, iter: T) {
+        for content_length in iter.into_iter().flatten() {
+            self.content_length = Some(content_length);
+        }
+    }
+}
+
+/// Parses a `Key: Value\r\n` header line into the length it declares if it is the
+/// `Content-Length` header. Other headers, such as `Content-Type`, are ignored. Header names are
+/// compared without regard to case.
+fn header_parser<'a, I>(
+) -> impl Parser<I, Output = Option<usize>, PartialState = AnySendPartialState> + 'a
+where
+    I: RangeStream<Token = u8, Range = &'a [u8]> + 'a,
+    // Necessary due to rust-lang/rust#24159
+    I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+    // Partial states must not borrow the input so every part is mapped to an owned value at once
+    any_send_partial_state(
+        (
+            take_while1(|b: u8| b != b':' && b != b'\r' && b != b'\n')
+                .map(|name: &[u8]| name.eq_ignore_ascii_case(b"Content-Length")),
+            range(&b":"[..]).map(|_| ()),
+            take_while(|b: u8| b != b'\r' && b != b'\n').map(|value: &[u8]| {
+                str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.trim().parse::<usize>().ok())
+            }),
+            range(&b"\r\n"[..]).map(|_| ()),
+        )
+            .and_then(|(is_content_length, _, length, _)| {
+                if !is_content_length {
+                    return Ok(None);
+                }
+                length.map(Some).ok_or_else(|| {
+                    StreamErrorFor::<I>::message_static_message("Invalid Content-Length")
+                })
+            }),
+    )
 }
 
 /// Parses blocks of data with length headers
 ///
 /// ```ignore
 /// Content-Length: 18
+/// Content-Type: application/vscode-jsonrpc; charset=utf-8
 ///
 /// { "some": "data" }
 /// ```
+///
+/// The headers may come in any order. The length is stored in `declared_length` once the header
+/// block has been parsed. A length above `max_length` stops after the headers and outputs `None`
+/// instead of the body.
 fn decode_parser<'a, I>(
-) -> impl Parser<I, Output = Vec<u8>, PartialState = AnySendPartialState> + 'a
+    declared_length: &'a mut Option<usize>,
+    max_length: usize,
+) -> impl Parser<I, Output = Option<Vec<u8>>, PartialState = AnySendPartialState> + 'a
 where
     I: RangeStream<Token = u8, Range = &'a [u8]> + 'a,
     // Necessary due to rust-lang/rust#24159
     I::Error: ParseError<I::Token, I::Range, I::Position>,
 {
-    let content_length =
-        range(&b"Content-Length: "[..]).with(from_str(take_while1(|b: u8| b.is_ascii_digit())));
-
-    any_send_partial_state(
-        (
-            skip_many(range(&b"\r\n"[..])),
-            content_length,
-            range(&b"\r\n\r\n"[..]).map(|_| ()),
-        )
-            .then_partial(|&mut (_, message_length, _)| {
-                take(message_length).map(|bytes: &[u8]| bytes.to_owned())
-            }),
+    let headers = (
+        skip_many(range(&b"\r\n"[..])),
+        many1(header_parser()),
+        range(&b"\r\n"[..]).map(|_| ()),
     )
+        .and_then(|(_, headers, _): (_, Headers, _)| {
+            headers.content_length.ok_or_else(|| {
+                StreamErrorFor::<I>::message_static_message("Missing Content-Length header")
+            })
+        });
+
+    any_send_partial_state(headers.then_partial(move |&mut message_length| {
+        *declared_length = Some(message_length);
+        if message_length > max_length {
+            value(None).left()
+        } else {
+            take(message_length)
+                .map(|bytes: &[u8]| Some(bytes.to_owned()))
+                .right()
+        }
+    }))
 }
 
 impl Decoder for LanguageServerDecoder {
@@ -289,8 +724,19 @@ impl Decoder for LanguageServerDecoder {
     type Error = anyhow::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let (opt, removed_len) = combine::stream::decode(
-            decode_parser(),
+        if self.skip > 0 {
+            let skipped = self.skip.min(src.len());
+            src.advance(skipped);
+            self.skip -= skipped;
+            self.stats.buffered_bytes.store(src.len(), Ordering::SeqCst);
+            if self.skip > 0 {
+                return Ok(None);
+            }
+        }
+
+        let max_content_length = self.max_content_length;
+        let result = combine::stream::decode(
+            decode_parser(&mut self.content_length, max_content_length),
             &mut easy::Stream(PartialStream(&src[..])),
             &mut self.state,
         )
@@ -303,33 +749,419 @@ impl Decoder for LanguageServerDecoder {
                 })
                 .map_position(|p| p.translate_position(&src[..]));
             anyhow!("{}\nIn input: `{}`", err, str::from_utf8(src).unwrap())
-        })?;
+        });
+        let (opt, removed_len) = result?;
 
         src.advance(removed_len);
+        self.stats.buffered_bytes.store(src.len(), Ordering::SeqCst);
+        if removed_len != 0 || opt.is_some() {
+            // The partial state holds the part of the message which has been consumed so far
+            self.stats
+                .partial_frame
+                .store(opt.is_none(), Ordering::SeqCst);
+        }
 
         match opt {
             None => Ok(None),
 
             Some(output) => {
-                let value = String::from_utf8(output)?;
-                Ok(Some(value))
+                let length = self.content_length.take();
+                match output {
+                    Some(output) => {
+                        self.stats.frames_decoded.fetch_add(1, Ordering::SeqCst);
+                        let value = String::from_utf8(output)?;
+                        Ok(Some(value))
+                    }
+                    // The body is skipped as it arrives so that the next message can be read
+                    None => {
+                        let length = length.expect("Content-Length");
+                        self.skip = length;
+                        Err(DecodeError::TooLong {
+                            length,
+                            max: max_content_length,
+                        }
+                        .into())
+                    }
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(value) = self.decode(src)? {
+            return Ok(Some(value));
+        }
+        // The body of a message stays in `src` until all of it has been read
+        match self.content_length {
+            Some(expected) => Err(DecodeError::Truncated {
+                expected,
+                got: src.len(),
             }
+            .into()),
+            None if src.iter().all(|b| b.is_ascii_whitespace()) => Ok(None),
+            None => Err(anyhow!(
+                "The input ended in the middle of a message header: `{}`",
+                String::from_utf8_lossy(src)
+            )),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct LanguageServerEncoder;
 
+impl LanguageServerEncoder {
+    pub fn new() -> LanguageServerEncoder {
+        LanguageServerEncoder
+    }
+}
+
 impl Encoder<String> for LanguageServerEncoder {
     type Error = anyhow::Error;
     fn encode(&mut self, item: String, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        dst.reserve(item.len() + 60); // Ensure Content-Length fits
-        write_message_str(dst.writer(), &item)?;
+        debug!("Respond: {}", item);
+        // Framed directly into `dst` instead of through another buffer which would hold a third
+        // copy of large messages
+        dst.reserve(item.len() + 40); // Ensure Content-Length fits
+        write!(dst.writer(), "Content-Length: {}\r\n\r\n", item.len())?;
+        dst.extend_from_slice(item.as_bytes());
+        Ok(())
+    }
+}
+
+/// Serializes the message straight into `dst` without building its JSON string first
+impl Encoder<OutgoingMessage> for LanguageServerEncoder {
+    type Error = anyhow::Error;
+    fn encode(&mut self, item: OutgoingMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if log_enabled!(log::Level::Debug) {
+            debug!("Respond: {}", item);
+        }
+        write_framed(dst.writer(), &item)?;
         Ok(())
     }
 }
 
+/// A message sent from the server to the client
+#[derive(Clone, Debug)]
+pub enum OutgoingMessage {
+    /// The response to a request of the client
+    Response {
+        id: Id,
+        result: Result<Value, Error>,
+    },
+    Notification {
+        method: String,
+        params: Value,
+    },
+    /// A request to the client, which the client answers with a response carrying the same `id`
+    Request {
+        id: Id,
+        method: String,
+        params: Value,
+    },
+    /// The responses to a batch of requests
+    Batch(Vec<OutgoingMessage>),
+}
+
+impl From<Output> for OutgoingMessage {
+    fn from(output: Output) -> Self {
+        match output {
+            Output::Success(success) => OutgoingMessage::Response {
+                id: success.id,
+                result: Ok(success.result),
+            },
+            Output::Failure(failure) => OutgoingMessage::Response {
+                id: failure.id,
+                result: Err(failure.error),
+            },
+        }
+    }
+}
+
+impl From<Response> for OutgoingMessage {
+    fn from(response: Response) -> Self {
+        match response {
+            Response::Single(output) => output.into(),
+            Response::Batch(outputs) => {
+                OutgoingMessage::Batch(outputs.into_iter().map(Into::into).collect())
+            }
+        }
+    }
+}
+
+impl OutgoingMessage {
+    /// Whether this is a notification with `method`
+    pub fn is_notification(&self, method: &str) -> bool {
+        match self {
+            OutgoingMessage::Notification { method: m, .. } => m == method,
+            _ => false,
+        }
+    }
+}
+
+impl serde::Serialize for OutgoingMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+
+        if let OutgoingMessage::Batch(messages) = self {
+            return serializer.collect_seq(messages);
+        }
+
+        // Serialized field by field since building the envelope as a `Value` would copy the
+        // result or parameters
+        let mut message = serializer.serialize_map(None)?;
+        message.serialize_entry("jsonrpc", "2.0")?;
+        match self {
+            OutgoingMessage::Response { id, result } => {
+                match result {
+                    Ok(result) => message.serialize_entry("result", result)?,
+                    Err(error) => message.serialize_entry("error", error)?,
+                }
+                message.serialize_entry("id", id)?;
+            }
+            OutgoingMessage::Notification { method, params } => {
+                message.serialize_entry("method", method)?;
+                // `params` may be omitted but may not be `null`
+                if !params.is_null() {
+                    message.serialize_entry("params", params)?;
+                }
+            }
+            OutgoingMessage::Request { id, method, params } => {
+                message.serialize_entry("id", id)?;
+                message.serialize_entry("method", method)?;
+                if !params.is_null() {
+                    message.serialize_entry("params", params)?;
+                }
+            }
+            OutgoingMessage::Batch(_) => unreachable!(),
+        }
+        message.end()
+    }
+}
+
+impl fmt::Display for OutgoingMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", to_string(self).map_err(|_| fmt::Error)?)
+    }
+}
+
+/// Serializes each `OutgoingMessage` into its JSON-RPC envelope and writes it, framed, straight
+/// into `output` without building its JSON string first
+pub struct SerializeMessages<W> {
+    output: W,
+}
+
+pub fn serialize_messages<W>(output: W) -> SerializeMessages<W>
+where
+    W: Write + Unpin,
+{
+    SerializeMessages { output }
+}
+
+impl<W> SerializeMessages<W> {
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+impl<W> Sink<OutgoingMessage> for SerializeMessages<W>
+where
+    W: Write + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        _: &mut task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: OutgoingMessage) -> Result<(), Self::Error> {
+        write_framed(&mut self.output, &item)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        _: &mut task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(self.output.flush())
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Calls a function with each item before forwarding it to another sink, for side effects such as
+/// logging or recording the items
+pub struct InspectSink<S, F> {
+    sink: S,
+    f: F,
+}
+
+pub fn inspect_sink<S, F, T>(sink: S, f: F) -> InspectSink<S, F>
+where
+    S: Sink<T> + Unpin,
+    F: FnMut(&T) + Unpin,
+{
+    InspectSink { sink, f }
+}
+
+impl<S, F> InspectSink<S, F> {
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<S, F, T> Sink<T> for InspectSink<S, F>
+where
+    S: Sink<T> + Unpin,
+    F: FnMut(&T) + Unpin,
+{
+    type Error = S::Error;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.sink.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        (self.f)(&item);
+        self.sink.start_send_unpin(item)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.sink.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.sink.poll_close_unpin(cx)
+    }
+}
+
+/// Identifies the transport which a message arrived on so that the response can be routed back
+/// to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TransportId(pub usize);
+
+/// A message along with the transport it arrived on
+#[derive(Debug, PartialEq)]
+pub struct Tagged<T> {
+    pub transport: TransportId,
+    pub value: T,
+}
+
+/// Merges the messages from several transports into one stream. The transports are polled
+/// round-robin so that a busy transport can not starve the others.
+pub struct MergeTransports<S> {
+    transports: Vec<Option<S>>,
+    next: usize,
+}
+
+/// Merges `transports`, tagging each message with the index of the transport it came from
+pub fn merge_transports<S>(transports: impl IntoIterator<Item = S>) -> MergeTransports<S>
+where
+    S: Stream + Unpin,
+{
+    MergeTransports {
+        transports: transports.into_iter().map(Some).collect(),
+        next: 0,
+    }
+}
+
+impl<S> Stream for MergeTransports<S>
+where
+    S: Stream + Unpin,
+{
+    type Item = Tagged<S::Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let len = self.transports.len();
+        let mut pending = false;
+        for offset in 0..len {
+            let index = (self.next + offset) % len;
+            let poll = match &mut self.transports[index] {
+                Some(transport) => transport.poll_next_unpin(cx),
+                None => continue,
+            };
+            match poll {
+                Poll::Ready(Some(value)) => {
+                    // Start with the following transport next time
+                    self.next = (index + 1) % len;
+                    return Poll::Ready(Some(Tagged {
+                        transport: TransportId(index),
+                        value,
+                    }));
+                }
+                Poll::Ready(None) => self.transports[index] = None,
+                Poll::Pending => pending = true,
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(None)
+        }
+    }
+}
+
+type DocumentWork = future::Shared<future::BoxFuture<'static, ()>>;
+
+/// Runs the work on each document (such as applying `textDocument/didChange`) in the order the
+/// client sent it while the work on different documents runs concurrently. Requests wait with
+/// `settled` so that they see the changes the client sent before them.
+#[derive(Clone, Default)]
+pub struct DocumentOrder(Arc<Mutex<FnvMap<Url, DocumentWork>>>);
+
+impl DocumentOrder {
+    /// Spawns `work` to run once the work which was spawned earlier for `uri` has finished
+    pub fn spawn<F>(&self, uri: Url, work: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut documents = self.0.lock().unwrap();
+        let previous = documents.remove(&uri);
+        let next = async move {
+            if let Some(previous) = previous {
+                previous.await;
+            }
+            // A panic must not reach the requests which wait for the work
+            if let Err(err) = std::panic::AssertUnwindSafe(work).catch_unwind().await {
+                error!("{:?}", err);
+            }
+        }
+        .boxed()
+        .shared();
+        documents.insert(uri, next.clone());
+        tokio::spawn(next);
+    }
+
+    /// Waits until the work which has been spawned for `uri` has finished
+    pub fn settled(&self, uri: &Url) -> impl Future<Output = ()> {
+        let work = self.0.lock().unwrap().get(uri).cloned();
+        async move {
+            if let Some(work) = work {
+                work.await;
+            }
+        }
+    }
+}
+
 pub struct Entry<K, V, W> {
     pub key: K,
     pub value: V,
@@ -438,3 +1270,568 @@ impl<K, V, W> Sink<Entry<K, V, W>> for UniqueSink<K, V, W> {
         Pin::new(&mut self.sender).poll_close(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use futures::{executor::block_on, stream};
+
+    fn tagged(transport: usize, value: &str) -> Tagged<String> {
+        Tagged {
+            transport: TransportId(transport),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn merge_transports_round_robin() {
+        let transports = vec![
+            stream::iter(vec!["a1", "a2", "a3"]).boxed(),
+            stream::iter(vec!["b1"]).boxed(),
+            stream::pending().boxed(),
+            stream::iter(vec!["d1", "d2"]).boxed(),
+        ];
+        let merged = merge_transports(transports).map(|tagged| Tagged {
+            transport: tagged.transport,
+            value: tagged.value.to_string(),
+        });
+
+        assert_eq!(
+            block_on(merged.take(6).collect::<Vec<_>>()),
+            vec![
+                tagged(0, "a1"),
+                tagged(1, "b1"),
+                tagged(3, "d1"),
+                tagged(0, "a2"),
+                tagged(3, "d2"),
+                tagged(0, "a3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_params_response_data() {
+        let err = block_on(invalid_params_response(
+            "missing field `uri`",
+            Some(&[1, 2]),
+        ))
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidParams);
+        assert_eq!(err.message, "Invalid params: missing field `uri`");
+        assert_eq!(err.data, Some(serde_json::json!([1, 2])));
+
+        let err = block_on(invalid_params_response("", None::<&()>)).unwrap_err();
+        assert_eq!(err.data, None);
+
+        // Maps with non-string keys can't be represented in JSON
+        let unserializable = std::iter::once(((1, 2), 3)).collect::<BTreeMap<_, _>>();
+        let err = block_on(invalid_params_response("", Some(&unserializable))).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidParams);
+        assert_eq!(err.data, None);
+    }
+
+    #[test]
+    fn unserializable_result_is_an_error_response() {
+        let mut io = jsonrpc_core::IoHandler::new();
+        // Maps with non-string keys can't be represented in JSON
+        io.add_method(
+            "unserializable",
+            ServerCommand::method(|()| async {
+                Ok::<_, ServerError<()>>(std::iter::once(((1, 2), 3)).collect::<BTreeMap<_, _>>())
+            }),
+        );
+
+        let response = block_on(io.handle_request(
+            r#"{ "jsonrpc": "2.0", "id": 1, "method": "unserializable", "params": null }"#,
+        ))
+        .expect("Response");
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["error"]["code"], -32603);
+        assert_eq!(
+            response["error"]["message"],
+            "The result could not be serialized: key must be a string"
+        );
+    }
+
+    #[test]
+    fn command_chooses_the_error_code() {
+        let mut io = jsonrpc_core::IoHandler::new();
+        io.add_method(
+            "outdated",
+            ServerCommand::method(|()| async {
+                Err::<(), _>(ServerError::<()> {
+                    code: CONTENT_MODIFIED,
+                    message: "The document changed".into(),
+                    data: None,
+                })
+            }),
+        );
+        io.add_method(
+            "failing",
+            ServerCommand::method(|()| async { Err::<(), ServerError<()>>("Failed".into()) }),
+        );
+
+        let response = |method: &str| {
+            let request = format!(
+                r#"{{ "jsonrpc": "2.0", "id": 1, "method": "{}", "params": null }}"#,
+                method
+            );
+            let response = block_on(io.handle_request(&request)).expect("Response");
+            serde_json::from_str::<Value>(&response).unwrap()
+        };
+        assert_eq!(response("outdated")["error"]["code"], -32801);
+        // Errors converted with `?` or `into` are internal errors
+        assert_eq!(response("failing")["error"]["code"], -32603);
+    }
+
+    fn serialize(message: OutgoingMessage) -> Value {
+        let mut sink = serialize_messages(Vec::new());
+        block_on(sink.send(message)).unwrap();
+        let output = String::from_utf8(sink.into_inner()).unwrap();
+        let (header, json) = output.split_once("\r\n\r\n").unwrap();
+        assert_eq!(header, format!("Content-Length: {}", json.len()));
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn serialize_response() {
+        let message = OutgoingMessage::Response {
+            id: Id::Num(1),
+            result: Ok(serde_json::json!({ "value": 2 })),
+        };
+        assert_eq!(
+            serialize(message),
+            serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": { "value": 2 } })
+        );
+
+        // `null` is a result of its own and must not be left out
+        let message = OutgoingMessage::Response {
+            id: Id::Str("a".into()),
+            result: Ok(Value::Null),
+        };
+        assert_eq!(
+            serialize(message),
+            serde_json::json!({ "jsonrpc": "2.0", "id": "a", "result": null })
+        );
+    }
+
+    #[test]
+    fn serialize_error_response() {
+        let message = OutgoingMessage::Response {
+            id: Id::Num(2),
+            result: Err(Error::method_not_found()),
+        };
+        assert_eq!(
+            serialize(message),
+            serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "error": { "code": -32601, "message": "Method not found" },
+            })
+        );
+    }
+
+    #[test]
+    fn serialize_notification() {
+        let message = OutgoingMessage::Notification {
+            method: "window/logMessage".into(),
+            params: serde_json::json!({ "type": 4, "message": "abc" }),
+        };
+        assert_eq!(
+            serialize(message),
+            serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "window/logMessage",
+                "params": { "type": 4, "message": "abc" },
+            })
+        );
+
+        // Notifications have no `id`, not even a `null` one
+        let message = OutgoingMessage::Notification {
+            method: "exit".into(),
+            params: Value::Null,
+        };
+        assert_eq!(
+            serialize(message),
+            serde_json::json!({ "jsonrpc": "2.0", "method": "exit" })
+        );
+    }
+
+    #[test]
+    fn serialize_request() {
+        let message = OutgoingMessage::Request {
+            id: Id::Str("token".into()),
+            method: "window/workDoneProgress/create".into(),
+            params: serde_json::json!({ "token": "token" }),
+        };
+        assert_eq!(
+            serialize(message),
+            serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": "token",
+                "method": "window/workDoneProgress/create",
+                "params": { "token": "token" },
+            })
+        );
+    }
+
+    #[test]
+    fn inspect_sink_forwards_every_item() {
+        let mut inspected = Vec::new();
+        let mut sink = inspect_sink(Vec::<u32>::new(), |item: &u32| inspected.push(*item));
+        block_on(sink.send_all(&mut stream::iter(vec![Ok(1), Ok(2), Ok(3)]))).unwrap();
+        block_on(sink.close()).unwrap();
+        assert_eq!(sink.into_inner(), vec![1, 2, 3]);
+        assert_eq!(inspected, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn merge_transports_ends_with_the_last_transport() {
+        let transports = vec![stream::iter(vec!["a1"]), stream::iter(vec![])];
+        let merged = merge_transports(transports).map(|tagged| tagged.value);
+        assert_eq!(block_on(merged.collect::<Vec<_>>()), vec!["a1"]);
+    }
+
+    fn entry(key: &'static str, version: u32) -> Entry<&'static str, u32, u32> {
+        Entry {
+            key,
+            value: version * 10,
+            version,
+        }
+    }
+
+    fn next_entry(
+        stream: &mut UniqueStream<&'static str, u32, u32>,
+    ) -> Option<Option<(&'static str, u32, u32)>> {
+        stream
+            .next()
+            .now_or_never()
+            .map(|entry| entry.map(|entry| (entry.key, entry.value, entry.version)))
+    }
+
+    #[test]
+    fn unique_queue_collapses_to_the_latest_version() {
+        let (mut sink, mut stream) = unique_queue();
+        for version in 1..=3 {
+            block_on(sink.send(entry("a", version))).unwrap();
+        }
+
+        assert_eq!(next_entry(&mut stream), Some(Some(("a", 30, 3))));
+        assert_eq!(next_entry(&mut stream), None);
+    }
+
+    #[test]
+    fn unique_queue_ignores_stale_versions() {
+        let (mut sink, mut stream) = unique_queue();
+        block_on(sink.send(entry("a", 2))).unwrap();
+        block_on(sink.send(entry("a", 1))).unwrap();
+        // An equal version does not replace the queued entry either
+        block_on(sink.send(Entry {
+            key: "a",
+            value: 0,
+            version: 2,
+        }))
+        .unwrap();
+
+        assert_eq!(next_entry(&mut stream), Some(Some(("a", 20, 2))));
+        assert_eq!(next_entry(&mut stream), None);
+    }
+
+    #[test]
+    fn unique_queue_keeps_distinct_keys_in_order() {
+        let (mut sink, mut stream) = unique_queue();
+        block_on(sink.send(entry("a", 1))).unwrap();
+        block_on(sink.send(entry("b", 1))).unwrap();
+        block_on(sink.send(entry("c", 1))).unwrap();
+        // Replacing an entry keeps its position in the queue
+        block_on(sink.send(entry("a", 2))).unwrap();
+
+        assert_eq!(next_entry(&mut stream), Some(Some(("a", 20, 2))));
+        assert_eq!(next_entry(&mut stream), Some(Some(("b", 10, 1))));
+        assert_eq!(next_entry(&mut stream), Some(Some(("c", 10, 1))));
+        assert_eq!(next_entry(&mut stream), None);
+    }
+
+    #[test]
+    fn unique_queue_interleaved_with_polling() {
+        let (mut sink, mut stream) = unique_queue();
+        block_on(sink.send(entry("a", 1))).unwrap();
+        block_on(sink.send(entry("b", 1))).unwrap();
+        assert_eq!(next_entry(&mut stream), Some(Some(("a", 10, 1))));
+
+        // `a` has already been taken from the queue so a new version is queued again, after `b`
+        block_on(sink.send(entry("a", 2))).unwrap();
+        block_on(sink.send(entry("b", 2))).unwrap();
+        assert_eq!(next_entry(&mut stream), Some(Some(("b", 20, 2))));
+        assert_eq!(next_entry(&mut stream), Some(Some(("a", 20, 2))));
+        assert_eq!(next_entry(&mut stream), None);
+
+        block_on(sink.send(entry("a", 3))).unwrap();
+        assert_eq!(next_entry(&mut stream), Some(Some(("a", 30, 3))));
+    }
+
+    #[test]
+    fn unique_queue_ends_after_the_queued_entries() {
+        let (mut sink, mut stream) = unique_queue();
+        block_on(sink.send(entry("a", 1))).unwrap();
+        block_on(sink.send(entry("b", 1))).unwrap();
+        block_on(sink.send(entry("a", 2))).unwrap();
+        drop(sink);
+
+        assert_eq!(next_entry(&mut stream), Some(Some(("a", 20, 2))));
+        assert_eq!(next_entry(&mut stream), Some(Some(("b", 10, 1))));
+        assert_eq!(next_entry(&mut stream), Some(None));
+        assert_eq!(next_entry(&mut stream), Some(None));
+    }
+
+    #[test]
+    fn write_message_into_reused_buffer() {
+        let mut buf = String::new();
+        write_message_into(&mut buf, "{}");
+        assert_eq!(buf, "Content-Length: 2\r\n\r\n{}");
+
+        buf.clear();
+        let capacity = buf.capacity();
+        write_message_into(&mut buf, "[]");
+        assert_eq!(buf, "Content-Length: 2\r\n\r\n[]");
+        assert_eq!(buf.capacity(), capacity);
+    }
+
+    #[test]
+    fn write_message_streams_the_framed_message() {
+        let value = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": [1, 2, 3] });
+        let mut output = Vec::new();
+        write_message(&mut output, &value).unwrap();
+
+        let mut expected = String::new();
+        write_message_into(&mut expected, &value.to_string());
+        assert_eq!(str::from_utf8(&output).unwrap(), expected);
+    }
+
+    #[test]
+    fn write_message_str_frames_like_write_message_into() {
+        let response = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        let mut output = Vec::new();
+        write_message_str(&mut output, response).unwrap();
+
+        let mut expected = String::new();
+        write_message_into(&mut expected, response);
+        assert_eq!(str::from_utf8(&output).unwrap(), expected);
+    }
+
+    #[test]
+    fn encode_outgoing_message_without_serializing_it_first() {
+        let message = OutgoingMessage::Response {
+            id: Id::Num(1),
+            result: Ok(serde_json::json!({ "data": [0, 4, 3, 1, 0] })),
+        };
+        let mut streamed = BytesMut::new();
+        LanguageServerEncoder::new()
+            .encode(message.clone(), &mut streamed)
+            .unwrap();
+        let mut framed = BytesMut::new();
+        LanguageServerEncoder::new()
+            .encode(message.to_string(), &mut framed)
+            .unwrap();
+        assert_eq!(streamed, framed);
+    }
+
+    #[test]
+    fn handle_request_responds_like_the_handler() {
+        let mut io = IoHandler::new();
+        io.add_method(
+            "echo",
+            |params: Params| async move { params.parse::<Value>() },
+        );
+
+        let requests = [
+            r#"{"jsonrpc":"2.0","id":1,"method":"echo","params":[1,2]}"#,
+            r#"{"jsonrpc":"2.0","id":"a","method":"missing"}"#,
+            r#"[{"jsonrpc":"2.0","id":2,"method":"echo","params":{"a":1}}]"#,
+            r#"{"jsonrpc":"2.0","#,
+        ];
+        for request in &requests {
+            let expected = block_on(io.handle_request(request)).unwrap();
+            let response = block_on(handle_request(&io, request)).unwrap();
+            assert_eq!(
+                to_value(&response).unwrap(),
+                serde_json::from_str::<Value>(&expected).unwrap(),
+                "{}",
+                request
+            );
+        }
+
+        let notification = r#"{"jsonrpc":"2.0","method":"echo"}"#;
+        assert!(block_on(handle_request(&io, notification)).is_none());
+    }
+
+    #[test]
+    fn decoder_records_partial_frames() {
+        let stats = Arc::new(PipelineStats::default());
+        let mut decoder = LanguageServerDecoder::with_stats(stats.clone());
+
+        let mut src = BytesMut::from(&b"Content-Length: 4\r\n\r\n12"[..]);
+        assert_eq!(decoder.decode(&mut src).unwrap(), None);
+        assert!(stats.partial_frame());
+        assert_eq!(stats.frames_decoded(), 0);
+
+        src.extend_from_slice(b"34Content-Le");
+        assert_eq!(decoder.decode(&mut src).unwrap(), Some("1234".to_string()));
+        assert!(!stats.partial_frame());
+        assert_eq!(stats.frames_decoded(), 1);
+        assert_eq!(stats.dispatch_queue_depth(), 1);
+
+        stats.dispatched();
+        assert_eq!(stats.dispatch_queue_depth(), 0);
+    }
+
+    #[test]
+    fn decoder_accepts_headers_in_any_order() {
+        let content_type = "Content-Type: application/vscode-jsonrpc; charset=utf-8\r\n";
+        for headers in [
+            format!("Content-Length: 2\r\n{}\r\n", content_type),
+            format!("{}Content-Length: 2\r\n\r\n", content_type),
+            "content-length:2\r\n\r\n".to_string(),
+        ] {
+            let mut decoder = LanguageServerDecoder::new();
+            let mut src = BytesMut::from(format!("{}{{}}", headers).as_bytes());
+            assert_eq!(
+                decoder.decode(&mut src).unwrap(),
+                Some("{}".to_string()),
+                "{:?}",
+                headers
+            );
+            assert!(src.is_empty());
+        }
+    }
+
+    #[test]
+    fn decoder_resumes_headers_split_across_reads() {
+        let mut decoder = LanguageServerDecoder::new();
+
+        let mut src = BytesMut::from(&b"Content-Type: application/vscode-json"[..]);
+        assert_eq!(decoder.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(b"rpc; charset=utf-8\r\nContent-Len");
+        assert_eq!(decoder.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(b"gth: 2\r\n\r\n[]");
+        assert_eq!(decoder.decode(&mut src).unwrap(), Some("[]".to_string()));
+    }
+
+    #[test]
+    fn decoder_requires_content_length() {
+        let mut decoder = LanguageServerDecoder::new();
+
+        let mut src = BytesMut::from(&b"Content-Type: application/vscode-jsonrpc\r\n\r\n{}"[..]);
+        assert!(decoder.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn decoder_rejects_content_length_over_the_maximum() {
+        let mut decoder = LanguageServerDecoder::new().with_max_content_length(4);
+        let mut src = BytesMut::from(&b"Content-Length: 4\r\n\r\n1234"[..]);
+        assert_eq!(decoder.decode(&mut src).unwrap(), Some("1234".to_string()));
+
+        let mut decoder = LanguageServerDecoder::new().with_max_content_length(4);
+        let mut src = BytesMut::from(&b"Content-Length: 5\r\n\r\n12345"[..]);
+        let err = decoder.decode(&mut src).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DecodeError>(),
+            Some(&DecodeError::TooLong { length: 5, max: 4 })
+        );
+
+        // The body of an overlong message is never waited for
+        let mut decoder = LanguageServerDecoder::new();
+        let mut src = BytesMut::from(&b"Content-Length: 99999999999\r\n\r\n"[..]);
+        let err = decoder.decode(&mut src).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DecodeError>(),
+            Some(&DecodeError::TooLong {
+                length: 99999999999,
+                max: DEFAULT_MAX_CONTENT_LENGTH
+            })
+        );
+    }
+
+    #[test]
+    fn decoder_skips_the_body_of_an_overlong_message() {
+        let mut decoder = LanguageServerDecoder::new().with_max_content_length(4);
+
+        let mut src = BytesMut::from(&b"Content-Length: 10\r\n\r\n12345"[..]);
+        let err = decoder.decode(&mut src).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DecodeError>(),
+            Some(&DecodeError::TooLong { length: 10, max: 4 })
+        );
+        assert_eq!(decoder.decode(&mut src).unwrap(), None);
+        assert_eq!(src.len(), 0);
+
+        src.extend_from_slice(b"67890Content-Length: 2\r\n\r\n{}");
+        assert_eq!(decoder.decode(&mut src).unwrap(), Some("{}".to_string()));
+        assert_eq!(decoder.decode_eof(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn decoder_reports_truncated_body() {
+        let mut decoder = LanguageServerDecoder::new();
+
+        let mut src = BytesMut::from(&b"Content-Length: 10\r\n\r\n{}\r\n"[..]);
+        assert_eq!(decoder.decode(&mut src).unwrap(), None);
+        let err = decoder.decode_eof(&mut src).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DecodeError>(),
+            Some(&DecodeError::Truncated {
+                expected: 10,
+                got: 4
+            })
+        );
+    }
+
+    #[test]
+    fn decoder_ends_after_complete_message() {
+        let mut decoder = LanguageServerDecoder::new();
+
+        let mut src = BytesMut::from(&b"Content-Length: 2\r\n\r\n{}"[..]);
+        assert_eq!(
+            decoder.decode_eof(&mut src).unwrap(),
+            Some("{}".to_string())
+        );
+        assert_eq!(decoder.decode_eof(&mut src).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn document_order_runs_work_in_order() {
+        let order = DocumentOrder::default();
+        let uri = Url::parse("file:///test.glu").unwrap();
+        let done = Arc::new(Mutex::new(Vec::new()));
+        {
+            let done = done.clone();
+            order.spawn(uri.clone(), async move {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                done.lock().unwrap().push(1);
+            });
+        }
+        {
+            let done = done.clone();
+            order.spawn(uri.clone(), async move { done.lock().unwrap().push(2) });
+        }
+        order.settled(&uri).await;
+        assert_eq!(*done.lock().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn from_params_without_parameters() {
+        let empty = || Params::Map(Default::default());
+        for params in [Params::None, empty()] {
+            from_params::<()>(params.clone()).unwrap();
+            from_params::<lsp_types::InitializedParams>(params.clone()).unwrap();
+            assert_eq!(from_params::<Option<u32>>(params).ok(), Some(None));
+        }
+
+        assert!(from_params::<lsp_types::HoverParams>(Params::None).is_err());
+        assert!(from_params::<lsp_types::HoverParams>(empty()).is_err());
+    }
+}
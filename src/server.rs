@@ -1,4 +1,11 @@
-use std::sync::{Mutex, RwLock};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
 
 use {
     anyhow::anyhow,
@@ -14,7 +21,9 @@ use gluon::{import::Import, RootedThread};
 
 use crate::{
     check_importer::CheckImporter,
+    module_loader::{self, ModuleLoader},
     rpc::{self, *},
+    startup::{self, StartupTimingsRef},
 };
 
 pub trait Handler {
@@ -64,15 +73,653 @@ macro_rules! notification {
 
 pub type ShutdownReceiver = future::Shared<futures::future::BoxFuture<'static, ()>>;
 
+/// The capabilities the client sent in `initialize`. Defaults to no capabilities until the client
+/// has initialized the server.
+pub(crate) type ClientCapabilitiesRef = Arc<RwLock<ClientCapabilities>>;
+
+#[derive(Debug, Default)]
+pub struct ClientCapabilities {
+    pub(crate) lsp: lsp_types::ClientCapabilities,
+    /// The `CompletionList.itemDefaults` properties which the client supports (LSP 3.17, which
+    /// `lsp_types` does not know about yet)
+    pub(crate) completion_item_defaults: Vec<String>,
+    /// The properties of `WorkspaceSymbol` which the client can resolve with
+    /// `workspaceSymbol/resolve` (LSP 3.17)
+    pub(crate) workspace_symbol_resolve_properties: Vec<String>,
+}
+
+/// Queries for the features which not every client supports. Handlers check these before sending
+/// anything beyond the basic form of a response.
+impl ClientCapabilities {
+    fn completion_item(&self) -> Option<&lsp_types::CompletionItemCapability> {
+        self.lsp
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.completion.as_ref())
+            .and_then(|completion| completion.completion_item.as_ref())
+    }
+
+    fn publish_diagnostics(&self) -> Option<&lsp_types::PublishDiagnosticsClientCapabilities> {
+        self.lsp
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.publish_diagnostics.as_ref())
+    }
+
+    /// Whether completion items may have `insertTextFormat: Snippet`
+    pub(crate) fn supports_snippets(&self) -> bool {
+        self.completion_item()
+            .and_then(|completion_item| completion_item.snippet_support)
+            .unwrap_or(false)
+    }
+
+    /// Whether completion items may have `labelDetails`
+    pub(crate) fn supports_label_details(&self) -> bool {
+        self.completion_item()
+            .and_then(|completion_item| completion_item.label_details_support)
+            .unwrap_or(false)
+    }
+
+    /// Whether completion items may be tagged as deprecated
+    pub(crate) fn supports_deprecated_completion_tag(&self) -> bool {
+        self.completion_item()
+            .and_then(|completion_item| completion_item.tag_support.as_ref())
+            .is_some_and(|tag_support| {
+                tag_support
+                    .value_set
+                    .contains(&lsp_types::CompletionItemTag::Deprecated)
+            })
+    }
+
+    /// Whether completion items may be preselected
+    pub(crate) fn supports_preselect(&self) -> bool {
+        self.completion_item()
+            .and_then(|completion_item| completion_item.preselect_support)
+            .unwrap_or(false)
+    }
+
+    /// Whether the documentation of completion items may be markdown
+    pub(crate) fn supports_markdown_completion(&self) -> bool {
+        supports_markdown(
+            self.completion_item()
+                .and_then(|completion_item| completion_item.documentation_format.as_ref()),
+        )
+    }
+
+    /// Whether the documentation of signatures may be markdown
+    pub(crate) fn supports_markdown_signature_help(&self) -> bool {
+        supports_markdown(
+            self.lsp
+                .text_document
+                .as_ref()
+                .and_then(|text_document| text_document.signature_help.as_ref())
+                .and_then(|signature_help| signature_help.signature_information.as_ref())
+                .and_then(|information| information.documentation_format.as_ref()),
+        )
+    }
+
+    /// Whether hovers may contain markdown
+    pub(crate) fn supports_markdown_hover(&self) -> bool {
+        supports_markdown(
+            self.lsp
+                .text_document
+                .as_ref()
+                .and_then(|text_document| text_document.hover.as_ref())
+                .and_then(|hover| hover.content_format.as_ref()),
+        )
+    }
+
+    /// Whether diagnostics may have `tags`
+    pub(crate) fn supports_diagnostic_tags(&self) -> bool {
+        self.publish_diagnostics()
+            .is_some_and(|publish| publish.tag_support.is_some())
+    }
+
+    /// Whether diagnostics may have `relatedInformation`
+    pub(crate) fn supports_related_information(&self) -> bool {
+        self.publish_diagnostics()
+            .and_then(|publish| publish.related_information)
+            .unwrap_or(false)
+    }
+
+    /// Whether diagnostics may have `codeDescription`
+    pub(crate) fn supports_code_description(&self) -> bool {
+        self.publish_diagnostics()
+            .and_then(|publish| publish.code_description_support)
+            .unwrap_or(false)
+    }
+
+    /// Whether the server may create progress tokens with `window/workDoneProgress/create`
+    pub(crate) fn supports_work_done_progress(&self) -> bool {
+        self.lsp
+            .window
+            .as_ref()
+            .and_then(|window| window.work_done_progress)
+            .unwrap_or(false)
+    }
+
+    /// The kinds of code actions which the client knows about. `None` if the client does not list
+    /// them
+    pub(crate) fn code_action_kinds(&self) -> Option<&[String]> {
+        self.lsp
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.code_action.as_ref())
+            .and_then(|code_action| code_action.code_action_literal_support.as_ref())
+            .map(|literal_support| &literal_support.code_action_kind.value_set[..])
+    }
+
+    /// Whether `workspace/symbol` may leave out the range of symbols for
+    /// `workspaceSymbol/resolve` to compute
+    pub(crate) fn supports_workspace_symbol_location_resolve(&self) -> bool {
+        self.workspace_symbol_resolve_properties
+            .iter()
+            .any(|property| property == "location.range")
+    }
+
+    /// Whether the server may edit documents with `workspace/applyEdit`
+    pub(crate) fn supports_apply_edit(&self) -> bool {
+        self.lsp
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.apply_edit)
+            .unwrap_or(false)
+    }
+}
+
+fn supports_markdown(formats: Option<&Vec<lsp_types::MarkupKind>>) -> bool {
+    formats.is_some_and(|formats| formats.contains(&lsp_types::MarkupKind::Markdown))
+}
+
+/// Settings for running the server
+pub struct ServerOptions {
+    /// Provides the source of imported modules which are not open in the editor. Modules which no
+    /// loader provides are read from the import paths.
+    pub loaders: Vec<Box<dyn ModuleLoader>>,
+    /// Shut down if no message is received for this long
+    pub idle_timeout: Option<Duration>,
+    /// Publish the errors of the modules that a checked module imports to the imported modules'
+    /// own files
+    pub dependency_diagnostics: bool,
+    /// When messages written to the output are flushed
+    pub flush_strategy: FlushStrategy,
+    /// Send a `$/gluon/keepalive` notification when no message has been read or written for this
+    /// long, so that connections which drop when idle are kept open
+    pub keepalive: Option<Duration>,
+    /// Bytes of the buffer which messages are read into, and so the most which is asked of the
+    /// input at once. Larger messages grow the buffer.
+    pub read_buffer_size: usize,
+    /// Print how long each phase of starting took to stderr
+    pub measure_startup: bool,
+    /// Accept messages whose `jsonrpc` field is missing or is not the string `"2.0"`, as sent by
+    /// some non-conformant clients, instead of rejecting them as invalid requests
+    pub lenient: bool,
+    /// The largest `Content-Length` accepted from the client. The body of a longer message is
+    /// skipped instead of being buffered and the message is answered with an error.
+    pub max_content_length: usize,
+    /// How often a module which fails to be read from the import paths with a transient error,
+    /// such as a file which an editor has locked while saving it, is read again
+    pub load_retries: usize,
+}
+
+/// The read buffer size for stdin, where messages are small and arrive one at a time
+pub const STDIO_READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// The read buffer size for a TCP connection, where large messages would otherwise take many
+/// small reads
+pub const TCP_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Decides when the messages written to the output are flushed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlushStrategy {
+    /// Flush after every message
+    #[default]
+    Immediate,
+    /// Flush once the given time has passed since the first message which has not been flushed
+    Coalesce(Duration),
+    /// Flush when there are no more messages waiting to be written
+    OnIdle,
+}
+
+const KEEPALIVE_METHOD: &str = "$/gluon/keepalive";
+
+/// How many messages are read ahead, while a request is handled, to find a cancellation of it
+const READ_AHEAD: usize = 64;
+
+/// Tracks when a message last went over the connection in either direction
+#[derive(Clone)]
+struct Keepalive {
+    interval: Duration,
+    last_activity: Arc<Mutex<tokio::time::Instant>>,
+}
+
+impl Keepalive {
+    fn new(interval: Duration) -> Self {
+        Keepalive {
+            interval,
+            last_activity: Arc::new(Mutex::new(tokio::time::Instant::now())),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = tokio::time::Instant::now();
+    }
+
+    fn deadline(&self) -> tokio::time::Instant {
+        *self.last_activity.lock().unwrap() + self.interval
+    }
+
+    /// Waits for the next message to write, returning a keepalive notification instead if the
+    /// connection has been idle for the whole interval
+    async fn next_message(
+        &self,
+        messages: &mut mpsc::Receiver<rpc::OutgoingMessage>,
+    ) -> Option<rpc::OutgoingMessage> {
+        loop {
+            match tokio::time::timeout_at(self.deadline(), messages.next()).await {
+                Ok(message) => {
+                    self.touch();
+                    return message;
+                }
+                // A message was read while waiting so the connection is not idle
+                Err(_) if self.deadline() > tokio::time::Instant::now() => (),
+                Err(_) => {
+                    self.touch();
+                    return Some(rpc::OutgoingMessage::Notification {
+                        method: KEEPALIVE_METHOD.into(),
+                        params: serde_json::Value::Null,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// The parts of a message which decide how it is dispatched. Each message is parsed into its
+/// envelope once, instead of once for each question about it, and only the handler parses all of
+/// it.
+#[derive(Debug, Default, PartialEq)]
+struct Envelope {
+    /// `Null` if the message has no `jsonrpc` field
+    jsonrpc: serde_json::Value,
+    /// `Some(Null)` if the message has a `null` id
+    id: Option<serde_json::Value>,
+    method: Option<String>,
+    /// The `params.textDocument.uri` of a message about a document
+    document: Option<String>,
+}
+
+impl Envelope {
+    /// Parses the envelope of `json`. Returns `None` if `json` is not a JSON object.
+    fn parse(json: &str) -> Option<Envelope> {
+        serde_json::from_str(json).ok()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Envelope {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct EnvelopeVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for EnvelopeVisitor {
+            type Value = Envelope;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a JSON-RPC message")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Envelope, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut envelope = Envelope::default();
+                while let Some(key) = map.next_key::<String>()? {
+                    match &key[..] {
+                        "jsonrpc" => envelope.jsonrpc = map.next_value()?,
+                        "id" => envelope.id = Some(map.next_value()?),
+                        "method" => envelope.method = map.next_value_seed(StringAt(&[]))?,
+                        "params" => {
+                            envelope.document =
+                                map.next_value_seed(StringAt(&["textDocument", "uri"]))?
+                        }
+                        _ => {
+                            map.next_value::<serde::de::IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(envelope)
+            }
+        }
+
+        deserializer.deserialize_map(EnvelopeVisitor)
+    }
+}
+
+/// Finds the string at a path of object keys, skipping everything else without copying it. Values
+/// of another type than expected are ignored since they are for the handler to reject.
+struct StringAt<'a>(&'a [&'a str]);
+
+impl<'de> serde::de::DeserializeSeed<'de> for StringAt<'_> {
+    type Value = Option<String>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for StringAt<'_> {
+    type Value = Option<String>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> {
+        Ok(if self.0.is_empty() {
+            Some(value.into())
+        } else {
+            None
+        })
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::MapAccess<'de>,
+    {
+        let mut found = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match self.0.split_first() {
+                Some((first, rest)) if *first == key => {
+                    found = map.next_value_seed(StringAt(rest))?;
+                }
+                _ => {
+                    map.next_value::<serde::de::IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        while seq.next_element::<serde::de::IgnoredAny>()?.is_some() {}
+        Ok(None)
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+}
+
+/// The uri of the document of a `textDocument/completion` request
+fn completion_document(envelope: &Envelope) -> Option<&str> {
+    if envelope.method.as_deref() != Some("textDocument/completion") {
+        return None;
+    }
+    envelope.document.as_deref()
+}
+
+/// The document of a request which refers to one
+fn request_document(envelope: &Envelope) -> Option<url::Url> {
+    envelope.id.as_ref()?;
+    url::Url::parse(envelope.document.as_deref()?).ok()
+}
+
+/// Whether the message of `envelope` outdates a completion in `uri`, either by completing in the
+/// same document again or by changing the document
+fn supersedes_completion(envelope: &Envelope, uri: &str) -> bool {
+    let method = envelope.method.as_deref();
+    (method == Some("textDocument/completion") || method == Some("textDocument/didChange"))
+        && envelope.document.as_deref() == Some(uri)
+}
+
+/// The id of the request which the `$/cancelRequest` notification `json` cancels
+fn cancelled_request(json: &str, envelope: &Envelope) -> Option<serde_json::Value> {
+    if envelope.method.as_deref() != Some("$/cancelRequest") {
+        return None;
+    }
+    let notification: serde_json::Value = serde_json::from_str(json).ok()?;
+    Some(notification["params"]["id"].clone()).filter(|id| !id.is_null())
+}
+
+/// Responds to the request of `envelope` with a `ContentModified` error
+fn content_modified(envelope: &Envelope) -> Option<rpc::OutgoingMessage> {
+    let id = serde_json::from_value(envelope.id.clone()?).ok()?;
+    Some(rpc::OutgoingMessage::Response {
+        id,
+        result: Err(jsonrpc_core::Error {
+            code: rpc::CONTENT_MODIFIED,
+            message: "The document changed before the request was handled".into(),
+            data: None,
+        }),
+    })
+}
+
+/// Handles an error from reading a message. A message whose body is too long to be read is skipped
+/// and answered with the returned error, its id is unknown. A message which was cut off ends the
+/// input (`Ok(None)`) since the next message cannot be found after it. Other errors stop the
+/// server.
+fn read_error(err: anyhow::Error) -> Result<Option<rpc::OutgoingMessage>, anyhow::Error> {
+    match err.downcast_ref::<rpc::DecodeError>() {
+        Some(decode_error @ rpc::DecodeError::TooLong { .. }) => {
+            error!("{}", decode_error);
+            Ok(Some(rpc::OutgoingMessage::Response {
+                id: jsonrpc_core::Id::Null,
+                result: Err(jsonrpc_core::Error {
+                    code: jsonrpc_core::ErrorCode::InvalidRequest,
+                    message: decode_error.to_string(),
+                    data: None,
+                }),
+            }))
+        }
+        Some(decode_error) => {
+            error!("{}", decode_error);
+            Ok(None)
+        }
+        None => Err(err),
+    }
+}
+
+fn is_response(envelope: &Envelope) -> bool {
+    envelope.method.is_none() && envelope.id.is_some()
+}
+
+/// Checks that `json`, whose envelope is `envelope`, is a JSON-RPC 2.0 message. In `lenient` mode
+/// a message with a missing or malformed `jsonrpc` field is assumed to be 2.0 (which is logged the
+/// first time, using `warned`), otherwise it is answered with an `InvalidRequest` error which is
+/// returned as the `Err`.
+fn check_version(
+    json: String,
+    envelope: Option<&Envelope>,
+    lenient: bool,
+    warned: &mut bool,
+) -> Result<String, rpc::OutgoingMessage> {
+    let envelope = match envelope {
+        // Anything else than an object is rejected (or handled) as usual by the handlers
+        Some(envelope) if envelope.jsonrpc != "2.0" => envelope,
+        _ => return Ok(json),
+    };
+    if lenient {
+        if !*warned {
+            warn!(
+                "Assuming JSON-RPC 2.0 for a message with `jsonrpc: {}`",
+                envelope.jsonrpc
+            );
+            *warned = true;
+        }
+        // Only messages which are not conformant are parsed a second time
+        let mut value = match serde_json::from_str::<serde_json::Value>(&json) {
+            Ok(value) => value,
+            Err(_) => return Ok(json),
+        };
+        value["jsonrpc"] = "2.0".into();
+        return Ok(value.to_string());
+    }
+    let id = envelope
+        .id
+        .as_ref()
+        .and_then(|id| serde_json::from_value(id.clone()).ok())
+        .unwrap_or(jsonrpc_core::Id::Null);
+    Err(rpc::OutgoingMessage::Response {
+        id,
+        result: Err(jsonrpc_core::Error {
+            message: "Expected `jsonrpc` to be \"2.0\"".into(),
+            ..jsonrpc_core::Error::invalid_request()
+        }),
+    })
+}
+
+/// Writes the messages sent to `messages` until every sender is gone. Once `exiting` is set,
+/// diagnostics which have not been written yet are dropped as the client no longer wants them, all
+/// other messages are still written.
+async fn write_messages<W>(
+    mut messages: mpsc::Receiver<rpc::OutgoingMessage>,
+    output: W,
+    flush_strategy: FlushStrategy,
+    keepalive: Option<Keepalive>,
+    exiting: Arc<AtomicBool>,
+) -> Result<(), anyhow::Error>
+where
+    W: tokio::io::AsyncWrite,
+{
+    let is_wanted = |message: &rpc::OutgoingMessage| {
+        let wanted = !exiting.load(atomic::Ordering::SeqCst)
+            || !message.is_notification("textDocument/publishDiagnostics");
+        if !wanted {
+            debug!("Dropping diagnostics after exit: {}", message);
+        }
+        wanted
+    };
+    // The encoder also accepts `String`s so flushing has to name the type of the items
+    let output = FramedWrite::new(output, LanguageServerEncoder::new());
+    futures::pin_mut!(output);
+    loop {
+        let message = match &keepalive {
+            Some(keepalive) => keepalive.next_message(&mut messages).await,
+            None => messages.next().await,
+        };
+        let message = match message {
+            Some(message) if is_wanted(&message) => message,
+            Some(_) => continue,
+            None => break,
+        };
+        match flush_strategy {
+            FlushStrategy::Immediate => output.send(message).await?,
+            FlushStrategy::Coalesce(delay) => {
+                output.feed(message).await?;
+                let deadline = tokio::time::Instant::now() + delay;
+                while let Ok(Some(message)) =
+                    tokio::time::timeout_at(deadline, messages.next()).await
+                {
+                    if is_wanted(&message) {
+                        output.feed(message).await?;
+                    }
+                }
+                futures::SinkExt::<rpc::OutgoingMessage>::flush(&mut output).await?;
+            }
+            FlushStrategy::OnIdle => {
+                output.feed(message).await?;
+                while let Ok(Some(message)) = messages.try_next() {
+                    if is_wanted(&message) {
+                        output.feed(message).await?;
+                    }
+                }
+                futures::SinkExt::<rpc::OutgoingMessage>::flush(&mut output).await?;
+            }
+        }
+    }
+    futures::SinkExt::<rpc::OutgoingMessage>::close(&mut output).await
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            loaders: Vec::new(),
+            idle_timeout: None,
+            dependency_diagnostics: true,
+            flush_strategy: FlushStrategy::default(),
+            keepalive: None,
+            read_buffer_size: STDIO_READ_BUFFER_SIZE,
+            measure_startup: false,
+            lenient: false,
+            max_content_length: rpc::DEFAULT_MAX_CONTENT_LENGTH,
+            load_retries: module_loader::DEFAULT_RETRIES,
+        }
+    }
+}
+
 pub struct Server {
     handlers: IoHandler,
     shutdown: ShutdownReceiver,
-    message_receiver: mpsc::Receiver<String>,
-    message_sender: mpsc::Sender<String>,
+    message_receiver: mpsc::Receiver<rpc::OutgoingMessage>,
+    message_sender: mpsc::Sender<rpc::OutgoingMessage>,
+    client_requests: ClientRequests,
+    stats: Arc<PipelineStats>,
+    settings: crate::command::configuration::SettingsRef,
+    document_order: DocumentOrder,
+    /// Set once `exit` is received
+    exiting: Arc<AtomicBool>,
 }
 
 impl Server {
     pub async fn start<R, W>(thread: RootedThread, input: R, output: W) -> Result<(), anyhow::Error>
+    where
+        R: tokio::io::AsyncRead,
+        W: tokio::io::AsyncWrite + Send + 'static,
+    {
+        Server::start_with_options(thread, ServerOptions::default(), input, output).await
+    }
+
+    /// Starts the server with `loaders` providing the source of imported modules which are not
+    /// open in the editor
+    pub async fn start_with_loaders<R, W>(
+        thread: RootedThread,
+        loaders: Vec<Box<dyn ModuleLoader>>,
+        input: R,
+        output: W,
+    ) -> Result<(), anyhow::Error>
+    where
+        R: tokio::io::AsyncRead,
+        W: tokio::io::AsyncWrite + Send + 'static,
+    {
+        let options = ServerOptions {
+            loaders,
+            ..ServerOptions::default()
+        };
+        Server::start_with_options(thread, options, input, output).await
+    }
+
+    /// `input` is read in place by the message loop rather than by a separate reader task, so
+    /// nothing keeps reading it once the server returns and drops it
+    pub async fn start_with_options<R, W>(
+        thread: RootedThread,
+        options: ServerOptions,
+        input: R,
+        output: W,
+    ) -> Result<(), anyhow::Error>
     where
         R: tokio::io::AsyncRead,
         W: tokio::io::AsyncWrite + Send + 'static,
@@ -81,11 +728,14 @@ impl Server {
 
         {
             let macros = thread.get_macros();
-            let mut check_import = Import::new(CheckImporter::new());
+            let mut check_import =
+                Import::new(CheckImporter::new(options.loaders, options.load_retries));
             {
                 let import = macros.get("import").expect("Import macro");
                 let import = import.downcast_ref::<Import>().expect("Importer");
-                check_import.paths = RwLock::new((*import.paths.read().unwrap()).clone());
+                let paths = import.paths.read().unwrap().clone();
+                check_import.importer.set_import_paths(paths.clone());
+                check_import.paths = RwLock::new(paths);
 
                 std::mem::swap(
                     check_import.compiler.get_mut().unwrap(),
@@ -95,55 +745,245 @@ impl Server {
             macros.insert("import".into(), check_import);
         }
 
+        let startup = startup::new(options.measure_startup);
         let Server {
             handlers,
             shutdown,
             message_receiver,
-            message_sender,
-        } = Server::initialize(&thread);
+            mut message_sender,
+            client_requests,
+            stats,
+            settings,
+            document_order,
+            exiting,
+        } = Server::initialize(&thread, options.dependency_diagnostics, &startup);
 
+        let keepalive = options.keepalive.map(Keepalive::new);
         let message_receiver_task = tokio::spawn(
-            message_receiver
-                .map(Ok)
-                .forward(FramedWrite::new(output, LanguageServerEncoder))
-                .map(|result| {
-                    if let Err(err) = result {
-                        error!("{}", err);
-                    }
-                }),
+            write_messages(
+                message_receiver,
+                output,
+                options.flush_strategy,
+                keepalive.clone(),
+                exiting,
+            )
+            .map(|result| {
+                if let Err(err) = result {
+                    error!("{}", err);
+                }
+            }),
         );
 
-        let handlers = &handlers;
-        FramedRead::new(input, rpc::LanguageServerDecoder::new())
-            .take_until(shutdown)
-            .try_for_each(move |json| {
-                let mut message_sender = message_sender.clone();
-                async move {
-                    debug!("Handle: {}", json);
-                    let result = handlers.handle_request(&json).await;
-                    match result {
-                        Some(response) => {
-                            debug!("Response: {}", response);
+        let input = FramedRead::with_capacity(
+            input,
+            rpc::LanguageServerDecoder::with_stats(stats.clone())
+                .with_max_content_length(options.max_content_length),
+            options.read_buffer_size,
+        )
+        .take_until(shutdown);
+        futures::pin_mut!(input);
+        // The messages which were read while waiting to see if a completion request is superseded
+        // or while a request was handled
+        let mut lookahead = VecDeque::new();
+        let mut input_ended = false;
+        let mut warned_version = false;
+        loop {
+            let (json, envelope) = match lookahead.pop_front() {
+                Some(lookahead) => lookahead,
+                None if input_ended => break,
+                None => {
+                    let json = match options.idle_timeout {
+                        Some(idle_timeout) => {
+                            match tokio::time::timeout(idle_timeout, input.next()).await {
+                                Ok(json) => json,
+                                Err(_) => {
+                                    info!(
+                                        "No message received for {:?}, shutting down",
+                                        idle_timeout
+                                    );
+                                    break;
+                                }
+                            }
+                        }
+                        None => input.next().await,
+                    };
+                    match json {
+                        Some(Ok(json)) => {
+                            let envelope = Envelope::parse(&json);
+                            (json, envelope)
+                        }
+                        Some(Err(err)) => match read_error(err)? {
+                            Some(response) => {
+                                message_sender
+                                    .send(response)
+                                    .await
+                                    .map_err(|_| anyhow!("Unable to send"))?;
+                                continue;
+                            }
+                            None => break,
+                        },
+                        None => break,
+                    }
+                }
+            };
+            if let Some(keepalive) = &keepalive {
+                keepalive.touch();
+            }
+
+            // Responses go to the task which sent the request
+            if envelope.as_ref().is_some_and(is_response) {
+                if !client_requests.respond(&json) {
+                    debug!("Ignoring response: {}", json);
+                }
+                stats.dispatched();
+                continue;
+            }
+
+            let json = match check_version(
+                json,
+                envelope.as_ref(),
+                options.lenient,
+                &mut warned_version,
+            ) {
+                Ok(json) => json,
+                Err(response) => {
+                    debug!("Invalid request: {}", response);
+                    stats.dispatched();
+                    message_sender
+                        .send(response)
+                        .await
+                        .map_err(|_| anyhow!("Unable to send"))?;
+                    continue;
+                }
+            };
+
+            // Wait a moment before completing so that a completion which is outdated by the next
+            // keystroke is not computed
+            let envelope = envelope.unwrap_or_default();
+            if let Some(uri) = completion_document(&envelope) {
+                let debounce = Duration::from_millis(settings.read().unwrap().completion_debounce);
+                if debounce > Duration::from_millis(0) {
+                    if lookahead.is_empty() && !input_ended {
+                        match tokio::time::timeout(debounce, input.next()).await {
+                            Ok(Some(Err(err))) => match read_error(err)? {
+                                Some(response) => {
+                                    message_sender
+                                        .send(response)
+                                        .await
+                                        .map_err(|_| anyhow!("Unable to send"))?;
+                                }
+                                None => input_ended = true,
+                            },
+                            Ok(Some(Ok(next))) => {
+                                if let Some(keepalive) = &keepalive {
+                                    keepalive.touch();
+                                }
+                                let next_envelope = Envelope::parse(&next);
+                                lookahead.push_back((next, next_envelope));
+                            }
+                            Ok(None) => input_ended = true,
+                            Err(_) => (),
+                        }
+                    }
+                    let superseded = lookahead
+                        .front()
+                        .and_then(|(_, next)| next.as_ref())
+                        .is_some_and(|next| supersedes_completion(next, uri));
+                    if superseded {
+                        debug!("Superseded: {}", json);
+                        stats.dispatched();
+                        if let Some(response) = content_modified(&envelope) {
                             message_sender
                                 .send(response)
                                 .await
                                 .map_err(|_| anyhow!("Unable to send"))?;
                         }
-                        None => (),
+                        continue;
                     }
-                    Ok(())
                 }
-            })
-            .await?;
+            }
+
+            // Requests see the changes which the client sent before them
+            if let Some(uri) = request_document(&envelope) {
+                document_order.settled(&uri).await;
+            }
+
+            debug!("Handle: {}", json);
+            let cancellation = Cancellation::default();
+            let handling = cancellation
+                .clone()
+                .scope(rpc::handle_request(&handlers, &json));
+            futures::pin_mut!(handling);
+            let result = loop {
+                // Messages are read ahead while a request is handled only to find out if the
+                // client cancels it, they are handled afterwards in order
+                if envelope.id.is_none() || input_ended || lookahead.len() >= READ_AHEAD {
+                    break handling.await;
+                }
+                tokio::select! {
+                    result = &mut handling => break result,
+                    next = input.next() => match next {
+                        Some(Ok(next)) => {
+                            if let Some(keepalive) = &keepalive {
+                                keepalive.touch();
+                            }
+                            let next_envelope = Envelope::parse(&next);
+                            let cancels = next_envelope
+                                .as_ref()
+                                .and_then(|next_envelope| cancelled_request(&next, next_envelope))
+                                .is_some_and(|id| Some(&id) == envelope.id.as_ref());
+                            if cancels {
+                                debug!("Cancelled: {}", json);
+                                stats.dispatched();
+                                cancellation.cancel();
+                            } else {
+                                lookahead.push_back((next, next_envelope));
+                            }
+                        }
+                        Some(Err(err)) => match read_error(err)? {
+                            Some(response) => {
+                                message_sender
+                                    .send(response)
+                                    .await
+                                    .map_err(|_| anyhow!("Unable to send"))?;
+                            }
+                            None => input_ended = true,
+                        },
+                        None => input_ended = true,
+                    },
+                }
+            };
+            stats.dispatched();
+            match result {
+                Some(response) => {
+                    message_sender
+                        .send(response)
+                        .await
+                        .map_err(|_| anyhow!("Unable to send"))?;
+                }
+                None => (),
+            }
+        }
 
+        // On `exit` the checks which are queued or running have been cancelled along with
+        // everything else waiting on `shutdown`, so only the output remains. Let it finish
+        // writing the messages that are already queued (less any diagnostics, if exiting).
+        message_sender.close_channel();
         message_receiver_task.await?;
 
+        if let Some(startup) = &startup {
+            startup.report();
+        }
         info!("Server shutdown");
 
         Ok(())
     }
 
-    fn initialize(thread: &RootedThread) -> Server {
+    fn initialize(
+        thread: &RootedThread,
+        dependency_diagnostics: bool,
+        startup: &StartupTimingsRef,
+    ) -> Server {
         use crate::command;
 
         let (message_log, message_log_receiver) = mpsc::channel(1);
@@ -151,36 +991,363 @@ impl Server {
         let (exit_sender, exit_receiver) = oneshot::channel();
         let exit_receiver = exit_receiver.map(|_| ()).boxed().shared();
 
+        let client_capabilities = ClientCapabilitiesRef::default();
+        let client_requests = ClientRequests::default();
+        let stats = Arc::new(PipelineStats::default());
+        let document_order = DocumentOrder::default();
+        let ready = Arc::new(AtomicBool::new(false));
+
         let mut io = IoHandler::new();
 
-        crate::diagnostics::register(&mut io, thread, &message_log, exit_receiver.clone());
+        let settings = command::configuration::SettingsRef::default();
+        let dependencies = crate::diagnostics::DependencyGraphRef::default();
+        let previous_tokens = command::semantic_tokens::PreviousTokensRef::default();
+        let diagnostics = crate::diagnostics::register(
+            &mut io,
+            thread,
+            &message_log,
+            exit_receiver.clone(),
+            &client_capabilities,
+            &settings,
+            &document_order,
+            dependency_diagnostics,
+            &dependencies,
+            &previous_tokens,
+            startup,
+        );
 
-        command::initialize::register(&mut io, thread);
-        command::completion::register(&mut io, thread, &message_log);
-        command::hover::register(&mut io, thread);
-        command::signature_help::register(&mut io, thread);
-        command::symbol::register(&mut io, thread);
+        let settings_sources = command::configuration::SettingsSourcesRef::default();
+        command::initialize::register(
+            &mut io,
+            thread,
+            &message_log,
+            &client_requests,
+            &client_capabilities,
+            &ready,
+            &settings,
+            &settings_sources,
+            startup,
+        );
+        command::ping::register(&mut io, &ready);
+        let completion_cache = command::completion::CompletionCacheRef::default();
+        let symbol_index = command::symbol::SymbolIndexRef::default();
+        command::completion::register(
+            &mut io,
+            thread,
+            &message_log,
+            &client_capabilities,
+            &settings,
+            &completion_cache,
+        );
+        command::configuration::register(
+            &mut io,
+            thread,
+            &settings,
+            &settings_sources,
+            &completion_cache,
+            &symbol_index,
+            &diagnostics,
+        );
+        command::hover::register(&mut io, thread, &client_capabilities, &settings);
+        command::signature_help::register(&mut io, thread, &client_capabilities);
+        command::symbol::register(
+            &mut io,
+            thread,
+            &client_capabilities,
+            &settings,
+            &symbol_index,
+        );
         command::document_highlight::register(&mut io, thread);
-        command::document_symbols::register(&mut io, thread);
-        command::formatting::register(&mut io, thread);
+        command::document_symbols::register(&mut io, thread, &settings);
+        command::formatting::register(&mut io, thread, &settings);
+        command::semantic_tokens::register(&mut io, thread, &previous_tokens);
+        command::declaration::register(&mut io, thread);
         command::definition::register(&mut io, thread);
+        command::node_info::register(&mut io, thread);
+        command::type_at::register(&mut io, thread);
+        command::evaluate::register(&mut io, thread);
+        command::module_graph::register(&mut io, thread, &dependencies);
+        command::code_action::register(
+            &mut io,
+            thread,
+            &message_log,
+            &client_capabilities,
+            &settings,
+            &client_requests,
+        );
+        command::dump_state::register(&mut io, thread, &stats, &client_requests);
 
         io.add_async_method(request!("shutdown"), |_| async {
             Ok::<(), ServerError<()>>(())
         });
 
-        let exit_sender = Mutex::new(Some(exit_sender));
-        io.add_notification(notification!("exit"), move |_| {
-            if let Some(exit_sender) = exit_sender.lock().unwrap().take() {
-                exit_sender.send(()).unwrap()
-            }
-        });
+        let exiting = Arc::new(AtomicBool::new(false));
+        {
+            let exiting = exiting.clone();
+            let exit_sender = Mutex::new(Some(exit_sender));
+            io.add_notification(notification!("exit"), move |_| {
+                // Set before `shutdown` completes so that no diagnostics are written after it
+                exiting.store(true, atomic::Ordering::SeqCst);
+                if let Some(exit_sender) = exit_sender.lock().unwrap().take() {
+                    exit_sender.send(()).unwrap()
+                }
+            });
+        }
 
         Server {
             handlers: io,
             shutdown: exit_receiver,
             message_receiver: message_log_receiver,
             message_sender: message_log,
+            client_requests,
+            stats,
+            settings,
+            document_order,
+            exiting,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncReadExt;
+
+    fn response(id: u64) -> rpc::OutgoingMessage {
+        rpc::OutgoingMessage::Response {
+            id: jsonrpc_core::Id::Num(id),
+            result: Ok(serde_json::Value::Null),
+        }
+    }
+
+    /// The response `id` as it is written to the output
+    fn framed(id: u64) -> String {
+        let json = format!(r#"{{"jsonrpc":"2.0","result":null,"id":{}}}"#, id);
+        format!("Content-Length: {}\r\n\r\n{}", json.len(), json)
+    }
+
+    async fn read_available(output: &mut tokio::io::DuplexStream) -> String {
+        let mut buf = vec![0; 1024];
+        match tokio::time::timeout(Duration::from_millis(50), output.read(&mut buf)).await {
+            Ok(len) => String::from_utf8(buf[..len.unwrap()].to_vec()).unwrap(),
+            Err(_) => String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesce_waits_before_flushing() {
+        let (mut sender, receiver) = mpsc::channel(2);
+        let (output, mut client) = tokio::io::duplex(1024);
+        tokio::spawn(write_messages(
+            receiver,
+            output,
+            FlushStrategy::Coalesce(Duration::from_millis(500)),
+            None,
+            Arc::default(),
+        ));
+
+        sender.send(response(1)).await.unwrap();
+        sender.send(response(2)).await.unwrap();
+        assert_eq!(read_available(&mut client).await, "");
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(read_available(&mut client).await, framed(1) + &framed(2));
+    }
+
+    #[tokio::test]
+    async fn on_idle_flushes_queued_messages_together() {
+        let (mut sender, receiver) = mpsc::channel(2);
+        sender.send(response(1)).await.unwrap();
+        sender.send(response(2)).await.unwrap();
+
+        let (output, mut client) = tokio::io::duplex(1024);
+        tokio::spawn(write_messages(
+            receiver,
+            output,
+            FlushStrategy::OnIdle,
+            None,
+            Arc::default(),
+        ));
+
+        assert_eq!(read_available(&mut client).await, framed(1) + &framed(2));
+    }
+
+    #[tokio::test]
+    async fn diagnostics_are_dropped_after_exit() {
+        let (mut sender, receiver) = mpsc::channel(2);
+        let (output, mut client) = tokio::io::duplex(1024);
+        let exiting = Arc::new(AtomicBool::new(true));
+        tokio::spawn(write_messages(
+            receiver,
+            output,
+            FlushStrategy::Immediate,
+            None,
+            exiting,
+        ));
+
+        let diagnostics = rpc::OutgoingMessage::Notification {
+            method: "textDocument/publishDiagnostics".into(),
+            params: serde_json::Value::Null,
+        };
+        sender.send(diagnostics).await.unwrap();
+        sender.send(response(1)).await.unwrap();
+        assert_eq!(read_available(&mut client).await, framed(1));
+    }
+
+    #[tokio::test]
+    async fn keepalive_is_sent_when_idle() {
+        let (mut sender, receiver) = mpsc::channel(2);
+        let (output, mut client) = tokio::io::duplex(1024);
+        let keepalive = Keepalive::new(Duration::from_millis(400));
+        tokio::spawn(write_messages(
+            receiver,
+            output,
+            FlushStrategy::Immediate,
+            Some(keepalive.clone()),
+            Arc::default(),
+        ));
+
+        let expected = r#"{"jsonrpc":"2.0","method":"$/gluon/keepalive"}"#;
+        let expected = format!("Content-Length: {}\r\n\r\n{}", expected.len(), expected);
+
+        // Writing a message resets the timer
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        sender.send(response(1)).await.unwrap();
+        assert_eq!(read_available(&mut client).await, framed(1));
+
+        // So does reading one
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        keepalive.touch();
+        assert_eq!(read_available(&mut client).await, "");
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(read_available(&mut client).await, expected);
+    }
+
+    fn check(json: &str, lenient: bool, warned: &mut bool) -> Result<String, rpc::OutgoingMessage> {
+        check_version(json.into(), Envelope::parse(json).as_ref(), lenient, warned)
+    }
+
+    #[test]
+    fn envelope_of_messages() {
+        let envelope = Envelope::parse(
+            r#"{"params":{"position":[1],"textDocument":{"uri":"file:///a.glu","version":2}},
+                "id":null,"jsonrpc":"2.0","method":"textDocument/completion"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            envelope,
+            Envelope {
+                jsonrpc: "2.0".into(),
+                id: Some(serde_json::Value::Null),
+                method: Some("textDocument/completion".into()),
+                document: Some("file:///a.glu".into()),
+            }
+        );
+        assert_eq!(completion_document(&envelope), Some("file:///a.glu"));
+        assert!(request_document(&envelope).is_some());
+        assert!(!is_response(&envelope));
+
+        // Values of the wrong type are left to the handlers
+        let envelope =
+            Envelope::parse(r#"{"id":1,"method":2,"params":{"textDocument":[]}}"#).unwrap();
+        assert_eq!(envelope.method, None);
+        assert_eq!(envelope.document, None);
+        assert!(is_response(&envelope));
+
+        assert_eq!(Envelope::parse("[]"), None);
+        assert_eq!(Envelope::parse("{"), None);
+    }
+
+    #[test]
+    fn strict_rejects_missing_version() {
+        let response = check(r#"{"id":1,"method":"shutdown"}"#, false, &mut false).unwrap_err();
+        let response = serde_json::to_value(&response).unwrap();
+        assert_eq!(response["jsonrpc"], "2.0");
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["error"]["code"], -32600);
+    }
+
+    #[test]
+    fn strict_rejects_numeric_version() {
+        let response = check(r#"{"jsonrpc":2.0,"method":"exit"}"#, false, &mut false).unwrap_err();
+        let response = serde_json::to_value(&response).unwrap();
+        assert_eq!(response["id"], serde_json::Value::Null);
+        assert_eq!(response["error"]["code"], -32600);
+    }
+
+    #[test]
+    fn lenient_assumes_version() {
+        let mut warned = false;
+        for json in &[
+            r#"{"id":1,"method":"shutdown"}"#,
+            r#"{"jsonrpc":2.0,"id":1,"method":"shutdown"}"#,
+        ] {
+            let json = check(json, true, &mut warned).unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+                serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "shutdown" })
+            );
+            assert!(warned);
         }
     }
+
+    #[test]
+    fn conformant_messages_are_unchanged() {
+        let json = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+        assert_eq!(check(json, false, &mut false).ok(), Some(json.to_string()));
+    }
+
+    #[test]
+    fn minimal_client_supports_no_optional_features() {
+        let capabilities = ClientCapabilities::default();
+        assert!(!capabilities.supports_snippets());
+        assert!(!capabilities.supports_label_details());
+        assert!(!capabilities.supports_deprecated_completion_tag());
+        assert!(!capabilities.supports_markdown_completion());
+        assert!(!capabilities.supports_markdown_signature_help());
+        assert!(!capabilities.supports_markdown_hover());
+        assert!(!capabilities.supports_diagnostic_tags());
+        assert!(!capabilities.supports_related_information());
+        assert!(!capabilities.supports_code_description());
+        assert!(!capabilities.supports_work_done_progress());
+    }
+
+    #[test]
+    fn client_capabilities_from_initialize() {
+        let lsp = serde_json::from_value(serde_json::json!({
+            "textDocument": {
+                "completion": {
+                    "completionItem": {
+                        "snippetSupport": true,
+                        "documentationFormat": ["plaintext"],
+                        "tagSupport": { "valueSet": [1] },
+                    },
+                },
+                "hover": { "contentFormat": ["markdown", "plaintext"] },
+                "publishDiagnostics": {
+                    "relatedInformation": true,
+                    "tagSupport": { "valueSet": [1, 2] },
+                    "codeDescriptionSupport": true,
+                },
+            },
+            "window": { "workDoneProgress": true },
+        }))
+        .unwrap();
+        let capabilities = ClientCapabilities {
+            lsp,
+            ..ClientCapabilities::default()
+        };
+        assert!(capabilities.supports_snippets());
+        assert!(!capabilities.supports_label_details());
+        assert!(capabilities.supports_deprecated_completion_tag());
+        assert!(!capabilities.supports_markdown_completion());
+        assert!(!capabilities.supports_markdown_signature_help());
+        assert!(capabilities.supports_markdown_hover());
+        assert!(capabilities.supports_diagnostic_tags());
+        assert!(capabilities.supports_related_information());
+        assert!(capabilities.supports_code_description());
+        assert!(capabilities.supports_work_done_progress());
+    }
 }
@@ -0,0 +1,168 @@
+//! `--measure-startup`, which times the phases of a cold start and prints them to stderr
+
+use std::{
+    fmt::Write,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The phases of starting the server, in the order they run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Phase {
+    /// Reading the project's settings file and applying the client's settings
+    ReadSettings,
+    /// Looking for the project's module directories and the modules in them
+    ScanModulePaths,
+    /// Checking the project's top level modules
+    IndexProject,
+    /// Checking the first document which the client opens
+    FirstCheck,
+}
+
+const PHASES: [(Phase, &str); 4] = [
+    (Phase::ReadSettings, "read settings"),
+    (Phase::ScanModulePaths, "scan module paths"),
+    (Phase::IndexProject, "index project"),
+    (Phase::FirstCheck, "first check"),
+];
+
+struct Timings {
+    durations: [Option<Duration>; 4],
+    /// Time from the start of the server until the first check finished
+    until_first_check: Option<Duration>,
+    reported: bool,
+}
+
+pub struct StartupTimings {
+    start: Instant,
+    timings: Mutex<Timings>,
+}
+
+/// `None` unless startup is measured, so that measuring costs nothing otherwise
+pub(crate) type StartupTimingsRef = Option<Arc<StartupTimings>>;
+
+pub(crate) fn new(measure: bool) -> StartupTimingsRef {
+    if measure {
+        Some(Arc::new(StartupTimings {
+            start: Instant::now(),
+            timings: Mutex::new(Timings {
+                durations: [None; 4],
+                until_first_check: None,
+                reported: false,
+            }),
+        }))
+    } else {
+        None
+    }
+}
+
+impl StartupTimings {
+    /// Adds `duration` to `phase`. The table is printed once the project is indexed and the first
+    /// document is checked, as that is when the server is fully started.
+    fn add(&self, phase: Phase, duration: Duration) {
+        let mut timings = self.timings.lock().unwrap();
+        let index = phase as usize;
+        timings.durations[index] = Some(timings.durations[index].unwrap_or_default() + duration);
+        if phase == Phase::FirstCheck && timings.until_first_check.is_none() {
+            timings.until_first_check = Some(self.start.elapsed());
+        }
+        let started = timings.durations[Phase::IndexProject as usize].is_some()
+            && timings.durations[Phase::FirstCheck as usize].is_some();
+        if started && !timings.reported {
+            timings.reported = true;
+            eprint!("{}", render(&timings));
+        }
+    }
+
+    /// Prints the phases which ran, if the table has not been printed yet
+    pub(crate) fn report(&self) {
+        let mut timings = self.timings.lock().unwrap();
+        if !timings.reported {
+            timings.reported = true;
+            eprint!("{}", render(&timings));
+        }
+    }
+}
+
+fn render(timings: &Timings) -> String {
+    let mut table = String::new();
+    writeln!(table, "{:<20} {:>10}", "Startup phase", "ms").unwrap();
+    let rows = PHASES
+        .iter()
+        .map(|&(phase, name)| (name, timings.durations[phase as usize]))
+        .chain(Some(("until first check", timings.until_first_check)));
+    for (name, duration) in rows {
+        match duration {
+            Some(duration) => writeln!(
+                table,
+                "{:<20} {:>10.1}",
+                name,
+                duration.as_secs_f64() * 1000.0
+            )
+            .unwrap(),
+            None => writeln!(table, "{:<20} {:>10}", name, "-").unwrap(),
+        }
+    }
+    table
+}
+
+/// Runs `f` and adds the time it took to `phase`, if startup is measured
+pub(crate) fn measure<T>(timings: &StartupTimingsRef, phase: Phase, f: impl FnOnce() -> T) -> T {
+    match timings {
+        Some(timings) => {
+            let start = Instant::now();
+            let value = f();
+            timings.add(phase, start.elapsed());
+            value
+        }
+        None => f(),
+    }
+}
+
+/// `measure` for futures
+pub(crate) async fn measure_async<T>(
+    timings: &StartupTimingsRef,
+    phase: Phase,
+    future: impl Future<Output = T>,
+) -> T {
+    match timings {
+        Some(timings) => {
+            let start = Instant::now();
+            let value = future.await;
+            timings.add(phase, start.elapsed());
+            value
+        }
+        None => future.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_table() {
+        let timings = Timings {
+            durations: [
+                Some(Duration::from_micros(1500)),
+                Some(Duration::from_millis(2)),
+                None,
+                Some(Duration::from_millis(120)),
+            ],
+            until_first_check: Some(Duration::from_millis(300)),
+            reported: false,
+        };
+        assert_eq!(
+            render(&timings),
+            "\
+Startup phase                ms
+read settings               1.5
+scan module paths           2.0
+index project                 -
+first check               120.0
+until first check         300.0
+"
+        );
+    }
+}
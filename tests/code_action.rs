@@ -0,0 +1,441 @@
+#[macro_use]
+extern crate pretty_assertions;
+
+mod support;
+
+use lsp_types::*;
+use serde_json::json;
+
+use crate::support::{
+    did_open, expect_message, expect_notification, expect_response, method_call, notification,
+    test_url, write_message,
+};
+
+/// Opens two modules which both export `zz_shared` and a module which uses it without importing
+/// it. Returns the diagnostic of the undefined variable.
+async fn open_ambiguous_modules<W, R>(stdin: &mut W, stdout: &mut R) -> Diagnostic
+where
+    W: ?Sized + tokio::io::AsyncWrite + Unpin,
+    R: ?Sized + tokio::io::AsyncBufRead + Unpin,
+{
+    for module in &["zz_first", "zz_second"] {
+        did_open(stdin, module, "let zz_shared = 1\n{ zz_shared }\n").await;
+        let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+    }
+    did_open(stdin, "test", "zz_shared\n").await;
+    let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+    assert_eq!(diagnostics.diagnostics.len(), 1, "{:#?}", diagnostics);
+    diagnostics.diagnostics[0].clone()
+}
+
+async fn code_action<W>(stdin: &mut W, id: u64, diagnostic: Diagnostic)
+where
+    W: ?Sized + tokio::io::AsyncWrite + Unpin,
+{
+    code_action_of_kinds(stdin, id, diagnostic, None).await
+}
+
+async fn code_action_of_kinds<W>(
+    stdin: &mut W,
+    id: u64,
+    diagnostic: Diagnostic,
+    only: Option<Vec<CodeActionKind>>,
+) where
+    W: ?Sized + tokio::io::AsyncWrite + Unpin,
+{
+    let params = CodeActionParams {
+        text_document: TextDocumentIdentifier {
+            uri: test_url("test"),
+        },
+        range: diagnostic.range,
+        context: CodeActionContext {
+            diagnostics: vec![diagnostic],
+            only,
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    write_message(stdin, method_call("textDocument/codeAction", id, params))
+        .await
+        .unwrap();
+}
+
+fn import_edit(module: &str) -> WorkspaceEdit {
+    WorkspaceEdit {
+        changes: Some(
+            vec![(
+                test_url("test"),
+                vec![TextEdit {
+                    range: Range::default(),
+                    new_text: format!("let {{ zz_shared }} = import! {}\n", module),
+                }],
+            )]
+            .into_iter()
+            .collect(),
+        ),
+        ..WorkspaceEdit::default()
+    }
+}
+
+#[test]
+fn import_action_for_each_module() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let diagnostic = open_ambiguous_modules(stdin, stdout).await;
+
+            code_action(stdin, 1, diagnostic).await;
+            let actions: Vec<CodeAction> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                actions
+                    .into_iter()
+                    .map(|action| (action.title, action.edit))
+                    .collect::<Vec<_>>(),
+                vec![
+                    (
+                        "Import `zz_shared` from `zz_first`".to_string(),
+                        Some(import_edit("zz_first"))
+                    ),
+                    (
+                        "Import `zz_shared` from `zz_second`".to_string(),
+                        Some(import_edit("zz_second"))
+                    ),
+                ]
+            );
+        })
+    });
+}
+
+#[test]
+fn only_requested_kinds_of_actions() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            did_open(stdin, "zz_first", "let zz_shared = 1\n{ zz_shared }\n").await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            did_open(
+                stdin,
+                "test",
+                "let int = import! std.int\nlet string = import! std.string\nlet bool = import! std.bool\nzz_shared\n",
+            )
+            .await;
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            let diagnostic = diagnostics.diagnostics[0].clone();
+
+            code_action_of_kinds(
+                stdin,
+                1,
+                diagnostic.clone(),
+                Some(vec![CodeActionKind::SOURCE_ORGANIZE_IMPORTS]),
+            )
+            .await;
+            let actions: Vec<CodeAction> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                actions
+                    .into_iter()
+                    .map(|action| (action.title, action.kind, action.edit))
+                    .collect::<Vec<_>>(),
+                vec![(
+                    "Organize imports".to_string(),
+                    Some(CodeActionKind::SOURCE_ORGANIZE_IMPORTS),
+                    Some(WorkspaceEdit {
+                        changes: Some(
+                            vec![(
+                                test_url("test"),
+                                vec![TextEdit {
+                                    range: Range::new(Position::new(0, 0), Position::new(2, 27)),
+                                    new_text: "let bool = import! std.bool\nlet int = import! std.int\nlet string = import! std.string".into(),
+                                }],
+                            )]
+                            .into_iter()
+                            .collect(),
+                        ),
+                        ..WorkspaceEdit::default()
+                    })
+                )]
+            );
+
+            code_action_of_kinds(stdin, 2, diagnostic, Some(vec![CodeActionKind::QUICKFIX])).await;
+            let actions: Vec<CodeAction> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                actions
+                    .into_iter()
+                    .map(|action| action.title)
+                    .collect::<Vec<_>>(),
+                vec!["Import `zz_shared` from `zz_first`".to_string()]
+            );
+        })
+    });
+}
+
+#[test]
+fn prompt_for_module_to_import() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let capabilities = ClientCapabilities {
+                workspace: Some(WorkspaceClientCapabilities {
+                    apply_edit: Some(true),
+                    ..WorkspaceClientCapabilities::default()
+                }),
+                ..ClientCapabilities::default()
+            };
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+            write_message(
+                stdin,
+                notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams {
+                        settings: json!({ "gluon": { "promptAmbiguousActions": true } }),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+
+            let diagnostic = open_ambiguous_modules(stdin, stdout).await;
+
+            code_action(stdin, 2, diagnostic).await;
+            let actions: Vec<CodeAction> = expect_response(&mut *stdout).await;
+            assert_eq!(actions.len(), 1, "{:#?}", actions);
+            let command = actions[0].command.clone().expect("Command");
+
+            write_message(
+                stdin,
+                method_call(
+                    "workspace/executeCommand",
+                    3,
+                    ExecuteCommandParams {
+                        command: command.command,
+                        arguments: command.arguments.unwrap_or_default(),
+                        work_done_progress_params: Default::default(),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+
+            // The command responds at once and asks for the module afterwards
+            let mut show_message_request = None;
+            while show_message_request.is_none() {
+                let message = expect_message(&mut *stdout).await;
+                match message["method"].as_str() {
+                    Some("window/showMessageRequest") => show_message_request = Some(message),
+                    Some(_) => (),
+                    None => assert_eq!(
+                        message,
+                        json!({ "jsonrpc": "2.0", "id": 3u64, "result": null })
+                    ),
+                }
+            }
+            let request = show_message_request.unwrap();
+            assert_eq!(
+                request["params"]["actions"],
+                json!([{ "title": "zz_first" }, { "title": "zz_second" }])
+            );
+            write_message(
+                stdin,
+                json!({ "jsonrpc": "2.0", "id": request["id"], "result": { "title": "zz_second" } }),
+            )
+            .await
+            .unwrap();
+
+            let request = loop {
+                let message = expect_message(&mut *stdout).await;
+                if message["method"] == "workspace/applyEdit" {
+                    break message;
+                }
+            };
+            let params: ApplyWorkspaceEditParams =
+                serde_json::from_value(request["params"].clone()).unwrap();
+            assert_eq!(params.edit, import_edit("zz_second"));
+            write_message(
+                stdin,
+                json!({ "jsonrpc": "2.0", "id": request["id"], "result": { "applied": true } }),
+            )
+            .await
+            .unwrap();
+        })
+    });
+}
+
+/// Opens `text` as `module` and returns the title and edit of the refactorings offered at
+/// `position`
+async fn refactor_actions<W, R>(
+    stdin: &mut W,
+    stdout: &mut R,
+    id: u64,
+    module: &str,
+    text: &str,
+    position: Position,
+) -> Vec<(String, Option<WorkspaceEdit>)>
+where
+    W: ?Sized + tokio::io::AsyncWrite + Unpin,
+    R: ?Sized + tokio::io::AsyncBufRead + Unpin,
+{
+    did_open(stdin, module, text).await;
+    let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+    let params = CodeActionParams {
+        text_document: TextDocumentIdentifier {
+            uri: test_url(module),
+        },
+        range: Range::new(position, position),
+        context: CodeActionContext {
+            diagnostics: vec![],
+            only: Some(vec![CodeActionKind::REFACTOR]),
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    write_message(stdin, method_call("textDocument/codeAction", id, params))
+        .await
+        .unwrap();
+    let actions: Vec<CodeAction> = expect_response(&mut *stdout).await;
+    actions
+        .into_iter()
+        .map(|action| (action.title, action.edit))
+        .collect()
+}
+
+fn document_edit(module: &str, edits: Vec<(Range, &str)>) -> WorkspaceEdit {
+    WorkspaceEdit {
+        changes: Some(
+            vec![(
+                test_url(module),
+                edits
+                    .into_iter()
+                    .map(|(range, new_text)| TextEdit {
+                        range,
+                        new_text: new_text.into(),
+                    })
+                    .collect(),
+            )]
+            .into_iter()
+            .collect(),
+        ),
+        ..WorkspaceEdit::default()
+    }
+}
+
+fn range(start: (u32, u32), end: (u32, u32)) -> Range {
+    Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))
+}
+
+#[test]
+fn inline_single_use() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let actions = refactor_actions(
+                stdin,
+                stdout,
+                1,
+                "inline_single",
+                "let x = 1 + 2\nx * 3\n",
+                Position::new(0, 4),
+            )
+            .await;
+            assert_eq!(
+                actions,
+                vec![(
+                    "Inline binding `x`".to_string(),
+                    Some(document_edit(
+                        "inline_single",
+                        vec![
+                            (range((0, 0), (1, 0)), ""),
+                            // The addition binds looser than the multiplication
+                            (range((1, 0), (1, 1)), "(1 + 2)"),
+                        ]
+                    ))
+                )]
+            );
+
+            // An application is only evaluated once so it may be inlined into its single use
+            let actions = refactor_actions(
+                stdin,
+                stdout,
+                2,
+                "inline_application",
+                "let f y = y\nlet x = f 1\nx + 2\n",
+                Position::new(1, 4),
+            )
+            .await;
+            assert_eq!(
+                actions,
+                vec![(
+                    "Inline binding `x`".to_string(),
+                    Some(document_edit(
+                        "inline_application",
+                        vec![(range((1, 0), (2, 0)), ""), (range((2, 0), (2, 1)), "f 1"),]
+                    ))
+                )]
+            );
+        })
+    });
+}
+
+#[test]
+fn inline_multiple_uses() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let actions = refactor_actions(
+                stdin,
+                stdout,
+                1,
+                "inline_multiple",
+                "let f = \\y -> y + 1\nlet r = { a = f 1 }\nf r.a + f 2\n",
+                Position::new(0, 4),
+            )
+            .await;
+            assert_eq!(
+                actions,
+                vec![(
+                    "Inline binding `f`".to_string(),
+                    Some(document_edit(
+                        "inline_multiple",
+                        vec![
+                            (range((0, 0), (1, 0)), ""),
+                            (range((1, 14), (1, 15)), "(\\y -> y + 1)"),
+                            (range((2, 0), (2, 1)), "(\\y -> y + 1)"),
+                            (range((2, 8), (2, 9)), "(\\y -> y + 1)"),
+                        ]
+                    ))
+                )]
+            );
+
+            // Inlining would evaluate the application twice
+            let actions = refactor_actions(
+                stdin,
+                stdout,
+                2,
+                "inline_effect",
+                "let f y = y\nlet x = f 1\nx + x\n",
+                Position::new(1, 4),
+            )
+            .await;
+            assert_eq!(actions, vec![]);
+        })
+    });
+}
+
+#[test]
+fn remove_unused_binding() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let actions = refactor_actions(
+                stdin,
+                stdout,
+                1,
+                "unused",
+                "let x = 1\n2\n",
+                Position::new(0, 4),
+            )
+            .await;
+            assert_eq!(
+                actions,
+                vec![(
+                    "Remove unused binding `x`".to_string(),
+                    Some(document_edit("unused", vec![(range((0, 0), (1, 0)), "")]))
+                )]
+            );
+        })
+    });
+}
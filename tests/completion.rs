@@ -11,7 +11,7 @@ use url::Url;
 
 use lsp_types::*;
 
-use gluon_language_server::CompletionData;
+use gluon_language_server::{CompletionData, WorkspaceLocation, WorkspaceSymbol};
 
 use crate::support::{did_change, expect_notification, expect_response};
 
@@ -112,6 +112,191 @@ te
     });
 }
 
+#[test]
+fn shadowed_binding_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let shadowed = 1
+let shadowed = ""
+shadowe
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 3,
+                    character: 7,
+                },
+            )
+            .await;
+
+            let completions: Vec<CompletionItem> = expect_response(stdout).await;
+            let completions = remove_completion_data(completions);
+            assert_eq!(
+                completions,
+                vec![CompletionItem {
+                    label: "shadowed".into(),
+                    kind: Some(CompletionItemKind::Variable),
+                    detail: Some("String".into()),
+                    ..CompletionItem::default()
+                }]
+            );
+        })
+    });
+}
+
+fn label_details_capabilities(label_details_support: bool) -> ClientCapabilities {
+    ClientCapabilities {
+        text_document: Some(TextDocumentClientCapabilities {
+            completion: Some(CompletionClientCapabilities {
+                completion_item: Some(CompletionItemCapability {
+                    label_details_support: Some(label_details_support),
+                    ..CompletionItemCapability::default()
+                }),
+                ..CompletionClientCapabilities::default()
+            }),
+            ..TextDocumentClientCapabilities::default()
+        }),
+        ..ClientCapabilities::default()
+    }
+}
+
+/// The label and `labelDetails` of each item, as LSP 3.17 defines them
+fn label_details(completions: &[serde_json::Value]) -> Vec<(&str, &serde_json::Value)> {
+    completions
+        .iter()
+        .map(|item| (item["label"].as_str().unwrap(), &item["labelDetails"]))
+        .collect()
+}
+
+#[test]
+fn local_completion_label_details() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::initialize(stdin, 1, label_details_capabilities(true)).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            let text = r#"
+let { flip } = import! std.function
+let test = 2
+let test1 = ""
+te
+fl
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 4,
+                    character: 2,
+                },
+            )
+            .await;
+
+            let completions: Vec<serde_json::Value> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                label_details(&completions),
+                vec![
+                    (
+                        "test",
+                        &serde_json::json!({ "detail": "Int", "description": "test" })
+                    ),
+                    (
+                        "test1",
+                        &serde_json::json!({ "detail": "String", "description": "test" })
+                    ),
+                ]
+            );
+
+            // Imported names, including those of the implicit prelude, have the module they are
+            // imported from
+            completion(
+                stdin,
+                3,
+                "test",
+                Position {
+                    line: 5,
+                    character: 2,
+                },
+            )
+            .await;
+
+            let completions: Vec<serde_json::Value> = expect_response(stdout).await;
+            assert_eq!(
+                label_details(&completions),
+                vec![
+                    (
+                        "flat_map",
+                        &serde_json::json!({
+                            "detail": "forall b a m . [std.monad.Monad m] -> (a -> m b) -> m a -> m b",
+                            "description": "std.prelude",
+                        })
+                    ),
+                    (
+                        "flip",
+                        &serde_json::json!({
+                            "detail": "forall c b a . (a -> b -> c) -> b -> a -> c",
+                            "description": "std.function",
+                        })
+                    ),
+                ]
+            );
+        })
+    });
+}
+
+#[test]
+fn local_completion_without_label_details_support() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::initialize(stdin, 1, label_details_capabilities(false)).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            let text = r#"
+let test = 2
+te
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 2,
+                    character: 2,
+                },
+            )
+            .await;
+
+            let completions: Vec<CompletionItem> = expect_response(stdout).await;
+            let completions = remove_completion_data(completions);
+            assert_eq!(
+                completions,
+                vec![CompletionItem {
+                    label: "test".into(),
+                    kind: Some(CompletionItemKind::Variable),
+                    detail: Some("Int".into()),
+                    ..CompletionItem::default()
+                }]
+            );
+        })
+    });
+}
+
 #[test]
 fn operator_completion_on_whitespace() {
     support::send_rpc(move |stdin, stdout| {
@@ -180,10 +365,12 @@ fn prelude_completion() {
     });
 }
 
-#[test]
-fn resolve_completion() {
+fn resolve_completion_with(capabilities: ClientCapabilities, kind: MarkupKind) {
     support::send_rpc(move |stdin, stdout| {
         Box::pin(async move {
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
             let completion = CompletionItem {
                 label: "test".into(),
                 kind: Some(CompletionItemKind::Variable),
@@ -195,6 +382,7 @@ fn resolve_completion() {
                             character: 2,
                             line: 4,
                         },
+                        import_from: None,
                     })
                     .unwrap(),
                 ),
@@ -211,7 +399,7 @@ te
 
             let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
 
-            resolve(stdin, 1, &completion).await;
+            resolve(stdin, 2, &completion).await;
 
             let actual: CompletionItem = expect_response(&mut *stdout).await;
 
@@ -219,7 +407,7 @@ te
                 actual,
                 CompletionItem {
                     documentation: Some(Documentation::MarkupContent(MarkupContent {
-                        kind: MarkupKind::Markdown,
+                        kind,
                         value: "doc".to_string()
                     })),
                     ..completion
@@ -229,6 +417,29 @@ te
     });
 }
 
+#[test]
+fn resolve_completion() {
+    let capabilities = ClientCapabilities {
+        text_document: Some(TextDocumentClientCapabilities {
+            completion: Some(CompletionClientCapabilities {
+                completion_item: Some(CompletionItemCapability {
+                    documentation_format: Some(vec![MarkupKind::Markdown, MarkupKind::PlainText]),
+                    ..CompletionItemCapability::default()
+                }),
+                ..CompletionClientCapabilities::default()
+            }),
+            ..TextDocumentClientCapabilities::default()
+        }),
+        ..ClientCapabilities::default()
+    };
+    resolve_completion_with(capabilities, MarkupKind::Markdown);
+}
+
+#[test]
+fn resolve_completion_without_markdown_support() {
+    resolve_completion_with(ClientCapabilities::default(), MarkupKind::PlainText);
+}
+
 #[test]
 fn url_encoded_path() {
     support::send_rpc(move |stdin, stdout| {
@@ -333,52 +544,99 @@ test2
 }
 
 #[test]
-fn local_completion_out_of_order_update() {
+fn local_completion_narrowed_while_typing() {
     support::send_rpc(move |stdin, stdout| {
         Box::pin(async move {
             let text = r#"
-let test = 2
-let test1 = ""
-test2
+let xyza = 1
+let xyzb = 2
+xy
 "#;
 
             support::did_open(stdin, "test", text).await;
 
             let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
 
+            let labels = |completions: Vec<CompletionItem>| {
+                completions
+                    .into_iter()
+                    .map(|item| item.label)
+                    .collect::<Vec<_>>()
+            };
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 3,
+                    character: 2,
+                },
+            )
+            .await;
+            let completions = expect_response(&mut *stdout).await;
+            assert_eq!(labels(completions), vec!["xyza", "xyzb"]);
+
+            // Extending the word reuses the earlier candidates
             did_change(
                 stdin,
                 "test",
-                3,
+                2,
                 Range {
                     start: Position {
                         line: 3,
-                        character: 3,
+                        character: 2,
                     },
                     end: Position {
                         line: 3,
-                        character: 5,
+                        character: 2,
                     },
                 },
-                "t1",
+                "za",
+            )
+            .await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 3,
+                    character: 4,
+                },
             )
             .await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            // Resolving the narrowed items refers to where the word ends now
+            let data: CompletionData =
+                serde_json::from_value(completions[0].data.clone().unwrap()).unwrap();
+            assert_eq!(
+                data.position,
+                Position {
+                    line: 3,
+                    character: 4,
+                }
+            );
+            assert_eq!(labels(completions), vec!["xyza"]);
 
+            // Edits outside of the word must compute the completions again
             did_change(
                 stdin,
                 "test",
-                2,
+                3,
                 Range {
                     start: Position {
-                        line: 3,
-                        character: 2,
+                        line: 1,
+                        character: 0,
                     },
                     end: Position {
-                        line: 3,
-                        character: 3,
+                        line: 1,
+                        character: 0,
                     },
                 },
-                "s",
+                "let xyzab = 3\n",
             )
             .await;
 
@@ -386,38 +644,99 @@ test2
 
             completion(
                 stdin,
-                1,
+                3,
                 "test",
                 Position {
-                    line: 3,
-                    character: 2,
+                    line: 4,
+                    character: 4,
                 },
             )
             .await;
-
             let completions = expect_response(stdout).await;
+            assert_eq!(labels(completions), vec!["xyza", "xyzab"]);
+        })
+    });
+}
+
+#[test]
+fn record_update_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let r = { alpha = 1, beta = "", gamma = 1.0 }
+{ alpha = 2, b, .. r }
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 2,
+                    character: 14,
+                },
+            )
+            .await;
 
+            let completions = expect_response(&mut *stdout).await;
             let completions = remove_completion_data(completions);
             assert_eq!(
                 completions,
                 vec![CompletionItem {
-                    label: "test1".into(),
-                    kind: Some(CompletionItemKind::Variable),
+                    label: "beta".into(),
+                    kind: Some(CompletionItemKind::Field),
                     detail: Some("String".into()),
+                    insert_text: Some("beta = ".into()),
+                    insert_text_format: Some(InsertTextFormat::PlainText),
                     ..CompletionItem::default()
                 }]
             );
+
+            // Fields which are already set are not suggested again
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 2,
+                    character: 13,
+                },
+            )
+            .await;
+
+            let completions: Vec<CompletionItem> = expect_response(stdout).await;
+            let labels: Vec<_> = completions.into_iter().map(|item| item.label).collect();
+            assert_eq!(labels, vec!["beta", "gamma"]);
         })
     });
 }
 
 #[test]
-fn completion_unicode_characters() {
+fn record_update_completion_snippet() {
     support::send_rpc(move |stdin, stdout| {
         Box::pin(async move {
+            let capabilities = ClientCapabilities {
+                text_document: Some(TextDocumentClientCapabilities {
+                    completion: Some(CompletionClientCapabilities {
+                        completion_item: Some(CompletionItemCapability {
+                            snippet_support: Some(true),
+                            ..CompletionItemCapability::default()
+                        }),
+                        ..CompletionClientCapabilities::default()
+                    }),
+                    ..TextDocumentClientCapabilities::default()
+                }),
+                ..ClientCapabilities::default()
+            };
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
             let text = r#"
-let test = 1
-"åäö" t
+let r = { alpha = 1, beta = "" }
+{ alpha = 2, .. r }
 "#;
             support::did_open(stdin, "test", text).await;
 
@@ -425,49 +744,1886 @@ let test = 1
 
             completion(
                 stdin,
-                1,
+                2,
                 "test",
                 Position {
                     line: 2,
-                    character: 7,
+                    character: 12,
                 },
             )
             .await;
 
             let completions: Vec<CompletionItem> = expect_response(stdout).await;
-            let completions = remove_completion_data(completions);
+            let completions: Vec<_> = completions
+                .into_iter()
+                .map(|item| (item.label, item.insert_text, item.insert_text_format))
+                .collect();
             assert_eq!(
                 completions,
-                vec![CompletionItem {
-                    label: "test".into(),
-                    kind: Some(CompletionItemKind::Variable),
-                    detail: Some("Int".into()),
-                    ..CompletionItem::default()
-                }]
+                vec![(
+                    "beta".to_string(),
+                    Some("beta = $0".to_string()),
+                    Some(InsertTextFormat::Snippet)
+                )]
             );
         })
     });
 }
 
 #[test]
-fn workspace_symbol_test() {
+fn completion_item_defaults() {
     support::send_rpc(move |stdin, stdout| {
         Box::pin(async move {
+            // `lsp_types` does not know about `completionList` so the capabilities are sent as JSON
+            let initialize = support::method_call(
+                "initialize",
+                1,
+                serde_json::json!({
+                    "processId": null,
+                    "rootUri": null,
+                    "capabilities": {
+                        "textDocument": {
+                            "completion": {
+                                "completionItem": { "snippetSupport": true },
+                                "completionList": {
+                                    "itemDefaults": ["editRange", "insertTextFormat"]
+                                }
+                            }
+                        }
+                    }
+                }),
+            );
+            support::write_message(stdin, initialize).await.unwrap();
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
             let text = r#"
-let myfunc x = x
-{ myfunc }
+let r = { alpha = 1, beta = "" }
+{ alpha = 2, .. r }
 "#;
             support::did_open(stdin, "test", text).await;
 
             let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
 
-            workspace_symbol(stdin, 2, "myfunc").await;
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 2,
+                    character: 13,
+                },
+            )
+            .await;
 
-            let symbols: Vec<SymbolInformation> = expect_response(stdout).await;
+            let completions: serde_json::Value = expect_response(stdout).await;
+            // Clients which apply the default `editRange` insert `textEditText` instead of
+            // `insertText`
+            assert_eq!(completions["items"][0]["textEditText"], "beta = $0");
             assert_eq!(
-                symbols.into_iter().map(|s| s.name).collect::<Vec<_>>(),
+                completions["itemDefaults"],
+                serde_json::json!({
+                    "editRange": {
+                        "start": { "line": 2, "character": 13 },
+                        "end": { "line": 2, "character": 13 },
+                    },
+                    "insertTextFormat": 2,
+                })
+            );
+            let items: Vec<CompletionItem> =
+                serde_json::from_value(completions["items"].clone()).unwrap();
+            let items: Vec<_> = items
+                .into_iter()
+                .map(|item| (item.label, item.insert_text, item.insert_text_format))
+                .collect();
+            assert_eq!(
+                items,
+                vec![("beta".to_string(), Some("beta = $0".to_string()), None)]
+            );
+        })
+    });
+}
+
+#[test]
+fn implicit_argument_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+type Describe a = { describe : a -> String }
+let describe_int : Describe Int = { describe = \_ -> "int" }
+let describe_string : Describe String = { describe = \_ -> "string" }
+let describe_array ?d : [Describe a] -> Describe (Array a) = { describe = \_ -> "array" }
+let count = 1
+let show ?d x : [Describe a] -> a -> String = d.describe x
+show ?
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 7,
+                    character: 6,
+                },
+            )
+            .await;
+
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                completions
+                    .iter()
+                    .map(|item| (&item.label[..], item.detail.as_deref()))
+                    .collect::<Vec<_>>(),
+                vec![
+                    ("describe_int", Some("test.Describe Int")),
+                    ("describe_string", Some("test.Describe String")),
+                    (
+                        "describe_array",
+                        Some("forall a .\n    [test.Describe a] -> test.Describe (Array a)")
+                    ),
+                ]
+            );
+
+            did_change(
+                stdin,
+                "test",
+                2,
+                Range {
+                    start: Position {
+                        line: 7,
+                        character: 6,
+                    },
+                    end: Position {
+                        line: 7,
+                        character: 6,
+                    },
+                },
+                "describe_s",
+            )
+            .await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 7,
+                    character: 16,
+                },
+            )
+            .await;
+
+            let completions: Vec<CompletionItem> = expect_response(stdout).await;
+            assert_eq!(
+                completions
+                    .into_iter()
+                    .map(|item| item.label)
+                    .collect::<Vec<_>>(),
+                vec!["describe_string"]
+            );
+        })
+    });
+}
+
+#[test]
+fn local_completion_out_of_order_update() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let test = 2
+let test1 = ""
+test2
+"#;
+
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            did_change(
+                stdin,
+                "test",
+                3,
+                Range {
+                    start: Position {
+                        line: 3,
+                        character: 3,
+                    },
+                    end: Position {
+                        line: 3,
+                        character: 5,
+                    },
+                },
+                "t1",
+            )
+            .await;
+
+            did_change(
+                stdin,
+                "test",
+                2,
+                Range {
+                    start: Position {
+                        line: 3,
+                        character: 2,
+                    },
+                    end: Position {
+                        line: 3,
+                        character: 3,
+                    },
+                },
+                "s",
+            )
+            .await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 3,
+                    character: 2,
+                },
+            )
+            .await;
+
+            let completions = expect_response(stdout).await;
+
+            let completions = remove_completion_data(completions);
+            assert_eq!(
+                completions,
+                vec![CompletionItem {
+                    label: "test1".into(),
+                    kind: Some(CompletionItemKind::Variable),
+                    detail: Some("String".into()),
+                    ..CompletionItem::default()
+                }]
+            );
+        })
+    });
+}
+
+#[test]
+fn completion_unicode_characters() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let test = 1
+"åäö" t
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 2,
+                    character: 7,
+                },
+            )
+            .await;
+
+            let completions: Vec<CompletionItem> = expect_response(stdout).await;
+            let completions = remove_completion_data(completions);
+            assert_eq!(
+                completions,
+                vec![CompletionItem {
+                    label: "test".into(),
+                    kind: Some(CompletionItemKind::Variable),
+                    detail: Some("Int".into()),
+                    ..CompletionItem::default()
+                }]
+            );
+        })
+    });
+}
+
+#[test]
+fn workspace_symbol_test() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let myfunc x = x
+{ myfunc }
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            workspace_symbol(stdin, 2, "myfunc").await;
+
+            let symbols: Vec<SymbolInformation> = expect_response(stdout).await;
+            assert_eq!(
+                symbols.into_iter().map(|s| s.name).collect::<Vec<_>>(),
                 vec!["myfunc".to_string()],
             );
         })
     });
-}
\ No newline at end of file
+}
+
+#[test]
+fn cancelled_workspace_symbol_keeps_the_index() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "a", "let zz_alpha = 1\n{ zz_alpha }").await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            // The first search indexes every module which has been imported, the standard library
+            // included, so it is cancelled part of the way through unless it finishes first
+            workspace_symbol(stdin, 1, "zz").await;
+            support::write_message(
+                stdin,
+                support::notification(
+                    "$/cancelRequest",
+                    CancelParams {
+                        id: NumberOrString::Number(1),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+            let response = support::expect_message(&mut *stdout).await;
+            if response.get("result").is_none() {
+                assert_eq!(response["error"]["code"], -32800, "{}", response);
+            }
+
+            workspace_symbol(stdin, 2, "zz").await;
+            let symbols: Vec<SymbolInformation> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                symbols.iter().map(|s| &s.name[..]).collect::<Vec<_>>(),
+                vec!["zz_alpha"],
+            );
+            workspace_symbol(stdin, 3, "Option").await;
+            let symbols: Vec<SymbolInformation> = expect_response(&mut *stdout).await;
+            assert!(symbols.iter().any(|s| s.name == "Option"), "{:?}", symbols);
+        })
+    });
+}
+
+#[test]
+fn workspace_symbols_after_edit() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "a", "let zz_alpha = 1\n{ zz_alpha }").await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            support::did_open(stdin, "b", "let zz_beta = 1\n{ zz_beta }").await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            workspace_symbol(stdin, 1, "zz").await;
+            let before: Vec<SymbolInformation> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                before.iter().map(|s| &s.name[..]).collect::<Vec<_>>(),
+                vec!["zz_alpha", "zz_beta"],
+            );
+
+            support::did_change_event(
+                stdin,
+                "a",
+                2,
+                vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: "let zz_alpha2 = 1\nlet zz_alpha3 = 2\n{ zz_alpha2, zz_alpha3 }".into(),
+                }],
+            )
+            .await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            workspace_symbol(stdin, 2, "zz").await;
+            let after: Vec<SymbolInformation> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                after.iter().map(|s| &s.name[..]).collect::<Vec<_>>(),
+                vec!["zz_alpha2", "zz_alpha3", "zz_beta"],
+            );
+            // The symbols of the unchanged module are the same
+            assert_eq!(
+                after.iter().find(|s| s.name == "zz_beta"),
+                before.iter().find(|s| s.name == "zz_beta"),
+            );
+        })
+    });
+}
+
+#[test]
+fn workspace_symbol_resolve() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            // `lsp_types` does not know about `resolveSupport` so the capabilities are sent as JSON
+            support::write_message(
+                stdin,
+                support::method_call(
+                    "initialize",
+                    1,
+                    serde_json::json!({
+                        "processId": null,
+                        "rootUri": null,
+                        "capabilities": {
+                            "workspace": {
+                                "symbol": { "resolveSupport": { "properties": ["location.range"] } }
+                            }
+                        }
+                    }),
+                ),
+            )
+            .await
+            .unwrap();
+            let result: serde_json::Value = expect_response(&mut *stdout).await;
+            assert_eq!(
+                result["capabilities"]["workspaceSymbolProvider"],
+                serde_json::json!({ "resolveProvider": true })
+            );
+
+            let text = r#"
+let myfunc x = x
+{ myfunc }
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            workspace_symbol(stdin, 2, "myfunc").await;
+            let symbols: Vec<WorkspaceSymbol> = expect_response(&mut *stdout).await;
+            assert_eq!(symbols.len(), 1, "{:#?}", symbols);
+            let symbol = symbols.into_iter().next().unwrap();
+            // Only the document is known until the symbol is resolved
+            assert_eq!(symbol.name, "myfunc");
+            assert_eq!(symbol.container_name.as_deref(), Some("test"));
+            assert_eq!(
+                symbol.location,
+                OneOf::Right(WorkspaceLocation {
+                    uri: support::test_url("test"),
+                })
+            );
+
+            support::write_message(
+                stdin,
+                support::method_call("workspaceSymbol/resolve", 3, symbol),
+            )
+            .await
+            .unwrap();
+            let resolved: WorkspaceSymbol = expect_response(&mut *stdout).await;
+            assert_eq!(
+                resolved.location,
+                OneOf::Left(Location {
+                    uri: support::test_url("test"),
+                    range: Range::new(Position::new(1, 4), Position::new(1, 10)),
+                })
+            );
+        })
+    });
+}
+
+#[test]
+fn workspace_symbol_order() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let test_b = 1
+let a_test = 2
+let test_a = 3
+let test = 4
+{ test_b, a_test, test_a, test }
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            workspace_symbol(stdin, 2, "test").await;
+
+            let symbols: Vec<SymbolInformation> = expect_response(stdout).await;
+            assert_eq!(
+                symbols.into_iter().map(|s| s.name).collect::<Vec<_>>(),
+                vec!["test", "test_a", "test_b", "a_test"],
+            );
+        })
+    });
+}
+
+#[test]
+fn completion_after_module_paths_change() {
+    let dir = std::env::temp_dir().join(format!("gluon_module_paths_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("configured_module.glu"),
+        "let configured_fn x : Int -> Int = x\n{ configured_fn }\n",
+    )
+    .unwrap();
+    let settings = serde_json::json!({ "gluon": { "modulePaths": [dir] } });
+
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let configured_module = import! configured_module
+configured_module.configured_fn 1
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_ne!(diagnostics.diagnostics, vec![]);
+
+            support::write_message(
+                stdin,
+                support::notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams { settings },
+                ),
+            )
+            .await
+            .unwrap();
+
+            // The open document is checked again with the new module path
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostics.diagnostics, vec![]);
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 2,
+                    character: 21,
+                },
+            )
+            .await;
+
+            let completions: Vec<CompletionItem> = expect_response(stdout).await;
+            let labels: Vec<_> = completions.into_iter().map(|item| item.label).collect();
+            assert_eq!(labels, vec!["configured_fn"]);
+        })
+    });
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+async fn field_chain_labels<W, R>(
+    stdin: &mut W,
+    stdout: R,
+    id: u64,
+    position: Position,
+) -> Vec<String>
+where
+    W: ?Sized + AsyncWrite + std::marker::Unpin,
+    R: tokio::io::AsyncBufRead + std::marker::Unpin,
+{
+    completion(stdin, id, "test", position).await;
+    let completions: Vec<CompletionItem> = expect_response(stdout).await;
+    completions.into_iter().map(|item| item.label).collect()
+}
+
+#[test]
+fn field_access_chain_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let r = { inner = { alpha = 1, beta = { gamma = "", delta = 2.0 } }, n = 1 }
+r.inner.
+r.inner.beta.
+r.inner.beta.ga
+r.n.
+r.missing.
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let cases = vec![
+                // Two levels
+                ((2, 8), vec!["alpha", "beta"]),
+                // Three levels
+                ((3, 13), vec!["delta", "gamma"]),
+                ((4, 15), vec!["gamma"]),
+                // `n` is not a record
+                ((5, 4), vec![]),
+                // `missing` is not a field of `r`
+                ((6, 10), vec![]),
+            ];
+            for (id, ((line, character), expected)) in cases.into_iter().enumerate() {
+                let labels = field_chain_labels(
+                    stdin,
+                    &mut *stdout,
+                    id as u64,
+                    Position { line, character },
+                )
+                .await;
+                assert_eq!(labels, expected, "{}:{}", line, character);
+            }
+        })
+    });
+}
+
+/// The labels in the order the client shows them, by `sortText` and then by label
+fn display_order(items: Vec<CompletionItem>) -> Vec<String> {
+    let mut items: Vec<_> = items
+        .into_iter()
+        .map(|item| {
+            (
+                item.sort_text.clone().unwrap_or_else(|| item.label.clone()),
+                item.label,
+            )
+        })
+        .collect();
+    items.sort();
+    items.into_iter().map(|(_, label)| label).collect()
+}
+
+#[test]
+fn order_is_stable_while_typing() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let zz_bx = 1
+let zz_abx = 2
+let zz_ax = ""
+let zz_abcx = 3.0
+let f x : Int -> Int = x
+f zz_
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(stdin, 1, "test", Position::new(6, 5)).await;
+            let before = display_order(expect_response(&mut *stdout).await);
+
+            support::did_change(
+                stdin,
+                "test",
+                2,
+                Range::new(Position::new(6, 5), Position::new(6, 5)),
+                "a",
+            )
+            .await;
+            completion(stdin, 2, "test", Position::new(6, 6)).await;
+            let after = display_order(expect_response(&mut *stdout).await);
+
+            // The integers have the expected type, the rest are ordered by label
+            assert_eq!(before, vec!["zz_abx", "zz_bx", "zz_abcx", "zz_ax"]);
+            assert_eq!(after, vec!["zz_abx", "zz_abcx", "zz_ax"]);
+            let shared: Vec<_> = before
+                .iter()
+                .filter(|label| after.contains(label))
+                .cloned()
+                .collect();
+            assert_eq!(shared, after);
+        })
+    });
+}
+
+#[test]
+fn method_style_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+type Point = { x : Int, y : Int }
+let norm p : Point -> Int = p.x
+let shift p dx : Point -> Int -> Point = { x = dx, y = p.y }
+let twice x : Int -> Int = x
+let p : Point = { x = 1, y = 2 }
+p.
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(stdin, 1, "test", Position::new(6, 2)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            let mut items: Vec<_> = completions
+                .into_iter()
+                .map(|item| {
+                    let sort_text = item.sort_text.clone().unwrap_or_else(|| item.label.clone());
+                    (sort_text, item.label, item.kind)
+                })
+                .collect();
+            items.sort_by(|l, r| l.0.cmp(&r.0));
+            // The fields come before the functions which take a `Point`
+            assert_eq!(
+                items
+                    .into_iter()
+                    .map(|(_, label, kind)| (label, kind))
+                    .collect::<Vec<_>>(),
+                vec![
+                    ("x".to_string(), Some(CompletionItemKind::Variable)),
+                    ("y".to_string(), Some(CompletionItemKind::Variable)),
+                    ("norm".to_string(), Some(CompletionItemKind::Method)),
+                    ("shift".to_string(), Some(CompletionItemKind::Method)),
+                ]
+            );
+
+            support::did_change(
+                stdin,
+                "test",
+                2,
+                Range::new(Position::new(6, 0), Position::new(6, 2)),
+                "p.sh",
+            )
+            .await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(stdin, 2, "test", Position::new(6, 4)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            let methods: Vec<_> = completions
+                .into_iter()
+                .filter(|item| item.kind == Some(CompletionItemKind::Method))
+                .map(|item| (item.label, item.text_edit))
+                .collect();
+            assert_eq!(
+                methods,
+                vec![(
+                    "shift".to_string(),
+                    Some(CompletionTextEdit::Edit(TextEdit {
+                        range: Range::new(Position::new(6, 0), Position::new(6, 4)),
+                        new_text: "shift p".into(),
+                    }))
+                )]
+            );
+        })
+    });
+}
+
+#[test]
+fn match_pattern_binding_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+type Point = { x : Int, y : Int }
+type Shape = | MyCtor { a : Point, b : Int } | Wrap Shape | Empty
+let area shape : Shape -> Int =
+    match shape with
+    | MyCtor { a, b } -> a.
+    | Wrap (MyCtor { a = inner, b }) -> inner.
+    | Wrap _ -> b
+    | Empty -> 0
+area
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let cases = vec![
+                // A field bound by the pattern
+                ((5, 27), vec!["x", "y"]),
+                // A field bound by a nested pattern
+                ((6, 46), vec!["x", "y"]),
+                // The names bound by the pattern are in scope in its arm only
+                ((5, 26), vec!["a", "area"]),
+                ((7, 17), vec![]),
+            ];
+            for (id, ((line, character), expected)) in cases.into_iter().enumerate() {
+                let labels = field_chain_labels(
+                    stdin,
+                    &mut *stdout,
+                    id as u64,
+                    Position { line, character },
+                )
+                .await;
+                assert_eq!(labels, expected, "{}:{}", line, character);
+            }
+        })
+    });
+}
+
+#[test]
+fn tuple_pattern_binding_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let describe p : (Int, String) -> String =
+    match p with
+    | (tally, title) -> t
+describe
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(stdin, 1, "test", Position::new(3, 25)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            let completions: Vec<_> = completions
+                .into_iter()
+                .map(|item| (item.label, item.detail))
+                .collect();
+            assert_eq!(
+                completions,
+                vec![
+                    ("tally".to_string(), Some("Int".to_string())),
+                    ("title".to_string(), Some("String".to_string())),
+                ]
+            );
+        })
+    });
+}
+
+#[test]
+fn recursive_group_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+rec
+let ping n : Int -> Int = if n == 0 then 0 else pong (n - 1)
+let pong n : Int -> Int = if n == 0 then 1 else ping (n - 1)
+in
+ping 3
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            // Each binding of the group is in scope in the body of the other
+            for (id, (line, character, expected)) in vec![(2, 52, "pong"), (3, 52, "ping")]
+                .into_iter()
+                .enumerate()
+            {
+                completion(stdin, id as u64, "test", Position { line, character }).await;
+                let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+                let completions: Vec<_> = completions
+                    .into_iter()
+                    .map(|item| (item.label, item.detail))
+                    .collect();
+                assert_eq!(
+                    completions,
+                    vec![(expected.to_string(), Some("Int -> Int".to_string()))],
+                    "{}:{}",
+                    line,
+                    character
+                );
+            }
+        })
+    });
+}
+
+#[test]
+fn continuation_keyword_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let f x : Int -> Int =
+    if x #Int== 0 th
+f
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(stdin, 1, "test", Position::new(2, 20)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            let keywords: Vec<_> = completions
+                .iter()
+                .filter(|item| item.kind == Some(CompletionItemKind::Keyword))
+                .map(|item| item.label.as_str())
+                .collect();
+            assert_eq!(keywords, vec!["then"]);
+            assert_eq!(completions[0].label, "then");
+
+            // Only the keyword which continues the construct is offered
+            support::did_change(
+                stdin,
+                "test",
+                2,
+                Range::new(Position::new(2, 4), Position::new(2, 20)),
+                "match x w",
+            )
+            .await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(stdin, 2, "test", Position::new(2, 13)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            let keywords: Vec<_> = completions
+                .iter()
+                .filter(|item| item.kind == Some(CompletionItemKind::Keyword))
+                .map(|item| item.label.as_str())
+                .collect();
+            assert_eq!(keywords, vec!["with"]);
+        })
+    });
+}
+
+#[test]
+fn kind_remap() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::write_message(
+                stdin,
+                support::notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams {
+                        settings: serde_json::json!({
+                            "gluon": {
+                                "kindRemap": { "EnumMember": "Constant", "Enum": "Value" }
+                            }
+                        }),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+
+            let text = r#"
+type Shape = | Circle Int | Square Int
+let test = Circle 1
+te
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(stdin, 1, "test", Position::new(3, 2)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            let kinds: Vec<_> = completions
+                .into_iter()
+                .map(|item| (item.label, item.kind))
+                .collect();
+            assert_eq!(
+                kinds,
+                vec![("test".to_string(), Some(CompletionItemKind::Value))]
+            );
+
+            let request = support::method_call(
+                "textDocument/documentSymbol",
+                2,
+                DocumentSymbolParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: support::test_url("test"),
+                    },
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                },
+            );
+            support::write_message(stdin, request).await.unwrap();
+            let symbols: Vec<DocumentSymbol> = expect_response(&mut *stdout).await;
+            let constructors: Vec<_> = symbols[0]
+                .children
+                .iter()
+                .flatten()
+                .map(|symbol| (symbol.name.as_str(), symbol.kind))
+                .collect();
+            assert_eq!(
+                constructors,
+                vec![
+                    ("Circle", SymbolKind::Constant),
+                    ("Square", SymbolKind::Constant)
+                ]
+            );
+        })
+    });
+}
+
+#[test]
+fn import_is_added_on_resolve() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let library = r#"
+let zz_imported_value = 1
+{ zz_imported_value }
+"#;
+            support::did_open(stdin, "zz_import", library).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let text = r#"
+zz_im
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(stdin, 1, "test", Position::new(1, 5)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            assert_eq!(completions.len(), 1, "{:?}", completions);
+            let item = completions.into_iter().next().unwrap();
+            assert_eq!(item.label, "zz_imported_value");
+            assert_eq!(item.detail, Some("Int".into()));
+            // The import is left out of the list
+            assert_eq!(item.additional_text_edits, None);
+
+            resolve(stdin, 2, &item).await;
+            let resolved: CompletionItem = expect_response(&mut *stdout).await;
+            assert_eq!(
+                resolved.additional_text_edits,
+                Some(vec![TextEdit {
+                    range: Range::default(),
+                    new_text: "let { zz_imported_value } = import! zz_import\n".into(),
+                }])
+            );
+
+            // Once the name is imported the item needs no import
+            did_change(
+                stdin,
+                "test",
+                2,
+                Range::default(),
+                "let { zz_imported_value } = import! zz_import",
+            )
+            .await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            resolve(stdin, 3, &item).await;
+            let resolved: CompletionItem = expect_response(&mut *stdout).await;
+            assert_eq!(resolved.additional_text_edits, None);
+        })
+    });
+}
+
+#[test]
+fn deprecated_completion_ranking() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let capabilities = ClientCapabilities {
+                text_document: Some(TextDocumentClientCapabilities {
+                    completion: Some(CompletionClientCapabilities {
+                        completion_item: Some(CompletionItemCapability {
+                            tag_support: Some(TagSupport {
+                                value_set: vec![CompletionItemTag::Deprecated],
+                            }),
+                            ..CompletionItemCapability::default()
+                        }),
+                        ..CompletionClientCapabilities::default()
+                    }),
+                    ..TextDocumentClientCapabilities::default()
+                }),
+                ..ClientCapabilities::default()
+            };
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            let library = r#"
+#[deprecated]
+let ab_lib_old = 1
+let ab_lib = 2
+{ ab_lib, ab_lib_old }
+"#;
+            support::did_open(stdin, "zz_ranking", library).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let text = r#"
+let { ab_lib, ab_lib_old } = import! zz_ranking
+#[deprecated]
+let abd = 1
+let abc = 2
+#[deprecated]
+let ab = 3
+ab
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 7,
+                    character: 2,
+                },
+            )
+            .await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                completions
+                    .into_iter()
+                    .map(|item| (item.label, item.tags.is_some(), item.sort_text))
+                    .collect::<Vec<_>>(),
+                vec![
+                    // The exact match comes first even though it is deprecated
+                    ("ab".to_string(), true, Some("0000".to_string())),
+                    ("ab_lib".to_string(), false, Some("0001".to_string())),
+                    ("abc".to_string(), false, Some("0002".to_string())),
+                    ("ab_lib_old".to_string(), true, Some("0003".to_string())),
+                    ("abd".to_string(), true, Some("0004".to_string())),
+                ]
+            );
+        })
+    });
+}
+
+#[test]
+fn private_bindings_of_other_modules_are_hidden() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let library = r#"
+let _internal = 1
+let public = 2
+{ _internal, public }
+"#;
+            support::did_open(stdin, "zz_private", library).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let text = r#"
+let lib = import! zz_private
+let _own = 1
+lib.
+_o
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let labels = field_chain_labels(stdin, &mut *stdout, 1, Position::new(3, 4)).await;
+            assert_eq!(labels, vec!["public"]);
+            // Private bindings of the module which is completed are kept
+            let labels = field_chain_labels(stdin, &mut *stdout, 2, Position::new(4, 2)).await;
+            assert_eq!(labels, vec!["_own"]);
+
+            support::write_message(
+                stdin,
+                support::notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams {
+                        settings: serde_json::json!({
+                            "gluon": { "completion": { "hidePrivate": false } }
+                        }),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+            let labels = field_chain_labels(stdin, &mut *stdout, 3, Position::new(3, 4)).await;
+            assert_eq!(labels, vec!["_internal", "public"]);
+        })
+    });
+}
+
+#[test]
+fn only_exported_names_of_modules() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let library = r#"
+type Hidden = Int
+let helper x : Hidden -> Hidden = x
+let exported = helper 1
+let also_exported = "a"
+{ exported, also_exported }
+"#;
+            support::did_open(stdin, "zz_exports", library).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let text = r#"
+let lib = import! zz_exports
+lib.
+lib.he
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let labels = field_chain_labels(stdin, &mut *stdout, 1, Position::new(2, 4)).await;
+            assert_eq!(labels, vec!["also_exported", "exported"]);
+            let labels = field_chain_labels(stdin, &mut *stdout, 2, Position::new(3, 6)).await;
+            assert_eq!(labels, Vec::<String>::new());
+        })
+    });
+}
+
+#[test]
+fn preselect_value_of_expected_type() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let capabilities = ClientCapabilities {
+                text_document: Some(TextDocumentClientCapabilities {
+                    completion: Some(CompletionClientCapabilities {
+                        completion_item: Some(CompletionItemCapability {
+                            preselect_support: Some(true),
+                            ..CompletionItemCapability::default()
+                        }),
+                        ..CompletionClientCapabilities::default()
+                    }),
+                    ..TextDocumentClientCapabilities::default()
+                }),
+                ..ClientCapabilities::default()
+            };
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            let text = r#"
+let takes_int x : Int -> Int = x
+let pre_int = 1
+let pre_string = ""
+let pre_one = 1
+let pre_two = 2
+takes_int pre_
+pre_
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let preselected = |completions: Vec<CompletionItem>| {
+                completions
+                    .into_iter()
+                    .filter(|item| item.preselect == Some(true))
+                    .map(|item| item.label)
+                    .collect::<Vec<_>>()
+            };
+
+            // `pre_int`, `pre_one` and `pre_two` are all integers
+            completion(stdin, 2, "test", Position::new(6, 14)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            assert_eq!(preselected(completions), Vec::<String>::new());
+
+            support::did_change(
+                stdin,
+                "test",
+                2,
+                Range::new(Position::new(4, 0), Position::new(6, 0)),
+                "",
+            )
+            .await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(stdin, 3, "test", Position::new(4, 14)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            assert_eq!(completions[0].label, "pre_int");
+            assert_eq!(preselected(completions), vec!["pre_int"]);
+
+            // Without an expected type no item is a better match than the others
+            completion(stdin, 4, "test", Position::new(5, 4)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            assert_eq!(preselected(completions), Vec::<String>::new());
+        })
+    });
+}
+
+#[test]
+fn postfix_let_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::write_message(
+                stdin,
+                support::notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams {
+                        settings: serde_json::json!({ "gluon": { "postfixCompletion": true } }),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+
+            let text = r#"
+let { Map, empty } = import! std.map
+let map : Map String Int = empty
+let m : Map String Int = empty
+m.let
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 4,
+                    character: 5,
+                },
+            )
+            .await;
+
+            let completions: Vec<CompletionItem> = expect_response(stdout).await;
+            let item = completions
+                .into_iter()
+                .find(|item| item.label == "let")
+                .expect("Postfix completion");
+            // `map` is already bound so the name gets a suffix
+            assert_eq!(
+                item.text_edit,
+                Some(CompletionTextEdit::Edit(TextEdit {
+                    range: Range {
+                        start: Position {
+                            line: 4,
+                            character: 0,
+                        },
+                        end: Position {
+                            line: 4,
+                            character: 5,
+                        },
+                    },
+                    new_text: "let map1 = m in ".into(),
+                }))
+            );
+        })
+    });
+}
+
+#[test]
+fn postfix_completion_is_disabled_by_default() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let x = 1
+x.le
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 2,
+                    character: 4,
+                },
+            )
+            .await;
+
+            let completions: Vec<CompletionItem> = expect_response(stdout).await;
+            assert_eq!(completions, vec![]);
+        })
+    });
+}
+
+#[test]
+fn type_annotation_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+type Point = { x : Int }
+let point = 1
+let x : 
+x
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 3,
+                    character: 8,
+                },
+            )
+            .await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+
+            // Only types are offered, not values such as `point`
+            assert!(
+                completions
+                    .iter()
+                    .all(|item| item.kind == Some(CompletionItemKind::Class)
+                        && item.label.starts_with(char::is_uppercase)),
+                "{:#?}",
+                completions
+            );
+            let detail = |label: &str| {
+                completions
+                    .iter()
+                    .find(|item| item.label == label)
+                    .map(|item| item.detail.clone())
+            };
+            assert_eq!(detail("Point"), Some(Some("Type".into())));
+            assert_eq!(detail("Int"), Some(Some("Type".into())));
+            assert_eq!(detail("Array"), Some(Some("Type -> Type".into())));
+            assert_eq!(detail("point"), None);
+
+            did_change(
+                stdin,
+                "test",
+                2,
+                Range {
+                    start: Position {
+                        line: 3,
+                        character: 8,
+                    },
+                    end: Position {
+                        line: 3,
+                        character: 8,
+                    },
+                },
+                "Po",
+            )
+            .await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 3,
+                    character: 10,
+                },
+            )
+            .await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                completions
+                    .into_iter()
+                    .map(|item| item.label)
+                    .collect::<Vec<_>>(),
+                vec!["Point"]
+            );
+        })
+    });
+}
+
+#[test]
+fn constructor_pattern_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let capabilities = ClientCapabilities {
+                text_document: Some(TextDocumentClientCapabilities {
+                    completion: Some(CompletionClientCapabilities {
+                        completion_item: Some(CompletionItemCapability {
+                            snippet_support: Some(true),
+                            ..CompletionItemCapability::default()
+                        }),
+                        ..CompletionClientCapabilities::default()
+                    }),
+                    ..TextDocumentClientCapabilities::default()
+                }),
+                ..ClientCapabilities::default()
+            };
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            let text = r#"
+type Shape = | Circle Float | Rect { width : Float, height : Float }
+let area shape : Shape -> Float =
+    match shape with
+    | Ci
+    | Re
+area
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let mut snippets = Vec::new();
+            for (id, line) in [(2, 4), (3, 5)] {
+                completion(stdin, id, "test", Position { line, character: 8 }).await;
+                let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+                snippets.extend(
+                    completions
+                        .into_iter()
+                        .map(|item| (item.label, item.insert_text, item.insert_text_format)),
+                );
+            }
+            assert_eq!(
+                snippets,
+                vec![
+                    (
+                        "Circle".to_string(),
+                        Some("Circle ${1:float} -> $0".to_string()),
+                        Some(InsertTextFormat::Snippet)
+                    ),
+                    (
+                        "Rect".to_string(),
+                        Some("Rect { width, height } -> $0".to_string()),
+                        Some(InsertTextFormat::Snippet)
+                    ),
+                ]
+            );
+        })
+    });
+}
+
+#[test]
+fn lambda_argument_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let capabilities = ClientCapabilities {
+                text_document: Some(TextDocumentClientCapabilities {
+                    completion: Some(CompletionClientCapabilities {
+                        completion_item: Some(CompletionItemCapability {
+                            snippet_support: Some(true),
+                            ..CompletionItemCapability::default()
+                        }),
+                        ..CompletionClientCapabilities::default()
+                    }),
+                    ..TextDocumentClientCapabilities::default()
+                }),
+                ..ClientCapabilities::default()
+            };
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            let text = r#"
+let fold f : (Int -> String -> Int) -> Int = f 1 "a"
+fold 
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 2,
+                    character: 5,
+                },
+            )
+            .await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            let lambda = &completions[0];
+            assert_eq!(
+                (
+                    &lambda.label[..],
+                    lambda.insert_text.as_deref(),
+                    lambda.insert_text_format
+                ),
+                (
+                    "\\int string ->",
+                    Some("\\${1:int} ${2:string} -> $0"),
+                    Some(InsertTextFormat::Snippet)
+                )
+            );
+            assert!(completions.iter().any(|item| item.label == "fold"));
+        })
+    });
+}
+
+#[test]
+fn lambda_argument_completion_after_other_arguments() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let capabilities = ClientCapabilities {
+                text_document: Some(TextDocumentClientCapabilities {
+                    completion: Some(CompletionClientCapabilities {
+                        completion_item: Some(CompletionItemCapability {
+                            snippet_support: Some(true),
+                            ..CompletionItemCapability::default()
+                        }),
+                        ..CompletionClientCapabilities::default()
+                    }),
+                    ..TextDocumentClientCapabilities::default()
+                }),
+                ..ClientCapabilities::default()
+            };
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            let text = r#"
+let apply x f : Int -> (Int -> Int) -> Int = f x
+apply 1 
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(stdin, 2, "test", Position::new(2, 8)).await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                (&completions[0].label[..], completions[0].detail.as_deref()),
+                ("\\int ->", Some("Int -> Int"))
+            );
+        })
+    });
+}
+
+#[test]
+fn record_argument_completion() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let capabilities = ClientCapabilities {
+                text_document: Some(TextDocumentClientCapabilities {
+                    completion: Some(CompletionClientCapabilities {
+                        completion_item: Some(CompletionItemCapability {
+                            snippet_support: Some(true),
+                            ..CompletionItemCapability::default()
+                        }),
+                        ..CompletionClientCapabilities::default()
+                    }),
+                    ..TextDocumentClientCapabilities::default()
+                }),
+                ..ClientCapabilities::default()
+            };
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            let text = r#"
+let area r : { width : Int, height : Int } -> Int = r.width
+area 
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 2,
+                    character: 5,
+                },
+            )
+            .await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            let record = &completions[0];
+            assert_eq!(
+                (
+                    &record.label[..],
+                    record.insert_text.as_deref(),
+                    record.insert_text_format
+                ),
+                (
+                    "{ width, height }",
+                    Some("{ width = ${1}, height = ${2} }"),
+                    Some(InsertTextFormat::Snippet)
+                )
+            );
+
+            // Inside the literal only the fields which are not yet set are offered
+            did_change(
+                stdin,
+                "test",
+                2,
+                Range {
+                    start: Position {
+                        line: 2,
+                        character: 5,
+                    },
+                    end: Position {
+                        line: 2,
+                        character: 5,
+                    },
+                },
+                "{ width = 1, h }",
+            )
+            .await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                3,
+                "test",
+                Position {
+                    line: 2,
+                    character: 19,
+                },
+            )
+            .await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            let completions: Vec<_> = completions
+                .iter()
+                .map(|item| (&item.label[..], item.insert_text.as_deref()))
+                .collect();
+            assert_eq!(completions, [("height", Some("height = $0"))]);
+        })
+    });
+}
+
+#[test]
+fn resolve_field_documentation() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+type Point = { x : Int, y : Int }
+rec
+type Counter = { value : Int, next : () -> Counter }
+in
+let start x : Int -> Counter = { value = x, next = \_ -> start (x + 1) }
+let shape = { origin = { x = 1, y = 2 }, move = \x -> { x, y = 2 }, start, counter = start 0 }
+shape.o
+"#;
+            support::did_open(stdin, "test", text).await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let mut documentation = Vec::new();
+            for (id, label) in [(2, "origin"), (3, "move"), (4, "start"), (5, "counter")] {
+                let item = CompletionItem {
+                    label: label.into(),
+                    data: Some(
+                        serde_json::to_value(CompletionData {
+                            text_document_uri: support::test_url("test"),
+                            position: Position {
+                                line: 7,
+                                character: 7,
+                            },
+                            import_from: None,
+                        })
+                        .unwrap(),
+                    ),
+                    ..CompletionItem::default()
+                };
+                resolve(stdin, id, &item).await;
+                let actual: CompletionItem = expect_response(&mut *stdout).await;
+                match actual.documentation {
+                    Some(Documentation::MarkupContent(content)) => {
+                        documentation.push(content.value)
+                    }
+                    documentation => panic!("{:?}", documentation),
+                }
+            }
+            assert_eq!(
+                documentation,
+                vec![
+                    "{\n    x : Int,\n    y : Int,\n}\n",
+                    "forall a . a -> {\n    x : a,\n    y : Int,\n}\n",
+                    "Int -> {\n    value : Int,\n    next : () -> test.Counter,\n}\n",
+                    "{\n    value : Int,\n    next : () -> test.Counter /* recursive */,\n}\n",
+                ]
+            );
+        })
+    });
+}
+
+#[test]
+fn no_completion_inside_string_literal() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let test = 2
+let test1 = "te"
+te
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 2,
+                    character: 15,
+                },
+            )
+            .await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            assert_eq!(completions, vec![]);
+
+            // The same word outside of the string is completed
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 3,
+                    character: 2,
+                },
+            )
+            .await;
+            let completions: Vec<CompletionItem> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                completions
+                    .into_iter()
+                    .map(|item| item.label)
+                    .collect::<Vec<_>>(),
+                vec!["test", "test1"]
+            );
+        })
+    });
+}
+
+#[test]
+fn completion_superseded_by_edit() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let test = 2
+let test1 = ""
+te
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            // Typing continues before the server has responded to the first completion
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 3,
+                    character: 2,
+                },
+            )
+            .await;
+            let end = Position {
+                line: 3,
+                character: 2,
+            };
+            did_change(stdin, "test", 2, Range { start: end, end }, "st1").await;
+            completion(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 3,
+                    character: 5,
+                },
+            )
+            .await;
+
+            let mut responses = Vec::new();
+            while responses.len() < 2 {
+                let message = support::expect_message(&mut *stdout).await;
+                if message.get("method").is_none() {
+                    responses.push(message);
+                }
+            }
+            assert_eq!(responses[0]["id"], 1);
+            assert_eq!(responses[0]["error"]["code"], -32801);
+            assert_eq!(responses[1]["id"], 2);
+            let completions: Vec<CompletionItem> =
+                serde_json::from_value(responses[1]["result"].clone()).unwrap();
+            assert_eq!(
+                completions
+                    .into_iter()
+                    .map(|item| item.label)
+                    .collect::<Vec<_>>(),
+                vec!["test1"]
+            );
+        })
+    });
+}
+
+#[test]
+fn completion_sees_preceding_change() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let test = 2
+let test1 = ""
+test2
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            // The completion is sent without waiting for the change to be checked
+            did_change(
+                stdin,
+                "test",
+                2,
+                Range {
+                    start: Position {
+                        line: 3,
+                        character: 2,
+                    },
+                    end: Position {
+                        line: 3,
+                        character: 5,
+                    },
+                },
+                "st1",
+            )
+            .await;
+            completion(
+                stdin,
+                1,
+                "test",
+                Position {
+                    line: 3,
+                    character: 5,
+                },
+            )
+            .await;
+
+            let completions: Vec<CompletionItem> = expect_response(stdout).await;
+            assert_eq!(
+                completions
+                    .into_iter()
+                    .map(|item| item.label)
+                    .collect::<Vec<_>>(),
+                vec!["test1"]
+            );
+        })
+    });
+}
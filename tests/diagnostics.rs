@@ -1,7 +1,13 @@
 #[allow(unused)]
 mod support;
 
-use lsp_types::{DiagnosticSeverity, Position, PublishDiagnosticsParams, Range};
+use lsp_types::{
+    ClientCapabilities, DiagnosticSeverity, DidCloseTextDocumentParams, InitializeParams,
+    InitializeResult, NumberOrString, Position, PublishDiagnosticsClientCapabilities,
+    PublishDiagnosticsParams, Range, TextDocumentClientCapabilities, TextDocumentIdentifier,
+};
+
+use gluon_language_server::MemoryLoader;
 
 #[test]
 fn type_error() {
@@ -39,3 +45,426 @@ not ""
         })
     });
 }
+
+#[test]
+fn diagnostic_codes() {
+    support::send_rpc(|stdin, stdout| {
+        Box::pin(async move {
+            let capabilities = ClientCapabilities {
+                text_document: Some(TextDocumentClientCapabilities {
+                    publish_diagnostics: Some(PublishDiagnosticsClientCapabilities {
+                        code_description_support: Some(true),
+                        ..PublishDiagnosticsClientCapabilities::default()
+                    }),
+                    ..TextDocumentClientCapabilities::default()
+                }),
+                ..ClientCapabilities::default()
+            };
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = support::expect_response(&mut *stdout).await;
+
+            let cases = vec![
+                ("undefined", "undefined_variable", "E0101"),
+                ("mismatch", "not \"\"", "E0106"),
+                ("unexpected", "let x = 1", "E0004"),
+                // The same error is given the same code every time
+                ("undefined2", "another_undefined_variable", "E0101"),
+            ];
+            for (uri, text, code) in cases {
+                support::did_open(stdin, uri, text).await;
+
+                let diagnostic: PublishDiagnosticsParams =
+                    support::expect_notification(&mut *stdout).await;
+                assert_eq!(diagnostic.uri, support::test_url(uri));
+                let error = &diagnostic.diagnostics[0];
+                assert_eq!(
+                    error.code,
+                    Some(NumberOrString::String(code.into())),
+                    "{:?}",
+                    error
+                );
+                let href = error
+                    .code_description
+                    .as_ref()
+                    .expect("codeDescription")
+                    .href
+                    .clone();
+                assert!(
+                    href.as_str()
+                        .ends_with(&format!("#{}", code.to_lowercase())),
+                    "{}",
+                    href
+                );
+            }
+        })
+    });
+}
+
+#[test]
+fn shadowed_binding_is_a_warning() {
+    support::send_rpc(|stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let x = 1
+let f x = x
+let x = f x
+let _y = 1
+let _y = 2
+x
+"#;
+            support::did_open(stdin, "test.glu", text).await;
+
+            let diagnostic: PublishDiagnosticsParams =
+                support::expect_notification(&mut *stdout).await;
+            // Only the `let` binding is reported, the argument and `_y` are not
+            assert_eq!(
+                diagnostic.diagnostics.len(),
+                1,
+                "{:?}",
+                diagnostic.diagnostics
+            );
+            let warning = &diagnostic.diagnostics[0];
+            assert_eq!(warning.severity, Some(DiagnosticSeverity::Warning));
+            assert_eq!(warning.code, Some(NumberOrString::String("W0001".into())));
+            assert_eq!(
+                warning.range,
+                Range {
+                    start: Position {
+                        line: 3,
+                        character: 4,
+                    },
+                    end: Position {
+                        line: 3,
+                        character: 5,
+                    },
+                }
+            );
+            assert!(warning.message.contains("`x`"), "{}", warning.message);
+        })
+    });
+}
+
+#[test]
+fn errors_in_imported_modules() {
+    let loader = MemoryLoader::new();
+    loader.insert("broken", "let x : Int = \"\"\nx");
+
+    support::send_rpc_with_loaders(vec![Box::new(loader)], |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test.glu", "let m = import! broken\nm").await;
+
+            let diagnostic: PublishDiagnosticsParams =
+                support::expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostic.uri, support::test_url("test.glu"));
+            assert_eq!(diagnostic.diagnostics, vec![]);
+
+            let diagnostic: PublishDiagnosticsParams =
+                support::expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostic.uri, support::test_url("broken.glu"));
+            assert_eq!(
+                diagnostic.diagnostics.len(),
+                1,
+                "{:?}",
+                diagnostic.diagnostics
+            );
+            assert_eq!(
+                diagnostic.diagnostics[0].range,
+                Range {
+                    start: Position {
+                        line: 0,
+                        character: 14,
+                    },
+                    end: Position {
+                        line: 0,
+                        character: 16,
+                    },
+                }
+            );
+
+            // The errors of `broken` are already published so they are not sent again
+            support::did_open(stdin, "test2.glu", "let m = import! broken\nm").await;
+
+            let diagnostic: PublishDiagnosticsParams =
+                support::expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostic.uri, support::test_url("test2.glu"));
+
+            support::did_open(stdin, "test3.glu", "1").await;
+
+            let diagnostic: PublishDiagnosticsParams =
+                support::expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostic.uri, support::test_url("test3.glu"));
+        })
+    });
+}
+
+#[test]
+fn close_clears_diagnostics() {
+    support::send_rpc(|stdin, stdout| {
+        Box::pin(async move {
+            let uri = support::test_url("test.glu");
+            support::did_open(stdin, "test.glu", "not \"\"").await;
+
+            let diagnostic: PublishDiagnosticsParams =
+                support::expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostic.diagnostics.len(), 1);
+
+            // Close the document while the change is still being checked
+            support::did_change(
+                stdin,
+                "test.glu",
+                2,
+                Range {
+                    start: Position {
+                        line: 0,
+                        character: 0,
+                    },
+                    end: Position {
+                        line: 0,
+                        character: 3,
+                    },
+                },
+                "not  ",
+            )
+            .await;
+            let did_close = support::notification(
+                "textDocument/didClose",
+                DidCloseTextDocumentParams {
+                    text_document: TextDocumentIdentifier { uri: uri.clone() },
+                },
+            );
+            support::write_message(stdin, did_close).await.unwrap();
+
+            // Once another document has been checked the change has been checked as well
+            support::did_open(stdin, "test2.glu", "1").await;
+
+            let mut cleared = false;
+            loop {
+                let message = support::expect_message(&mut *stdout).await;
+                if message["method"] != "textDocument/publishDiagnostics" {
+                    continue;
+                }
+                let diagnostic: PublishDiagnosticsParams =
+                    serde_json::from_value(message["params"].clone()).unwrap();
+                if diagnostic.uri == uri {
+                    assert!(!cleared, "Published after close: {:?}", diagnostic);
+                    if diagnostic.diagnostics.is_empty() && diagnostic.version.is_none() {
+                        cleared = true;
+                    }
+                } else {
+                    break;
+                }
+            }
+            assert!(cleared, "Diagnostics were not cleared");
+        })
+    });
+}
+
+#[test]
+fn changes_recheck_dependent_documents() {
+    support::send_rpc(|stdin, stdout| {
+        Box::pin(async move {
+            let documents = [
+                ("chain_a.glu", "let x = 1\n{ x }"),
+                ("chain_b.glu", "let a = import! chain_a\n{ y = a.x }"),
+                (
+                    "chain_c.glu",
+                    "let b = import! chain_b\nlet z : Int = b.y\nz",
+                ),
+                ("chain_other.glu", "1"),
+            ];
+            for (uri, text) in &documents {
+                support::did_open(stdin, uri, text).await;
+                let diagnostic: PublishDiagnosticsParams =
+                    support::expect_notification(&mut *stdout).await;
+                assert_eq!(diagnostic.uri, support::test_url(uri));
+                assert_eq!(diagnostic.diagnostics, vec![]);
+            }
+
+            // `chain_c` gets a type error from the change of `chain_a` which it imports through
+            // `chain_b`. Changing `chain_a` twice in a row checks its dependents only once.
+            for (version, text) in &[(2, "\"\""), (3, "\"a\"")] {
+                support::did_change(
+                    stdin,
+                    "chain_a.glu",
+                    *version,
+                    Range {
+                        start: Position {
+                            line: 0,
+                            character: 8,
+                        },
+                        end: Position {
+                            line: 0,
+                            character: 8 + if *version == 2 { 1 } else { 2 },
+                        },
+                    },
+                    text,
+                )
+                .await;
+            }
+
+            let mut published = Vec::new();
+            loop {
+                let diagnostic: PublishDiagnosticsParams =
+                    support::expect_notification(&mut *stdout).await;
+                let uri = diagnostic.uri.clone();
+                // `chain_a` is checked for one or both of the changes
+                if uri != support::test_url("chain_a.glu") {
+                    published.push(diagnostic);
+                }
+                if uri == support::test_url("chain_c.glu") {
+                    break;
+                }
+            }
+            assert_eq!(
+                published
+                    .iter()
+                    .map(|diagnostic| (diagnostic.uri.clone(), diagnostic.diagnostics.len()))
+                    .collect::<Vec<_>>(),
+                vec![
+                    (support::test_url("chain_b.glu"), 0),
+                    (support::test_url("chain_c.glu"), 1),
+                ]
+            );
+
+            // No other check is left
+            support::did_open(stdin, "chain_end.glu", "1").await;
+            let diagnostic: PublishDiagnosticsParams =
+                support::expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostic.uri, support::test_url("chain_end.glu"));
+        })
+    });
+}
+
+#[test]
+fn import_cycle() {
+    let loader = MemoryLoader::new();
+    loader.insert("zz_cycle_b", "let a = import! zz_cycle_a\n2");
+
+    support::send_rpc_with_loaders(vec![Box::new(loader)], |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "zz_cycle_a.glu", "let b = import! zz_cycle_b\n1").await;
+
+            let mut diagnostics = Vec::new();
+            for _ in 0..2 {
+                let params: PublishDiagnosticsParams =
+                    support::expect_notification(&mut *stdout).await;
+                assert_eq!(params.diagnostics.len(), 1, "{:?}", params);
+                let diagnostic = &params.diagnostics[0];
+                assert_eq!(
+                    diagnostic.code,
+                    Some(NumberOrString::String("import-cycle".into()))
+                );
+                diagnostics.push((params.uri, diagnostic.message.clone(), diagnostic.range));
+            }
+            let range = Range::new(Position::new(0, 8), Position::new(0, 26));
+            assert_eq!(
+                diagnostics,
+                vec![
+                    (
+                        support::test_url("zz_cycle_a.glu"),
+                        "Import cycle: `zz_cycle_a` -> `zz_cycle_b` -> `zz_cycle_a`".to_string(),
+                        range,
+                    ),
+                    (
+                        support::test_url("zz_cycle_b.glu"),
+                        "Import cycle: `zz_cycle_b` -> `zz_cycle_a` -> `zz_cycle_b`".to_string(),
+                        range,
+                    ),
+                ]
+            );
+
+            // Breaking the cycle clears the diagnostics
+            support::did_change(
+                stdin,
+                "zz_cycle_a.glu",
+                2,
+                Range::new(Position::new(0, 0), Position::new(0, 26)),
+                "let b = 2",
+            )
+            .await;
+            let params: PublishDiagnosticsParams = support::expect_notification(&mut *stdout).await;
+            assert_eq!(params.uri, support::test_url("zz_cycle_a.glu"));
+            assert_eq!(params.diagnostics, vec![]);
+        })
+    });
+}
+
+/// Opens a module with an expression nested `depth` parentheses deep
+async fn open_nested<W, R>(stdin: &mut W, stdout: R, depth: usize) -> PublishDiagnosticsParams
+where
+    W: ?Sized + tokio::io::AsyncWrite + Unpin,
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let text = format!("{}1{}\n", "(".repeat(depth), ")".repeat(depth));
+    support::did_open(stdin, "test.glu", &text).await;
+    support::expect_notification(stdout).await
+}
+
+#[test]
+fn deeply_nested_expression() {
+    support::send_rpc(|stdin, stdout| {
+        Box::pin(async move {
+            // Overflowed the stack of the runtime's threads
+            let diagnostics = open_nested(stdin, &mut *stdout, 1000).await;
+            assert_eq!(diagnostics.diagnostics, vec![]);
+
+            let diagnostics = open_nested(stdin, &mut *stdout, 5000).await;
+            assert_eq!(diagnostics.diagnostics.len(), 1, "{:?}", diagnostics);
+            let diagnostic = &diagnostics.diagnostics[0];
+            assert_eq!(
+                diagnostic.code,
+                Some(NumberOrString::String("E0902".into()))
+            );
+            assert_eq!(
+                diagnostic.range,
+                Range::new(Position::new(0, 4096), Position::new(0, 4097))
+            );
+
+            // Operators nest without brackets
+            let text = format!("1{}\n", " + 1".repeat(5000));
+            support::did_open(stdin, "operators.glu", &text).await;
+            let diagnostics: PublishDiagnosticsParams =
+                support::expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostics.diagnostics.len(), 1, "{:?}", diagnostics);
+            assert_eq!(
+                diagnostics.diagnostics[0].code,
+                Some(NumberOrString::String("E0902".into()))
+            );
+        })
+    });
+}
+
+#[test]
+fn analysis_stack_size_from_initialization_options() {
+    support::send_rpc(|stdin, stdout| {
+        Box::pin(async move {
+            #[allow(deprecated)]
+            let initialize = support::method_call(
+                "initialize",
+                1,
+                InitializeParams {
+                    process_id: None,
+                    root_path: None,
+                    root_uri: None,
+                    initialization_options: Some(serde_json::json!({
+                        "analysisStackSize": 1024 * 1024
+                    })),
+                    capabilities: ClientCapabilities::default(),
+                    trace: None,
+                    workspace_folders: None,
+                    client_info: None,
+                    locale: None,
+                },
+            );
+            support::write_message(stdin, initialize).await.unwrap();
+            let _: InitializeResult = support::expect_response(&mut *stdout).await;
+
+            let diagnostics = open_nested(stdin, &mut *stdout, 100).await;
+            assert_eq!(diagnostics.diagnostics.len(), 1, "{:?}", diagnostics);
+            assert_eq!(
+                diagnostics.diagnostics[0].message,
+                "Expression too deeply nested, more than 64 levels can not be checked"
+            );
+        })
+    });
+}
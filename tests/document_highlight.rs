@@ -0,0 +1,72 @@
+#[allow(unused)]
+mod support;
+
+use lsp_types::*;
+
+use gluon_language_server::MemoryLoader;
+
+use crate::support::{expect_notification, expect_response, method_call, write_message};
+
+async fn document_highlight<W>(stdin: &mut W, id: u64, uri: &str, position: Position)
+where
+    W: ?Sized + tokio::io::AsyncWrite + Unpin,
+{
+    let params = DocumentHighlightParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: support::test_url(uri),
+            },
+            position,
+        },
+        work_done_progress_params: WorkDoneProgressParams::default(),
+        partial_result_params: PartialResultParams::default(),
+    };
+    write_message(
+        stdin,
+        method_call("textDocument/documentHighlight", id, params),
+    )
+    .await
+    .unwrap();
+}
+
+#[test]
+fn highlight_imported_symbol() {
+    let loader = MemoryLoader::new();
+    loader.insert(
+        "highlight_dep",
+        "let twice x : Int -> Int = x #Int+ x\n{ twice }",
+    );
+
+    support::send_rpc_with_loaders(vec![Box::new(loader)], |stdin, stdout| {
+        Box::pin(async move {
+            let text = "let { twice } = import! highlight_dep\ntwice (twice 1)";
+            support::did_open(stdin, "test.glu", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            // Highlighting the binding or any use of it gives the same ranges, all in this file
+            let expected = vec![
+                Range::new(Position::new(0, 6), Position::new(0, 11)),
+                Range::new(Position::new(1, 0), Position::new(1, 5)),
+                Range::new(Position::new(1, 7), Position::new(1, 12)),
+            ];
+            let positions = vec![
+                Position::new(0, 8),
+                Position::new(1, 2),
+                Position::new(1, 9),
+            ];
+            for (id, position) in positions.into_iter().enumerate() {
+                document_highlight(stdin, id as u64, "test.glu", position).await;
+                let highlights: Option<Vec<DocumentHighlight>> =
+                    expect_response(&mut *stdout).await;
+                let mut ranges: Vec<_> = highlights
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|highlight| highlight.range)
+                    .collect();
+                ranges.sort_by_key(|range| (range.start.line, range.start.character));
+                assert_eq!(ranges, expected, "{:?}", position);
+            }
+        })
+    });
+}
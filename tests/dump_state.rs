@@ -0,0 +1,243 @@
+#[allow(unused)]
+mod support;
+
+use lsp_types::*;
+use serde_json::json;
+
+use gluon_language_server::{DocumentState, DumpStateResult};
+
+use crate::support::{expect_notification, expect_response, test_url, write_message};
+
+#[test]
+fn dump_state() {
+    // The request is only answered while tracing the module which answers it
+    std::env::set_var(
+        "RUST_LOG",
+        "gluon_language_server::command::dump_state=trace",
+    );
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", "1").await;
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            write_message(
+                stdin,
+                json!({ "jsonrpc": "2.0", "id": 1, "method": "gluon/dumpState" }),
+            )
+            .await
+            .unwrap();
+            let state: DumpStateResult = expect_response(&mut *stdout).await;
+            assert_eq!(state.frames_decoded, 2);
+            assert!(!state.decoder.partial_frame);
+            assert_eq!(state.dispatch_queue_depth, 1);
+            assert_eq!(state.pending_requests, vec![]);
+            assert!(
+                state.documents.contains(&DocumentState {
+                    uri: test_url("test"),
+                    version: Some(1),
+                    cached: true,
+                }),
+                "{:#?}",
+                state.documents
+            );
+        })
+    });
+}
+
+#[test]
+fn check_cache_keeps_open_documents() {
+    std::env::set_var(
+        "RUST_LOG",
+        "gluon_language_server::command::dump_state=trace",
+    );
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            write_message(
+                stdin,
+                support::notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams {
+                        settings: json!({ "gluon": { "checkCacheSize": 4 } }),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+
+            for document in &["cache_a", "cache_b", "cache_c"] {
+                support::did_open(stdin, document, "1").await;
+                let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            }
+
+            // Checks every module of the standard library which the prelude imports
+            write_message(
+                stdin,
+                support::method_call(
+                    "workspace/symbol",
+                    1,
+                    WorkspaceSymbolParams {
+                        query: "zz".into(),
+                        ..WorkspaceSymbolParams::default()
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+            let _: Vec<SymbolInformation> = expect_response(&mut *stdout).await;
+
+            write_message(
+                stdin,
+                json!({ "jsonrpc": "2.0", "id": 2, "method": "gluon/dumpState" }),
+            )
+            .await
+            .unwrap();
+            let state: DumpStateResult = expect_response(&mut *stdout).await;
+            let (open, closed): (Vec<_>, Vec<_>) = state
+                .documents
+                .iter()
+                .partition(|document| document.version.is_some());
+            assert_eq!(open.len(), 3);
+            assert!(open.iter().all(|document| document.cached), "{:#?}", open);
+            // The standard library shares the one slot which the open documents leave
+            assert!(closed.len() > 1);
+            assert_eq!(
+                closed.iter().filter(|document| document.cached).count(),
+                1,
+                "{:#?}",
+                closed
+            );
+        })
+    });
+}
+
+#[test]
+fn evicted_modules_are_imported_again() {
+    std::env::set_var(
+        "RUST_LOG",
+        "gluon_language_server::command::dump_state=trace",
+    );
+    let dir = std::env::temp_dir().join(format!("gluon_check_cache_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let dependencies = ["cache_dep_a", "cache_dep_b", "cache_dep_c"];
+    for (i, dependency) in dependencies.iter().enumerate() {
+        std::fs::write(
+            dir.join(format!("{}.glu", dependency)),
+            format!("{{ x = {} }}", i),
+        )
+        .unwrap();
+    }
+    let settings = json!({ "gluon": { "modulePaths": [dir], "checkCacheSize": 2 } });
+
+    // The modules among `dependencies` which the server keeps a state for
+    fn known<'a>(state: &DumpStateResult, dependencies: &[&'a str]) -> Vec<&'a str> {
+        dependencies
+            .iter()
+            .cloned()
+            .filter(|dependency| {
+                let file = format!("/{}.glu", dependency);
+                state
+                    .documents
+                    .iter()
+                    .any(|document| document.uri.as_str().ends_with(&file))
+            })
+            .collect()
+    }
+
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            write_message(
+                stdin,
+                support::notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams { settings },
+                ),
+            )
+            .await
+            .unwrap();
+
+            let text = r#"
+let a = import! cache_dep_a
+let b = import! cache_dep_b
+let c = import! cache_dep_c
+a.x #Int+ b.x #Int+ c.x
+"#;
+            support::did_open(stdin, "cache_main", text).await;
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostics.diagnostics, vec![]);
+
+            write_message(
+                stdin,
+                json!({ "jsonrpc": "2.0", "id": 1, "method": "gluon/dumpState" }),
+            )
+            .await
+            .unwrap();
+            let state: DumpStateResult = expect_response(&mut *stdout).await;
+            assert_eq!(known(&state, &dependencies), dependencies);
+
+            // Checks every module so that all but one of the modules which are not open are evicted
+            write_message(
+                stdin,
+                support::method_call(
+                    "workspace/symbol",
+                    2,
+                    WorkspaceSymbolParams {
+                        query: "zz".into(),
+                        ..WorkspaceSymbolParams::default()
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+            let _: Vec<SymbolInformation> = expect_response(&mut *stdout).await;
+
+            write_message(
+                stdin,
+                json!({ "jsonrpc": "2.0", "id": 3, "method": "gluon/dumpState" }),
+            )
+            .await
+            .unwrap();
+            let state: DumpStateResult = expect_response(&mut *stdout).await;
+            assert!(
+                known(&state, &dependencies).len() <= 1,
+                "{:#?}",
+                state.documents
+            );
+            assert_eq!(
+                state
+                    .documents
+                    .iter()
+                    .filter(|document| document.version.is_none() && document.cached)
+                    .count(),
+                1,
+                "{:#?}",
+                state.documents
+            );
+
+            // Checking the document again imports the evicted modules again
+            support::did_change(
+                stdin,
+                "cache_main",
+                2,
+                Range {
+                    start: Position::new(4, 23),
+                    end: Position::new(4, 23),
+                },
+                " #Int+ 1",
+            )
+            .await;
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostics.diagnostics, vec![]);
+
+            write_message(
+                stdin,
+                json!({ "jsonrpc": "2.0", "id": 4, "method": "gluon/dumpState" }),
+            )
+            .await
+            .unwrap();
+            let state: DumpStateResult = expect_response(&mut *stdout).await;
+            assert_eq!(known(&state, &dependencies), dependencies);
+        })
+    });
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,119 @@
+#[macro_use]
+extern crate pretty_assertions;
+
+#[allow(unused)]
+mod support;
+
+use lsp_types::*;
+
+use gluon_language_server::MemoryLoader;
+
+use crate::support::{did_change, expect_notification, expect_response, hover};
+
+const PROGRAM: &str = r#"let { identity } = import! e2e_math
+let answer = identity 21
+answer
+"#;
+
+fn gluon_string(s: &str) -> HoverContents {
+    HoverContents::Scalar(MarkedString::LanguageString(LanguageString {
+        language: "gluon".into(),
+        value: s.into(),
+    }))
+}
+
+/// Initializes the server and opens `PROGRAM`, which imports a module that only the server's
+/// loader knows about
+async fn start_session<W, R>(stdin: &mut W, stdout: &mut R)
+where
+    W: ?Sized + tokio::io::AsyncWrite + Unpin,
+    R: ?Sized + tokio::io::AsyncBufRead + Unpin,
+{
+    support::initialize(stdin, 1, ClientCapabilities::default()).await;
+    let result: InitializeResult = expect_response(&mut *stdout).await;
+    assert!(result.capabilities.hover_provider.is_some());
+    support::write_message(
+        stdin,
+        support::notification("initialized", InitializedParams {}),
+    )
+    .await
+    .unwrap();
+
+    support::did_open(stdin, "e2e.glu", PROGRAM).await;
+    let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+    assert_eq!(diagnostics.uri, support::test_url("e2e.glu"));
+    assert_eq!(diagnostics.version, Some(1));
+    assert_eq!(diagnostics.diagnostics, vec![]);
+}
+
+fn math_loader() -> Vec<Box<dyn gluon_language_server::ModuleLoader>> {
+    let loader = MemoryLoader::new();
+    loader.insert(
+        "e2e_math",
+        "let identity x : Int -> Int = x\n{ identity }\n",
+    );
+    vec![Box::new(loader)]
+}
+
+#[test]
+fn initialize_open_and_hover() {
+    support::send_rpc_with_loaders(math_loader(), |stdin, stdout| {
+        Box::pin(async move {
+            start_session(stdin, stdout).await;
+
+            hover(stdin, 2, "e2e.glu", Position::new(2, 3)).await;
+            let result: Hover = expect_response(&mut *stdout).await;
+            assert_eq!(
+                result,
+                Hover {
+                    contents: gluon_string("Int"),
+                    range: Some(Range::new(Position::new(2, 0), Position::new(2, 6))),
+                }
+            );
+
+            // A name which comes from the imported module
+            hover(stdin, 3, "e2e.glu", Position::new(1, 15)).await;
+            let result: Hover = expect_response(&mut *stdout).await;
+            assert_eq!(result.contents, gluon_string("Int -> Int"));
+        })
+    });
+}
+
+#[test]
+fn hover_after_change() {
+    support::send_rpc_with_loaders(math_loader(), |stdin, stdout| {
+        Box::pin(async move {
+            start_session(stdin, stdout).await;
+
+            // `answer` becomes a string
+            did_change(
+                stdin,
+                "e2e.glu",
+                2,
+                Range::new(Position::new(1, 13), Position::new(1, 24)),
+                "\"forty two\"",
+            )
+            .await;
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostics.version, Some(2));
+            assert_eq!(diagnostics.diagnostics, vec![]);
+
+            hover(stdin, 2, "e2e.glu", Position::new(2, 3)).await;
+            let result: Hover = expect_response(&mut *stdout).await;
+            assert_eq!(result.contents, gluon_string("String"));
+
+            // An edit for an older version than the current one is not applied
+            did_change(
+                stdin,
+                "e2e.glu",
+                1,
+                Range::new(Position::new(1, 13), Position::new(1, 24)),
+                "1.0",
+            )
+            .await;
+            hover(stdin, 3, "e2e.glu", Position::new(2, 3)).await;
+            let result: Hover = expect_response(&mut *stdout).await;
+            assert_eq!(result.contents, gluon_string("String"));
+        })
+    });
+}
@@ -0,0 +1,138 @@
+#[allow(unused)]
+mod support;
+
+use lsp_types::*;
+
+use serde_json::{json, Value};
+
+use gluon_language_server::{EvaluateParams, EvaluateResult};
+
+use crate::support::{
+    expect_message, expect_notification, expect_response, method_call, write_message,
+};
+
+async fn evaluate<W>(stdin: &mut W, id: u64, expression: &str)
+where
+    W: ?Sized + tokio::io::AsyncWrite + Unpin,
+{
+    let params = EvaluateParams {
+        uri: support::test_url("test"),
+        expression: expression.into(),
+    };
+    write_message(stdin, method_call("gluon/evaluate", id, params))
+        .await
+        .unwrap();
+}
+
+async fn expect_error<R>(stdout: R) -> Value
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let response = expect_message(stdout).await;
+    assert!(response.get("result").is_none(), "{}", response);
+    response["error"].clone()
+}
+
+const MODULE: &str = r#"
+let x = 1
+let double y : Int -> Int = y + y
+let loop y : Int -> Int = loop y
+{ double }
+"#;
+
+#[test]
+fn evaluate_in_module_scope() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", MODULE).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            // `x` is not exported but it is in scope
+            evaluate(stdin, 1, "double x").await;
+            let result: EvaluateResult = expect_response(&mut *stdout).await;
+            assert_eq!(
+                result,
+                EvaluateResult {
+                    value: "2".into(),
+                    typ: "Int".into(),
+                }
+            );
+        })
+    });
+}
+
+#[test]
+fn evaluate_type_error() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", MODULE).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            evaluate(stdin, 1, r#"double "1""#).await;
+            let error = expect_error(&mut *stdout).await;
+            assert_eq!(error["data"], json!({ "kind": "compile" }));
+            assert!(
+                error["message"]
+                    .as_str()
+                    .unwrap()
+                    .contains("Expected the following types to be equal"),
+                "{}",
+                error
+            );
+        })
+    });
+}
+
+#[test]
+fn evaluation_is_interrupted() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", MODULE).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            evaluate(stdin, 1, "loop x").await;
+            let error = expect_error(&mut *stdout).await;
+            assert_eq!(error["data"], json!({ "kind": "timeout" }));
+
+            // The server is still responsive
+            evaluate(stdin, 2, "x").await;
+            let result: EvaluateResult = expect_response(&mut *stdout).await;
+            assert_eq!(result.value, "1");
+        })
+    });
+}
+
+#[test]
+fn cancelled_evaluation_is_interrupted() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", MODULE).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            evaluate(stdin, 1, "loop x").await;
+            write_message(
+                stdin,
+                support::notification(
+                    "$/cancelRequest",
+                    CancelParams {
+                        id: NumberOrString::Number(1),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+            // Cancelled rather than interrupted after the timeout
+            let error = expect_error(&mut *stdout).await;
+            assert_eq!(error["code"], -32800, "{}", error);
+
+            // The server is still responsive
+            evaluate(stdin, 2, "x").await;
+            let result: EvaluateResult = expect_response(&mut *stdout).await;
+            assert_eq!(result.value, "1");
+        })
+    });
+}
@@ -0,0 +1,68 @@
+#[allow(unused)]
+mod support;
+
+use std::{io, thread, time::Duration};
+
+use {
+    jsonrpc_core::{
+        params::Params,
+        request::{Call, Notification},
+        version::Version,
+    },
+    tokio::io::AsyncReadExt,
+};
+
+use gluon::ThreadExt;
+
+use gluon_language_server::{ModuleLoader, Server};
+
+use crate::support::{method_call, write_message};
+
+/// Holds up the check of any module which imports `slow`
+struct SlowLoader;
+
+impl ModuleLoader for SlowLoader {
+    fn load_module(&self, module: &str) -> io::Result<Option<String>> {
+        if module == "slow" {
+            thread::sleep(Duration::from_millis(500));
+            Ok(Some("1".into()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[test]
+fn exit_writes_responses_but_drops_pending_diagnostics() {
+    support::run_no_panic_catch(async {
+        let (mut stdin, stdin_read) = tokio::io::duplex(4096);
+        let (stdout_write, mut stdout) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let thread = gluon::new_vm_async().await;
+            thread.get_database_mut().set_implicit_prelude(false);
+            Server::start_with_loaders(thread, vec![Box::new(SlowLoader)], stdin_read, stdout_write)
+                .await
+                .unwrap()
+        });
+
+        // Checking the document waits on the loader so its diagnostics are still pending when
+        // `exit` arrives
+        support::did_open(&mut stdin, "test", "import! slow").await;
+        write_message(&mut stdin, method_call("shutdown", 1, ()))
+            .await
+            .unwrap();
+        let exit = Call::Notification(Notification {
+            jsonrpc: Some(Version::V2),
+            method: "exit".into(),
+            params: Params::None,
+        });
+        write_message(&mut stdin, exit).await.unwrap();
+
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).await.unwrap();
+        server.await.unwrap();
+
+        assert!(output.contains(r#""id":1"#), "{}", output);
+        assert!(!output.contains("publishDiagnostics"), "{}", output);
+    });
+}
@@ -8,11 +8,30 @@ use tokio::io::AsyncWrite;
 
 use lsp_types::*;
 
+use gluon_language_server::{FormatPreviewParams, FormatPreviewResult};
+
 use crate::support::{did_change_event, expect_notification, expect_response, hover};
 
 async fn format<W: ?Sized>(stdin: &mut W, id: u64, uri: &str)
 where
     W: AsyncWrite + std::marker::Unpin,
+{
+    format_with_options(
+        stdin,
+        id,
+        uri,
+        FormattingOptions {
+            tab_size: 4,
+            insert_spaces: true,
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+async fn format_with_options<W>(stdin: &mut W, id: u64, uri: &str, options: FormattingOptions)
+where
+    W: ?Sized + AsyncWrite + std::marker::Unpin,
 {
     let hover = support::method_call(
         "textDocument/formatting",
@@ -21,11 +40,7 @@ where
             text_document: TextDocumentIdentifier {
                 uri: support::test_url(uri),
             },
-            options: FormattingOptions {
-                tab_size: 4,
-                insert_spaces: true,
-                ..Default::default()
-            },
+            options,
             work_done_progress_params: Default::default(),
         },
     );
@@ -74,6 +89,41 @@ x + 2
     });
 }
 
+#[test]
+fn indentation_options() {
+    let text = r#"
+let f x =
+  let y = x
+  y
+f
+"#;
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let cases = vec![
+                (4, true, "\nlet f x =\n    let y = x\n    y\nf\n"),
+                (2, true, "\nlet f x =\n  let y = x\n  y\nf\n"),
+                (4, false, "\nlet f x =\n\tlet y = x\n\ty\nf\n"),
+            ];
+            for (id, (tab_size, insert_spaces, expected)) in cases.into_iter().enumerate() {
+                let options = FormattingOptions {
+                    tab_size,
+                    insert_spaces,
+                    ..Default::default()
+                };
+                format_with_options(stdin, id as u64, "test", options).await;
+
+                let edits: Vec<TextEdit> = expect_response(&mut *stdout).await;
+                assert_eq!(edits.len(), 1);
+                assert_eq!(edits[0].new_text, expected);
+            }
+        })
+    });
+}
+
 #[test]
 fn empty_content_changes_do_not_lockup_server() {
     let text = r#"
@@ -126,3 +176,250 @@ x + "abc"
         })
     });
 }
+
+const COMMENTED: &str = r#"
+/// The answer
+let   x =    42 // not 41
+let y =  x   + 1 // trailing
+y
+"#;
+
+#[test]
+fn comments_are_kept_in_place() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", COMMENTED).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            format(stdin, 2, "test").await;
+
+            let edits: Vec<TextEdit> = expect_response(&mut *stdout).await;
+            assert_eq!(edits.len(), 1);
+            assert_eq!(
+                edits[0].new_text,
+                "\n/// The answer\nlet x = 42 // not 41\nlet y = x + 1 // trailing\ny\n"
+            );
+        })
+    });
+}
+
+#[test]
+fn range_formatting() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", COMMENTED).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let line = |line| Position { line, character: 0 };
+            let request = support::method_call(
+                "textDocument/rangeFormatting",
+                2,
+                DocumentRangeFormattingParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: support::test_url("test"),
+                    },
+                    range: Range {
+                        start: line(3),
+                        end: line(4),
+                    },
+                    options: FormattingOptions {
+                        tab_size: 4,
+                        insert_spaces: true,
+                        ..Default::default()
+                    },
+                    work_done_progress_params: Default::default(),
+                },
+            );
+            support::write_message(stdin, request).await.unwrap();
+
+            // `let x` is outside of the range and is left as is
+            let edits: Vec<TextEdit> = expect_response(&mut *stdout).await;
+            assert_eq!(
+                edits,
+                vec![TextEdit {
+                    range: Range {
+                        start: line(3),
+                        end: line(4),
+                    },
+                    new_text: "let y = x + 1 // trailing\n".into(),
+                }]
+            );
+        })
+    });
+}
+
+async fn will_save<W>(stdin: &mut W, id: u64)
+where
+    W: ?Sized + AsyncWrite + std::marker::Unpin,
+{
+    let request = support::method_call(
+        "textDocument/willSaveWaitUntil",
+        id,
+        WillSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier {
+                uri: support::test_url("test"),
+            },
+            reason: TextDocumentSaveReason::Manual,
+        },
+    );
+    support::write_message(stdin, request).await.unwrap();
+}
+
+#[test]
+fn format_on_save() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", COMMENTED).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            // Formatting on save is disabled by default
+            will_save(stdin, 2).await;
+            let edits: Option<Vec<TextEdit>> = expect_response(&mut *stdout).await;
+            assert_eq!(edits, None);
+
+            support::write_message(
+                stdin,
+                support::notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams {
+                        settings: serde_json::json!({ "gluon": { "formatOnSave": true } }),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+            // The open document is checked again with the new settings
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            will_save(stdin, 3).await;
+            let edits: Option<Vec<TextEdit>> = expect_response(&mut *stdout).await;
+            let line = |line| Position { line, character: 0 };
+            assert_eq!(
+                edits,
+                Some(vec![
+                    TextEdit {
+                        range: Range {
+                            start: line(2),
+                            end: line(3),
+                        },
+                        new_text: "let x = 42 // not 41\n".into(),
+                    },
+                    TextEdit {
+                        range: Range {
+                            start: line(3),
+                            end: line(4),
+                        },
+                        new_text: "let y = x + 1 // trailing\n".into(),
+                    },
+                ])
+            );
+        })
+    });
+}
+
+#[test]
+fn documents_which_do_not_parse_are_saved_unformatted() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::write_message(
+                stdin,
+                support::notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams {
+                        settings: serde_json::json!({ "gluon": { "formatOnSave": true } }),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+            support::did_open(stdin, "test", "let x = \n").await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            will_save(stdin, 2).await;
+            let edits: Option<Vec<TextEdit>> = expect_response(&mut *stdout).await;
+            assert_eq!(edits, None);
+        })
+    });
+}
+
+async fn format_preview<W>(stdin: &mut W, id: u64, range: Option<Range>)
+where
+    W: ?Sized + AsyncWrite + std::marker::Unpin,
+{
+    let request = support::method_call(
+        "gluon/formatPreview",
+        id,
+        FormatPreviewParams {
+            text_document: TextDocumentIdentifier {
+                uri: support::test_url("test"),
+            },
+            range,
+            options: None,
+        },
+    );
+    support::write_message(stdin, request).await.unwrap();
+}
+
+#[test]
+fn format_preview_returns_the_formatted_text() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", COMMENTED).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            format_preview(stdin, 2, None).await;
+            let preview: FormatPreviewResult = expect_response(&mut *stdout).await;
+            assert_eq!(
+                preview,
+                FormatPreviewResult {
+                    text: "\n/// The answer\nlet x = 42 // not 41\nlet y = x + 1 // trailing\ny\n"
+                        .into(),
+                    skipped: false,
+                }
+            );
+
+            // `let x` is outside of the range and is left as is
+            let line = |line| Position { line, character: 0 };
+            format_preview(
+                stdin,
+                3,
+                Some(Range {
+                    start: line(3),
+                    end: line(4),
+                }),
+            )
+            .await;
+            let preview: FormatPreviewResult = expect_response(&mut *stdout).await;
+            assert_eq!(
+                preview.text,
+                COMMENTED.replace("let y =  x   + 1", "let y = x + 1")
+            );
+        })
+    });
+}
+
+#[test]
+fn format_preview_skips_documents_which_do_not_parse() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", "let x = \n").await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            format_preview(stdin, 2, None).await;
+            let preview: FormatPreviewResult = expect_response(&mut *stdout).await;
+            assert_eq!(
+                preview,
+                FormatPreviewResult {
+                    text: "let x = \n".into(),
+                    skipped: true,
+                }
+            );
+        })
+    });
+}
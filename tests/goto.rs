@@ -156,3 +156,84 @@ test
         }),
     )
 }
+
+async fn text_document_declaration<W>(stdin: &mut W, id: u64, uri: &str, position: Position)
+where
+    W: ?Sized + AsyncWrite + std::marker::Unpin,
+{
+    let declaration = support::method_call(
+        "textDocument/declaration",
+        id,
+        TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: support::test_url(uri),
+            },
+            position,
+        },
+    );
+
+    support::write_message(stdin, declaration).await.unwrap();
+}
+
+fn test_goto_declaration(text: &str, expected: Vec<(Position, Option<Range>)>) {
+    let text = text.to_string();
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", &text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            for (id, (position, range)) in expected.into_iter().enumerate() {
+                text_document_declaration(stdin, id as u64, "test", position).await;
+
+                let declaration: Option<GotoDefinitionResponse> =
+                    expect_response(&mut *stdout).await;
+                assert_eq!(
+                    declaration,
+                    range.map(|range| GotoDefinitionResponse::Scalar(Location {
+                        uri: test_url("test"),
+                        range,
+                    })),
+                    "{:?}",
+                    position
+                );
+            }
+        })
+    });
+}
+
+fn range(start_line: u32, start: u32, end_line: u32, end: u32) -> Range {
+    Range {
+        start: Position::new(start_line, start),
+        end: Position::new(end_line, end),
+    }
+}
+
+#[test]
+fn goto_declaration() {
+    let text = r#"
+rec
+let even x = if x #Int== 0 then True else odd (x #Int- 1)
+let odd x = if x #Int== 0 then False else even (x #Int- 1)
+rec
+type Tree = | Leaf | Node Forest
+type Forest = Array Tree
+let plain = 1
+let t : Forest = []
+even (plain #Int+ 1)
+"#;
+    test_goto_declaration(
+        text,
+        vec![
+            // Mutually recursive bindings go to the header of the group
+            (Position::new(9, 2), Some(range(1, 0, 2, 8))),
+            (Position::new(2, 43), Some(range(1, 0, 2, 8))),
+            // Recursive types as well
+            (Position::new(8, 10), Some(range(4, 0, 5, 9))),
+            // Plain bindings are declared where they are defined
+            (Position::new(9, 7), Some(range(7, 4, 7, 9))),
+            // Builtins are not declared anywhere
+            (Position::new(9, 13), None),
+        ],
+    );
+}
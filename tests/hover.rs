@@ -5,7 +5,7 @@ mod support;
 
 use lsp_types::*;
 
-use crate::support::{expect_notification, expect_response, hover};
+use crate::support::{did_change, expect_notification, expect_response, hover};
 
 const STREAM_SOURCE: &'static str = r#"
 let prelude = import! "std/prelude.glu"
@@ -201,3 +201,543 @@ let { Functor } = import! std.functor
         })
     });
 }
+
+fn range(line: u32, start: u32, end: u32) -> Option<Range> {
+    multiline_range(line, start, line, end)
+}
+
+fn multiline_range(start_line: u32, start: u32, end_line: u32, end: u32) -> Option<Range> {
+    Some(Range {
+        start: Position {
+            line: start_line,
+            character: start,
+        },
+        end: Position {
+            line: end_line,
+            character: end,
+        },
+    })
+}
+
+#[test]
+fn expression_hover() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let src = r#"
+let add x y : Int -> Int -> Int = x #Int+ y
+let s = "abc"
+add 1 (add 2 3)
+(s, [1, 2])
+add
+    1
+    2
+"#;
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let expected = vec![
+                // String literal
+                ((2, 10), "String", range(2, 8, 13)),
+                // The function of an application
+                ((3, 0), "Int -> Int -> Int", range(3, 0, 3)),
+                // Parenthesized application, only the parenthesis is highlighted
+                ((3, 6), "Int", range(3, 6, 7)),
+                // Tuple
+                ((4, 0), "(String, Array Int)", range(4, 0, 1)),
+                // Array
+                ((4, 4), "Array Int", range(4, 4, 5)),
+                // Whitespace inside an application shows the result of the application but
+                // highlights nothing
+                ((6, 2), "Int", None),
+            ];
+            for (id, ((line, character), typ, range)) in expected.into_iter().enumerate() {
+                hover(stdin, id as u64, "test", Position { line, character }).await;
+
+                let hover: Hover = expect_response(&mut *stdout).await;
+                assert_eq!(
+                    hover,
+                    Hover {
+                        contents: HoverContents::Scalar(gluon_string(typ)),
+                        range,
+                    }
+                );
+            }
+        })
+    });
+}
+
+#[test]
+fn hover_range_is_token() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let src = r#"
+let add x y : Int -> Int -> Int = x #Int+ y
+add (add 1 2) [3, 4] 5
+"#;
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let expected = vec![
+                // The type of the application in parentheses
+                ((2, 4), "Int", range(2, 4, 5)),
+                // The type of the array, which is an error as `add` takes an `Int`
+                ((2, 14), "Array Int", range(2, 14, 15)),
+            ];
+            for (id, ((line, character), typ, range)) in expected.into_iter().enumerate() {
+                hover(stdin, id as u64, "test", Position { line, character }).await;
+
+                let hover: Hover = expect_response(&mut *stdout).await;
+                assert_eq!(
+                    hover,
+                    Hover {
+                        contents: HoverContents::Scalar(gluon_string(typ)),
+                        range,
+                    },
+                    "{}:{}",
+                    line,
+                    character
+                );
+            }
+        })
+    });
+}
+
+#[test]
+fn hover_expression_starting_with_string_literal() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let src = r##"
+let (<>) x y : String -> String -> String = x
+"a"  <>  "b"
+r#"a"#  <> "b"
+"##;
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let expected = vec![
+                ((2, 1), range(2, 0, 3)),
+                ((2, 10), range(2, 9, 12)),
+                // The whitespace shows the type of the whole expression which only starts with a
+                // literal so nothing is highlighted
+                ((2, 4), None),
+                ((3, 2), range(3, 0, 6)),
+                ((3, 7), None),
+            ];
+            for (id, ((line, character), range)) in expected.into_iter().enumerate() {
+                hover(stdin, id as u64, "test", Position { line, character }).await;
+
+                let hover: Hover = expect_response(&mut *stdout).await;
+                assert_eq!(
+                    hover,
+                    Hover {
+                        contents: HoverContents::Scalar(gluon_string("String")),
+                        range,
+                    },
+                    "{}:{}",
+                    line,
+                    character
+                );
+            }
+        })
+    });
+}
+
+#[test]
+fn numeric_literal_hover() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let src = r#"
+let half x : Float -> Float = x #Float/ 2.0
+half 3.0
+half 3
+"#;
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let expected = vec![
+                ((1, 40), "Float", range(1, 40, 43)),
+                ((2, 6), "Float", range(2, 5, 8)),
+                // Literals are not converted, an integer where a float is expected is still an
+                // `Int` (and an error)
+                ((3, 5), "Int", range(3, 5, 6)),
+            ];
+            for (id, ((line, character), typ, range)) in expected.into_iter().enumerate() {
+                hover(stdin, id as u64, "test", Position { line, character }).await;
+
+                let hover: Hover = expect_response(&mut *stdout).await;
+                assert_eq!(
+                    hover,
+                    Hover {
+                        contents: HoverContents::Scalar(gluon_string(typ)),
+                        range,
+                    }
+                );
+            }
+        })
+    });
+}
+
+#[test]
+fn hover_markup_follows_client_capabilities() {
+    for (content_format, kind) in [
+        (Some(&[MarkupKind::Markdown][..]), MarkupKind::Markdown),
+        (Some(&[MarkupKind::PlainText][..]), MarkupKind::PlainText),
+        (None, MarkupKind::PlainText),
+    ] {
+        let capabilities = ClientCapabilities {
+            text_document: Some(TextDocumentClientCapabilities {
+                hover: Some(HoverClientCapabilities {
+                    dynamic_registration: None,
+                    content_format: content_format.map(|formats| formats.to_vec()),
+                }),
+                ..TextDocumentClientCapabilities::default()
+            }),
+            ..ClientCapabilities::default()
+        };
+        support::send_rpc(move |stdin, stdout| {
+            Box::pin(async move {
+                support::initialize(stdin, 1, capabilities).await;
+                let _: InitializeResult = expect_response(&mut *stdout).await;
+
+                let src = r#"
+/// The answer
+let answer = 42
+answer
+"#;
+                support::did_open(stdin, "test", src).await;
+
+                let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+                hover(
+                    stdin,
+                    2,
+                    "test",
+                    Position {
+                        line: 3,
+                        character: 2,
+                    },
+                )
+                .await;
+
+                let hover: Hover = expect_response(stdout).await;
+                assert_eq!(
+                    hover.contents,
+                    HoverContents::Markup(MarkupContent {
+                        kind,
+                        value: "Int\n\nThe answer".into(),
+                    })
+                );
+            })
+        });
+    }
+}
+
+#[test]
+fn hover_falls_back_to_last_successful_check() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let src = r#"
+let test = 1
+let test2 = test
+test2
+"#;
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            // The syntax error leaves no types in the latest check
+            let end = Position {
+                line: 3,
+                character: 0,
+            };
+            did_change(stdin, "test", 2, Range { start: end, end }, "let x = (\n").await;
+
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_ne!(diagnostics.diagnostics, vec![]);
+
+            hover(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 2,
+                    character: 14,
+                },
+            )
+            .await;
+
+            let hover: Hover = expect_response(stdout).await;
+
+            assert_eq!(
+                hover,
+                Hover {
+                    contents: HoverContents::Scalar(gluon_string("Int")),
+                    range: range(2, 12, 16),
+                }
+            );
+        })
+    });
+}
+
+#[test]
+fn hover_record_table() {
+    let capabilities = ClientCapabilities {
+        text_document: Some(TextDocumentClientCapabilities {
+            hover: Some(HoverClientCapabilities {
+                dynamic_registration: None,
+                content_format: Some(vec![MarkupKind::Markdown]),
+            }),
+            ..TextDocumentClientCapabilities::default()
+        }),
+        ..ClientCapabilities::default()
+    };
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+            support::write_message(
+                stdin,
+                support::notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams {
+                        settings: serde_json::json!({
+                            "gluon": { "hover": { "recordTables": true } }
+                        }),
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+
+            let src = r#"
+type Point = { x : Int, y : Int, label : String }
+let origin : Point = { x = 0, y = 0, label = "origin" }
+origin
+"#;
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            hover(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 3,
+                    character: 2,
+                },
+            )
+            .await;
+
+            let hover: Hover = expect_response(stdout).await;
+            assert_eq!(
+                hover.contents,
+                HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: "`Point`\n\n\
+                            | Field | Type |\n\
+                            | --- | --- |\n\
+                            | `x` | `Int` |\n\
+                            | `y` | `Int` |\n\
+                            | `label` | `String` |\n"
+                        .into(),
+                })
+            );
+        })
+    });
+}
+
+#[test]
+fn hover_tuple_pattern() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let src = r#"
+let describe p : (Int, String) -> String =
+    match p with
+    | (tally, title) -> title
+describe (1, "a")
+"#;
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let cases = vec![
+                // Each name bound by the pattern has the type of its element
+                ((3, 8), "Int", range(3, 7, 12)),
+                ((3, 16), "String", range(3, 14, 19)),
+                // A use of a name bound by the pattern
+                ((3, 26), "String", range(3, 24, 29)),
+            ];
+            for (id, ((line, character), typ, range)) in cases.into_iter().enumerate() {
+                hover(stdin, id as u64, "test", Position { line, character }).await;
+                let hover: Hover = expect_response(&mut *stdout).await;
+                assert_eq!(
+                    hover,
+                    Hover {
+                        contents: HoverContents::Scalar(gluon_string(typ)),
+                        range,
+                    },
+                    "{}:{}",
+                    line,
+                    character
+                );
+            }
+        })
+    });
+}
+
+#[test]
+fn hover_recursive_group() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let src = r#"
+rec
+let ping n : Int -> Int = if n == 0 then 0 else pong (n - 1)
+let pong n : Int -> Int = if n == 0 then 1 else ping (n - 1)
+in
+ping 3
+"#;
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let cases = vec![
+                // A reference to the binding which comes after it in the group
+                ((2, 50), range(2, 48, 52)),
+                // A reference to the binding which comes before it in the group
+                ((3, 50), range(3, 48, 52)),
+            ];
+            for (id, ((line, character), range)) in cases.into_iter().enumerate() {
+                hover(stdin, id as u64, "test", Position { line, character }).await;
+                let hover: Hover = expect_response(&mut *stdout).await;
+                assert_eq!(
+                    hover,
+                    Hover {
+                        contents: HoverContents::Scalar(gluon_string("Int -> Int")),
+                        range,
+                    },
+                    "{}:{}",
+                    line,
+                    character
+                );
+            }
+        })
+    });
+}
+
+#[test]
+fn hover_constraints() {
+    let capabilities = ClientCapabilities {
+        text_document: Some(TextDocumentClientCapabilities {
+            hover: Some(HoverClientCapabilities {
+                dynamic_registration: None,
+                content_format: Some(vec![MarkupKind::Markdown]),
+            }),
+            ..TextDocumentClientCapabilities::default()
+        }),
+        ..ClientCapabilities::default()
+    };
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::initialize(stdin, 1, capabilities).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            let src = r#"
+let { Eq, (==) } = import! std.cmp
+let same ?eq x y : [Eq a] -> a -> a -> Bool = x == y
+same 1 2
+"#;
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let markdown = |value: &str| {
+                HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: value.into(),
+                })
+            };
+
+            // The definition lists the constraints of the type
+            hover(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 2,
+                    character: 5,
+                },
+            )
+            .await;
+            let definition: Hover = expect_response(&mut *stdout).await;
+            assert_eq!(
+                definition.contents,
+                markdown(
+                    "```gluon\nforall a . [std.cmp.Eq a] -> a -> a -> std.types.Bool\n```\n\n\
+                     Constraints:\n\n\
+                     * `std.cmp.Eq a`\n"
+                )
+            );
+
+            // A call also lists the instance which was selected
+            hover(
+                stdin,
+                3,
+                "test",
+                Position {
+                    line: 3,
+                    character: 1,
+                },
+            )
+            .await;
+            let call: Hover = expect_response(stdout).await;
+            assert_eq!(
+                call.contents,
+                markdown(
+                    "```gluon\n[std.cmp.Eq Int] -> Int -> Int -> std.types.Bool\n```\n\n\
+                     Constraints:\n\n\
+                     * `std.cmp.Eq Int` from `eq`\n"
+                )
+            );
+        })
+    });
+}
+
+#[test]
+fn hover_deeply_nested_expression() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            // Checks with room to spare on the server's stack, but overflowed the default stack of
+            // the thread which handles requests
+            let depth = 3000;
+            let src = format!("{}1{}\n", "(".repeat(depth), ")".repeat(depth));
+            support::did_open(stdin, "test", &src).await;
+
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostics.diagnostics, vec![]);
+
+            hover(
+                stdin,
+                2,
+                "test",
+                Position {
+                    line: 0,
+                    character: depth as u32,
+                },
+            )
+            .await;
+            let hover: Hover = expect_response(stdout).await;
+            assert_eq!(
+                hover,
+                Hover {
+                    contents: HoverContents::Scalar(gluon_string("Int")),
+                    range: range(0, depth as u32, depth as u32 + 1),
+                }
+            );
+        })
+    });
+}
@@ -0,0 +1,30 @@
+use std::{
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn shuts_down_when_idle() {
+    let mut child = Command::new("target/debug/gluon_language-server")
+        .args(["--idle-timeout", "1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Keep stdin open so that only the timeout can stop the server
+    let _stdin = child.stdin.take();
+
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(30) {
+        if let Some(status) = child.try_wait().unwrap() {
+            assert!(status.success(), "{}", status);
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    panic!("The server did not shut down");
+}
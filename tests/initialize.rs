@@ -0,0 +1,89 @@
+#[allow(unused)]
+mod support;
+
+use lsp_types::*;
+use serde_json::json;
+
+use crate::support::{expect_response, method_call, write_message};
+
+#[test]
+fn server_info_in_initialize_result() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::initialize(stdin, 1, ClientCapabilities::default()).await;
+            let result: InitializeResult = expect_response(&mut *stdout).await;
+
+            let server_info = result.server_info.expect("serverInfo");
+            assert_eq!(server_info.name, "gluon-language-server");
+            let version = server_info.version.expect("version");
+            assert!(
+                version.starts_with(env!("CARGO_PKG_VERSION")),
+                "{}",
+                version
+            );
+        })
+    });
+}
+
+/// Initializes the server with `general.positionEncodings` and returns the `positionEncoding`
+/// which it responds with
+async fn negotiate_position_encoding<W, R>(
+    stdin: &mut W,
+    stdout: &mut R,
+    position_encodings: Option<serde_json::Value>,
+) -> Option<serde_json::Value>
+where
+    W: ?Sized + tokio::io::AsyncWrite + Unpin,
+    R: ?Sized + tokio::io::AsyncBufRead + Unpin,
+{
+    // `lsp_types` does not know about `positionEncodings` so the capabilities are sent as JSON
+    let capabilities = match position_encodings {
+        Some(position_encodings) => {
+            json!({ "general": { "positionEncodings": position_encodings } })
+        }
+        None => json!({}),
+    };
+    write_message(
+        stdin,
+        method_call(
+            "initialize",
+            1,
+            json!({ "processId": null, "rootUri": null, "capabilities": capabilities }),
+        ),
+    )
+    .await
+    .unwrap();
+    let result: serde_json::Value = expect_response(&mut *stdout).await;
+    result["capabilities"].get("positionEncoding").cloned()
+}
+
+#[test]
+fn position_encoding_picked_from_the_offered_encodings() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let encoding =
+                negotiate_position_encoding(stdin, stdout, Some(json!(["utf-8", "utf-16"]))).await;
+            assert_eq!(encoding, Some(json!("utf-16")));
+        })
+    });
+}
+
+#[test]
+fn no_position_encoding_unless_offered() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let encoding = negotiate_position_encoding(stdin, stdout, None).await;
+            assert_eq!(encoding, None);
+        })
+    });
+}
+
+#[test]
+fn no_position_encoding_when_none_of_the_offered_are_supported() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let encoding = negotiate_position_encoding(stdin, stdout, Some(json!(["utf-8"]))).await;
+            assert_eq!(encoding, None);
+        })
+    });
+}
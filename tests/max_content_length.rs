@@ -0,0 +1,48 @@
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+};
+
+#[test]
+fn skips_messages_over_the_maximum() {
+    let mut child = Command::new("target/debug/gluon_language-server")
+        .args(["--max-content-length", "100"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let too_long = format!(
+        r#"{{"jsonrpc":"2.0","id":1,"method":"gluon/ping","params":"{}"}}"#,
+        "x".repeat(100)
+    );
+    let request = r#"{"jsonrpc":"2.0","id":2,"method":"gluon/ping","params":null}"#;
+    let mut stdin = child.stdin.take().unwrap();
+    for message in &[&too_long[..], request] {
+        write!(
+            stdin,
+            "Content-Length: {}\r\n\r\n{}",
+            message.len(),
+            message
+        )
+        .unwrap();
+    }
+    // The server stops once the input ends
+    drop(stdin);
+
+    let mut output = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    // The id of the skipped message is unknown
+    assert!(output.contains(r#""code":-32600"#), "{}", output);
+    assert!(output.contains(r#""id":null"#), "{}", output);
+    assert!(!output.contains(r#""id":1"#), "{}", output);
+    // The message after it is still handled
+    assert!(output.contains(r#""id":2"#), "{}", output);
+    assert!(output.contains(r#""result""#), "{}", output);
+}
@@ -0,0 +1,80 @@
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+};
+
+fn write_message(stdin: &mut impl Write, message: &str) {
+    write!(
+        stdin,
+        "Content-Length: {}\r\n\r\n{}",
+        message.len(),
+        message
+    )
+    .unwrap();
+}
+
+#[test]
+fn prints_the_time_of_each_phase() {
+    let mut child = Command::new("target/debug/gluon_language-server")
+        .arg("--measure-startup")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = child.stdin.take().unwrap();
+    write_message(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#,
+    );
+    write_message(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+    );
+    write_message(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///test.glu","languageId":"gluon","version":1,"text":"1"}}}"#,
+    );
+
+    // Wait for the first check to finish
+    let mut stdout = child.stdout.take().unwrap();
+    let mut output = Vec::new();
+    let mut buf = [0; 1024];
+    while !String::from_utf8_lossy(&output).contains("textDocument/publishDiagnostics") {
+        let len = stdout.read(&mut buf).unwrap();
+        assert!(len > 0, "{}", String::from_utf8_lossy(&output));
+        output.extend_from_slice(&buf[..len]);
+    }
+    drop(stdin);
+
+    let result = child.wait_with_output().unwrap();
+    assert!(result.status.success());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    for phase in &[
+        "Startup phase",
+        "read settings",
+        "scan module paths",
+        "index project",
+        "first check",
+        "until first check",
+    ] {
+        assert!(stderr.contains(phase), "{}", stderr);
+    }
+    // Only the first check is measured
+    assert_eq!(stderr.matches("first check").count(), 2, "{}", stderr);
+}
+
+#[test]
+fn prints_nothing_by_default() {
+    let output = Command::new("target/debug/gluon_language-server")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(
+        !String::from_utf8_lossy(&output.stderr).contains("Startup phase"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,87 @@
+#[allow(unused)]
+mod support;
+
+use lsp_types::*;
+
+use gluon_language_server::{MemoryLoader, ModuleGraphEdge, ModuleGraphResult};
+
+use crate::support::{expect_notification, expect_response, method_call, write_message};
+
+async fn module_graph<W, R>(stdin: &mut W, stdout: R) -> Vec<(String, String, bool)>
+where
+    W: ?Sized + tokio::io::AsyncWrite + Unpin,
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    write_message(stdin, method_call("gluon/moduleGraph", 1, ()))
+        .await
+        .unwrap();
+    let graph: ModuleGraphResult = expect_response(stdout).await;
+    // Only the file names are compared as the modules are relative to the working directory
+    let file_name = |uri: &Url| {
+        uri.path_segments()
+            .unwrap()
+            .next_back()
+            .unwrap()
+            .to_string()
+    };
+    graph
+        .edges
+        .iter()
+        .map(|ModuleGraphEdge { from, to, cyclic }| (file_name(from), file_name(to), *cyclic))
+        .collect()
+}
+
+fn edge(from: &str, to: &str, cyclic: bool) -> (String, String, bool) {
+    (from.to_string(), to.to_string(), cyclic)
+}
+
+#[test]
+fn module_graph_of_imports() {
+    let loader = MemoryLoader::new();
+    loader.insert("graph_b", "let c = import! graph_c\n{ y = c.x }");
+    loader.insert("graph_c", "let x = 1\n{ x }");
+
+    support::send_rpc_with_loaders(vec![Box::new(loader)], |stdin, stdout| {
+        Box::pin(async move {
+            let text = "let b = import! graph_b\nlet c = import! graph_c\nb.y + c.x";
+            support::did_open(stdin, "graph_a.glu", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            assert_eq!(
+                module_graph(stdin, &mut *stdout).await,
+                vec![
+                    edge("graph_a.glu", "graph_b.glu", false),
+                    edge("graph_a.glu", "graph_c.glu", false),
+                    edge("graph_b.glu", "graph_c.glu", false),
+                ]
+            );
+        })
+    });
+}
+
+#[test]
+fn module_graph_marks_cycles() {
+    let loader = MemoryLoader::new();
+    loader.insert("graph_cycle_b", "let a = import! graph_cycle_a\n2");
+
+    support::send_rpc_with_loaders(vec![Box::new(loader)], |stdin, stdout| {
+        Box::pin(async move {
+            let text = "let b = import! graph_cycle_b\n1";
+            support::did_open(stdin, "graph_cycle_a.glu", text).await;
+
+            // Both modules of the cycle get a diagnostic
+            for _ in 0..2 {
+                let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            }
+
+            assert_eq!(
+                module_graph(stdin, &mut *stdout).await,
+                vec![
+                    edge("graph_cycle_a.glu", "graph_cycle_b.glu", true),
+                    edge("graph_cycle_b.glu", "graph_cycle_a.glu", true),
+                ]
+            );
+        })
+    });
+}
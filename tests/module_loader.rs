@@ -0,0 +1,139 @@
+#[allow(unused)]
+mod support;
+
+use std::fs;
+
+use lsp_types::*;
+
+use gluon_language_server::MemoryLoader;
+
+use crate::support::expect_notification;
+
+#[test]
+fn import_in_memory_module() {
+    let loader = MemoryLoader::new();
+    loader.insert("virtual.module", "{ x = 1 }");
+
+    support::send_rpc_with_loaders(vec![Box::new(loader)], move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let m = import! virtual.module
+m.x #Int+ 2
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let diagnostics: PublishDiagnosticsParams = expect_notification(stdout).await;
+            assert_eq!(diagnostics.diagnostics, vec![]);
+        })
+    });
+}
+
+#[test]
+fn missing_module_is_still_an_error() {
+    support::send_rpc_with_loaders(vec![Box::new(MemoryLoader::new())], move |stdin, stdout| {
+        Box::pin(async move {
+            support::did_open(stdin, "test", "import! virtual.module").await;
+
+            let diagnostics: PublishDiagnosticsParams = expect_notification(stdout).await;
+            assert_eq!(diagnostics.diagnostics.len(), 1);
+        })
+    });
+}
+
+#[test]
+fn open_documents_replace_loaded_modules_until_closed() {
+    let loader = MemoryLoader::new();
+    loader.insert("virtual.module", "{ x = 1 }");
+
+    support::send_rpc_with_loaders(vec![Box::new(loader)], move |stdin, stdout| {
+        Box::pin(async move {
+            let module_uri = support::test_url("virtual/module.glu");
+            support::did_open_uri(stdin, module_uri.clone(), r#"{ x = "" }"#).await;
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostics.diagnostics, vec![]);
+
+            let text = "let m = import! virtual.module\nm.x #Int+ 2";
+            support::did_open(stdin, "test.glu", text).await;
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostics.diagnostics.len(), 1);
+
+            let did_close = support::notification(
+                "textDocument/didClose",
+                DidCloseTextDocumentParams {
+                    text_document: TextDocumentIdentifier { uri: module_uri },
+                },
+            );
+            support::write_message(stdin, did_close).await.unwrap();
+
+            support::did_change(
+                stdin,
+                "test.glu",
+                2,
+                Range {
+                    start: Position::new(1, 10),
+                    end: Position::new(1, 11),
+                },
+                "3",
+            )
+            .await;
+            loop {
+                let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+                if diagnostics.uri == support::test_url("test.glu") {
+                    assert_eq!(diagnostics.version, Some(2));
+                    assert_eq!(diagnostics.diagnostics, vec![]);
+                    break;
+                }
+            }
+        })
+    });
+}
+
+fn tar_gz(files: &[(&str, &str)]) -> Vec<u8> {
+    let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, path, contents.as_bytes())
+            .unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+#[test]
+fn import_from_archive_in_module_paths() {
+    let dir = std::env::temp_dir().join(format!("gluon_archive_import_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let archive = dir.join("lib.tar.gz");
+    fs::write(
+        &archive,
+        tar_gz(&[("zz_archived.glu", "let zz_archived = 1\n{ zz_archived }\n")]),
+    )
+    .unwrap();
+    let settings = serde_json::json!({ "gluon": { "modulePaths": [archive] } });
+
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            support::write_message(
+                stdin,
+                support::notification(
+                    "workspace/didChangeConfiguration",
+                    DidChangeConfigurationParams { settings },
+                ),
+            )
+            .await
+            .unwrap();
+
+            let text = "let { zz_archived } = import! zz_archived\nzz_archived #Int+ 1\n";
+            support::did_open(stdin, "test", text).await;
+
+            let diagnostics: PublishDiagnosticsParams = expect_notification(stdout).await;
+            assert_eq!(diagnostics.diagnostics, vec![]);
+        })
+    });
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,82 @@
+#[allow(unused)]
+mod support;
+
+use lsp_types::*;
+
+use gluon_language_server::{NodeInfoResult, NodeKind};
+
+use crate::support::{expect_notification, expect_response, method_call, write_message};
+
+fn range(line: u32, start: u32, end: u32) -> Range {
+    Range {
+        start: Position {
+            line,
+            character: start,
+        },
+        end: Position {
+            line,
+            character: end,
+        },
+    }
+}
+
+#[test]
+fn node_kinds() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let src = r#"
+let f x : Int -> Int = x
+let { y } = { y = 1 }
+f y
+"#;
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            let expected = vec![
+                (
+                    (1, 4),
+                    NodeKind::Pattern,
+                    range(1, 4, 5),
+                    Some("Int -> Int"),
+                ),
+                ((1, 6), NodeKind::Pattern, range(1, 6, 7), Some("Int")),
+                ((1, 11), NodeKind::Type, range(1, 10, 13), None),
+                (
+                    (1, 21),
+                    NodeKind::Binding,
+                    range(1, 4, 24),
+                    Some("Int -> Int"),
+                ),
+                (
+                    (2, 6),
+                    NodeKind::Pattern,
+                    range(2, 4, 9),
+                    Some("{ y : Int }"),
+                ),
+                ((3, 2), NodeKind::Expression, range(3, 2, 3), Some("Int")),
+            ];
+            for (id, ((line, character), kind, range, typ)) in expected.into_iter().enumerate() {
+                let params = TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: support::test_url("test"),
+                    },
+                    position: Position { line, character },
+                };
+                write_message(stdin, method_call("gluon/nodeInfo", id as u64, params))
+                    .await
+                    .unwrap();
+
+                let info: Option<NodeInfoResult> = expect_response(&mut *stdout).await;
+                assert_eq!(
+                    info,
+                    Some(NodeInfoResult {
+                        kind,
+                        range,
+                        typ: typ.map(String::from),
+                    })
+                );
+            }
+        })
+    });
+}
@@ -0,0 +1,38 @@
+#[allow(unused)]
+mod support;
+
+use crate::support::{expect_message, expect_response, method_call, write_message};
+
+#[test]
+fn shutdown_without_params() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            write_message(stdin, method_call("shutdown", 1, ()))
+                .await
+                .unwrap();
+            let () = expect_response(&mut *stdout).await;
+
+            write_message(stdin, method_call("shutdown", 2, serde_json::json!({})))
+                .await
+                .unwrap();
+            let () = expect_response(&mut *stdout).await;
+        })
+    });
+}
+
+#[test]
+fn null_params_are_invalid_for_methods_with_params() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            write_message(stdin, method_call("textDocument/hover", 1, ()))
+                .await
+                .unwrap();
+            let response = expect_message(&mut *stdout).await;
+            assert_eq!(response["id"], 1);
+            assert_eq!(
+                response["error"]["code"],
+                jsonrpc_core::ErrorCode::InvalidParams.code()
+            );
+        })
+    });
+}
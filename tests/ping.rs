@@ -0,0 +1,30 @@
+#[allow(unused)]
+mod support;
+
+use lsp_types::*;
+
+use gluon_language_server::PingResult;
+
+use crate::support::{expect_response, method_call, write_message};
+
+#[test]
+fn ping_before_and_after_initialize() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            write_message(stdin, method_call("gluon/ping", 1, ()))
+                .await
+                .unwrap();
+            let ping: PingResult = expect_response(&mut *stdout).await;
+            assert!(!ping.ready);
+
+            support::initialize(stdin, 2, ClientCapabilities::default()).await;
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            write_message(stdin, method_call("gluon/ping", 3, ()))
+                .await
+                .unwrap();
+            let ping: PingResult = expect_response(&mut *stdout).await;
+            assert!(ping.ready);
+        })
+    });
+}
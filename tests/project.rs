@@ -0,0 +1,155 @@
+mod support;
+
+use std::{fs, time::Duration};
+
+use tokio::io::AsyncBufReadExt;
+
+use lsp_types::*;
+use serde_json::json;
+
+use crate::support::{
+    expect_message, expect_notification, expect_response, method_call, notification, write_message,
+};
+
+#[test]
+fn index_project_modules() {
+    let root = std::env::temp_dir().join(format!("gluon_index_project_{}", std::process::id()));
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(
+        root.join("src/util.glu"),
+        "let util_fn x : Int -> Int = x\n{ util_fn }\n",
+    )
+    .unwrap();
+    let root_uri = Url::from_directory_path(&root).unwrap();
+    let util_uri = Url::from_file_path(root.join("src/util.glu")).unwrap();
+
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let initialize = method_call(
+                "initialize",
+                1,
+                json!({
+                    "processId": null,
+                    "rootUri": root_uri,
+                    "capabilities": { "window": { "workDoneProgress": true } },
+                }),
+            );
+            write_message(stdin, initialize).await.unwrap();
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            write_message(stdin, notification("initialized", InitializedParams {}))
+                .await
+                .unwrap();
+
+            let create = expect_message(&mut *stdout).await;
+            assert_eq!(create["method"], "window/workDoneProgress/create");
+            let token = create["params"]["token"].clone();
+            // Nothing is reported until the client has created the token
+            let pending = tokio::time::timeout(Duration::from_millis(200), stdout.fill_buf());
+            assert!(
+                pending.await.is_err(),
+                "Reported before the token was created"
+            );
+            write_message(
+                stdin,
+                json!({ "jsonrpc": "2.0", "id": create["id"], "result": null }),
+            )
+            .await
+            .unwrap();
+
+            let mut kinds = Vec::new();
+            loop {
+                let progress = expect_message(&mut *stdout).await;
+                assert_eq!(progress["method"], "$/progress");
+                assert_eq!(progress["params"]["token"], token);
+                let kind = progress["params"]["value"]["kind"]
+                    .as_str()
+                    .unwrap()
+                    .to_string();
+                kinds.push(kind.clone());
+                if kind == "end" {
+                    break;
+                }
+            }
+            assert_eq!(kinds, ["begin", "report", "end"]);
+
+            let symbols = method_call(
+                "workspace/symbol",
+                2,
+                WorkspaceSymbolParams {
+                    query: "util_fn".into(),
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                },
+            );
+            write_message(stdin, symbols).await.unwrap();
+            let symbols: Vec<SymbolInformation> = expect_response(&mut *stdout).await;
+            assert!(
+                symbols
+                    .iter()
+                    .any(|symbol| symbol.name == "util_fn" && symbol.location.uri == util_uri),
+                "{:#?}",
+                symbols
+            );
+        })
+    });
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn project_settings_file() {
+    let root = std::env::temp_dir().join(format!("gluon_project_settings_{}", std::process::id()));
+    fs::create_dir_all(root.join("deps")).unwrap();
+    fs::write(
+        root.join("deps/zz_extra.glu"),
+        "let zz_extra = 1\n{ zz_extra }\n",
+    )
+    .unwrap();
+    fs::write(root.join(".gluonrc"), r#"{ "modulePaths": ["deps"] }"#).unwrap();
+    let root_uri = Url::from_directory_path(&root).unwrap();
+    let settings_file = root.join(".gluonrc");
+    let settings_uri = Url::from_file_path(&settings_file).unwrap();
+
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let initialize = method_call(
+                "initialize",
+                1,
+                json!({ "processId": null, "rootUri": root_uri, "capabilities": {} }),
+            );
+            write_message(stdin, initialize).await.unwrap();
+            let _: InitializeResult = expect_response(&mut *stdout).await;
+
+            support::did_open(
+                stdin,
+                "test",
+                "let { zz_extra } = import! zz_extra\nzz_extra\n",
+            )
+            .await;
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostics.diagnostics, vec![]);
+
+            // Removing the module path from the file makes the import fail
+            fs::write(settings_file, r#"{ "modulePaths": [] }"#).unwrap();
+            write_message(
+                stdin,
+                notification(
+                    "workspace/didChangeWatchedFiles",
+                    DidChangeWatchedFilesParams {
+                        changes: vec![FileEvent {
+                            uri: settings_uri,
+                            typ: FileChangeType::Changed,
+                        }],
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+            let diagnostics: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+            assert_eq!(diagnostics.diagnostics.len(), 1, "{:#?}", diagnostics);
+        })
+    });
+
+    fs::remove_dir_all(&root).unwrap();
+}
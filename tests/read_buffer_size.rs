@@ -0,0 +1,47 @@
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+};
+
+#[test]
+fn reads_messages_larger_than_the_buffer() {
+    let mut child = Command::new("target/debug/gluon_language-server")
+        .args(["--read-buffer-size", "1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let request = r#"{"jsonrpc":"2.0","id":1,"method":"gluon/ping","params":null}"#;
+    let mut stdin = child.stdin.take().unwrap();
+    write!(
+        stdin,
+        "Content-Length: {}\r\n\r\n{}",
+        request.len(),
+        request
+    )
+    .unwrap();
+    // The server stops once the input ends
+    drop(stdin);
+
+    let mut output = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    assert!(output.contains(r#""id":1"#), "{}", output);
+    assert!(output.contains(r#""result""#), "{}", output);
+}
+
+#[test]
+fn rejects_an_empty_buffer() {
+    let output = Command::new("target/debug/gluon_language-server")
+        .args(["--read-buffer-size", "0"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
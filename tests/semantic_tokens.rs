@@ -0,0 +1,166 @@
+#[macro_use]
+extern crate pretty_assertions;
+
+#[allow(unused)]
+mod support;
+
+use tokio::io::AsyncWrite;
+
+use lsp_types::*;
+
+use crate::support::{did_change, expect_notification, expect_response};
+
+async fn semantic_tokens_full<W>(stdin: &mut W, id: u64, uri: &str)
+where
+    W: ?Sized + AsyncWrite + std::marker::Unpin,
+{
+    let msg = support::method_call(
+        "textDocument/semanticTokens/full",
+        id,
+        SemanticTokensParams {
+            text_document: TextDocumentIdentifier {
+                uri: support::test_url(uri),
+            },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        },
+    );
+
+    support::write_message(stdin, msg).await.unwrap();
+}
+
+async fn semantic_tokens_delta<W>(stdin: &mut W, id: u64, uri: &str, previous_result_id: &str)
+where
+    W: ?Sized + AsyncWrite + std::marker::Unpin,
+{
+    let msg = support::method_call(
+        "textDocument/semanticTokens/full/delta",
+        id,
+        SemanticTokensDeltaParams {
+            text_document: TextDocumentIdentifier {
+                uri: support::test_url(uri),
+            },
+            previous_result_id: previous_result_id.into(),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        },
+    );
+
+    support::write_message(stdin, msg).await.unwrap();
+}
+
+fn token(delta_line: u32, delta_start: u32, length: u32, token_type: u32) -> SemanticToken {
+    SemanticToken {
+        delta_line,
+        delta_start,
+        length,
+        token_type,
+        token_modifiers_bitset: 0,
+    }
+}
+
+#[test]
+fn full_and_delta() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = r#"
+let id x = x
+id 1
+"#;
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            semantic_tokens_full(stdin, 1, "test").await;
+
+            let tokens: SemanticTokens = expect_response(&mut *stdout).await;
+            assert_eq!(
+                tokens.data,
+                vec![
+                    token(1, 4, 2, 1),
+                    token(0, 3, 1, 2),
+                    token(0, 4, 1, 0),
+                    token(1, 0, 2, 1),
+                ]
+            );
+            let result_id = tokens.result_id.expect("result_id");
+
+            did_change(
+                stdin,
+                "test",
+                2,
+                Range {
+                    start: Position {
+                        line: 2,
+                        character: 3,
+                    },
+                    end: Position {
+                        line: 2,
+                        character: 4,
+                    },
+                },
+                "id",
+            )
+            .await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            semantic_tokens_delta(stdin, 2, "test", &result_id).await;
+
+            let delta: SemanticTokensDelta = expect_response(&mut *stdout).await;
+            assert_ne!(delta.result_id, Some(result_id));
+            assert_eq!(
+                delta.edits,
+                vec![SemanticTokensEdit {
+                    start: 20,
+                    delete_count: 0,
+                    data: Some(vec![token(0, 3, 2, 1)]),
+                }]
+            );
+
+            // An unknown result falls back to sending all tokens
+            semantic_tokens_delta(stdin, 3, "test", "unknown").await;
+
+            let tokens: SemanticTokens = expect_response(stdout).await;
+            assert_eq!(tokens.data.len(), 5);
+        })
+    });
+}
+
+#[test]
+fn close_forgets_the_previous_tokens() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let text = "let id x = x\nid 1\n";
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            semantic_tokens_full(stdin, 1, "test").await;
+
+            let tokens: SemanticTokens = expect_response(&mut *stdout).await;
+            let result_id = tokens.result_id.expect("result_id");
+
+            let did_close = support::notification(
+                "textDocument/didClose",
+                DidCloseTextDocumentParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: support::test_url("test"),
+                    },
+                },
+            );
+            support::write_message(stdin, did_close).await.unwrap();
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            support::did_open(stdin, "test", text).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            // The result from before the document was closed is gone so all tokens are sent
+            semantic_tokens_delta(stdin, 2, "test", &result_id).await;
+
+            let tokens: SemanticTokens = expect_response(stdout).await;
+            assert_eq!(tokens.data.len(), 4);
+        })
+    });
+}
@@ -24,13 +24,15 @@ use {
     lsp_types::*,
     serde::{de::DeserializeOwned, Serialize},
     serde_json::{from_str, from_value, ser::Serializer, to_value, Value},
-    tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufReader},
-    tokio_util::codec::{Decoder, FramedRead},
+    tokio::io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    },
+    tokio_util::codec::Decoder,
     url::Url,
 };
 
 use gluon::ThreadExt;
-use gluon_language_server::rpc::LanguageServerDecoder;
+use gluon_language_server::ModuleLoader;
 
 pub fn test_url(uri: &str) -> Url {
     Url::from_file_path(&env::current_dir().unwrap().join(uri)).unwrap()
@@ -90,6 +92,30 @@ where
     })
 }
 
+pub async fn initialize<W>(stdin: &mut W, id: u64, capabilities: ClientCapabilities)
+where
+    W: ?Sized + AsyncWrite + Unpin,
+{
+    #[allow(deprecated)]
+    let initialize = method_call(
+        "initialize",
+        id,
+        InitializeParams {
+            process_id: None,
+            root_path: None,
+            root_uri: None,
+            initialization_options: None,
+            capabilities,
+            trace: None,
+            workspace_folders: None,
+            client_info: None,
+            locale: None,
+        },
+    );
+
+    write_message(stdin, initialize).await.unwrap();
+}
+
 pub async fn did_open_uri<W: ?Sized>(stdin: &mut W, uri: Url, text: &str)
 where
     W: AsyncWrite + Unpin,
@@ -155,20 +181,56 @@ pub async fn did_change_event<W: ?Sized>(
     write_message(stdin, hover).await.unwrap();
 }
 
+/// Reads a single message without reading past its end so that no messages are lost if the server
+/// sends several at once
+async fn read_message(output: &mut (impl AsyncBufRead + Unpin)) -> String {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        output.read_line(&mut line).await.unwrap();
+        assert!(!line.is_empty(), "Expected a response");
+        match line.trim_end() {
+            "" if content_length.is_some() => break,
+            line => {
+                // Header names are case insensitive
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("Content-Length") {
+                        content_length = Some(value.trim().parse().unwrap());
+                    }
+                }
+            }
+        }
+    }
+    let content_length = content_length.unwrap();
+    // The same cap as the server's so that a bad header fails the test instead of allocating
+    assert!(
+        content_length <= gluon_language_server::rpc::DEFAULT_MAX_CONTENT_LENGTH,
+        "Content-Length {} is too long",
+        content_length
+    );
+    let mut body = vec![0; content_length];
+    output.read_exact(&mut body).await.unwrap();
+    String::from_utf8(body).unwrap()
+}
+
 async fn read_until<T>(
-    output: impl AsyncBufRead + Unpin,
+    mut output: impl AsyncBufRead + Unpin,
     mut f: impl FnMut(String) -> Option<T>,
 ) -> T {
-    let stream = FramedRead::new(output, LanguageServerDecoder::new()).try_filter_map(|s| {
-        let x = f(s);
-        async { Ok(x) }
-    });
-    futures::pin_mut!(stream);
-    stream
-        .next()
-        .await
-        .expect("Expected a response")
-        .expect("Success")
+    loop {
+        if let Some(x) = f(read_message(&mut output).await) {
+            return x;
+        }
+    }
+}
+
+/// Reads the next message of any kind, including requests sent by the server
+pub async fn expect_message<R>(mut output: R) -> Value
+where
+    R: AsyncBufRead + Unpin,
+{
+    let json = read_message(&mut output).await;
+    from_str(&json).unwrap_or_else(|err| panic!("{}\n{}", err, json))
 }
 
 pub async fn expect_response<R, T>(output: R) -> T
@@ -231,11 +293,20 @@ pub fn run_no_panic_catch<F>(fut: F)
 where
     F: Future<Output = ()> + Send + 'static,
 {
-    tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
+    // Like the server binary, a server started in this process handles requests on the thread
+    // which runs it and needs a large stack for deeply nested modules
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(fut)
+        })
         .unwrap()
-        .block_on(fut)
+        .join()
+        .unwrap_or_else(|err| std::panic::resume_unwind(err))
 }
 
 struct ServerHandle {
@@ -243,7 +314,7 @@ struct ServerHandle {
     stdout: Box<dyn AsyncBufRead + Send + Unpin>,
 }
 
-fn start_local() -> ServerHandle {
+fn start_local(loaders: Vec<Box<dyn ModuleLoader>>) -> ServerHandle {
     let (mut stdin_write, stdin_read) = tokio::io::duplex(4096);
     let (stdout_write, stdout_read) = tokio::io::duplex(4096);
     let stdout_read = BufReader::new(stdout_read);
@@ -251,8 +322,13 @@ fn start_local() -> ServerHandle {
     tokio::spawn(async move {
         let thread = gluon::new_vm_async().await;
         thread.get_database_mut().set_implicit_prelude(false);
-        if let Err(err) =
-            ::gluon_language_server::Server::start(thread, stdin_read, stdout_write).await
+        if let Err(err) = ::gluon_language_server::Server::start_with_loaders(
+            thread,
+            loaders,
+            stdin_read,
+            stdout_write,
+        )
+        .await
         {
             panic!("{}", err)
         }
@@ -289,31 +365,54 @@ where
         + 'static,
 {
     run_no_panic_catch(async move {
-        let ServerHandle {
-            mut stdin,
-            mut stdout,
-        } = if env::var("GLUON_TEST_LOCAL_SERVER").is_ok() {
-            start_local()
+        let server = if env::var("GLUON_TEST_LOCAL_SERVER").is_ok() {
+            start_local(Vec::new())
         } else {
             start_remote()
         };
+        run_server(server, f).await
+    })
+}
 
-        {
-            f(&mut stdin, &mut stdout).await;
+/// Like `send_rpc` but always runs the server in this process so that it can use `loaders`
+pub fn send_rpc_with_loaders<F>(loaders: Vec<Box<dyn ModuleLoader>>, f: F)
+where
+    F: for<'a> FnOnce(
+            &'a mut (dyn AsyncWrite + Send + Unpin),
+            &'a mut (dyn AsyncBufRead + Send + Unpin),
+        ) -> futures::future::BoxFuture<'a, ()>
+        + Send
+        + ::std::panic::UnwindSafe
+        + 'static,
+{
+    run_no_panic_catch(async move { run_server(start_local(loaders), f).await })
+}
 
-            write_message(&mut stdin, method_call("shutdown", 1_000_000, ()))
-                .await
-                .unwrap();
+async fn run_server<F>(server: ServerHandle, f: F)
+where
+    F: for<'a> FnOnce(
+        &'a mut (dyn AsyncWrite + Send + Unpin),
+        &'a mut (dyn AsyncBufRead + Send + Unpin),
+    ) -> futures::future::BoxFuture<'a, ()>,
+{
+    let ServerHandle {
+        mut stdin,
+        mut stdout,
+    } = server;
 
-            let () = expect_response(&mut stdout).await;
+    f(&mut stdin, &mut stdout).await;
 
-            let exit = Call::Notification(Notification {
-                jsonrpc: Some(Version::V2),
-                method: "exit".into(),
-                params: Params::None,
-            });
-            write_message(&mut stdin, exit).await.unwrap();
-            drop(stdin);
-        }
-    })
+    write_message(&mut stdin, method_call("shutdown", 1_000_000, ()))
+        .await
+        .unwrap();
+
+    let () = expect_response(&mut stdout).await;
+
+    let exit = Call::Notification(Notification {
+        jsonrpc: Some(Version::V2),
+        method: "exit".into(),
+        params: Params::None,
+    });
+    write_message(&mut stdin, exit).await.unwrap();
+    drop(stdin);
 }
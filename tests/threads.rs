@@ -0,0 +1,40 @@
+use std::{
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn runs_with_a_single_thread() {
+    let mut child = Command::new("target/debug/gluon_language-server")
+        .args(["--threads", "1", "--idle-timeout", "1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Keep stdin open so that only the timeout can stop the server
+    let _stdin = child.stdin.take();
+
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(30) {
+        if let Some(status) = child.try_wait().unwrap() {
+            assert!(status.success(), "{}", status);
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    panic!("The server did not shut down");
+}
+
+#[test]
+fn rejects_zero_threads() {
+    let output = Command::new("target/debug/gluon_language-server")
+        .args(["--threads", "0"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
@@ -0,0 +1,35 @@
+#[allow(unused)]
+mod support;
+
+use lsp_types::*;
+
+use gluon_language_server::TypeAtParams;
+
+use crate::support::{expect_notification, expect_response, method_call, write_message};
+
+#[test]
+fn type_at_offset() {
+    support::send_rpc(move |stdin, stdout| {
+        Box::pin(async move {
+            let src = "let f x : Int -> Int = x\nf 1\n";
+            support::did_open(stdin, "test", src).await;
+
+            let _: PublishDiagnosticsParams = expect_notification(&mut *stdout).await;
+
+            // `f` and `1` in `f 1`, and the end of the module
+            let expected = vec![(25, Some("Int -> Int")), (27, Some("Int")), (29, None)];
+            for (id, (offset, typ)) in expected.into_iter().enumerate() {
+                let params = TypeAtParams {
+                    uri: support::test_url("test"),
+                    offset,
+                };
+                write_message(stdin, method_call("gluon/typeAt", id as u64, params))
+                    .await
+                    .unwrap();
+
+                let found: Option<String> = expect_response(&mut *stdout).await;
+                assert_eq!(found.as_deref(), typ, "At offset {}", offset);
+            }
+        })
+    });
+}